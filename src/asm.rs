@@ -0,0 +1,499 @@
+use std::collections::HashMap;
+
+use crate::memory::NesError;
+use crate::opmap::{AddressingMode, OPCODE_INFO};
+
+/*
+    Small 6502 assembler for writing test programs as text instead of opcode bytes.
+    One statement per line, ';' starts a comment:
+        loop:   lda #$10        ; labels end with ':' and may share a line with an instruction
+                sta $0200,x
+                bne loop        ; branch targets are addresses or labels
+                .byte $01, 2, %11
+                .word loop
+    Numbers are '$hex', '%binary' or decimal, '<' and '>' select the low or high byte.
+    Operands of one or two hex digits use zero page addressing when the instruction has it,
+    labels and four digit operands always use absolute addressing.
+    Official opcodes are preferred, unofficial mnemonics (LAX, DCP, KIL, ...) are accepted too.
+*/
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Value {
+    Number(u16),
+    Label(String),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Part {
+    Full,
+    Low,
+    High,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Expression {
+    value: Value,
+    part: Part,
+    // fits in the zero page without knowing any label addresses
+    zero_page: bool,
+}
+
+impl Expression {
+    fn parse(text: &str) -> Result<Self, &'static str> {
+        let text = text.trim();
+        let (part, text) = match text.chars().next() {
+            Some('<') => (Part::Low, &text[1..]),
+            Some('>') => (Part::High, &text[1..]),
+            _ => (Part::Full, text),
+        };
+        let number = |digits: &str, radix: u32| {
+            u16::from_str_radix(digits, radix).map_err(|_| "invalid number")
+        };
+        let (value, short) = if let Some(hex) = text.strip_prefix('$') {
+            (Value::Number(number(hex, 16)?), hex.len() <= 2)
+        } else if let Some(binary) = text.strip_prefix('%') {
+            (Value::Number(number(binary, 2)?), binary.len() <= 8)
+        } else if text.starts_with(|c: char| c.is_ascii_digit()) {
+            let value = number(text, 10)?;
+            (Value::Number(value), value <= 0xff)
+        } else if !text.is_empty() && text.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            (Value::Label(text.to_string()), false)
+        } else {
+            return Err("invalid operand");
+        };
+        Ok(Expression { value, part, zero_page: short || part != Part::Full })
+    }
+
+    fn resolve(&self, labels: &HashMap<String, u16>) -> Result<u16, &'static str> {
+        let value = match &self.value {
+            Value::Number(value) => *value,
+            Value::Label(name) => *labels.get(name).ok_or("undefined label")?,
+        };
+        Ok(match self.part {
+            Part::Full => value,
+            Part::Low => value & 0xff,
+            Part::High => value >> 8,
+        })
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Statement {
+    Instruction { opcode: u8, operand: Option<Expression> },
+    Bytes(Vec<Expression>),
+    Words(Vec<Expression>),
+}
+
+impl Statement {
+    fn len(&self) -> usize {
+        match self {
+            Statement::Instruction { opcode, .. } => OPCODE_INFO[*opcode as usize].length as usize,
+            Statement::Bytes(values) => values.len(),
+            Statement::Words(values) => values.len() * 2,
+        }
+    }
+}
+
+// opcode for a mnemonic and addressing mode, official opcodes first
+fn find_opcode(mnemonic: &str, mode: AddressingMode) -> Option<u8> {
+    let matches = |opcode: &usize| {
+        let info = &OPCODE_INFO[*opcode];
+        info.mnemonic == mnemonic && info.mode == mode
+    };
+    (0..256).filter(matches).find(|&opcode| OPCODE_INFO[opcode].official)
+        .or_else(|| (0..256).find(matches))
+        .map(|opcode| opcode as u8)
+}
+
+fn parse_list(operands: &str) -> Result<Vec<Expression>, &'static str> {
+    operands.split(',').map(Expression::parse).collect()
+}
+
+fn parse_instruction(mnemonic: &str, operand: &str) -> Result<Statement, &'static str> {
+    let mnemonic = mnemonic.to_ascii_uppercase();
+    let operand = operand.replace(' ', "");
+    let upper = operand.to_ascii_uppercase();
+    let opcode = |mode| find_opcode(&mnemonic, mode);
+    // zero page form if the operand allows it and the instruction has one, absolute otherwise
+    let sized = |expression: &str, zero_page: AddressingMode, absolute: AddressingMode| {
+        let expression = Expression::parse(expression)?;
+        let opcode = match expression.zero_page {
+            true => opcode(zero_page).or_else(|| opcode(absolute)),
+            false => opcode(absolute),
+        };
+        Ok::<_, &'static str>((opcode, Some(expression)))
+    };
+
+    let (opcode, operand) = if operand.is_empty() {
+        (opcode(AddressingMode::Implied).or_else(|| opcode(AddressingMode::Accumulator)), None)
+    } else if upper == "A" {
+        (opcode(AddressingMode::Accumulator), None)
+    } else if let Some(immediate) = operand.strip_prefix('#') {
+        (opcode(AddressingMode::Immediate), Some(Expression::parse(immediate)?))
+    } else if upper.starts_with('(') && upper.ends_with(",X)") {
+        (opcode(AddressingMode::ZeroPageXIndirect), Some(Expression::parse(&operand[1..operand.len() - 3])?))
+    } else if upper.starts_with('(') && upper.ends_with("),Y") {
+        (opcode(AddressingMode::ZeroPageYIndirect), Some(Expression::parse(&operand[1..operand.len() - 3])?))
+    } else if upper.starts_with('(') && upper.ends_with(')') {
+        (opcode(AddressingMode::Indirect), Some(Expression::parse(&operand[1..operand.len() - 1])?))
+    } else if upper.ends_with(",X") {
+        sized(&operand[..operand.len() - 2], AddressingMode::ZeroPageX, AddressingMode::AbsoluteX)?
+    } else if upper.ends_with(",Y") {
+        sized(&operand[..operand.len() - 2], AddressingMode::ZeroPageY, AddressingMode::AbsoluteY)?
+    } else if let Some(opcode) = opcode(AddressingMode::Relative) {
+        (Some(opcode), Some(Expression::parse(&operand)?))
+    } else {
+        sized(&operand, AddressingMode::ZeroPage, AddressingMode::Absolute)?
+    };
+    match opcode {
+        Some(opcode) => Ok(Statement::Instruction { opcode, operand }),
+        None if OPCODE_INFO.iter().any(|info| info.mnemonic == mnemonic) => Err("addressing mode not supported by instruction"),
+        None => Err("unknown mnemonic"),
+    }
+}
+
+// assemble 'source' for a program located at 'origin'
+pub fn assemble(source: &str, origin: u16) -> Result<Vec<u8>, NesError> {
+    // first pass: parse every line and place the labels
+    let mut statements = Vec::new();
+    let mut labels = HashMap::new();
+    let mut address = origin as usize;
+    for (index, line) in source.lines().enumerate() {
+        let error = |reason| NesError::Assembly { line: index + 1, reason };
+        let mut line = line.split(';').next().unwrap_or_default().trim();
+        while let Some((label, rest)) = line.split_once(':') {
+            let label = label.trim();
+            if label.is_empty() || !label.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+                return Err(error("invalid label"));
+            }
+            if labels.insert(label.to_string(), address as u16).is_some() {
+                return Err(error("label defined twice"));
+            }
+            line = rest.trim();
+        }
+        if line.is_empty() {
+            continue;
+        }
+        let (mnemonic, operand) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let statement = match mnemonic.to_ascii_lowercase().as_str() {
+            ".byte" | ".db" => Statement::Bytes(parse_list(operand).map_err(error)?),
+            ".word" | ".dw" => Statement::Words(parse_list(operand).map_err(error)?),
+            _ => parse_instruction(mnemonic, operand).map_err(error)?,
+        };
+        address += statement.len();
+        if address > 0x10000 {
+            return Err(error("program runs past $FFFF"));
+        }
+        statements.push((index + 1, statement));
+    }
+
+    // second pass: resolve operands and emit the bytes
+    let mut program = Vec::with_capacity(address - origin as usize);
+    for (line, statement) in statements {
+        let error = |reason| NesError::Assembly { line, reason };
+        let resolve = |expression: &Expression| expression.resolve(&labels).map_err(error);
+        let byte = |expression: &Expression| {
+            let value = resolve(expression)?;
+            u8::try_from(value).map_err(|_| error("value doesn't fit in a byte"))
+        };
+        match statement {
+            Statement::Bytes(values) => for value in &values {
+                program.push(byte(value)?);
+            },
+            Statement::Words(values) => for value in &values {
+                program.extend_from_slice(&resolve(value)?.to_le_bytes());
+            },
+            Statement::Instruction { opcode, operand } => {
+                let info = OPCODE_INFO[opcode as usize];
+                let next = origin.wrapping_add(program.len() as u16).wrapping_add(info.length as u16);
+                program.push(opcode);
+                match (info.mode, operand) {
+                    (_, None) => (),
+                    (AddressingMode::Relative, Some(target)) => {
+                        let offset = resolve(&target)?.wrapping_sub(next) as i16;
+                        let offset = i8::try_from(offset).map_err(|_| error("branch target out of range"))?;
+                        program.push(offset as u8);
+                    }
+                    (_, Some(operand)) if info.length == 2 => program.push(byte(&operand)?),
+                    (_, Some(operand)) => program.extend_from_slice(&resolve(&operand)?.to_le_bytes()),
+                }
+            }
+        }
+    }
+    Ok(program)
+}
+
+/*
+    Program builder, the same programs built from method calls:
+        Program::new().label("loop").dex().bne("loop").lda_imm(0x10).sta_abs(0x0200).kil().build()
+    Methods are named after the mnemonic and addressing mode ('_imm', '_zp', '_zpx', '_zpy', '_abs',
+    '_absx', '_absy', '_ind', '_indx', '_indy', '_a' for the accumulator), branches and 'jmp_to'/'jsr_to'
+    take label names which are resolved by 'build'. Programs start at $8000 like 'CPU::with_program'.
+*/
+#[derive(Debug, Clone)]
+pub struct Program {
+    origin: u16,
+    bytes: Vec<u8>,
+    labels: HashMap<String, u16>,
+    // operands waiting for a label: byte offset, label name and whether it's a branch offset
+    fixups: Vec<(usize, String, bool)>,
+}
+
+macro_rules! program_ops {
+    (implied => $($name:ident $mnemonic:literal),* $(,)?) => {
+        $(pub fn $name(self) -> Self {
+            self.op($mnemonic, AddressingMode::Implied, &[])
+        })*
+    };
+    (accumulator => $($name:ident $mnemonic:literal),* $(,)?) => {
+        $(pub fn $name(self) -> Self {
+            self.op($mnemonic, AddressingMode::Accumulator, &[])
+        })*
+    };
+    (relative => $($name:ident $mnemonic:literal),* $(,)?) => {
+        $(pub fn $name(self, label: &str) -> Self {
+            self.op_label($mnemonic, AddressingMode::Relative, label)
+        })*
+    };
+    ($mode:ident, $operand:ty => $($name:ident $mnemonic:literal),* $(,)?) => {
+        $(pub fn $name(self, operand: $operand) -> Self {
+            self.op($mnemonic, AddressingMode::$mode, &operand.to_le_bytes())
+        })*
+    };
+}
+
+impl Program {
+    pub fn new() -> Self {
+        Self::at(0x8000)
+    }
+
+    pub fn at(origin: u16) -> Self {
+        Program { origin, bytes: Vec::new(), labels: HashMap::new(), fixups: Vec::new() }
+    }
+
+    // address of the next byte
+    pub fn address(&self) -> u16 {
+        self.origin.wrapping_add(self.bytes.len() as u16)
+    }
+
+    // name the address of the next instruction
+    pub fn label(mut self, name: &str) -> Self {
+        let address = self.address();
+        assert!(self.labels.insert(name.to_string(), address).is_none(), "label '{}' defined twice", name);
+        self
+    }
+
+    pub fn bytes(mut self, bytes: &[u8]) -> Self {
+        self.bytes.extend_from_slice(bytes);
+        self
+    }
+
+    fn op(mut self, mnemonic: &str, mode: AddressingMode, operand: &[u8]) -> Self {
+        let opcode = find_opcode(mnemonic, mode).unwrap_or_else(|| panic!("no {:?} form of {}", mode, mnemonic));
+        self.bytes.push(opcode);
+        self.bytes.extend_from_slice(operand);
+        self
+    }
+
+    fn op_label(mut self, mnemonic: &str, mode: AddressingMode, label: &str) -> Self {
+        let branch = mode == AddressingMode::Relative;
+        self.fixups.push((self.bytes.len() + 1, label.to_string(), branch));
+        let operand: &[u8] = if branch {&[0]} else {&[0, 0]};
+        self.op(mnemonic, mode, operand)
+    }
+
+    pub fn jmp_to(self, label: &str) -> Self {
+        self.op_label("JMP", AddressingMode::Absolute, label)
+    }
+
+    pub fn jsr_to(self, label: &str) -> Self {
+        self.op_label("JSR", AddressingMode::Absolute, label)
+    }
+
+    // resolve the labels, panics if one is undefined or a branch is out of range
+    pub fn build(mut self) -> Vec<u8> {
+        for (offset, label, branch) in std::mem::take(&mut self.fixups) {
+            let target = *self.labels.get(&label).unwrap_or_else(|| panic!("undefined label '{}'", label));
+            if branch {
+                let next = self.origin.wrapping_add(offset as u16 + 1);
+                let relative = i8::try_from(target.wrapping_sub(next) as i16)
+                    .unwrap_or_else(|_| panic!("branch to '{}' out of range", label));
+                self.bytes[offset] = relative as u8;
+            } else {
+                self.bytes[offset..offset + 2].copy_from_slice(&target.to_le_bytes());
+            }
+        }
+        self.bytes
+    }
+
+    program_ops!(implied =>
+        brk "BRK", clc "CLC", cld "CLD", cli "CLI", clv "CLV", dex "DEX", dey "DEY", inx "INX", iny "INY",
+        kil "KIL", nop "NOP", pha "PHA", php "PHP", pla "PLA", plp "PLP", rti "RTI", rts "RTS", sec "SEC",
+        sed "SED", sei "SEI", tax "TAX", tay "TAY", tsx "TSX", txa "TXA", txs "TXS", tya "TYA",
+    );
+    program_ops!(relative =>
+        bcc "BCC", bcs "BCS", beq "BEQ", bmi "BMI", bne "BNE", bpl "BPL", bvc "BVC", bvs "BVS",
+    );
+    program_ops!(accumulator => asl_a "ASL", lsr_a "LSR", rol_a "ROL", ror_a "ROR");
+    program_ops!(Immediate, u8 =>
+        adc_imm "ADC", and_imm "AND", cmp_imm "CMP", cpx_imm "CPX", cpy_imm "CPY", eor_imm "EOR",
+        lda_imm "LDA", ldx_imm "LDX", ldy_imm "LDY", ora_imm "ORA", sbc_imm "SBC",
+    );
+    program_ops!(ZeroPage, u8 =>
+        adc_zp "ADC", and_zp "AND", asl_zp "ASL", bit_zp "BIT", cmp_zp "CMP", cpx_zp "CPX", cpy_zp "CPY",
+        dec_zp "DEC", eor_zp "EOR", inc_zp "INC", lda_zp "LDA", ldx_zp "LDX", ldy_zp "LDY", lsr_zp "LSR",
+        ora_zp "ORA", rol_zp "ROL", ror_zp "ROR", sbc_zp "SBC", sta_zp "STA", stx_zp "STX", sty_zp "STY",
+    );
+    program_ops!(ZeroPageX, u8 =>
+        adc_zpx "ADC", and_zpx "AND", asl_zpx "ASL", cmp_zpx "CMP", dec_zpx "DEC", eor_zpx "EOR",
+        inc_zpx "INC", lda_zpx "LDA", ldy_zpx "LDY", lsr_zpx "LSR", ora_zpx "ORA", rol_zpx "ROL",
+        ror_zpx "ROR", sbc_zpx "SBC", sta_zpx "STA", sty_zpx "STY",
+    );
+    program_ops!(ZeroPageY, u8 => ldx_zpy "LDX", stx_zpy "STX");
+    program_ops!(Absolute, u16 =>
+        adc_abs "ADC", and_abs "AND", asl_abs "ASL", bit_abs "BIT", cmp_abs "CMP", cpx_abs "CPX",
+        cpy_abs "CPY", dec_abs "DEC", eor_abs "EOR", inc_abs "INC", jmp_abs "JMP", jsr_abs "JSR",
+        lda_abs "LDA", ldx_abs "LDX", ldy_abs "LDY", lsr_abs "LSR", ora_abs "ORA", rol_abs "ROL",
+        ror_abs "ROR", sbc_abs "SBC", sta_abs "STA", stx_abs "STX", sty_abs "STY",
+    );
+    program_ops!(AbsoluteX, u16 =>
+        adc_absx "ADC", and_absx "AND", asl_absx "ASL", cmp_absx "CMP", dec_absx "DEC", eor_absx "EOR",
+        inc_absx "INC", lda_absx "LDA", ldy_absx "LDY", lsr_absx "LSR", ora_absx "ORA", rol_absx "ROL",
+        ror_absx "ROR", sbc_absx "SBC", sta_absx "STA",
+    );
+    program_ops!(AbsoluteY, u16 =>
+        adc_absy "ADC", and_absy "AND", cmp_absy "CMP", eor_absy "EOR", lda_absy "LDA", ldx_absy "LDX",
+        ora_absy "ORA", sbc_absy "SBC", sta_absy "STA",
+    );
+    program_ops!(Indirect, u16 => jmp_ind "JMP");
+    program_ops!(ZeroPageXIndirect, u8 =>
+        adc_indx "ADC", and_indx "AND", cmp_indx "CMP", eor_indx "EOR", lda_indx "LDA", ora_indx "ORA",
+        sbc_indx "SBC", sta_indx "STA",
+    );
+    program_ops!(ZeroPageYIndirect, u8 =>
+        adc_indy "ADC", and_indy "AND", cmp_indy "CMP", eor_indy "EOR", lda_indy "LDA", ora_indy "ORA",
+        sbc_indy "SBC", sta_indy "STA",
+    );
+}
+
+impl Default for Program {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::disasm::disassemble;
+
+    #[test]
+    fn test_assemble() {
+        let source = "
+            start:  ldx #$03        ; count down
+            loop:   dex
+                    bne loop
+                    lda ($40,x)
+                    lda ($02),y
+                    sta $0200,x
+                    sta $10,x
+                    lda $10,y       ; no zero page,y form for LDA
+                    stx $80,y
+                    asl
+                    ror a
+                    jmp ($fffc)
+                    jsr start
+                    lda #<data
+                    ldy #>data
+                    lax $20
+                    kil
+            data:   .byte $01, 2, %11
+                    .word start
+        ";
+        let program = assemble(source, 0xc000).unwrap();
+        let lines: Vec<String> = disassemble(&program, 0xc000).map(|instruction| instruction.to_string()).collect();
+        assert_eq!(lines[..17], [
+            "LDX #$03",
+            "DEX",
+            "BNE $C002",
+            "LDA ($40,X)",
+            "LDA ($02),Y",
+            "STA $0200,X",
+            "STA $10,X",
+            "LDA $0010,Y",
+            "STX $80,Y",
+            "ASL A",
+            "ROR A",
+            "JMP ($FFFC)",
+            "JSR $C000",
+            "LDA #$22",
+            "LDY #$C0",
+            "LAX $20",
+            "KIL",
+        ]);
+        assert_eq!(program[program.len() - 5..], [0x01, 0x02, 0x03, 0x00, 0xc0]);
+    }
+
+    #[test]
+    fn test_assemble_errors() {
+        let line = |source| match assemble(source, 0x8000) {
+            Err(NesError::Assembly { line, reason }) => (line, reason),
+            result => panic!("expected an assembly error, got {:?}", result),
+        };
+        assert_eq!(line("nop\nfoo #$10"), (2, "unknown mnemonic"));
+        assert_eq!(line("jmp ($10),y"), (1, "addressing mode not supported by instruction"));
+        assert_eq!(line("bne missing"), (1, "undefined label"));
+        assert_eq!(line("lda #$100"), (1, "value doesn't fit in a byte"));
+        assert_eq!(line("here: nop\n.byte 0\nhere: nop"), (3, "label defined twice"));
+        let far = format!("beq far\n{}far: nop", ".word 0\n".repeat(64));
+        assert_eq!(line(&far), (1, "branch target out of range"));
+    }
+
+    #[test]
+    fn test_program_builder() {
+        let built = Program::new()
+            .ldx_imm(0x03)
+            .label("loop")
+            .dex()
+            .bne("loop")
+            .jsr_to("routine")
+            .lda_indy(0x02)
+            .sta_absx(0x0200)
+            .asl_a()
+            .ldx_zpy(0x80)
+            .jmp_ind(0xfffc)
+            .kil()
+            .label("routine")
+            .beq("done")
+            .bytes(&[0xea])
+            .label("done")
+            .rts()
+            .build();
+        let source = "
+                    ldx #$03
+            loop:   dex
+                    bne loop
+                    jsr routine
+                    lda ($02),y
+                    sta $0200,x
+                    asl a
+                    ldx $80,y
+                    jmp ($fffc)
+                    kil
+            routine: beq done
+                    nop
+            done:   rts
+        ";
+        assert_eq!(built, assemble(source, 0x8000).unwrap());
+
+        let program = Program::at(0xc000).jmp_to("end").label("end");
+        assert_eq!(program.address(), 0xc003);
+        assert_eq!(program.build(), vec![0x4c, 0x03, 0xc0]);
+    }
+
+    #[test]
+    #[should_panic(expected = "undefined label 'missing'")]
+    fn test_program_undefined_label() {
+        Program::new().bne("missing").build();
+    }
+}
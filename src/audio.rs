@@ -0,0 +1,386 @@
+/*
+    Audio output stages shared by the desktop and I2S frontends.
+
+    Emulation speed is never locked exactly to the audio clock, so a fixed resampling ratio
+    slowly drains or overfills the output buffer, causing audible pops. Dynamic rate control
+    nudges the ratio (by at most 'MAX_RATE_DEVIATION') based on how full the output buffer is,
+    steering it toward half full. A deviation of 0.5% is small enough to not be heard as pitch.
+*/
+
+use std::cell::UnsafeCell;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+pub const MAX_RATE_DEVIATION: f64 = 0.005;
+
+// Computes the resampling adjustment from output buffer occupancy
+#[derive(Debug, Clone, Copy)]
+pub struct RateControl {
+    max_deviation: f64,
+}
+
+impl Default for RateControl {
+    fn default() -> Self {
+        Self::new(MAX_RATE_DEVIATION)
+    }
+}
+
+impl RateControl {
+    pub fn new(max_deviation: f64) -> Self {
+        RateControl{max_deviation}
+    }
+
+    /*
+        Factor to scale the nominal output/input ratio by, given 'buffered' samples waiting in an
+        output buffer holding at most 'capacity' samples. An empty buffer produces more output
+        per input sample, a full buffer produces less.
+     */
+    pub fn adjustment(&self, buffered: usize, capacity: usize) -> f64 {
+        if capacity == 0 {return 1.0}
+        let fill = (buffered as f64 / capacity as f64).min(1.0);
+        1.0 + self.max_deviation * (1.0 - 2.0 * fill)
+    }
+}
+
+// Linear interpolating resampler with an adjustable ratio
+#[derive(Debug, Clone)]
+pub struct Resampler {
+    // output samples per input sample without adjustment
+    nominal_ratio: f64,
+    adjustment: f64,
+    // position of the next output sample between 'previous' and the next input sample
+    phase: f64,
+    previous: f32,
+}
+
+impl Resampler {
+    pub fn new(input_rate: f64, output_rate: f64) -> Self {
+        Resampler {
+            nominal_ratio: output_rate / input_rate,
+            adjustment: 1.0,
+            phase: 0.0,
+            previous: 0.0,
+        }
+    }
+
+    pub fn set_adjustment(&mut self, adjustment: f64) {
+        self.adjustment = adjustment;
+    }
+
+    pub fn ratio(&self) -> f64 {
+        self.nominal_ratio * self.adjustment
+    }
+
+    // resample 'input' and append the result to 'output'
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>) {
+        let step = 1.0 / self.ratio();
+        for sample in input.iter() {
+            while self.phase < 1.0 {
+                let t = self.phase as f32;
+                output.push(self.previous + (sample - self.previous) * t);
+                self.phase += step;
+            }
+            self.phase -= 1.0;
+            self.previous = *sample;
+        }
+    }
+}
+
+// A resampler whose ratio follows the occupancy of the output buffer
+#[derive(Debug, Clone)]
+pub struct AdaptiveResampler {
+    resampler: Resampler,
+    control: RateControl,
+}
+
+impl AdaptiveResampler {
+    pub fn new(input_rate: f64, output_rate: f64) -> Self {
+        AdaptiveResampler {
+            resampler: Resampler::new(input_rate, output_rate),
+            control: RateControl::default(),
+        }
+    }
+
+    pub fn with_control(mut self, control: RateControl) -> Self {
+        self.control = control;
+        self
+    }
+
+    pub fn ratio(&self) -> f64 {
+        self.resampler.ratio()
+    }
+
+    /*
+        Resample 'input' into 'output'. 'buffered' is the number of samples still queued in the
+        audio device and 'capacity' the size of that queue, both sampled just before the call.
+     */
+    pub fn process(&mut self, input: &[f32], output: &mut Vec<f32>, buffered: usize, capacity: usize) {
+        self.resampler.set_adjustment(self.control.adjustment(buffered, capacity));
+        self.resampler.process(input, output);
+    }
+}
+
+/*
+    Threaded audio
+    APU register writes are timestamped with the CPU cycle they happened on and passed through
+    a lock-free single producer/single consumer queue to a dedicated audio thread, which replays
+    them into a synthesizer at the right time. Audio then no longer depends on the pacing of
+    the video loop, and on dual core targets synthesis runs on the second core.
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ApuEvent {
+    // a write of 'data' to APU register 'address' on CPU cycle 'cycle'
+    Write{cycle: u64, address: u16, data: u8},
+    // emulation has reached 'cycle', samples up to this point can be produced
+    Sync{cycle: u64},
+    // the DMC fetched sample byte 'data' on CPU cycle 'cycle'
+    DmcSample{cycle: u64, data: u8},
+}
+
+// Receives replayed APU events on the audio thread
+pub trait ApuSynth: Send {
+    fn write_register(&mut self, address: u16, data: u8);
+    // a sample byte for the DMC channel, fetched from CPU memory by 'dmc::DmcReader'
+    fn dmc_sample(&mut self, _data: u8) {}
+    // synthesize samples up to CPU cycle 'cycle', appending them to 'output'
+    fn run_until(&mut self, cycle: u64, output: &mut Vec<f32>);
+}
+
+// run 'synth' up to 'event' and apply it, appending the samples produced meanwhile to 'output'
+pub fn replay<S: ApuSynth + ?Sized>(synth: &mut S, event: ApuEvent, output: &mut Vec<f32>) {
+    match event {
+        ApuEvent::Write{cycle, address, data} => {
+            synth.run_until(cycle, output);
+            synth.write_register(address, data);
+        },
+        ApuEvent::Sync{cycle} => synth.run_until(cycle, output),
+        ApuEvent::DmcSample{cycle, data} => {
+            synth.run_until(cycle, output);
+            synth.dmc_sample(data);
+        },
+    }
+}
+
+struct Ring {
+    events: Box<[UnsafeCell<ApuEvent>]>,
+    // next slot to read, only advanced by the consumer
+    head: AtomicUsize,
+    // next slot to write, only advanced by the producer
+    tail: AtomicUsize,
+}
+
+// slots are only accessed by the side which currently owns them according to head/tail
+unsafe impl Sync for Ring {}
+
+impl Ring {
+    fn mask(&self) -> usize {
+        self.events.len() - 1
+    }
+}
+
+// Create a queue holding up to 'capacity' events, rounded up to a power of two
+pub fn apu_queue(capacity: usize) -> (ApuQueueProducer, ApuQueueConsumer) {
+    let ring = Arc::new(Ring {
+        events: (0..capacity.max(2).next_power_of_two())
+            .map(|_| UnsafeCell::new(ApuEvent::Sync{cycle: 0}))
+            .collect(),
+        head: AtomicUsize::new(0),
+        tail: AtomicUsize::new(0),
+    });
+    (ApuQueueProducer{ring: ring.clone()}, ApuQueueConsumer{ring})
+}
+
+pub struct ApuQueueProducer {
+    ring: Arc<Ring>,
+}
+
+impl ApuQueueProducer {
+    // Returns the event if the queue is full
+    pub fn try_push(&mut self, event: ApuEvent) -> Result<(), ApuEvent> {
+        let tail = self.ring.tail.load(Ordering::Relaxed);
+        if tail.wrapping_sub(self.ring.head.load(Ordering::Acquire)) == self.ring.events.len() {
+            return Err(event);
+        }
+        // the consumer won't read this slot until the new tail is published
+        unsafe {*self.ring.events[tail & self.ring.mask()].get() = event};
+        self.ring.tail.store(tail.wrapping_add(1), Ordering::Release);
+        Ok(())
+    }
+
+    // Push an event, waiting for the audio thread to make room if the queue is full.
+    // Register writes must not be dropped or the channels would desync.
+    pub fn push(&mut self, mut event: ApuEvent) {
+        while let Err(rejected) = self.try_push(event) {
+            event = rejected;
+            thread::yield_now();
+        }
+    }
+}
+
+pub struct ApuQueueConsumer {
+    ring: Arc<Ring>,
+}
+
+impl ApuQueueConsumer {
+    pub fn pop(&mut self) -> Option<ApuEvent> {
+        let head = self.ring.head.load(Ordering::Relaxed);
+        if head == self.ring.tail.load(Ordering::Acquire) {
+            return None;
+        }
+        let event = unsafe {*self.ring.events[head & self.ring.mask()].get()};
+        self.ring.head.store(head.wrapping_add(1), Ordering::Release);
+        Some(event)
+    }
+}
+
+pub struct AudioThread {
+    handle: JoinHandle<()>,
+    running: Arc<AtomicBool>,
+}
+
+impl AudioThread {
+    /*
+        Spawn a thread which replays events from 'queue' into 'synth' and passes the produced
+        samples to 'output'.
+     */
+    pub fn spawn<S, F>(mut queue: ApuQueueConsumer, mut synth: S, mut output: F) -> Self
+    where S: ApuSynth + 'static, F: FnMut(&[f32]) + Send + 'static {
+        let running = Arc::new(AtomicBool::new(true));
+        let thread_running = running.clone();
+        let handle = thread::spawn(move || {
+            let mut samples = Vec::new();
+            while thread_running.load(Ordering::Relaxed) {
+                while let Some(event) = queue.pop() {
+                    replay(&mut synth, event, &mut samples);
+                }
+                if samples.is_empty() {
+                    thread::sleep(Duration::from_micros(500));
+                } else {
+                    output(&samples);
+                    samples.clear();
+                }
+            }
+            // events queued before 'stop' still reach the output
+            while let Some(event) = queue.pop() {
+                replay(&mut synth, event, &mut samples);
+            }
+            if !samples.is_empty() {
+                output(&samples);
+            }
+        });
+        AudioThread{handle, running}
+    }
+
+    // stop the thread once it has drained the events already queued
+    pub fn stop(self) {
+        self.running.store(false, Ordering::Relaxed);
+        self.handle.join().expect("audio thread panicked");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rate_control() {
+        let control = RateControl::default();
+        assert_eq!(control.adjustment(0, 1024), 1.005);
+        assert_eq!(control.adjustment(512, 1024), 1.0);
+        assert_eq!(control.adjustment(1024, 1024), 0.995);
+        assert_eq!(control.adjustment(4096, 1024), 0.995);
+    }
+
+    #[test]
+    fn test_resampler_output_count() {
+        let input = vec![0.5f32; 48000];
+        let mut output = Vec::new();
+        let mut resampler = Resampler::new(48000.0, 44100.0);
+        resampler.process(&input, &mut output);
+        assert!((output.len() as i64 - 44100).abs() <= 1);
+        assert!(output[10..].iter().all(|sample| (sample - 0.5).abs() < 1e-6));
+
+        // a nearly empty output buffer stretches the audio
+        let mut output = Vec::new();
+        let mut resampler = AdaptiveResampler::new(48000.0, 44100.0);
+        resampler.process(&input, &mut output, 0, 4096);
+        assert!((output.len() as f64 - 44100.0 * 1.005).abs() <= 1.0);
+    }
+
+    #[test]
+    fn test_apu_queue() {
+        let (mut producer, mut consumer) = apu_queue(3);
+        for cycle in 0..4 {
+            assert!(producer.try_push(ApuEvent::Sync{cycle}).is_ok());
+        }
+        assert!(producer.try_push(ApuEvent::Sync{cycle: 4}).is_err());
+        assert_eq!(consumer.pop(), Some(ApuEvent::Sync{cycle: 0}));
+        assert!(producer.try_push(ApuEvent::Write{cycle: 4, address: 0x4000, data: 1}).is_ok());
+        for cycle in 1..4 {
+            assert_eq!(consumer.pop(), Some(ApuEvent::Sync{cycle}));
+        }
+        assert_eq!(consumer.pop(), Some(ApuEvent::Write{cycle: 4, address: 0x4000, data: 1}));
+        assert_eq!(consumer.pop(), None);
+    }
+
+    #[test]
+    fn test_memory_forwards_apu_writes() {
+        use crate::cpu::CPU;
+
+        // lda #$3f, sta $4000, sta $2000, sta $4015
+        let mut cpu = CPU::with_program(vec![0xa9, 0x3f, 0x8d, 0x00, 0x40, 0x8d, 0x00, 0x20, 0x8d, 0x15, 0x40]);
+        let (producer, mut consumer) = apu_queue(16);
+        cpu.memory.attach_apu_queue(producer);
+        cpu.execute(Some(4));
+        assert_eq!(consumer.pop(), Some(ApuEvent::Write{cycle: 9, address: 0x4000, data: 0x3f}));
+        assert_eq!(consumer.pop(), Some(ApuEvent::Write{cycle: 17, address: 0x4015, data: 0x3f}));
+        assert_eq!(consumer.pop(), None);
+    }
+
+    #[test]
+    fn test_audio_thread() {
+        use std::sync::mpsc;
+
+        // emits one sample per elapsed cycle holding the last written value
+        struct Latch {cycle: u64, value: f32}
+        impl ApuSynth for Latch {
+            fn write_register(&mut self, _address: u16, data: u8) {
+                self.value = data as f32;
+            }
+            fn run_until(&mut self, cycle: u64, output: &mut Vec<f32>) {
+                while self.cycle < cycle {
+                    output.push(self.value);
+                    self.cycle += 1;
+                }
+            }
+        }
+
+        let (mut producer, consumer) = apu_queue(4);
+        let (sender, receiver) = mpsc::channel();
+        let thread = AudioThread::spawn(consumer, Latch{cycle: 0, value: 0.0}, move |samples| {
+            sender.send(samples.to_vec()).unwrap();
+        });
+        producer.push(ApuEvent::Write{cycle: 2, address: 0x4000, data: 1});
+        producer.push(ApuEvent::Write{cycle: 3, address: 0x4000, data: 2});
+        producer.push(ApuEvent::Sync{cycle: 5});
+        let mut samples = Vec::new();
+        while samples.len() < 5 {
+            samples.extend(receiver.recv_timeout(Duration::from_secs(5)).expect("audio thread stalled"));
+        }
+        thread.stop();
+        assert_eq!(samples, vec![0.0, 0.0, 1.0, 2.0, 2.0]);
+
+        // events still queued when the thread is stopped are replayed
+        let (mut producer, consumer) = apu_queue(4);
+        let (sender, receiver) = mpsc::channel();
+        let thread = AudioThread::spawn(consumer, Latch{cycle: 0, value: 0.0}, move |samples| {
+            sender.send(samples.to_vec()).unwrap();
+        });
+        producer.push(ApuEvent::Sync{cycle: 3});
+        thread.stop();
+        assert_eq!(receiver.try_iter().flatten().collect::<Vec<f32>>(), vec![0.0; 3]);
+    }
+}
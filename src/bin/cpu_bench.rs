@@ -0,0 +1,97 @@
+use std::time::{Duration, Instant};
+
+use clap::Parser;
+use rust_nes_esp::cpu::CPU;
+use rust_nes_esp::memory::NesError;
+
+/*
+    CPU throughput benchmark
+    Measures emulated instructions and cycles per second of the opcode dispatch, run it with
+    'cargo run --release --bin cpu_bench' on the host and on the ESP32 targets before changing
+    how instructions are decoded or dispatched.
+*/
+
+#[derive(Parser)]
+#[command(version, about, long_about = None)]
+struct CpuBench {
+    // Path to nestest.nes
+    #[arg(default_value = "test_data/nes_test_data/nestest.nes")]
+    nestest: String,
+
+    // Minimum run time of each workload in milliseconds
+    #[arg(short, long, default_value_t = 2000)]
+    millis: u64,
+}
+
+struct BenchResult {
+    instructions: u64,
+    cycles: u64,
+    elapsed: Duration,
+}
+
+impl BenchResult {
+    fn report(&self, name: &str) {
+        let seconds = self.elapsed.as_secs_f64();
+        println!(
+            "{:<10} {:>8.2} M instructions/s {:>8.2} MHz ({:.1}x NTSC)",
+            name,
+            self.instructions as f64 / seconds / 1e6,
+            self.cycles as f64 / seconds / 1e6,
+            // NTSC CPU clock
+            self.cycles as f64 / seconds / 1_789_773.0
+        );
+    }
+}
+
+// run 'batch' until 'duration' has passed, 'batch' returns the instructions and cycles it executed
+fn measure<F>(duration: Duration, mut batch: F) -> BenchResult
+where F: FnMut() -> (u64, u64) {
+    let start = Instant::now();
+    let mut result = BenchResult { instructions: 0, cycles: 0, elapsed: Duration::ZERO };
+    while result.elapsed < duration {
+        let (instructions, cycles) = batch();
+        result.instructions += instructions;
+        result.cycles += cycles;
+        result.elapsed = start.elapsed();
+    }
+    result
+}
+
+// every official instruction of nestest, a mix with many flag and memory operations
+fn nestest(path: &str, duration: Duration) -> Result<BenchResult, NesError> {
+    const INSTRUCTIONS: usize = 8991;
+    let mut cpu = CPU::from_file_nestest(path.to_string())?;
+    let cpu_state = cpu.save_state();
+    let memory_state = cpu.memory.save_state();
+    Ok(measure(duration, || {
+        cpu.load_state(&cpu_state);
+        cpu.memory.load_state(&memory_state);
+        let cycles = cpu.execute(Some(INSTRUCTIONS));
+        (INSTRUCTIONS as u64, cycles as u64)
+    }))
+}
+
+// a typical inner loop of a game: copy a page with indexed loads and stores
+fn copy_loop(duration: Duration) -> BenchResult {
+    /*
+        $0400:
+        ldx #$00
+        lda $1000,x
+        sta $2000,x
+        inx
+        bne $0402
+        jmp $0400
+     */
+    let program = [0xa2, 0x00, 0xbd, 0x00, 0x10, 0x9d, 0x00, 0x20, 0xe8, 0xd0, 0xf7, 0x4c, 0x00, 0x04];
+    let mut cpu = CPU::with_flat_memory(&program, 0x0400, 0x0400);
+    const INSTRUCTIONS: usize = 100_000;
+    measure(duration, || (INSTRUCTIONS as u64, cpu.execute(Some(INSTRUCTIONS)) as u64))
+}
+
+fn main() -> Result<(), NesError> {
+    let args = CpuBench::parse();
+    let duration = Duration::from_millis(args.millis);
+    nestest(&args.nestest, duration)?.report("nestest");
+    copy_loop(duration).report("copy loop");
+    Ok(())
+}
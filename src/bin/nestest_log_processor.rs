@@ -1,7 +1,7 @@
 use std::fs::File;
 use std::io::{self, BufRead, BufReader, Write};
 use std::path::Path;
-use rust_nes_esp::opmap::OP_NAME_MAP;
+use rust_nes_esp::opmap::OPCODE_INFO;
 
 pub fn process_log_file(input_path: &str, output_path: &str) -> io::Result<()> {
     let input_file = File::open(input_path)?;   // Open the input file
@@ -35,7 +35,7 @@ pub fn process_log_file(input_path: &str, output_path: &str) -> io::Result<()> {
         }
 
         // Write the formatted line to the output file
-        writeln!(output_file, "{:} OP:({:}){:30} A:{:} X:{:} Y:{:} P:{:} SP:{:} CYC:{:}", address, opcode, OP_NAME_MAP[u8::from_str_radix(opcode, 16).expect("") as usize], a, x, y, p, sp, cyc)?;
+        writeln!(output_file, "{:} OP:({:}){:30} A:{:} X:{:} Y:{:} P:{:} SP:{:} CYC:{:}", address, opcode, OPCODE_INFO[u8::from_str_radix(opcode, 16).expect("") as usize].name, a, x, y, p, sp, cyc)?;
     }
 
     Ok(())
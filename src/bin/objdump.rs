@@ -1,5 +1,7 @@
-use rust_nes_esp::memory::{Memory, NesError};
-use rust_nes_esp::opmap::OP_NAME_MAP;
+use rust_nes_esp::memory::{Memory, NesError, PROGRAM_ROM, PROGRAM_ROM_2};
+use rust_nes_esp::disasm::disassemble;
+use rust_nes_esp::opmap::AddressingMode;
+use rust_nes_esp::symbols::SymbolTable;
 use clap::Parser;
 
 
@@ -20,23 +22,50 @@ struct ObjDump {
     // Offset into ROM
     #[arg(short, long)]
     offset: Option<usize>,
+
+    // Symbol files (FCEUX .nl or ca65 .dbg) used to label addresses
+    #[arg(short, long)]
+    symbols: Vec<String>,
+
+    // CPU address the ROM is mapped at, defaults to $C000 for the last ROM and $8000 otherwise
+    #[arg(long, value_parser = parse_address)]
+    origin: Option<u16>,
+}
+
+fn parse_address(arg: &str) -> Result<u16, String> {
+    let hex = arg.trim_start_matches('$').trim_start_matches("0x");
+    u16::from_str_radix(hex, 16).map_err(|e| e.to_string())
 }
 
 fn obj_dump(obj_dump: ObjDump) -> Result<(), NesError> {
     let mem = Memory::from_file(obj_dump.file_path)?;
-    let rom = mem.get_program_rom(obj_dump.program_id.unwrap_or(0));
+    let program_id = obj_dump.program_id.unwrap_or(0);
+    let rom = mem.get_program_rom(program_id);
     let offset = obj_dump.offset.unwrap_or(0);
-    for (idx, instr) in rom[offset as u16..rom.len() as u16]
-        .iter()
-        .take(obj_dump.num.unwrap_or(usize::MAX))
-        .enumerate()
-    {
-        let instr_name = if OP_NAME_MAP[*instr as usize] == "! INVALID !" {
-            format!("INVALID - Value:0x{:x} Signed:{:}", *instr, *instr as i8)
-        } else {
-            String::from(OP_NAME_MAP[*instr as usize])
-        };
-        println!("0x{:<8x}:(0x{:2x}){:}", idx + offset, *instr, instr_name);
+    let mut symbols = SymbolTable::new();
+    for path in obj_dump.symbols.iter() {
+        symbols.load_file(path)?;
+    }
+    // the last program rom is fixed at the upper bank for the common mappers
+    let origin = obj_dump.origin.unwrap_or(
+        if program_id + 1 == mem.program_rom_count() {PROGRAM_ROM_2} else {PROGRAM_ROM}
+    );
+    let bytes = &rom[offset as u16..rom.len() as u16];
+    for instruction in disassemble(bytes, origin.wrapping_add(offset as u16)).take(obj_dump.num.unwrap_or(usize::MAX)) {
+        if let Some(label) = symbols.label(instruction.address) {
+            println!("{}:", label);
+        }
+        let raw: Vec<String> = instruction.bytes().iter().map(|byte| format!("{:02X}", byte)).collect();
+        let target = instruction.branch_target()
+            .or(match instruction.info.mode {
+                AddressingMode::Absolute => Some(instruction.operand()),
+                _ => None,
+            })
+            .and_then(|address| symbols.label(address));
+        match target {
+            Some(label) => println!("{:04X}  {:8}  {:16} ; {}", instruction.address, raw.join(" "), instruction.to_string(), label),
+            None => println!("{:04X}  {:8}  {}", instruction.address, raw.join(" "), instruction),
+        }
     }
     Ok(())
 }
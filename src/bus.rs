@@ -0,0 +1,117 @@
+use crate::memory::WatchpointHit;
+
+/*
+    CPU bus
+    Everything the CPU core needs from the system it is plugged into. 'Memory' implements it
+    for the NES, other targets (test harnesses, flat 64K 6502 machines, the ESP32 memory
+    layout) only need 'read', 'write' and 'peek', the remaining hooks default to a bus
+    without a PPU.
+*/
+pub trait Bus {
+    fn read(&mut self, address: u16) -> u8;
+
+    fn write(&mut self, address: u16, data: u8);
+
+    // read without side effects, used to decode instructions for debuggers and tracers
+    fn peek(&self, address: u16) -> u8;
+
+    // called before every instruction with the current CPU cycle
+    fn sync(&mut self, _cycle: u64) {}
+
+    // true once for every NMI raised by a device on the bus
+    fn take_nmi(&mut self) -> bool {
+        false
+    }
+
+    // PPU dots until the next NMI if it is already scheduled, used to emulate NMI hijacking
+    fn dots_until_nmi(&self) -> Option<usize> {
+        None
+    }
+
+    // the upcoming NMI was taken early by BRK/IRQ
+    fn acknowledge_upcoming_nmi(&mut self) {}
+
+    // PPU dots per CPU cycle as a fraction, see 'Region::dots_per_cpu_cycle'
+    fn dots_per_cpu_cycle(&self) -> (usize, usize) {
+        (3, 1)
+    }
+
+    // reset line of the devices on the bus
+    fn reset(&mut self) {}
+
+    // PPU (scanline, dot) shown in trace logs, None without a PPU
+    fn ppu_position(&self) -> Option<(isize, usize)> {
+        None
+    }
+
+    // cycles the CPU is stalled by DMA started during the last instruction, 'cycle' is the CPU cycle after it
+    fn take_stall_cycles(&mut self, _cycle: u64) -> u32 {
+        0
+    }
+
+    // a watched address was accessed since the last call, checked by 'CPU::execute' after every instruction
+    fn take_watchpoint_hit(&mut self) -> Option<WatchpointHit> {
+        None
+    }
+}
+
+// 64K of RAM with no memory mapped devices
+pub struct FlatMemory {
+    ram: Box<[u8]>,
+}
+
+impl FlatMemory {
+    pub fn new() -> Self {
+        FlatMemory { ram: vec![0u8; 0x10000].into_boxed_slice() }
+    }
+
+    // copy 'data' into memory starting at 'address'
+    pub fn load(&mut self, address: u16, data: &[u8]) {
+        let start = address as usize;
+        self.ram[start..start + data.len()].copy_from_slice(data);
+    }
+}
+
+impl Default for FlatMemory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Bus for FlatMemory {
+    fn read(&mut self, address: u16) -> u8 {
+        self.ram[address as usize]
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        self.ram[address as usize] = data;
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        self.ram[address as usize]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPU;
+
+    #[test]
+    fn test_flat_memory() {
+        /*
+            lda #$42
+            sta $c000
+            inc $c000
+            kil
+         */
+        let mut memory = FlatMemory::new();
+        memory.load(0x0200, &[0xa9, 0x42, 0x8d, 0x00, 0xc0, 0xee, 0x00, 0xc0, 0x02]);
+        memory.load(0xfffc, &[0x00, 0x02]);
+        let mut cpu = CPU::new(memory);
+        assert_eq!(cpu.program_counter, 0x0200);
+        cpu.execute(None);
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.memory.peek(0xc000), 0x43);
+    }
+}
@@ -0,0 +1,127 @@
+use bitflags::bitflags;
+
+bitflags! {
+    // buttons of a standard controller, in the order they are shifted out
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    pub struct Buttons: u8 {
+        const A      = 1 << 0;
+        const B      = 1 << 1;
+        const SELECT = 1 << 2;
+        const START  = 1 << 3;
+        const UP     = 1 << 4;
+        const DOWN   = 1 << 5;
+        const LEFT   = 1 << 6;
+        const RIGHT  = 1 << 7;
+    }
+}
+
+/*
+    Standard controller, read serially through $4016/$4017.
+    While the strobe bit of $4016 is set the shift register is continuously reloaded with
+    the button state, once cleared each read returns the next button starting with A.
+    After all 8 buttons have been read, reads return 1.
+*/
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Controller {
+    buttons: Buttons,
+    shift: u8,
+    // number of buttons read since the strobe was released
+    read_count: u8,
+    strobe: bool,
+    // Famicom microphone, only present on controller 2
+    microphone: bool,
+}
+
+impl Controller {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn set_buttons(&mut self, buttons: Buttons) {
+        self.buttons = buttons;
+        if self.strobe {self.reload()}
+    }
+
+    pub fn buttons(&self) -> Buttons {
+        self.buttons
+    }
+
+    fn reload(&mut self) {
+        self.shift = self.buttons.bits();
+        self.read_count = 0;
+    }
+
+    // microphone level, true while the frontend detects sound (or a key bound to it is held)
+    pub fn set_microphone(&mut self, active: bool) {
+        self.microphone = active;
+    }
+
+    pub fn microphone(&self) -> bool {
+        self.microphone
+    }
+
+    // the microphone is read through bit 2 of $4016 instead of the port it's attached to
+    pub fn microphone_bit(&self) -> u8 {
+        (self.microphone as u8) << 2
+    }
+
+    // write to $4016, only bit 0 (strobe) is used
+    pub fn write(&mut self, data: u8) {
+        self.strobe = data & 1 != 0;
+        if self.strobe {self.reload()}
+    }
+
+    // serial data bit, placed in bit 0 of the $4016/$4017 read
+    pub fn read(&mut self) -> u8 {
+        let bit = self.peek();
+        if !self.strobe && self.read_count < 8 {
+            self.shift >>= 1;
+            self.read_count += 1;
+        }
+        bit
+    }
+
+    // same as 'read' without shifting
+    pub fn peek(&self) -> u8 {
+        if self.strobe {
+            self.buttons.contains(Buttons::A) as u8
+        } else if self.read_count < 8 {
+            self.shift & 1
+        } else {
+            1
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_controller_shift() {
+        let mut controller = Controller::new();
+        controller.set_buttons(Buttons::A | Buttons::START | Buttons::RIGHT);
+        controller.write(1);
+        assert_eq!(controller.read(), 1);
+        assert_eq!(controller.read(), 1);
+        controller.write(0);
+        let bits: Vec<u8> = (0..10).map(|_| controller.read()).collect();
+        assert_eq!(bits, vec![1, 0, 0, 1, 0, 0, 0, 1, 1, 1]);
+        controller.write(1);
+        controller.write(0);
+        assert_eq!(controller.read(), 1);
+        assert_eq!(controller.read(), 0);
+    }
+
+    #[test]
+    fn test_microphone() {
+        let mut memory = crate::memory::Memory::from_program(vec![]);
+        memory.controllers[0].set_buttons(Buttons::A);
+        memory.controllers[1].set_microphone(true);
+        memory.write(0x4016, 1);
+        assert_eq!(memory.read(0x4016), 0x05);
+        assert_eq!(memory.read(0x4017), 0x00);
+        memory.controllers[1].set_microphone(false);
+        assert_eq!(memory.read(0x4016), 0x01);
+    }
+}
@@ -4,9 +4,14 @@ use std::u8;
 use bitflags::bitflags;
 use std::fs::File; // FOr testing NES File
 use std::io::Write;
+use std::collections::HashSet;
 
-use crate::memory::{Memory, NesError, PROGRAM_ROM, MMIO};
-use crate::opmap::{OP_MAP, OP_NAME_MAP};
+use crate::bus::{Bus, FlatMemory};
+use crate::disasm::Instruction;
+use crate::profiler::Profiler;
+use crate::memory::{Memory, NesError, WatchpointHit, PROGRAM_ROM, MMIO};
+use crate::opmap::{op_map, AddressingMode, OPCODE_INFO};
+use crate::symbols::SymbolTable;
 
 // TODO: read log file path from environment variable?
 const DEFAULT_LOG_FILE: &'static str = "test_data/nes_test_data/cpu_log.txt";
@@ -14,9 +19,20 @@ const DEFAULT_LOG_FILE: &'static str = "test_data/nes_test_data/cpu_log.txt";
 // Primary Registers?
 const STACK_RESET: u8 = 0xff;
 const STACK_OFFSET: u16 = 0x0100;
+const NMI_VECTOR: u16 = 0xfffa;
+const RESET_VECTOR: u16 = 0xfffc;
+const IRQ_VECTOR: u16 = 0xfffe;
+// an NMI raised during the first 4 cycles of BRK/IRQ replaces the IRQ vector
+const NMI_HIJACK_CYCLES: usize = 4;
+// stack events kept until taken, later ones are dropped
+const STACK_EVENT_LIMIT: usize = 64;
+const OP_CLI: u8 = 0x58;
+const OP_SEI: u8 = 0x78;
+const OP_PLP: u8 = 0x28;
 
 bitflags! {
     #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
     pub struct ProcessorStatusFlags: u8 {
         const CARRY     = 1 << 0;
         const ZERO      = 1 << 1;
@@ -29,6 +45,18 @@ bitflags! {
     }
 }
 
+bitflags! {
+    // devices which can hold the IRQ line low, the line stays asserted until every source is acknowledged
+    #[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+    #[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+    pub struct IrqSource: u8 {
+        const APU_FRAME = 1 << 0;
+        const DMC       = 1 << 1;
+        const MAPPER    = 1 << 2;
+        const EXTERNAL  = 1 << 3;
+    }
+}
+
 impl fmt::Display for ProcessorStatusFlags {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         write!(
@@ -46,8 +74,12 @@ impl fmt::Display for ProcessorStatusFlags {
     }
 }
 
-pub struct CPU {
-    pub memory: Memory,
+// bytes of the stack shown by the CPU's Debug and Display output
+const STACK_PREVIEW: usize = 8;
+
+// the CPU core is generic over its bus, 'Memory' is the NES bus
+pub struct CPU<B: Bus = Memory> {
+    pub memory: B,
     pub program_counter: u16,
     pub stack_pointer: u8,
     pub accumulator: u8,
@@ -55,6 +87,282 @@ pub struct CPU {
     pub idx_register_y: u8,
     pub processor_status: ProcessorStatusFlags,
     pub cycle_count: u32,
+    // cycles since power on, the 32 bit 'cycle_count' wraps around after 40 minutes
+    total_cycles: u64,
+    // labels shown in trace logs, empty unless symbols are loaded
+    pub symbols: SymbolTable,
+    // set by 'trigger_nmi', serviced before the next instruction
+    nmi_pending: bool,
+    // sources currently asserting the IRQ line
+    irq_sources: IrqSource,
+    // I flag before the last CLI/SEI/PLP, their change is seen one instruction late by IRQ polling
+    delayed_interrupt_disable: Option<bool>,
+    // set by a KIL opcode, the CPU stops until reset
+    halted: bool,
+    // 'execute' stops before running an instruction at one of these addresses
+    breakpoints: HashSet<u16>,
+    breakpoint_hit: Option<u16>,
+    watchpoint_hit: Option<WatchpointHit>,
+    // 'execute' stops on an instruction jumping to itself, see 'set_stop_on_spin'
+    stop_on_spin: bool,
+    spin_hit: Option<u16>,
+    // called before every instruction, see 'set_trace_hook'
+    trace_hook: Option<TraceHook>,
+    profiler: Option<Profiler>,
+    // debug aid for unbalanced pushes/pulls, see 'set_stack_diagnostics'
+    stack_diagnostics: bool,
+    // pushes minus pulls since reset or the last TXS, negative after more pulls than pushes
+    stack_depth: i32,
+    stack_events: Vec<StackEvent>,
+    // address of the instruction being executed
+    instruction_address: u16,
+    decimal_mode: DecimalMode,
+    // set when an opcode without a handler halted the CPU
+    unimplemented_opcode: Option<UnimplementedOpcode>,
+}
+
+// an opcode the core doesn't emulate was fetched
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct UnimplementedOpcode {
+    pub pc: u16,
+    pub opcode: u8,
+}
+
+impl fmt::Display for UnimplementedOpcode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "unimplemented opcode ${:02X} ({}) at ${:04X}", self.opcode, OPCODE_INFO[self.opcode as usize].mnemonic, self.pc)
+    }
+}
+
+// how ADC/SBC treat the D flag
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DecimalMode {
+    // the NES' 2A03 has the BCD circuitry disconnected, D is stored but has no effect
+    #[default]
+    Ignored,
+    // NMOS 6502 BCD arithmetic, for using the core as a general 6502
+    Enabled,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StackEventKind {
+    // a push wrapped from $0100 to $01FF
+    Overflow,
+    // more pulls than pushes since reset or the last TXS
+    Underflow,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StackEvent {
+    pub kind: StackEventKind,
+    // instruction that pushed or pulled
+    pub address: u16,
+    // stack pointer before the access
+    pub stack_pointer: u8,
+}
+
+// interrupt taken between instructions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupt {
+    Nmi,
+    Irq,
+}
+
+// snapshot of the programmer visible registers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Registers {
+    pub program_counter: u16,
+    pub stack_pointer: u8,
+    pub accumulator: u8,
+    pub idx_register_x: u8,
+    pub idx_register_y: u8,
+    pub processor_status: ProcessorStatusFlags,
+}
+
+// everything needed to restore the CPU, memory and the other devices are saved separately
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct CpuState {
+    pub registers: Registers,
+    pub cycle_count: u32,
+    pub total_cycles: u64,
+    pub nmi_pending: bool,
+    pub irq_sources: IrqSource,
+    pub delayed_interrupt_disable: Option<bool>,
+    pub halted: bool,
+    pub unimplemented_opcode: Option<UnimplementedOpcode>,
+}
+
+pub type TraceHook = Box<dyn FnMut(&TraceEntry)>;
+
+// CPU state before an instruction executes, passed to the trace hook
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceEntry {
+    pub opcode: u8,
+    operands: [u8; 2],
+    // the program counter is the address of the instruction
+    pub registers: Registers,
+    pub cycle_count: u64,
+    // PPU (scanline, dot) if the bus has a PPU
+    pub ppu_position: Option<(isize, usize)>,
+}
+
+impl TraceEntry {
+    pub fn operands(&self) -> &[u8] {
+        &self.operands[..OPCODE_INFO[self.opcode as usize].mode.operand_count()]
+    }
+
+    // the instruction about to execute, e.g. 'LDA ($40,X)'
+    pub fn instruction(&self) -> Instruction {
+        let bytes = [self.opcode, self.operands[0], self.operands[1]];
+        Instruction::decode(&bytes, self.registers.program_counter).expect("three bytes always decode")
+    }
+
+    /*
+        Line in the layout of Mesen's and FCEUX's trace loggers and nestest.log, for diffing against them:
+        C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+     */
+    pub fn mesen(&self) -> MesenTrace<'_> {
+        MesenTrace(self)
+    }
+}
+
+pub struct MesenTrace<'a>(&'a TraceEntry);
+
+impl fmt::Display for MesenTrace<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let entry = self.0;
+        let instruction = entry.instruction();
+        let bytes: Vec<String> = instruction.bytes().iter().map(|byte| format!("{:02X}", byte)).collect();
+        let registers = entry.registers;
+        write!(
+            f,
+            "{:04X}  {:10}{:32}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X}",
+            registers.program_counter,
+            bytes.join(" "),
+            instruction.to_string(),
+            registers.accumulator,
+            registers.idx_register_x,
+            registers.idx_register_y,
+            registers.processor_status.bits(),
+            registers.stack_pointer
+        )?;
+        if let Some((scanline, dot)) = entry.ppu_position {
+            write!(f, " PPU:{:3},{:3}", scanline, dot)?;
+        }
+        write!(f, " CYC:{}", entry.cycle_count)
+    }
+}
+
+// matches the log written by 'nestest_log_processor'
+impl fmt::Display for TraceEntry {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04X} OP:({:02X}){:30} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{:}",
+            self.registers.program_counter,
+            self.opcode,
+            OPCODE_INFO[self.opcode as usize].name,
+            self.registers.accumulator,
+            self.registers.idx_register_x,
+            self.registers.idx_register_y,
+            self.registers.processor_status.bits(),
+            self.registers.stack_pointer,
+            self.cycle_count
+        )
+    }
+}
+
+impl<B: Bus> CPU<B> {
+    // up to 'STACK_PREVIEW' bytes from the top of the stack, most recently pushed first
+    fn stack_preview(&self) -> Vec<u8> {
+        let used = (STACK_RESET - self.stack_pointer) as usize;
+        (1..=used.min(STACK_PREVIEW) as u16)
+            .map(|offset| self.memory.peek(STACK_OFFSET + self.stack_pointer as u16 + offset))
+            .collect()
+    }
+
+    fn hex_bytes(bytes: &[u8]) -> String {
+        bytes.iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<_>>().join(" ")
+    }
+}
+
+/*
+    Machine state for test failures and 'dbg!':
+    PC:C000 A:00 X:00 Y:00 SP:FD P:24 (N:0 V:0 -:1 B:0 D:0 I:1 Z:0 C:0) CYC:7
+    next: C000  4C F5 C5  JMP $C5F5
+    stack: 01FE: 12 34
+ */
+impl<B: Bus> fmt::Display for CPU<B> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "PC:{:04X} A:{:02X} X:{:02X} Y:{:02X} SP:{:02X} P:{:02X} ({}) CYC:{}",
+            self.program_counter,
+            self.accumulator,
+            self.idx_register_x,
+            self.idx_register_y,
+            self.stack_pointer,
+            self.processor_status.bits(),
+            self.processor_status,
+            self.total_cycles
+        )?;
+        let instruction = self.trace_entry().instruction();
+        writeln!(f, "next: {:04X}  {:8}  {}", instruction.address, Self::hex_bytes(instruction.bytes()), instruction)?;
+        write!(f, "stack: {:04X}: {}", STACK_OFFSET + self.stack_pointer as u16 + 1, Self::hex_bytes(&self.stack_preview()))?;
+        if self.halted {
+            write!(f, "\nhalted")?;
+        }
+        Ok(())
+    }
+}
+
+impl<B: Bus> fmt::Debug for CPU<B> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let instruction = self.trace_entry().instruction();
+        f.debug_struct("CPU")
+            .field("program_counter", &format_args!("{:04X}", self.program_counter))
+            .field("accumulator", &format_args!("{:02X}", self.accumulator))
+            .field("idx_register_x", &format_args!("{:02X}", self.idx_register_x))
+            .field("idx_register_y", &format_args!("{:02X}", self.idx_register_y))
+            .field("stack_pointer", &format_args!("{:02X}", self.stack_pointer))
+            .field("processor_status", &format_args!("{:02X} ({})", self.processor_status.bits(), self.processor_status))
+            .field("cycle_count", &self.cycle_count)
+            .field("total_cycles", &self.total_cycles)
+            .field("next_instruction", &format_args!("{}", instruction))
+            .field("stack", &format_args!("[{}]", Self::hex_bytes(&self.stack_preview())))
+            .field("nmi_pending", &self.nmi_pending)
+            .field("irq_sources", &self.irq_sources)
+            .field("halted", &self.halted)
+            .finish_non_exhaustive()
+    }
+}
+
+/*
+    Result of 'CPU::step'. The instruction is decoded before it executes, so 'effective_address'
+    is the address it accessed (or the branch/jump target). If 'interrupt' is set the CPU
+    serviced that interrupt instead, and the decoded instruction will run after the handler returns.
+*/
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct StepInfo {
+    pub address: u16,
+    pub opcode: u8,
+    pub mnemonic: &'static str,
+    pub mode: AddressingMode,
+    operands: [u8; 2],
+    pub effective_address: Option<u16>,
+    pub cycles: u32,
+    pub interrupt: Option<Interrupt>,
+    // registers after the step
+    pub registers: Registers,
+}
+
+impl StepInfo {
+    pub fn operands(&self) -> &[u8] {
+        &self.operands[..self.mode.operand_count()]
+    }
 }
 
 enum Register {
@@ -65,44 +373,79 @@ enum Register {
 impl CPU {
     // reset vector points to beginning of program ROM
     pub fn with_program(program: Vec<u8>) -> Self {
-        CPU {
-            memory: Memory::from_program(program),
-            program_counter: PROGRAM_ROM,
-            stack_pointer: STACK_RESET,
-            accumulator: 0,
-            idx_register_x: 0,
-            idx_register_y: 0,
-            processor_status: ProcessorStatusFlags::from_bits_truncate(0b000000),
-            cycle_count: 7, // starts at 7?
-        }
+        let mut cpu = CPU::new(Memory::from_program(program));
+        cpu.program_counter = PROGRAM_ROM;
+        cpu
     }
 
     // reset vector is taken from memory location 0xfffc
     pub fn from_file(path: String) -> Result<Self, NesError> {
-        let mut memory = Memory::from_file(path)?;
-        Ok(CPU {
-            program_counter: u16::from_le_bytes([memory.read(0xfffc), memory.read(0xfffd)]),
-            memory: memory,
-            stack_pointer: STACK_RESET,
-            accumulator: 0,
-            idx_register_x: 0,
-            idx_register_y: 0,
-            processor_status: ProcessorStatusFlags::from_bits_truncate(0b000000),
-            cycle_count: 7, // starts at 7?
-        })
+        Ok(CPU::new(Memory::from_file(path)?))
     }
 
     pub fn from_file_nestest(path: String) -> Result<Self, NesError> {
-        Ok(CPU {
-            memory: Memory::from_file(path)?,
-            program_counter: 0xC000, // Needed to initate logging
-            stack_pointer: STACK_RESET - 2, // Stack pointer starts at FD?
+        let mut cpu = CPU::new(Memory::from_file(path)?);
+        cpu.program_counter = 0xC000; // Needed to initate logging
+        cpu.stack_pointer = STACK_RESET - 2; // Stack pointer starts at FD?
+        cpu.processor_status = ProcessorStatusFlags::from_bits_truncate(0x24);
+        Ok(cpu)
+    }
+
+}
+
+/*
+    Generic 6502 machine
+    The core runs against 64K of plain RAM with BCD arithmetic enabled, e.g. for Klaus Dormann's
+    6502 functional tests or non-NES projects. Tests of that kind signal success or failure by
+    jumping to themselves, see 'run_until'.
+*/
+impl CPU<FlatMemory> {
+    // load 'image' at 'origin' and start executing at 'entry' instead of the reset vector
+    pub fn with_flat_memory(image: &[u8], origin: u16, entry: u16) -> Self {
+        let mut memory = FlatMemory::new();
+        memory.load(origin, image);
+        let mut cpu = CPU::new(memory);
+        cpu.program_counter = entry;
+        cpu.decimal_mode = DecimalMode::Enabled;
+        cpu
+    }
+}
+
+impl<B: Bus> CPU<B> {
+    // opcode table instantiated for this bus
+    const OP_MAP: [fn(&mut CPU<B>); 256] = op_map::<B>();
+
+    // reset vector is taken from memory location 0xfffc
+    pub fn new(mut memory: B) -> Self {
+        CPU {
+            program_counter: u16::from_le_bytes([memory.read(RESET_VECTOR), memory.read(RESET_VECTOR + 1)]),
+            memory,
+            stack_pointer: STACK_RESET,
             accumulator: 0,
             idx_register_x: 0,
             idx_register_y: 0,
-            processor_status: ProcessorStatusFlags::from_bits_truncate(0x24),
-            cycle_count: 7, // starts at 7?
-        })
+            processor_status: ProcessorStatusFlags::from_bits_truncate(0b000000),
+            cycle_count: 7,
+            total_cycles: 7,
+            symbols: SymbolTable::new(),
+            nmi_pending: false,
+            irq_sources: IrqSource::empty(),
+            delayed_interrupt_disable: None,
+            halted: false,
+            breakpoints: HashSet::new(),
+            breakpoint_hit: None,
+            watchpoint_hit: None,
+            stop_on_spin: false,
+            spin_hit: None,
+            trace_hook: None,
+            profiler: None,
+            stack_diagnostics: cfg!(debug_assertions),
+            stack_depth: 0,
+            stack_events: Vec::new(),
+            instruction_address: 0,
+            decimal_mode: DecimalMode::Ignored,
+            unimplemented_opcode: None,
+        }
     }
 
     // Execute steps strictly for testing using nestest
@@ -110,54 +453,505 @@ impl CPU {
         let mut log_file = File::create(output_log_path).expect("Failed to create log file");
         if let Some(steps) = steps {
             for _ in 0..steps {
+                if self.halted {break}
                 self.log_cpu(&mut log_file);
                 self.advance();
             }
         }
-        else { loop {
+        else { while !self.halted {
             self.log_cpu(&mut log_file);
             self.advance();} }
     }
 
     fn log_cpu(&mut self, log_file: &mut File) {
-        let opcode = self.memory.read(self.program_counter);
-        let log_entry = format!(
-            "{:04X} OP:({:02X}){:30} A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} CYC:{:}\n",
-            self.program_counter,
-            opcode,
-            OP_NAME_MAP[opcode as usize],
+        if let Some(label) = self.symbols.label(self.program_counter) {
+            writeln!(log_file, "{}:", label).expect("Failed to write log");
+        }
+        writeln!(log_file, "{}", self.nestest_line()).expect("Failed to write log");
+    }
+
+    /*
+        The next instruction in nestest.log's (Nintendulator's) layout, including the '= value'
+        annotations of memory operands, so logs can be diffed against it directly:
+        C000  4C F5 C5  JMP $C5F5                       A:00 X:00 Y:00 P:24 SP:FD PPU:  0, 21 CYC:7
+        The PPU position is derived from the cycle count, with the PPU starting on dot 0 of
+        scanline 0 like in nestest.log, since the PPU isn't clocked without a frontend.
+     */
+    pub fn nestest_line(&self) -> String {
+        let entry = self.trace_entry();
+        let instruction = entry.instruction();
+        let info = instruction.info;
+        let pc = self.program_counter;
+        let operands = [self.memory.peek(pc.wrapping_add(1)), self.memory.peek(pc.wrapping_add(2))];
+        let address = self.effective_address(info.mode, pc, operands).unwrap_or(0);
+        // memory mapped registers aren't read for the log, nestest.log shows them as FF
+        let value = if (MMIO..0x4020).contains(&address) {0xff} else {self.memory.peek(address)};
+        let zero_page = operands[0];
+        let absolute = u16::from_le_bytes(operands);
+        let pointer = operands[0].wrapping_add(self.idx_register_x);
+        let indirect = address.wrapping_sub(self.idx_register_y as u16);
+
+        let operand = match info.mode {
+            AddressingMode::Implied => String::new(),
+            AddressingMode::Accumulator => String::from("A"),
+            AddressingMode::Immediate => format!("#${:02X}", zero_page),
+            AddressingMode::ZeroPage => format!("${:02X} = {:02X}", zero_page, value),
+            AddressingMode::ZeroPageX => format!("${:02X},X @ {:02X} = {:02X}", zero_page, address, value),
+            AddressingMode::ZeroPageY => format!("${:02X},Y @ {:02X} = {:02X}", zero_page, address, value),
+            AddressingMode::Absolute if matches!(info.mnemonic, "JMP" | "JSR") => format!("${:04X}", absolute),
+            AddressingMode::Absolute => format!("${:04X} = {:02X}", absolute, value),
+            AddressingMode::AbsoluteX => format!("${:04X},X @ {:04X} = {:02X}", absolute, address, value),
+            AddressingMode::AbsoluteY => format!("${:04X},Y @ {:04X} = {:02X}", absolute, address, value),
+            AddressingMode::Indirect => format!("(${:04X}) = {:04X}", absolute, address),
+            AddressingMode::ZeroPageXIndirect => format!("(${:02X},X) @ {:02X} = {:04X} = {:02X}", zero_page, pointer, address, value),
+            AddressingMode::ZeroPageYIndirect => format!("(${:02X}),Y = {:04X} @ {:04X} = {:02X}", zero_page, indirect, address, value),
+            AddressingMode::Relative => format!("${:04X}", address),
+        };
+        // nestest.log names ISC by its other common name
+        let mnemonic = if info.mnemonic == "ISC" {"ISB"} else {info.mnemonic};
+        let text = if operand.is_empty() {mnemonic.to_string()} else {format!("{} {}", mnemonic, operand)};
+        let bytes: Vec<String> = instruction.bytes().iter().map(|byte| format!("{:02X}", byte)).collect();
+        let dots = self.total_cycles * 3;
+        format!(
+            "{:04X}  {:8} {}{:32}A:{:02X} X:{:02X} Y:{:02X} P:{:02X} SP:{:02X} PPU:{:3},{:3} CYC:{}",
+            pc,
+            bytes.join(" "),
+            if info.official {' '} else {'*'},
+            text,
             self.accumulator,
             self.idx_register_x,
             self.idx_register_y,
             self.processor_status.bits(),
             self.stack_pointer,
-            self.cycle_count
-        );
-        log_file.write_all(log_entry.as_bytes()).expect("Failed to write log");
+            dots / 341 % 262,
+            dots % 341,
+            self.total_cycles
+        )
+    }
+
+    pub fn trace_entry(&self) -> TraceEntry {
+        let pc = self.program_counter;
+        TraceEntry {
+            opcode: self.memory.peek(pc),
+            operands: [self.memory.peek(pc.wrapping_add(1)), self.memory.peek(pc.wrapping_add(2))],
+            registers: self.registers(),
+            cycle_count: self.total_cycles,
+            ppu_position: self.memory.ppu_position(),
+        }
+    }
+
+    // call 'hook' before every instruction, e.g. to log to memory, a socket or a custom format
+    pub fn set_trace_hook<F>(&mut self, hook: F)
+    where F: FnMut(&TraceEntry) + 'static {
+        self.trace_hook = Some(Box::new(hook));
+    }
+
+    pub fn clear_trace_hook(&mut self) {
+        self.trace_hook = None;
+    }
+
+    // count executions and cycles per opcode, and per instruction address if 'per_address'
+    pub fn enable_profiler(&mut self, per_address: bool) {
+        self.profiler = Some(Profiler::new(per_address));
+    }
+
+    // stop profiling and return the collected profile
+    pub fn disable_profiler(&mut self) -> Option<Profiler> {
+        self.profiler.take()
+    }
+
+    pub fn profiler(&self) -> Option<&Profiler> {
+        self.profiler.as_ref()
+    }
+
+    pub fn profiler_mut(&mut self) -> Option<&mut Profiler> {
+        self.profiler.as_mut()
     }
 
     //execute 'steps' instructions if steps is Some, otherwise run until program terminates
-    pub fn execute(&mut self, steps: Option<usize>) {
+    //stops early if the CPU halts, reaches a breakpoint or accesses a watchpoint, returns the number of cycles elapsed
+    pub fn execute(&mut self, steps: Option<usize>) -> u32 {
         #[cfg(feature = "logging")]
         {let mut log_file = File::create(DEFAULT_LOG_FILE).expect("Failed to create log file");}
-        if let Some(steps) = steps {
-            for _ in 0..steps {
-                #[cfg(feature = "logging")]
-                {self.log_cpu(&mut log_file);}
-                self.advance();
+        self.breakpoint_hit = None;
+        self.watchpoint_hit = None;
+        self.spin_hit = None;
+        let mut cycles = 0u32;
+        let mut executed = 0usize;
+        while !self.halted && steps.is_none_or(|steps| executed < steps) {
+            // the first instruction always runs so execution can resume from a breakpoint
+            if executed > 0 && self.breakpoints.contains(&self.program_counter) {
+                self.breakpoint_hit = Some(self.program_counter);
+                break;
             }
-        }
-        else { loop {
             #[cfg(feature = "logging")]
             {self.log_cpu(&mut log_file);}
-            self.advance();
-        } }
+            cycles = cycles.wrapping_add(self.advance());
+            executed += 1;
+            // the accessing instruction completes before stopping
+            if let Some(hit) = self.memory.take_watchpoint_hit() {
+                self.watchpoint_hit = Some(hit);
+                break;
+            }
+            if self.stop_on_spin && self.is_spinning() {
+                self.spin_hit = Some(self.program_counter);
+                break;
+            }
+        }
+        cycles
     }
 
-    fn advance(&mut self) {
-        let i = OP_MAP[self.memory.read(self.program_counter) as usize];
+    /*
+        Stop 'execute' when an instruction jumps to itself ('JMP *' or a branch to itself) while
+        no interrupt can be taken, the usual way test ROMs signal that they are done.
+        Off by default since games wait for the vblank NMI the same way, which the CPU can't
+        see coming.
+     */
+    pub fn set_stop_on_spin(&mut self, enabled: bool) {
+        self.stop_on_spin = enabled;
+    }
+
+    // address of the instruction spinning on itself if 'execute' stopped there
+    pub fn spin_hit(&self) -> Option<u16> {
+        self.spin_hit
+    }
+
+    fn is_spinning(&self) -> bool {
+        let irq_enabled = self.irq_asserted() && !self.processor_status.contains(ProcessorStatusFlags::INTERRUPT);
+        self.program_counter == self.instruction_address && !self.nmi_pending && !irq_enabled
+    }
+
+    // like 'execute', but an unimplemented opcode is an error instead of a silent halt
+    pub fn try_execute(&mut self, steps: Option<usize>) -> Result<u32, NesError> {
+        let cycles = self.execute(steps);
+        match self.unimplemented_opcode {
+            Some(UnimplementedOpcode { pc, opcode }) => Err(NesError::UnimplementedOpcode { pc, opcode }),
+            None => Ok(cycles),
+        }
+    }
+
+    // the opcode which halted the CPU, if it halted because the opcode isn't emulated
+    pub fn unimplemented_opcode(&self) -> Option<UnimplementedOpcode> {
+        self.unimplemented_opcode
+    }
+
+    pub fn add_breakpoint(&mut self, address: u16) {
+        self.breakpoints.insert(address);
+    }
+
+    // returns false if there was no breakpoint at 'address'
+    pub fn remove_breakpoint(&mut self, address: u16) -> bool {
+        self.breakpoints.remove(&address)
+    }
+
+    pub fn has_breakpoint(&self, address: u16) -> bool {
+        self.breakpoints.contains(&address)
+    }
+
+    pub fn clear_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = u16> + '_ {
+        self.breakpoints.iter().copied()
+    }
+
+    // the breakpoint the last 'execute' stopped at, None if it stopped for another reason
+    pub fn breakpoint_hit(&self) -> Option<u16> {
+        self.breakpoint_hit
+    }
+
+    // the watched access the last 'execute' stopped after, see 'Memory::add_watchpoint'
+    pub fn watchpoint_hit(&self) -> Option<WatchpointHit> {
+        self.watchpoint_hit
+    }
+
+    /*
+        Run until at least 'cycles' have elapsed, e.g. one frame worth, or the CPU halts.
+        Instructions aren't split, so the last one usually runs past the budget. The overshoot is
+        returned so the caller can subtract it from the next budget and stay in sync over time.
+     */
+    pub fn run_for_cycles(&mut self, cycles: u32) -> u32 {
+        let mut elapsed = 0u32;
+        while elapsed < cycles && !self.halted {
+            elapsed = elapsed.wrapping_add(self.advance());
+        }
+        elapsed.saturating_sub(cycles)
+    }
+
+    // execute until 'condition' holds before the next instruction or the CPU halts, returns the cycles elapsed
+    pub fn run_until<F>(&mut self, mut condition: F) -> u32
+    where F: FnMut(&Self) -> bool {
+        let mut cycles = 0u32;
+        while !self.halted && !condition(self) {
+            cycles = cycles.wrapping_add(self.advance());
+        }
+        cycles
+    }
+
+    // execute until the instruction at 'address' is next, e.g. a breakpoint
+    pub fn run_to_address(&mut self, address: u16) -> u32 {
+        self.run_until(|cpu| cpu.program_counter == address)
+    }
+
+    /*
+        Reset button: jump through the reset vector with interrupts disabled. The reset sequence
+        performs 3 stack reads without writing, so the stack pointer drops by 3 while RAM and
+        A/X/Y keep their values.
+     */
+    pub fn reset(&mut self) {
+        self.memory.reset();
+        self.stack_pointer = self.stack_pointer.wrapping_sub(3);
+        self.stack_depth = 0;
+        self.processor_status |= ProcessorStatusFlags::INTERRUPT;
+        self.program_counter = u16::from_le_bytes([self.memory.read(RESET_VECTOR), self.memory.read(RESET_VECTOR + 1)]);
+        self.nmi_pending = false;
+        self.irq_sources = IrqSource::empty();
+        self.delayed_interrupt_disable = None;
+        self.halted = false;
+        self.unimplemented_opcode = None;
+        self.cycle_count = self.cycle_count.wrapping_add(7);
+        self.total_cycles += 7;
+    }
+
+    // true after a KIL or unimplemented opcode stopped the CPU, only a reset recovers
+    pub fn is_halted(&self) -> bool {
+        self.halted
+    }
+
+    // raise the NMI line, e.g. from the PPU at the start of vblank
+    pub fn trigger_nmi(&mut self) {
+        self.nmi_pending = true;
+    }
+
+    // IRQ is level triggered, it's taken between instructions while asserted and interrupts are enabled
+    pub fn assert_irq(&mut self, source: IrqSource) {
+        self.irq_sources |= source;
+    }
+
+    pub fn acknowledge_irq(&mut self, source: IrqSource) {
+        self.irq_sources.remove(source);
+    }
+
+    pub fn irq_asserted(&self) -> bool {
+        !self.irq_sources.is_empty()
+    }
+
+    // vector used by BRK/IRQ, an NMI arriving early in the sequence hijacks it
+    fn break_vector(&mut self) -> u16 {
+        let (numerator, denominator) = self.memory.dots_per_cpu_cycle();
+        match self.memory.dots_until_nmi() {
+            Some(dots) if dots <= NMI_HIJACK_CYCLES * numerator / denominator => {
+                self.memory.acknowledge_upcoming_nmi();
+                NMI_VECTOR
+            }
+            _ => IRQ_VECTOR,
+        }
+    }
+
+    // push the program counter and 'status' then jump through 'vector'
+    fn interrupt(&mut self, vector: u16, status: ProcessorStatusFlags) {
+        let pc = self.program_counter.to_le_bytes();
+        self.push_stack(pc[1]);
+        self.push_stack(pc[0]);
+        self.push_stack((status | ProcessorStatusFlags::UNUSED).bits());
+        self.processor_status |= ProcessorStatusFlags::INTERRUPT;
+        self.program_counter = u16::from_le_bytes([self.memory.read(vector), self.memory.read(vector + 1)]);
+    }
+
+    // execute a single instruction, returns the cycles it took
+    // base cycles come from OPCODE_INFO, page cross and branch penalties from the instructions
+    fn advance(&mut self) -> u32 {
+        let start = self.cycle_count;
+        self.advance_instruction();
+        self.cycle_count.wrapping_sub(start)
+    }
+
+    // a pending interrupt is serviced instead of an instruction
+    fn advance_instruction(&mut self) -> Option<Interrupt> {
+        self.instruction_address = self.program_counter;
+        self.memory.sync(self.total_cycles);
+        let start = self.cycle_count;
+        let interrupt = self.poll_interrupts();
+        if interrupt.is_none() {
+            self.execute_instruction();
+        }
+        // DMA started during the instruction halts the CPU
+        let elapsed = self.cycle_count.wrapping_sub(start);
+        let stall = self.memory.take_stall_cycles(self.total_cycles + elapsed as u64);
+        self.cycle_count = self.cycle_count.wrapping_add(stall);
+        self.total_cycles += (elapsed + stall) as u64;
+        interrupt
+    }
+
+    fn poll_interrupts(&mut self) -> Option<Interrupt> {
+        if self.memory.take_nmi() {
+            self.nmi_pending = true;
+        }
+        let interrupt_disable = self.delayed_interrupt_disable.take()
+            .unwrap_or(self.processor_status.contains(ProcessorStatusFlags::INTERRUPT));
+        if self.nmi_pending {
+            self.nmi_pending = false;
+            self.interrupt(NMI_VECTOR, self.processor_status & !ProcessorStatusFlags::BREAK);
+            self.cycle_count = self.cycle_count.wrapping_add(7);
+            return Some(Interrupt::Nmi);
+        }
+        if self.irq_asserted() && !interrupt_disable {
+            let vector = self.break_vector();
+            self.interrupt(vector, self.processor_status & !ProcessorStatusFlags::BREAK);
+            self.cycle_count = self.cycle_count.wrapping_add(7);
+            return Some(Interrupt::Irq);
+        }
+        None
+    }
+
+    fn execute_instruction(&mut self) {
+        if self.trace_hook.is_some() {
+            let entry = self.trace_entry();
+            if let Some(hook) = self.trace_hook.as_mut() {
+                hook(&entry);
+            }
+        }
+        let address = self.program_counter;
+        let start = self.cycle_count;
+        let opcode = self.memory.read(address);
         self.program_counter += 1;
-        i(self);
+        let interrupt_disable = self.processor_status.contains(ProcessorStatusFlags::INTERRUPT);
+        #[cfg(not(feature = "match_dispatch"))]
+        Self::OP_MAP[opcode as usize](self);
+        #[cfg(feature = "match_dispatch")]
+        self.dispatch_match(opcode);
+        // handlers only add page cross and branch penalties to the base cycles of the table
+        self.cycle_count = self.cycle_count.wrapping_add(OPCODE_INFO[opcode as usize].cycles as u32);
+        // CLI, SEI and PLP change I after the IRQ line was polled, RTI's change takes effect immediately
+        if matches!(opcode, OP_CLI | OP_SEI | OP_PLP) {
+            self.delayed_interrupt_disable = Some(interrupt_disable);
+        }
+        if let Some(ref mut profiler) = self.profiler {
+            profiler.record(address, opcode, self.cycle_count.wrapping_sub(start));
+        }
+    }
+
+    // same handlers as OP_MAP, but every arm calls a constant so the compiler can inline it
+    #[cfg(feature = "match_dispatch")]
+    fn dispatch_match(&mut self, opcode: u8) {
+        macro_rules! arms {
+            ($($op:literal)*) => {
+                match opcode {
+                    $($op => (Self::OP_MAP[$op])(self),)*
+                }
+            };
+        }
+        arms!(0x00 0x01 0x02 0x03 0x04 0x05 0x06 0x07 0x08 0x09 0x0a 0x0b 0x0c 0x0d 0x0e 0x0f
+            0x10 0x11 0x12 0x13 0x14 0x15 0x16 0x17 0x18 0x19 0x1a 0x1b 0x1c 0x1d 0x1e 0x1f
+            0x20 0x21 0x22 0x23 0x24 0x25 0x26 0x27 0x28 0x29 0x2a 0x2b 0x2c 0x2d 0x2e 0x2f
+            0x30 0x31 0x32 0x33 0x34 0x35 0x36 0x37 0x38 0x39 0x3a 0x3b 0x3c 0x3d 0x3e 0x3f
+            0x40 0x41 0x42 0x43 0x44 0x45 0x46 0x47 0x48 0x49 0x4a 0x4b 0x4c 0x4d 0x4e 0x4f
+            0x50 0x51 0x52 0x53 0x54 0x55 0x56 0x57 0x58 0x59 0x5a 0x5b 0x5c 0x5d 0x5e 0x5f
+            0x60 0x61 0x62 0x63 0x64 0x65 0x66 0x67 0x68 0x69 0x6a 0x6b 0x6c 0x6d 0x6e 0x6f
+            0x70 0x71 0x72 0x73 0x74 0x75 0x76 0x77 0x78 0x79 0x7a 0x7b 0x7c 0x7d 0x7e 0x7f
+            0x80 0x81 0x82 0x83 0x84 0x85 0x86 0x87 0x88 0x89 0x8a 0x8b 0x8c 0x8d 0x8e 0x8f
+            0x90 0x91 0x92 0x93 0x94 0x95 0x96 0x97 0x98 0x99 0x9a 0x9b 0x9c 0x9d 0x9e 0x9f
+            0xa0 0xa1 0xa2 0xa3 0xa4 0xa5 0xa6 0xa7 0xa8 0xa9 0xaa 0xab 0xac 0xad 0xae 0xaf
+            0xb0 0xb1 0xb2 0xb3 0xb4 0xb5 0xb6 0xb7 0xb8 0xb9 0xba 0xbb 0xbc 0xbd 0xbe 0xbf
+            0xc0 0xc1 0xc2 0xc3 0xc4 0xc5 0xc6 0xc7 0xc8 0xc9 0xca 0xcb 0xcc 0xcd 0xce 0xcf
+            0xd0 0xd1 0xd2 0xd3 0xd4 0xd5 0xd6 0xd7 0xd8 0xd9 0xda 0xdb 0xdc 0xdd 0xde 0xdf
+            0xe0 0xe1 0xe2 0xe3 0xe4 0xe5 0xe6 0xe7 0xe8 0xe9 0xea 0xeb 0xec 0xed 0xee 0xef
+            0xf0 0xf1 0xf2 0xf3 0xf4 0xf5 0xf6 0xf7 0xf8 0xf9 0xfa 0xfb 0xfc 0xfd 0xfe 0xff)
+    }
+
+    // cycles since power on, the timebase of the PPU, APU and trace logs
+    pub fn total_cycles(&self) -> u64 {
+        self.total_cycles
+    }
+
+    // execute a single instruction and describe what it did
+    pub fn step(&mut self) -> StepInfo {
+        let start = self.cycle_count;
+        let address = self.program_counter;
+        let opcode = self.memory.peek(address);
+        let info = OPCODE_INFO[opcode as usize];
+        let mode = info.mode;
+        let operands = [self.memory.peek(address.wrapping_add(1)), self.memory.peek(address.wrapping_add(2))];
+        let effective_address = self.effective_address(mode, address, operands);
+        let interrupt = self.advance_instruction();
+        StepInfo {
+            address,
+            opcode,
+            mnemonic: info.mnemonic,
+            mode,
+            operands,
+            effective_address,
+            cycles: self.cycle_count.wrapping_sub(start),
+            interrupt,
+            registers: self.registers(),
+        }
+    }
+
+    pub fn save_state(&self) -> CpuState {
+        CpuState {
+            registers: self.registers(),
+            cycle_count: self.cycle_count,
+            total_cycles: self.total_cycles,
+            nmi_pending: self.nmi_pending,
+            irq_sources: self.irq_sources,
+            delayed_interrupt_disable: self.delayed_interrupt_disable,
+            halted: self.halted,
+            unimplemented_opcode: self.unimplemented_opcode,
+        }
+    }
+
+    pub fn load_state(&mut self, state: &CpuState) {
+        let registers = state.registers;
+        self.program_counter = registers.program_counter;
+        self.stack_pointer = registers.stack_pointer;
+        self.accumulator = registers.accumulator;
+        self.idx_register_x = registers.idx_register_x;
+        self.idx_register_y = registers.idx_register_y;
+        self.processor_status = registers.processor_status;
+        self.cycle_count = state.cycle_count;
+        self.total_cycles = state.total_cycles;
+        self.nmi_pending = state.nmi_pending;
+        self.irq_sources = state.irq_sources;
+        self.delayed_interrupt_disable = state.delayed_interrupt_disable;
+        self.halted = state.halted;
+        self.unimplemented_opcode = state.unimplemented_opcode;
+    }
+
+    pub fn registers(&self) -> Registers {
+        Registers {
+            program_counter: self.program_counter,
+            stack_pointer: self.stack_pointer,
+            accumulator: self.accumulator,
+            idx_register_x: self.idx_register_x,
+            idx_register_y: self.idx_register_y,
+            processor_status: self.processor_status,
+        }
+    }
+
+    // resolve the operand address of the instruction at 'address' without side effects
+    fn effective_address(&self, mode: AddressingMode, address: u16, operands: [u8; 2]) -> Option<u16> {
+        let zero_page_word = |pointer: u8| {
+            u16::from_le_bytes([self.memory.peek(pointer as u16), self.memory.peek(pointer.wrapping_add(1) as u16)])
+        };
+        let absolute = u16::from_le_bytes(operands);
+        match mode {
+            AddressingMode::Implied | AddressingMode::Accumulator | AddressingMode::Immediate => None,
+            AddressingMode::ZeroPage => Some(operands[0] as u16),
+            AddressingMode::ZeroPageX => Some(operands[0].wrapping_add(self.idx_register_x) as u16),
+            AddressingMode::ZeroPageY => Some(operands[0].wrapping_add(self.idx_register_y) as u16),
+            AddressingMode::Absolute => Some(absolute),
+            AddressingMode::AbsoluteX => Some(absolute.wrapping_add(self.idx_register_x as u16)),
+            AddressingMode::AbsoluteY => Some(absolute.wrapping_add(self.idx_register_y as u16)),
+            // the pointer's high byte is fetched without carrying into the page
+            AddressingMode::Indirect => Some(u16::from_le_bytes([
+                self.memory.peek(absolute),
+                self.memory.peek((absolute & 0xff00) | (absolute.wrapping_add(1) & 0x00ff)),
+            ])),
+            AddressingMode::ZeroPageXIndirect => Some(zero_page_word(operands[0].wrapping_add(self.idx_register_x))),
+            AddressingMode::ZeroPageYIndirect => Some(zero_page_word(operands[0]).wrapping_add(self.idx_register_y as u16)),
+            AddressingMode::Relative => Some(address.wrapping_add(2).wrapping_add(operands[0] as i8 as u16)),
+        }
     }
 
     fn get_immediate(&mut self, _check_page_cross: bool) -> u16 {
@@ -204,12 +998,7 @@ impl CPU {
             self.memory.read(indirect_address.wrapping_add(1) as u16)
         ]);
 
-        let final_address = base_address.wrapping_add(self.idx_register_y as u16);
-
-        if check_page_cross && (base_address & 0xFF00) != (final_address & 0xFF00){
-            self.cycle_count += 1;  // Page crossing incurs +1 cycle
-        }
-        final_address
+        self.index_address(base_address, self.idx_register_y, check_page_cross)
     }
 
     /// Fetches an absolute address but does NOT return the value.
@@ -224,19 +1013,28 @@ impl CPU {
 
     fn get_absolute_x(&mut self, check_page_cross: bool) -> u16 {
         let base_address = self.get_absolute(false);
-        let final_address = base_address.wrapping_add(self.idx_register_x as u16);
-        if check_page_cross && (base_address & 0xFF00) != (final_address & 0xFF00){
-            self.cycle_count += 1;  // Page crossing incurs +1 cycle
-        }
-        final_address
+        self.index_address(base_address, self.idx_register_x, check_page_cross)
     }
 
     fn get_absolute_y(&mut self, check_page_cross: bool) -> u16 {
         let base_address = self.get_absolute(false);
-        let final_address = base_address.wrapping_add(self.idx_register_y as u16);
+        self.index_address(base_address, self.idx_register_y, check_page_cross)
+    }
+
+    /*
+        Add an index to 'base_address'. The CPU adds the index to the low byte first and reads
+        that address before fixing up the high byte. Reads ('check_page_cross') only do this dummy
+        read when a page is crossed, which costs a cycle, while writes and read-modify-writes always do.
+     */
+    fn index_address(&mut self, base_address: u16, index: u8, check_page_cross: bool) -> u16 {
+        let final_address = base_address.wrapping_add(index as u16);
         // Check for page crossing by comparing high bytes (page cross occurs every 0xFF)
-        if check_page_cross && (base_address & 0xFF00) != (final_address & 0xFF00){
-            self.cycle_count += 1;  // Page crossing incurs +1 cycle
+        let page_crossed = (base_address & 0xFF00) != (final_address & 0xFF00);
+        if page_crossed || !check_page_cross {
+            self.memory.read((base_address & 0xFF00) | (final_address & 0x00FF));
+        }
+        if check_page_cross && page_crossed {
+            self.cycle_count = self.cycle_count.wrapping_add(1);  // Page crossing incurs +1 cycle
         }
         final_address
     }
@@ -267,7 +1065,7 @@ impl CPU {
         let final_address = self.program_counter.wrapping_add(offset as u16);
         // Check for page crossing
         if (self.program_counter & 0xFF00) != (final_address & 0xFF00){
-            self.cycle_count += 1;  // Page crossing incurs +1 cycle
+            self.cycle_count = self.cycle_count.wrapping_add(1);  // Page crossing incurs +1 cycle
         }
         final_address
     }
@@ -278,23 +1076,81 @@ impl CPU {
 
     #[inline(always)]
     fn push_stack(&mut self, data: u8) {
+        if self.stack_diagnostics {
+            if self.stack_pointer == 0x00 {
+                self.stack_event(StackEventKind::Overflow);
+            }
+            self.stack_depth += 1;
+        }
         self.memory.write(self.get_stack(), data);
         self.stack_pointer = self.stack_pointer.wrapping_sub(1);
     }
 
     #[inline(always)]
     fn pop_stack(&mut self) -> u8 {
+        if self.stack_diagnostics {
+            self.stack_depth -= 1;
+            if self.stack_depth < 0 {
+                self.stack_event(StackEventKind::Underflow);
+            }
+        }
         self.stack_pointer = self.stack_pointer.wrapping_add(1);
         self.memory.read(self.get_stack())
     }
 
-    pub fn noop(&mut self) {
-        self.cycle_count += 2;
+    fn stack_event(&mut self, kind: StackEventKind) {
+        if self.stack_events.len() < STACK_EVENT_LIMIT {
+            self.stack_events.push(StackEvent { kind, address: self.instruction_address, stack_pointer: self.stack_pointer });
+        }
+    }
+
+    // BCD arithmetic is off for the NES, see 'DecimalMode'
+    pub fn set_decimal_mode(&mut self, mode: DecimalMode) {
+        self.decimal_mode = mode;
+    }
+
+    pub fn decimal_mode(&self) -> DecimalMode {
+        self.decimal_mode
+    }
+
+    /*
+        Stack diagnostics record a StackEvent when a push wraps past $0100 or a pull goes above
+        the stack set up by the program, usually a JSR/RTS or PHA/PLA imbalance.
+        Enabled by default in debug builds.
+     */
+    pub fn set_stack_diagnostics(&mut self, enabled: bool) {
+        self.stack_diagnostics = enabled;
+    }
+
+    pub fn stack_events(&self) -> &[StackEvent] {
+        &self.stack_events
+    }
+
+    pub fn take_stack_events(&mut self) -> Vec<StackEvent> {
+        std::mem::take(&mut self.stack_events)
+    }
+
+    // KIL/JAM: the CPU locks up, the program counter stays on the opcode
+    pub fn kill(&mut self) {
+        self.program_counter -= 1;
+        self.halted = true;
+    }
+
+    pub fn noop(&mut self) {}
+
+    // default handler, stops on the opcode instead of running on into garbage
+    pub fn unimplemented(&mut self) {
+        self.program_counter -= 1;
+        self.halted = true;
+        self.unimplemented_opcode = Some(UnimplementedOpcode {
+            pc: self.program_counter,
+            opcode: self.memory.peek(self.program_counter),
+        });
     }
 
     pub fn transfer_x_sp(&mut self) {
         self.stack_pointer = self.idx_register_x;
-        self.cycle_count += 2;
+        self.stack_depth = 0;
     }
 
     pub fn load_m_a_immediate(&mut self) {
@@ -305,39 +1161,30 @@ impl CPU {
 
     pub fn push_a(&mut self) {
         self.push_stack(self.accumulator);
-        self.cycle_count += 3;
     }
 
     pub fn push_status(&mut self) {
         self.push_stack((self.processor_status | ProcessorStatusFlags::UNUSED | ProcessorStatusFlags::BREAK).bits());
-        self.cycle_count += 3;
     }
 
     pub fn pull_a(&mut self) {
         self.accumulator = self.pop_stack();
         self.update_negative_zero_flags(self.accumulator);
-        self.cycle_count += 4;
     }
 
     // NOTE: minor inaccuracy: changes to the interrupt flag are delayed a cycle
     pub fn pull_status(&mut self) {
-        let mask = ProcessorStatusFlags::UNUSED | ProcessorStatusFlags::BREAK;
-        // the unused and break bits are ignored
-        let new_status = ProcessorStatusFlags::from_bits_retain(self.pop_stack()) & !mask;
-        self.processor_status = (self.processor_status & mask) | new_status;
-        self.cycle_count += 4;
+        // the break bit only exists on the stack and the unused bit always reads as set
+        let new_status = ProcessorStatusFlags::from_bits_retain(self.pop_stack()) & !ProcessorStatusFlags::BREAK;
+        self.processor_status = new_status | ProcessorStatusFlags::UNUSED;
     }
 
+    // software interrupt, BRK is followed by a padding byte which is skipped on return
     pub fn break_instr(&mut self) {
-        if self.processor_status.contains(ProcessorStatusFlags::INTERRUPT) {
-            let pc = (self.program_counter + 1).to_le_bytes();
-            self.push_stack(pc[1]);
-            self.push_stack(pc[0]);
-            self.push_stack((self.processor_status | ProcessorStatusFlags::BREAK).bits());
-            self.processor_status &= !ProcessorStatusFlags::INTERRUPT;
-            self.program_counter = u16::from_le_bytes([self.memory.read(0xfffe), self.memory.read(0xffff)]);
-            self.cycle_count += 7;
-        }
+        self.program_counter = self.program_counter.wrapping_add(1);
+        let vector = self.break_vector();
+        // the pushed status still has B set when hijacked by an NMI
+        self.interrupt(vector, self.processor_status | ProcessorStatusFlags::BREAK);
     }
 
     pub fn return_from_interrupt(&mut self) {
@@ -346,17 +1193,14 @@ impl CPU {
         let lower_pc = self.pop_stack();
         let upper_pc = self.pop_stack();
         self.program_counter = u16::from_le_bytes([lower_pc, upper_pc]);
-        self.cycle_count += 2; //+4 from pull_status for a total of 6
     }
 
     pub fn jump_absolute(&mut self) {
         self.program_counter = self.get_absolute(false);
-        self.cycle_count += 3;
     }
 
     pub fn jump_absolute_indirect(&mut self) {
         self.program_counter = self.get_absolute_indirect();
-        self.cycle_count += 5;
     }
 
     pub fn jump_subroutine(&mut self) {
@@ -364,54 +1208,12 @@ impl CPU {
         self.push_stack(pc[1]);
         self.push_stack(pc[0]);
         self.program_counter = self.get_absolute(false);
-        self.cycle_count += 6;
     }
 
     pub fn return_from_subroutine(&mut self) {
         let lower_pc = self.pop_stack();
         let upper_pc = self.pop_stack();
         self.program_counter = u16::from_le_bytes([lower_pc, upper_pc]) + 1;
-        self.cycle_count += 6;
-    }
-
-    // Arithmetic Shift Left Accumulator - see arithmetic_shift_left_gen for specifics
-    pub fn asl_a(&mut self) {
-        self.processor_status.set(ProcessorStatusFlags::CARRY, self.accumulator >> 7 == 1);
-        self.accumulator <<= 1;
-        self.update_negative_zero_flags(self.accumulator);
-        self.cycle_count += 2;
-    }
-
-    // Logical Shift Right Accumulator - see logical_shift_right_gen for specifics
-    pub fn lsr_a(&mut self) {
-        self.processor_status.set(ProcessorStatusFlags::CARRY, self.accumulator & 1 == 1);
-        self.accumulator >>= 1;
-        self.update_negative_zero_flags(self.accumulator);
-        self.cycle_count += 2;
-    }
-
-    // Rotate Right Accumulator - see rotate_right_gen for specifics
-    pub fn ror_a(&mut self) {
-        // carry bit becomes top bit
-        let top_bit = if self.processor_status.contains(ProcessorStatusFlags::CARRY) { 1 } else { 0 };
-        // Assign carry bit based on 0th bit of data
-        self.processor_status.set(ProcessorStatusFlags::CARRY, self.accumulator & 1 == 1);
-        // new value is rotated to the right and the top bit is set to the carry bit
-        self.accumulator = (self.accumulator >> 1) | (top_bit << 7);
-        self.update_negative_zero_flags(self.accumulator);
-        self.cycle_count += 2
-    }
-
-    // Rotate Left Accumulator - See rotate_left_gen for specifics
-    pub fn rol_a(&mut self) {
-        // carry bit becomes bottom bit
-        let bottom_bit = if self.processor_status.contains(ProcessorStatusFlags::CARRY) { 1 } else { 0 };
-        // Assign carry bit based on bit 7
-        self.processor_status.set(ProcessorStatusFlags::CARRY, self.accumulator >> 7 == 1);
-        // new value is rotated to the left and the bottom bit is set to the carry bit
-        self.accumulator = (self.accumulator << 1) | bottom_bit;
-        self.update_negative_zero_flags(self.accumulator);
-        self.cycle_count += 2;
     }
 
     #[inline]
@@ -434,11 +1236,10 @@ impl CPU {
 // Does not work for 'transfer X to SP' instruction
 macro_rules! transfer_gen {
     ($name: ident, $source: ident, $target: ident) => {
-        impl CPU {
+        impl<B: Bus> CPU<B> {
             pub fn $name(&mut self) {
                 self.$target = self.$source;
                 self.update_negative_zero_flags(self.$target);
-                self.cycle_count += 2;
             }
         }
     };
@@ -453,61 +1254,58 @@ transfer_gen!(transfer_sp_x, stack_pointer, idx_register_x);
     load instructions
 */
 macro_rules! load_gen {
-    ($name: ident, $addressing_mode: path, $target: ident, $check_page_cross:literal, $num_cycles:literal) => {
-        impl CPU {
+    ($name: ident, $addressing_mode: path, $target: ident, $check_page_cross:literal) => {
+        impl<B: Bus> CPU<B> {
             pub fn $name(&mut self) {
                 let address = $addressing_mode(self, $check_page_cross);
                 self.$target = self.memory.read(address);
                 self.update_negative_zero_flags(self.$target);
-                self.cycle_count += $num_cycles;
             }
         }
     };
 }
-load_gen!(load_a_immediate, CPU::get_immediate, accumulator, false, 2);
-load_gen!(load_a_absolute, CPU::get_absolute, accumulator, false, 4);
-load_gen!(load_a_absolute_x, CPU::get_absolute_x, accumulator, true, 4);
-load_gen!(load_a_absolute_y, CPU::get_absolute_y, accumulator, true, 4);
-load_gen!(load_a_zero_page, CPU::get_zero_page, accumulator, false, 3);
-load_gen!(load_a_zero_page_x, CPU::get_zero_page_x, accumulator, false, 4);
-load_gen!(load_a_zero_page_x_indirect, CPU::get_zero_page_x_indirect, accumulator, false, 6);
-load_gen!(load_a_zero_page_y_indirect, CPU::get_zero_page_y_indirect, accumulator, true, 5);
-
-load_gen!(load_x_immediate, CPU::get_immediate, idx_register_x, false, 2);
-load_gen!(load_x_absolute, CPU::get_absolute, idx_register_x, false, 4);
-load_gen!(load_x_absolute_y, CPU::get_absolute_y, idx_register_x, true, 4);
-load_gen!(load_x_zero_page, CPU::get_zero_page, idx_register_x, false, 3);
-load_gen!(load_x_zero_page_y, CPU::get_zero_page_y, idx_register_x, false, 4);
-
-load_gen!(load_y_immediate, CPU::get_immediate, idx_register_y, false, 2);
-load_gen!(load_y_absolute, CPU::get_absolute, idx_register_y, false, 4);
-load_gen!(load_y_absolute_x, CPU::get_absolute_x, idx_register_y, true, 4);
-load_gen!(load_y_zero_page, CPU::get_zero_page, idx_register_y, false, 3);
-load_gen!(load_y_zero_page_x, CPU::get_zero_page_x, idx_register_y, false, 4);
+load_gen!(load_a_immediate, CPU::get_immediate, accumulator, false);
+load_gen!(load_a_absolute, CPU::get_absolute, accumulator, false);
+load_gen!(load_a_absolute_x, CPU::get_absolute_x, accumulator, true);
+load_gen!(load_a_absolute_y, CPU::get_absolute_y, accumulator, true);
+load_gen!(load_a_zero_page, CPU::get_zero_page, accumulator, false);
+load_gen!(load_a_zero_page_x, CPU::get_zero_page_x, accumulator, false);
+load_gen!(load_a_zero_page_x_indirect, CPU::get_zero_page_x_indirect, accumulator, false);
+load_gen!(load_a_zero_page_y_indirect, CPU::get_zero_page_y_indirect, accumulator, true);
+
+load_gen!(load_x_immediate, CPU::get_immediate, idx_register_x, false);
+load_gen!(load_x_absolute, CPU::get_absolute, idx_register_x, false);
+load_gen!(load_x_absolute_y, CPU::get_absolute_y, idx_register_x, true);
+load_gen!(load_x_zero_page, CPU::get_zero_page, idx_register_x, false);
+load_gen!(load_x_zero_page_y, CPU::get_zero_page_y, idx_register_x, false);
+
+load_gen!(load_y_immediate, CPU::get_immediate, idx_register_y, false);
+load_gen!(load_y_absolute, CPU::get_absolute, idx_register_y, false);
+load_gen!(load_y_absolute_x, CPU::get_absolute_x, idx_register_y, true);
+load_gen!(load_y_zero_page, CPU::get_zero_page, idx_register_y, false);
+load_gen!(load_y_zero_page_x, CPU::get_zero_page_x, idx_register_y, false);
 
 /*
     branch instructions
 */
 macro_rules! branch_gen {
     ($name: ident, $inverse_name: ident, $flag: expr) => {
-        impl CPU {
+        impl<B: Bus> CPU<B> {
             pub fn $name(&mut self) {
                 if self.processor_status.contains($flag) {
                     self.program_counter = self.get_relative();
-                    self.cycle_count += 3; //+1 if page crossing (checked in get_relative)
+                    self.cycle_count = self.cycle_count.wrapping_add(1); // taken, +1 more if page crossing (checked in get_relative)
                 } else {
                     self.program_counter += 1;
-                    self.cycle_count += 2;
                 }
             }
 
             pub fn $inverse_name(&mut self) {
                 if !self.processor_status.contains($flag) {
                     self.program_counter = self.get_relative();
-                    self.cycle_count += 3; //+1 if page crossing (checked in get_relative)
+                    self.cycle_count = self.cycle_count.wrapping_add(1); // taken, +1 more if page crossing (checked in get_relative)
                 } else {
                     self.program_counter += 1;
-                    self.cycle_count += 2;
                 }
             }
         }
@@ -522,116 +1320,111 @@ branch_gen!(branch_on_overflow_set, branch_on_overflow_reset, ProcessorStatusFla
     store instructions
 */
 macro_rules! store_gen {
-    ($name: ident, $addr_mode: path, $register:ident, $num_cycles:literal) => {
-        impl CPU {
+    ($name: ident, $addr_mode: path, $register:ident) => {
+        impl<B: Bus> CPU<B> {
             pub fn $name(&mut self) {
                 let address = $addr_mode(self, false);
                 self.memory.write(address, self.$register);
-                self.cycle_count += $num_cycles;
             }
         }
     };
 }
 // store for accumulator
-store_gen!(store_a_absolute, CPU::get_absolute, accumulator, 4);
-store_gen!(store_a_absolute_x, CPU::get_absolute_x, accumulator, 5);
-store_gen!(store_a_absolute_y, CPU::get_absolute_y, accumulator, 5);
-store_gen!(store_a_zero_page, CPU::get_zero_page, accumulator, 3);
-store_gen!(store_a_zero_page_x, CPU::get_zero_page_x, accumulator, 4);
-store_gen!(store_a_zero_page_x_indirect, CPU::get_zero_page_x_indirect, accumulator, 6);
-store_gen!(store_a_zero_page_y_indirect, CPU::get_zero_page_y_indirect, accumulator, 6);
+store_gen!(store_a_absolute, CPU::get_absolute, accumulator);
+store_gen!(store_a_absolute_x, CPU::get_absolute_x, accumulator);
+store_gen!(store_a_absolute_y, CPU::get_absolute_y, accumulator);
+store_gen!(store_a_zero_page, CPU::get_zero_page, accumulator);
+store_gen!(store_a_zero_page_x, CPU::get_zero_page_x, accumulator);
+store_gen!(store_a_zero_page_x_indirect, CPU::get_zero_page_x_indirect, accumulator);
+store_gen!(store_a_zero_page_y_indirect, CPU::get_zero_page_y_indirect, accumulator);
 
 // store for reg x
-store_gen!(store_x_absolute, CPU::get_absolute, idx_register_x, 4);
-store_gen!(store_x_zero_page, CPU::get_zero_page, idx_register_x, 3);
-store_gen!(store_x_zero_page_y, CPU::get_zero_page_y, idx_register_x, 4);
+store_gen!(store_x_absolute, CPU::get_absolute, idx_register_x);
+store_gen!(store_x_zero_page, CPU::get_zero_page, idx_register_x);
+store_gen!(store_x_zero_page_y, CPU::get_zero_page_y, idx_register_x);
 
 // store for reg y
-store_gen!(store_y_absolute, CPU::get_absolute, idx_register_y, 4);
-store_gen!(store_y_zero_page, CPU::get_zero_page, idx_register_y, 3);
-store_gen!(store_y_zero_page_x, CPU::get_zero_page_x, idx_register_y, 4);
+store_gen!(store_y_absolute, CPU::get_absolute, idx_register_y);
+store_gen!(store_y_zero_page, CPU::get_zero_page, idx_register_y);
+store_gen!(store_y_zero_page_x, CPU::get_zero_page_x, idx_register_y);
 
 /*
     or instructions
 */
 macro_rules! or_gen {
-    ($name: ident, $addr_mode: path, $check_page_cross:literal, $num_cycles:literal) => {
-        impl CPU {
+    ($name: ident, $addr_mode: path, $check_page_cross:literal) => {
+        impl<B: Bus> CPU<B> {
             pub fn $name(&mut self) {
                 let address = $addr_mode(self, $check_page_cross);
                 let data = self.memory.read(address);
                 self.accumulator |= data;
                 self.update_negative_zero_flags(self.accumulator);
-                self.cycle_count += $num_cycles;
             }
         }
     };
 }
-or_gen!(or_immediate, CPU::get_immediate, false, 2);
-or_gen!(or_absolute, CPU::get_absolute, false, 4);
-or_gen!(or_absolute_x, CPU::get_absolute_x, true, 4);
-or_gen!(or_absolute_y, CPU::get_absolute_y, true, 4);
-or_gen!(or_zero_page, CPU::get_zero_page, false, 3);
-or_gen!(or_zero_page_x, CPU::get_zero_page_x, false, 4);
-or_gen!(or_zero_page_x_indirect, CPU::get_zero_page_x_indirect, false, 6);
-or_gen!(or_zero_page_y_indirect, CPU::get_zero_page_y_indirect, false, 5);
+or_gen!(or_immediate, CPU::get_immediate, false);
+or_gen!(or_absolute, CPU::get_absolute, false);
+or_gen!(or_absolute_x, CPU::get_absolute_x, true);
+or_gen!(or_absolute_y, CPU::get_absolute_y, true);
+or_gen!(or_zero_page, CPU::get_zero_page, false);
+or_gen!(or_zero_page_x, CPU::get_zero_page_x, false);
+or_gen!(or_zero_page_x_indirect, CPU::get_zero_page_x_indirect, false);
+or_gen!(or_zero_page_y_indirect, CPU::get_zero_page_y_indirect, true);
 
 /*
     exclusive or instructions
 */
 macro_rules! exclusive_or_gen {
-    ($name: ident, $addr_mode: path, $check_page_cross:literal, $num_cycles:literal) => {
-        impl CPU {
+    ($name: ident, $addr_mode: path, $check_page_cross:literal) => {
+        impl<B: Bus> CPU<B> {
             pub fn $name(&mut self) {
                 let address = $addr_mode(self, $check_page_cross);
                 let data = self.memory.read(address);
                 self.accumulator ^= data;
                 self.update_negative_zero_flags(self.accumulator);
-                self.cycle_count += $num_cycles;
             }
         }
     };
 }
-exclusive_or_gen!(exclusive_or_immediate, CPU::get_immediate, false, 2);
-exclusive_or_gen!(exclusive_or_absolute, CPU::get_absolute, false, 4);
-exclusive_or_gen!(exclusive_or_absolute_x, CPU::get_absolute_x, true, 4);
-exclusive_or_gen!(exclusive_or_absolute_y, CPU::get_absolute_y, true, 4);
-exclusive_or_gen!(exclusive_or_zero_page, CPU::get_zero_page, false, 3);
-exclusive_or_gen!(exclusive_or_zero_page_x, CPU::get_zero_page_x, false, 4);
-exclusive_or_gen!(exclusive_or_zero_page_x_indirect, CPU::get_zero_page_x_indirect, false, 6);
-exclusive_or_gen!(exclusive_or_zero_page_y_indirect, CPU::get_zero_page_y_indirect, true, 5);
+exclusive_or_gen!(exclusive_or_immediate, CPU::get_immediate, false);
+exclusive_or_gen!(exclusive_or_absolute, CPU::get_absolute, false);
+exclusive_or_gen!(exclusive_or_absolute_x, CPU::get_absolute_x, true);
+exclusive_or_gen!(exclusive_or_absolute_y, CPU::get_absolute_y, true);
+exclusive_or_gen!(exclusive_or_zero_page, CPU::get_zero_page, false);
+exclusive_or_gen!(exclusive_or_zero_page_x, CPU::get_zero_page_x, false);
+exclusive_or_gen!(exclusive_or_zero_page_x_indirect, CPU::get_zero_page_x_indirect, false);
+exclusive_or_gen!(exclusive_or_zero_page_y_indirect, CPU::get_zero_page_y_indirect, true);
 
 /*
     and instructions
 */
 macro_rules! and_gen {
-    ($name: ident, $addr_mode: path, $check_page_cross:literal, $num_cycles:literal) => {
-        impl CPU {
+    ($name: ident, $addr_mode: path, $check_page_cross:literal) => {
+        impl<B: Bus> CPU<B> {
             pub fn $name(&mut self) {
                 let address = $addr_mode(self, $check_page_cross);
                 let data = self.memory.read(address);
                 self.accumulator &= data;
                 self.update_negative_zero_flags(self.accumulator);
-                self.cycle_count += $num_cycles;
             }
         }
     };
 }
-and_gen!(and_immediate, CPU::get_immediate, false, 2);
-and_gen!(and_absolute, CPU::get_absolute, false, 4);
-and_gen!(and_absolute_x, CPU::get_absolute_x, true, 4);
-and_gen!(and_absolute_y, CPU::get_absolute_y, true, 4);
-and_gen!(and_zero_page, CPU::get_zero_page, false, 3);
-and_gen!(and_zero_page_x, CPU::get_zero_page_x, false, 4);
-and_gen!(and_zero_page_x_indirect, CPU::get_zero_page_x_indirect, false, 6);
-and_gen!(and_zero_page_y_indirect, CPU::get_zero_page_y_indirect, true, 5);
+and_gen!(and_immediate, CPU::get_immediate, false);
+and_gen!(and_absolute, CPU::get_absolute, false);
+and_gen!(and_absolute_x, CPU::get_absolute_x, true);
+and_gen!(and_absolute_y, CPU::get_absolute_y, true);
+and_gen!(and_zero_page, CPU::get_zero_page, false);
+and_gen!(and_zero_page_x, CPU::get_zero_page_x, false);
+and_gen!(and_zero_page_x_indirect, CPU::get_zero_page_x_indirect, false);
+and_gen!(and_zero_page_y_indirect, CPU::get_zero_page_y_indirect, true);
 
 macro_rules! clear_flag_gen {
     ($name:ident, $flag:expr) => {
-        impl CPU {
+        impl<B: Bus> CPU<B> {
             pub fn $name(&mut self) {
                 self.processor_status &= !$flag;
-                self.cycle_count += 2;
             }
         }
     };
@@ -643,10 +1436,9 @@ clear_flag_gen!(clear_overflow, ProcessorStatusFlags::OVERFLOW);
 
 macro_rules! set_flag_gen {
     ($name:ident, $flag:expr) => {
-        impl CPU {
+        impl<B: Bus> CPU<B> {
             pub fn $name(&mut self) {
                 self.processor_status |= $flag;
-                self.cycle_count += 2;
             }
         }
     };
@@ -655,251 +1447,276 @@ set_flag_gen!(set_carry, ProcessorStatusFlags::CARRY);
 set_flag_gen!(set_decimal, ProcessorStatusFlags::DECIMAL);
 set_flag_gen!(set_interrupt, ProcessorStatusFlags::INTERRUPT);
 
+impl<B: Bus> CPU<B> {
+    fn add_with_carry(&mut self, data: u8) {
+        if self.bcd_active() {
+            self.add_with_carry_decimal(data);
+        } else {
+            self.add_with_carry_binary(data);
+        }
+    }
+
+    fn add_with_carry_binary(&mut self, data: u8) {
+        // Extract carry bit as u8 (0 or 1)
+        let carry = if self.processor_status.contains(ProcessorStatusFlags::CARRY) { 1 } else { 0 };
+
+        // Perform addition with carry
+        let (sum, carry1) = self.accumulator.overflowing_add(data);
+        let (sum, carry2) = sum.overflowing_add(carry);
+
+        // Set carry flag if an overflow occurs
+        self.processor_status.set(ProcessorStatusFlags::CARRY, carry1 || carry2);
+
+        // Detect signed overflow: Occurs if both operands have the same sign and the result has a different sign
+        let signed_overflow = (self.accumulator ^ sum) & (data ^ sum) & 0b10000000 != 0;
+        self.processor_status.set(ProcessorStatusFlags::OVERFLOW, signed_overflow);
+
+        self.accumulator = sum;
+        self.update_negative_zero_flags(self.accumulator);
+    }
+
+    // A - M - (1 - C) is A + !M + C, carry is set when no borrow occurs
+    fn subtract_with_carry(&mut self, data: u8) {
+        if self.bcd_active() {
+            self.subtract_with_carry_decimal(data);
+        } else {
+            self.add_with_carry_binary(!data);
+        }
+    }
+
+    fn bcd_active(&self) -> bool {
+        self.decimal_mode == DecimalMode::Enabled && self.processor_status.contains(ProcessorStatusFlags::DECIMAL)
+    }
+
+    /*
+        NMOS 6502 decimal addition
+        Z comes from the binary sum, N and V from the sum after adjusting the low digit only,
+        C from the fully adjusted result. Invalid BCD operands give the same results as the hardware.
+     */
+    fn add_with_carry_decimal(&mut self, data: u8) {
+        let carry = self.processor_status.contains(ProcessorStatusFlags::CARRY) as u16;
+        let (a, m) = (self.accumulator as u16, data as u16);
+
+        let binary = (a + m + carry) as u8;
+        let mut low = (a & 0x0f) + (m & 0x0f) + carry;
+        if low >= 0x0a {
+            low = ((low + 0x06) & 0x0f) + 0x10;
+        }
+        let mut sum = (a & 0xf0) + (m & 0xf0) + low;
+
+        self.processor_status.set(ProcessorStatusFlags::ZERO, binary == 0);
+        self.processor_status.set(ProcessorStatusFlags::NEGATIVE, sum & 0x80 != 0);
+        self.processor_status.set(ProcessorStatusFlags::OVERFLOW, (a ^ sum) & (m ^ sum) & 0x80 != 0);
+        if sum >= 0xa0 {
+            sum += 0x60;
+        }
+        self.processor_status.set(ProcessorStatusFlags::CARRY, sum >= 0x100);
+        self.accumulator = sum as u8;
+    }
+
+    // NMOS 6502 decimal subtraction, all flags are set as in binary mode
+    fn subtract_with_carry_decimal(&mut self, data: u8) {
+        let borrow = 1 - self.processor_status.contains(ProcessorStatusFlags::CARRY) as i16;
+        let (a, m) = (self.accumulator as i16, data as i16);
+
+        let mut low = (a & 0x0f) - (m & 0x0f) - borrow;
+        if low < 0 {
+            low = ((low - 0x06) & 0x0f) - 0x10;
+        }
+        let mut difference = (a & 0xf0) - (m & 0xf0) + low;
+        if difference < 0 {
+            difference -= 0x60;
+        }
+
+        self.add_with_carry_binary(!data);
+        self.accumulator = difference as u8;
+    }
+}
+
 /*
     add with carry
 */
 macro_rules! add_with_carry_gen {
-    ($name:ident, $addr_mode:path, $check_page_cross:literal, $num_cycles:literal) => {
-        impl CPU {
+    ($name:ident, $addr_mode:path, $check_page_cross:literal) => {
+        impl<B: Bus> CPU<B> {
             pub fn $name(&mut self) {
                 let address = $addr_mode(self, $check_page_cross);
                 let data = self.memory.read(address);
-
-                // Extract carry bit as u8 (0 or 1)
-                let carry = if self.processor_status.contains(ProcessorStatusFlags::CARRY) { 1 } else { 0 };
-
-                // Perform addition with carry
-                let (sum, carry1) = self.accumulator.overflowing_add(data);
-                let (sum, carry2) = sum.overflowing_add(carry);
-
-                // Set carry flag if an overflow occurs
-                if carry1 || carry2 {
-                    self.processor_status.insert(ProcessorStatusFlags::CARRY);
-                } else {
-                    self.processor_status.remove(ProcessorStatusFlags::CARRY);
-                }
-
-                // Detect signed overflow: Occurs if both operands have the same sign and the result has a different sign
-                let signed_overflow = (self.accumulator ^ sum) & (data ^ sum) & 0b10000000 != 0;
-                if signed_overflow {
-                    self.processor_status.insert(ProcessorStatusFlags::OVERFLOW);
-                } else {
-                    self.processor_status.remove(ProcessorStatusFlags::OVERFLOW);
-                }
-
-                self.accumulator = sum;
-                self.update_negative_zero_flags(self.accumulator);
+                self.add_with_carry(data);
                 //Page crossing is handled in address_mode
-                self.cycle_count += $num_cycles;
             }
         }
     };
 }
-add_with_carry_gen!(adc_immediate, CPU::get_immediate, false, 2);
-add_with_carry_gen!(adc_absolute, CPU::get_absolute, false, 4);
-add_with_carry_gen!(adc_absolute_x, CPU::get_absolute_x, true, 4);
-add_with_carry_gen!(adc_absolute_y, CPU::get_absolute_y, true, 4);
-add_with_carry_gen!(adc_zero_page, CPU::get_zero_page, false, 3);
-add_with_carry_gen!(adc_zero_page_x, CPU::get_zero_page_x, false, 4);
-add_with_carry_gen!(adc_zero_page_x_indirect, CPU::get_zero_page_x_indirect, false, 6);
-add_with_carry_gen!(adc_zero_page_y_indirect, CPU::get_zero_page_y_indirect, true, 5);
+add_with_carry_gen!(adc_immediate, CPU::get_immediate, false);
+add_with_carry_gen!(adc_absolute, CPU::get_absolute, false);
+add_with_carry_gen!(adc_absolute_x, CPU::get_absolute_x, true);
+add_with_carry_gen!(adc_absolute_y, CPU::get_absolute_y, true);
+add_with_carry_gen!(adc_zero_page, CPU::get_zero_page, false);
+add_with_carry_gen!(adc_zero_page_x, CPU::get_zero_page_x, false);
+add_with_carry_gen!(adc_zero_page_x_indirect, CPU::get_zero_page_x_indirect, false);
+add_with_carry_gen!(adc_zero_page_y_indirect, CPU::get_zero_page_y_indirect, true);
 
 /*
     subtract with carry
 */
 macro_rules! subtract_with_carry_gen {
-    ($name:ident, $addr_mode:path, $check_page_cross:literal, $num_cycles:literal) => {
-        impl CPU {
+    ($name:ident, $addr_mode:path, $check_page_cross:literal) => {
+        impl<B: Bus> CPU<B> {
             pub fn $name(&mut self) {
                 let address = $addr_mode(self, $check_page_cross);
                 let data = self.memory.read(address);
-
-                // Extract carry bit as u8 (0 or 1)
-                let carry = if self.processor_status.contains(ProcessorStatusFlags::CARRY) { 1 } else { 0 };
-
-                let sum = self.accumulator.wrapping_add(!data).wrapping_add(carry);
-
-                // Check for underflow to set the borrow (inverse of carry)
-                if self.accumulator <= sum{
-                    self.processor_status.remove(ProcessorStatusFlags::CARRY);
-                } else {
-                    self.processor_status.insert(ProcessorStatusFlags::CARRY);
-                }
-
-                // Detect signed overflow: Occurs if the result has a different sign than A but the same as memory
-                let signed_overflow = (self.accumulator ^ sum) & (!data ^ sum) & 0b10000000 != 0;
-                if signed_overflow {
-                    self.processor_status.insert(ProcessorStatusFlags::OVERFLOW);
-                } else {
-                    self.processor_status.remove(ProcessorStatusFlags::OVERFLOW);
-                }
-
-                self.accumulator = sum;
-                self.update_negative_zero_flags(self.accumulator);
-                self.cycle_count += $num_cycles;
+                self.subtract_with_carry(data);
             }
         }
     };
 }
-subtract_with_carry_gen!(sbc_immediate, CPU::get_immediate, false, 2);
-subtract_with_carry_gen!(sbc_absolute, CPU::get_absolute, false, 4);
-subtract_with_carry_gen!(sbc_absolute_x, CPU::get_absolute_x, true, 4);
-subtract_with_carry_gen!(sbc_absolute_y, CPU::get_absolute_y, true, 4);
-subtract_with_carry_gen!(sbc_zero_page, CPU::get_zero_page, false, 3);
-subtract_with_carry_gen!(sbc_zero_page_x, CPU::get_zero_page_x, false, 4);
-subtract_with_carry_gen!(sbc_zero_page_x_indirect, CPU::get_zero_page_x_indirect, false, 6);
-subtract_with_carry_gen!(sbc_zero_page_y_indirect, CPU::get_zero_page_y_indirect, false, 5);
+subtract_with_carry_gen!(sbc_immediate, CPU::get_immediate, false);
+subtract_with_carry_gen!(sbc_absolute, CPU::get_absolute, false);
+subtract_with_carry_gen!(sbc_absolute_x, CPU::get_absolute_x, true);
+subtract_with_carry_gen!(sbc_absolute_y, CPU::get_absolute_y, true);
+subtract_with_carry_gen!(sbc_zero_page, CPU::get_zero_page, false);
+subtract_with_carry_gen!(sbc_zero_page_x, CPU::get_zero_page_x, false);
+subtract_with_carry_gen!(sbc_zero_page_x_indirect, CPU::get_zero_page_x_indirect, false);
+subtract_with_carry_gen!(sbc_zero_page_y_indirect, CPU::get_zero_page_y_indirect, true);
 
 /*
     Increment/Decrement
 */
 macro_rules! inc_dec_gen {
-    ($name:ident, $target:ident, $operation:path, $num_cycles:literal) => {
-        impl CPU {
+    ($name:ident, $target:ident, $operation:path) => {
+        impl<B: Bus> CPU<B> {
             pub fn $name(&mut self) {
                 self.$target = $operation(self.$target, 1);
                 self.update_negative_zero_flags(self.$target);
-                self.cycle_count += $num_cycles;
             }
         }
     };
 }
 macro_rules! inc_dec_mem_gen {
-    ($name:ident, $addr_mode:path, $operation:path, $num_cycles:literal) => {
-        impl CPU {
+    ($name:ident, $addr_mode:path, $operation:path) => {
+        impl<B: Bus> CPU<B> {
             pub fn $name(&mut self) {
                 let address = $addr_mode(self, false);
-                let value: u8 = $operation(self.memory.read(address), 1);
+                let data = self.memory.read(address);
+                // the unmodified value is written back first
+                self.memory.write(address, data);
+                let value: u8 = $operation(data, 1);
                 self.memory.write(address, value);
                 self.update_negative_zero_flags(value);
-                self.cycle_count += $num_cycles;
             }
         }
     };
 }
-inc_dec_gen!(inc_x, idx_register_x, u8::wrapping_add, 2);
-inc_dec_gen!(inc_y, idx_register_y, u8::wrapping_add, 2);
-inc_dec_gen!(dec_x, idx_register_x, u8::wrapping_sub, 2);
-inc_dec_gen!(dec_y, idx_register_y, u8::wrapping_sub, 2);
-inc_dec_mem_gen!(inc_absolute, CPU::get_absolute, u8::wrapping_add, 6);
-inc_dec_mem_gen!(inc_absolute_x, CPU::get_absolute_x, u8::wrapping_add, 7);
-inc_dec_mem_gen!(inc_zero_page, CPU::get_zero_page, u8::wrapping_add, 5);
-inc_dec_mem_gen!(inc_zero_page_x, CPU::get_zero_page_x, u8::wrapping_add, 6);
-inc_dec_mem_gen!(dec_absolute, CPU::get_absolute, u8::wrapping_sub, 6);
-inc_dec_mem_gen!(dec_absolute_x, CPU::get_absolute_x, u8::wrapping_sub, 7);
-inc_dec_mem_gen!(dec_zero_page, CPU::get_zero_page, u8::wrapping_sub, 5);
-inc_dec_mem_gen!(dec_zero_page_x, CPU::get_zero_page_x, u8::wrapping_sub, 6);
+inc_dec_gen!(inc_x, idx_register_x, u8::wrapping_add);
+inc_dec_gen!(inc_y, idx_register_y, u8::wrapping_add);
+inc_dec_gen!(dec_x, idx_register_x, u8::wrapping_sub);
+inc_dec_gen!(dec_y, idx_register_y, u8::wrapping_sub);
+inc_dec_mem_gen!(inc_absolute, CPU::get_absolute, u8::wrapping_add);
+inc_dec_mem_gen!(inc_absolute_x, CPU::get_absolute_x, u8::wrapping_add);
+inc_dec_mem_gen!(inc_zero_page, CPU::get_zero_page, u8::wrapping_add);
+inc_dec_mem_gen!(inc_zero_page_x, CPU::get_zero_page_x, u8::wrapping_add);
+inc_dec_mem_gen!(dec_absolute, CPU::get_absolute, u8::wrapping_sub);
+inc_dec_mem_gen!(dec_absolute_x, CPU::get_absolute_x, u8::wrapping_sub);
+inc_dec_mem_gen!(dec_zero_page, CPU::get_zero_page, u8::wrapping_sub);
+inc_dec_mem_gen!(dec_zero_page_x, CPU::get_zero_page_x, u8::wrapping_sub);
 
 /*
-    Arithmetic Left Shift
-    ASL shifts all of the bits of a memory value or the accumulator one position to the left, moving the value of each bit into the next bit.
-    Bit 7 is shifted into the carry flag, and 0 is shifted into bit 0.
-    This is equivalent to multiplying an unsigned value by 2, with carry indicating overflow.
+    Shifts and rotates
+    ASL/LSR/ROL/ROR operate on the accumulator or a memory value, the bit operations are written once
+    in '*_value' below and applied to either through 'Operand'. Bit 7 (ASL/ROL) or bit 0 (LSR/ROR)
+    is shifted into the carry flag, ROL/ROR shift the old carry into the vacated bit, so rotating
+    9 times returns the value and carry to their original state.
 */
 
-macro_rules! arithmetic_left_shift_gen {
-    ($name:ident, $addr_mode:path, $num_cycles:literal) => {
-        impl CPU {
-            pub fn $name(&mut self) {
-                // Get the address using the provided addressing mode
-                let address = $addr_mode(self, false);
-                let mut data = self.memory.read(address);
-                // Assign carry bit based on top bit of data
-                self.processor_status.set(ProcessorStatusFlags::CARRY, data >> 7 == 1);
-                data <<= 1;
-                self.memory.write(address, data);
-                self.update_negative_zero_flags(data);
-                self.cycle_count += $num_cycles;
-            }
-        }
-    };
+// target of a read-modify-write instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Operand {
+    Accumulator,
+    Memory(u16),
 }
-arithmetic_left_shift_gen!(asl_zero_page, CPU::get_zero_page, 5);
-arithmetic_left_shift_gen!(asl_zero_page_x, CPU::get_zero_page_x, 6);
-arithmetic_left_shift_gen!(asl_absolute, CPU::get_absolute, 6);
-arithmetic_left_shift_gen!(asl_absolute_x, CPU::get_absolute_x, 7);
 
-/*
-    Rotate Left
-    shifts a memory value or the accumulator to the left, moving the value of each bit into the next bit and treating the carry flag as though it is both above bit 7 and below bit 0.
-    Specifically, the value in carry is shifted into bit 0, and bit 7 is shifted into carry. Rotating left 9 times simply returns the value and carry back to their original state.
-*/
-macro_rules! rotate_left_gen {
-    ($name:ident, $addr_mode:path, $num_cycles:literal) => {
-        impl CPU {
-            pub fn $name(&mut self) {
-                // Get the address using the provided addressing mode
-                let address = $addr_mode(self, false);
-                let mut data = self.memory.read(address);
-                // carry bit becomes bottom bit
-                let bottom_bit = if self.processor_status.contains(ProcessorStatusFlags::CARRY) { 1 } else { 0 };
-                // Assign carry bit based on bit 7
-                self.processor_status.set(ProcessorStatusFlags::CARRY, data >> 7 == 1);
-                // new value is rotated to the left and the bottom bit is set to the carry bit
-                data = (data << 1) | bottom_bit;
+impl<B: Bus> CPU<B> {
+    // apply 'modify' to the operand and store the result, memory operands first get the unmodified value written back
+    #[inline]
+    fn modify_operand(&mut self, operand: Operand, modify: fn(&mut Self, u8) -> u8) -> u8 {
+        match operand {
+            Operand::Accumulator => {
+                self.accumulator = modify(self, self.accumulator);
+                self.accumulator
+            }
+            Operand::Memory(address) => {
+                let data = self.memory.read(address);
+                self.memory.write(address, data);
+                let data = modify(self, data);
                 self.memory.write(address, data);
-                self.update_negative_zero_flags(data); // Negative flag should always be clear
-                self.cycle_count += $num_cycles;
+                data
             }
         }
-    };
-}
-rotate_left_gen!(rol_zero_page, CPU::get_zero_page, 5);
-rotate_left_gen!(rol_zero_page_x, CPU::get_zero_page_x, 6);
-rotate_left_gen!(rol_absolute, CPU::get_absolute, 6);
-rotate_left_gen!(rol_absolute_x, CPU::get_absolute_x, 7);
+    }
+
+    fn shift_left_value(&mut self, data: u8) -> u8 {
+        self.processor_status.set(ProcessorStatusFlags::CARRY, data >> 7 == 1);
+        data << 1
+    }
+
+    fn rotate_left_value(&mut self, data: u8) -> u8 {
+        let bottom_bit = self.processor_status.contains(ProcessorStatusFlags::CARRY) as u8;
+        self.processor_status.set(ProcessorStatusFlags::CARRY, data >> 7 == 1);
+        (data << 1) | bottom_bit
+    }
+
+    fn shift_right_value(&mut self, data: u8) -> u8 {
+        self.processor_status.set(ProcessorStatusFlags::CARRY, data & 1 == 1);
+        data >> 1
+    }
 
+    fn rotate_right_value(&mut self, data: u8) -> u8 {
+        let top_bit = self.processor_status.contains(ProcessorStatusFlags::CARRY) as u8;
+        self.processor_status.set(ProcessorStatusFlags::CARRY, data & 1 == 1);
+        (data >> 1) | (top_bit << 7)
+    }
+}
 
-macro_rules! logical_shift_right_gen {
-    ($name:ident, $addr_mode:path, $num_cycles:literal) => {
-        impl CPU {
+macro_rules! shift_rotate_gen {
+    ($name:ident, accumulator, $modify:path) => {
+        impl<B: Bus> CPU<B> {
             pub fn $name(&mut self) {
-                // Get the address using the provided addressing mode
-                let address = $addr_mode(self, false);
-                let mut data = self.memory.read(address);
-                // Assign carry bit based on 0th bit of data
-                self.processor_status.set(ProcessorStatusFlags::CARRY, data & 1 == 1);
-                data >>= 1;
-                self.memory.write(address, data);
-                self.update_negative_zero_flags(data); // Negative flag should always be clear
-                self.cycle_count += $num_cycles;
+                let result = self.modify_operand(Operand::Accumulator, $modify);
+                self.update_negative_zero_flags(result);
             }
         }
     };
-}
-logical_shift_right_gen!(lsr_zero_page, CPU::get_zero_page, 5);
-logical_shift_right_gen!(lsr_zero_page_x, CPU::get_zero_page_x, 6);
-logical_shift_right_gen!(lsr_absolute, CPU::get_absolute, 6);
-logical_shift_right_gen!(lsr_absolute_x, CPU::get_absolute_x, 7);
-
-/* ROR shifts a memory value or the accumulator to the right, moving the value of each bit into the next bit and treating the carry flag as though it is both above bit 7 and below bit 0.
-Specifically, the value in carry is shifted into bit 7, and bit 0 is shifted into carry.
-Rotating right 9 times simply returns the value and carry back to their original state.
-*/
-macro_rules! rotate_right_gen {
-    ($name:ident, $addr_mode:path, $num_cycles:literal) => {
-        impl CPU {
+    ($name:ident, $addr_mode:path, $modify:path) => {
+        impl<B: Bus> CPU<B> {
             pub fn $name(&mut self) {
-                // Get the address using the provided addressing mode
                 let address = $addr_mode(self, false);
-                let mut data = self.memory.read(address);
-                // carry bit becomes top bit
-                let top_bit = if self.processor_status.contains(ProcessorStatusFlags::CARRY) { 1 } else { 0 };
-                // Assign carry bit based on 0th bit of data
-                self.processor_status.set(ProcessorStatusFlags::CARRY, data & 1 == 1);
-                // new value is rotated to the right and the top bit is set to the carry bit
-                data = (data >> 1) | (top_bit << 7);
-                self.memory.write(address, data);
-                self.update_negative_zero_flags(data); // Negative flag should always be clear
-                self.cycle_count += $num_cycles;
+                let result = self.modify_operand(Operand::Memory(address), $modify);
+                self.update_negative_zero_flags(result);
             }
         }
     };
 }
-rotate_right_gen!(ror_zero_page, CPU::get_zero_page, 5);
-rotate_right_gen!(ror_zero_page_x, CPU::get_zero_page_x, 6);
-rotate_right_gen!(ror_absolute, CPU::get_absolute, 6);
-rotate_right_gen!(ror_absolute_x, CPU::get_absolute_x, 7);
+shift_rotate_gen!(asl_a, accumulator, CPU::shift_left_value);
+shift_rotate_gen!(asl_zero_page, CPU::get_zero_page, CPU::shift_left_value);
+shift_rotate_gen!(asl_zero_page_x, CPU::get_zero_page_x, CPU::shift_left_value);
+shift_rotate_gen!(asl_absolute, CPU::get_absolute, CPU::shift_left_value);
+shift_rotate_gen!(asl_absolute_x, CPU::get_absolute_x, CPU::shift_left_value);
+shift_rotate_gen!(rol_a, accumulator, CPU::rotate_left_value);
+shift_rotate_gen!(rol_zero_page, CPU::get_zero_page, CPU::rotate_left_value);
+shift_rotate_gen!(rol_zero_page_x, CPU::get_zero_page_x, CPU::rotate_left_value);
+shift_rotate_gen!(rol_absolute, CPU::get_absolute, CPU::rotate_left_value);
+shift_rotate_gen!(rol_absolute_x, CPU::get_absolute_x, CPU::rotate_left_value);
+shift_rotate_gen!(lsr_a, accumulator, CPU::shift_right_value);
+shift_rotate_gen!(lsr_zero_page, CPU::get_zero_page, CPU::shift_right_value);
+shift_rotate_gen!(lsr_zero_page_x, CPU::get_zero_page_x, CPU::shift_right_value);
+shift_rotate_gen!(lsr_absolute, CPU::get_absolute, CPU::shift_right_value);
+shift_rotate_gen!(lsr_absolute_x, CPU::get_absolute_x, CPU::shift_right_value);
+shift_rotate_gen!(ror_a, accumulator, CPU::rotate_right_value);
+shift_rotate_gen!(ror_zero_page, CPU::get_zero_page, CPU::rotate_right_value);
+shift_rotate_gen!(ror_zero_page_x, CPU::get_zero_page_x, CPU::rotate_right_value);
+shift_rotate_gen!(ror_absolute, CPU::get_absolute, CPU::rotate_right_value);
+shift_rotate_gen!(ror_absolute_x, CPU::get_absolute_x, CPU::rotate_right_value);
 
 /*
 Bit Test- BIT modifies flags, but does not change memory or registers. The zero flag is set depending on the result of the accumulator AND memory value,
@@ -912,8 +1729,8 @@ if jumped to directly, allowing two code paths to be interleaved. However, becau
 this carries risk of triggering side effects if it reads a hardware register. This trick can be useful when working under tight constraints on space, time, or register usage.
 */
 macro_rules! bit_test_gen {
-    ($name:ident, $addr_mode:path, $num_cycles:literal) => {
-        impl CPU {
+    ($name:ident, $addr_mode:path) => {
+        impl<B: Bus> CPU<B> {
             pub fn $name(&mut self) {
                 let address = $addr_mode(self, false);
                 let data = self.memory.read(address);
@@ -924,53 +1741,212 @@ macro_rules! bit_test_gen {
 
                 // Zero flag is set if (A & memory) == 0
                 self.processor_status.set(ProcessorStatusFlags::ZERO, (self.accumulator & data) == 0);
-                self.cycle_count += $num_cycles;
             }
         }
     };
 }
-bit_test_gen!(bit_absolute, CPU::get_absolute, 4);
-bit_test_gen!(bit_zero_page, CPU::get_zero_page, 3);
+bit_test_gen!(bit_absolute, CPU::get_absolute);
+bit_test_gen!(bit_zero_page, CPU::get_zero_page);
+
+/*
+Compare:compares a register to a memory value, setting flags as appropriate but not modifying any registers. The comparison is implemented as a subtraction,
+setting carry if there is no borrow, zero if the result is 0, and negative if the result is negative.
+However, carry and zero are often most easily remembered as inequalities.
+*/
+
+macro_rules!  compare_gen{
+    ($name: ident, $register: ident, $addr_mode:path, $check_page_cross:literal) => {
+        impl<B: Bus> CPU<B> {
+            pub fn $name(&mut self) {
+                let address = $addr_mode(self, $check_page_cross);
+                let data = self.memory.read(address);
+
+                let result = self.$register.wrapping_sub(data);
+
+                self.processor_status.set(ProcessorStatusFlags::CARRY, self.$register >= data);
+                self.processor_status.set(ProcessorStatusFlags::ZERO, self.$register == data);
+                self.processor_status.set(ProcessorStatusFlags::NEGATIVE, result & 0x80 != 0);
+
+            }
+        }
+
+    };
+}
+compare_gen!(cmp_immediate, accumulator, CPU::get_immediate, false);
+compare_gen!(cmp_absolute, accumulator, CPU::get_absolute, false);
+compare_gen!(cmp_absolute_x, accumulator, CPU::get_absolute_x, true);
+compare_gen!(cmp_absolute_y, accumulator, CPU::get_absolute_y, true);
+compare_gen!(cmp_zero_page, accumulator, CPU::get_zero_page, false);
+compare_gen!(cmp_zero_page_x, accumulator, CPU::get_zero_page_x, false);
+compare_gen!(cmp_zero_page_x_indirect, accumulator, CPU::get_zero_page_x_indirect, false);
+compare_gen!(cmp_zero_page_y_indirect, accumulator, CPU::get_zero_page_y_indirect, true);
+compare_gen!(cpx_immediate, idx_register_x, CPU::get_immediate, false);
+compare_gen!(cpx_absolute, idx_register_x, CPU::get_absolute, false);
+compare_gen!(cpx_zero_page, idx_register_x, CPU::get_zero_page, false);
+compare_gen!(cpy_immediate, idx_register_y, CPU::get_immediate, false);
+compare_gen!(cpy_absolute, idx_register_y, CPU::get_absolute, false);
+compare_gen!(cpy_zero_page, idx_register_y, CPU::get_zero_page, false);
+
+/*
+    unofficial instructions
+    undocumented opcodes which are stable on the NES' 6502 and used by some games and test roms
+*/
+
+// LAX: load A and X with the same value
+macro_rules! lax_gen {
+    ($name: ident, $addressing_mode: path, $check_page_cross:literal) => {
+        impl<B: Bus> CPU<B> {
+            pub fn $name(&mut self) {
+                let address = $addressing_mode(self, $check_page_cross);
+                self.accumulator = self.memory.read(address);
+                self.idx_register_x = self.accumulator;
+                self.update_negative_zero_flags(self.accumulator);
+            }
+        }
+    };
+}
+lax_gen!(lax_absolute, CPU::get_absolute, false);
+lax_gen!(lax_absolute_y, CPU::get_absolute_y, true);
+lax_gen!(lax_zero_page, CPU::get_zero_page, false);
+lax_gen!(lax_zero_page_y, CPU::get_zero_page_y, false);
+lax_gen!(lax_zero_page_x_indirect, CPU::get_zero_page_x_indirect, false);
+lax_gen!(lax_zero_page_y_indirect, CPU::get_zero_page_y_indirect, true);
+
+// SAX: store A & X, flags are unaffected
+macro_rules! sax_gen {
+    ($name: ident, $addr_mode: path) => {
+        impl<B: Bus> CPU<B> {
+            pub fn $name(&mut self) {
+                let address = $addr_mode(self, false);
+                self.memory.write(address, self.accumulator & self.idx_register_x);
+            }
+        }
+    };
+}
+sax_gen!(sax_absolute, CPU::get_absolute);
+sax_gen!(sax_zero_page, CPU::get_zero_page);
+sax_gen!(sax_zero_page_y, CPU::get_zero_page_y);
+sax_gen!(sax_zero_page_x_indirect, CPU::get_zero_page_x_indirect);
+
+// NOP with an operand, the operand is read (and discarded) like any other load
+macro_rules! nop_gen {
+    ($name: ident, $addressing_mode: path, $check_page_cross:literal) => {
+        impl<B: Bus> CPU<B> {
+            pub fn $name(&mut self) {
+                let address = $addressing_mode(self, $check_page_cross);
+                self.memory.read(address);
+            }
+        }
+    };
+}
+nop_gen!(nop_immediate, CPU::get_immediate, false);
+nop_gen!(nop_zero_page, CPU::get_zero_page, false);
+nop_gen!(nop_zero_page_x, CPU::get_zero_page_x, false);
+nop_gen!(nop_absolute, CPU::get_absolute, false);
+nop_gen!(nop_absolute_x, CPU::get_absolute_x, true);
 
 /*
-Compare:compares a register to a memory value, setting flags as appropriate but not modifying any registers. The comparison is implemented as a subtraction,
-setting carry if there is no borrow, zero if the result is 0, and negative if the result is negative.
-However, carry and zero are often most easily remembered as inequalities.
+    read-modify-write combinations
+    modify a memory value like ASL/ROL/LSR/ROR/DEC/INC, then combine the result with the accumulator
+    like ORA/AND/EOR/ADC/CMP/SBC. Indexed modes always take the extra cycle.
 */
+impl<B: Bus> CPU<B> {
+    fn decrement_value(&mut self, data: u8) -> u8 {
+        data.wrapping_sub(1)
+    }
 
-macro_rules!  compare_gen{
-    ($name: ident, $register: ident, $addr_mode:path, $check_page_cross:literal, $num_cycles:literal) => {
-        impl CPU{
-            pub fn $name(&mut self) {
-                let address = $addr_mode(self, $check_page_cross);
-                let data = self.memory.read(address);
+    fn increment_value(&mut self, data: u8) -> u8 {
+        data.wrapping_add(1)
+    }
 
-                let result = self.$register.wrapping_sub(data);
+    fn or_accumulator(&mut self, data: u8) {
+        self.accumulator |= data;
+        self.update_negative_zero_flags(self.accumulator);
+    }
 
-                self.processor_status.set(ProcessorStatusFlags::CARRY, self.$register >= data);
-                self.processor_status.set(ProcessorStatusFlags::ZERO, self.$register == data);
-                self.processor_status.set(ProcessorStatusFlags::NEGATIVE, result & 0x80 != 0);
-                self.cycle_count += $num_cycles;
+    fn and_accumulator(&mut self, data: u8) {
+        self.accumulator &= data;
+        self.update_negative_zero_flags(self.accumulator);
+    }
+
+    fn exclusive_or_accumulator(&mut self, data: u8) {
+        self.accumulator ^= data;
+        self.update_negative_zero_flags(self.accumulator);
+    }
+
+    fn compare_accumulator(&mut self, data: u8) {
+        let result = self.accumulator.wrapping_sub(data);
+        self.processor_status.set(ProcessorStatusFlags::CARRY, self.accumulator >= data);
+        self.processor_status.set(ProcessorStatusFlags::ZERO, self.accumulator == data);
+        self.processor_status.set(ProcessorStatusFlags::NEGATIVE, result & 0x80 != 0);
+    }
+}
 
+macro_rules! read_modify_combine_gen {
+    ($name:ident, $addr_mode:path, $modify:path, $combine:path) => {
+        impl<B: Bus> CPU<B> {
+            pub fn $name(&mut self) {
+                let address = $addr_mode(self, false);
+                let data = self.modify_operand(Operand::Memory(address), $modify);
+                $combine(self, data);
             }
         }
-
     };
 }
-compare_gen!(cmp_immediate, accumulator, CPU::get_immediate, false, 2);
-compare_gen!(cmp_absolute, accumulator, CPU::get_absolute, false, 4);
-compare_gen!(cmp_absolute_x, accumulator, CPU::get_absolute_x, true, 4);
-compare_gen!(cmp_absolute_y, accumulator, CPU::get_absolute_y, true, 4);
-compare_gen!(cmp_zero_page, accumulator, CPU::get_zero_page, false, 3);
-compare_gen!(cmp_zero_page_x, accumulator, CPU::get_zero_page_x, false, 4);
-compare_gen!(cmp_zero_page_x_indirect, accumulator, CPU::get_zero_page_x_indirect, false, 6);
-compare_gen!(cmp_zero_page_y_indirect, accumulator, CPU::get_zero_page_y_indirect, true, 5);
-compare_gen!(cpx_immediate, idx_register_x, CPU::get_immediate, false, 2);
-compare_gen!(cpx_absolute, idx_register_x, CPU::get_absolute, false, 4);
-compare_gen!(cpx_zero_page, idx_register_x, CPU::get_zero_page, false, 3);
-compare_gen!(cpy_immediate, idx_register_y, CPU::get_immediate, false, 2);
-compare_gen!(cpy_absolute, idx_register_y, CPU::get_absolute, false, 4);
-compare_gen!(cpy_zero_page, idx_register_y, CPU::get_zero_page, false, 3);
+
+// SLO: ASL then ORA
+read_modify_combine_gen!(slo_zero_page, CPU::get_zero_page, CPU::shift_left_value, CPU::or_accumulator);
+read_modify_combine_gen!(slo_zero_page_x, CPU::get_zero_page_x, CPU::shift_left_value, CPU::or_accumulator);
+read_modify_combine_gen!(slo_absolute, CPU::get_absolute, CPU::shift_left_value, CPU::or_accumulator);
+read_modify_combine_gen!(slo_absolute_x, CPU::get_absolute_x, CPU::shift_left_value, CPU::or_accumulator);
+read_modify_combine_gen!(slo_absolute_y, CPU::get_absolute_y, CPU::shift_left_value, CPU::or_accumulator);
+read_modify_combine_gen!(slo_zero_page_x_indirect, CPU::get_zero_page_x_indirect, CPU::shift_left_value, CPU::or_accumulator);
+read_modify_combine_gen!(slo_zero_page_y_indirect, CPU::get_zero_page_y_indirect, CPU::shift_left_value, CPU::or_accumulator);
+
+// RLA: ROL then AND
+read_modify_combine_gen!(rla_zero_page, CPU::get_zero_page, CPU::rotate_left_value, CPU::and_accumulator);
+read_modify_combine_gen!(rla_zero_page_x, CPU::get_zero_page_x, CPU::rotate_left_value, CPU::and_accumulator);
+read_modify_combine_gen!(rla_absolute, CPU::get_absolute, CPU::rotate_left_value, CPU::and_accumulator);
+read_modify_combine_gen!(rla_absolute_x, CPU::get_absolute_x, CPU::rotate_left_value, CPU::and_accumulator);
+read_modify_combine_gen!(rla_absolute_y, CPU::get_absolute_y, CPU::rotate_left_value, CPU::and_accumulator);
+read_modify_combine_gen!(rla_zero_page_x_indirect, CPU::get_zero_page_x_indirect, CPU::rotate_left_value, CPU::and_accumulator);
+read_modify_combine_gen!(rla_zero_page_y_indirect, CPU::get_zero_page_y_indirect, CPU::rotate_left_value, CPU::and_accumulator);
+
+// SRE: LSR then EOR
+read_modify_combine_gen!(sre_zero_page, CPU::get_zero_page, CPU::shift_right_value, CPU::exclusive_or_accumulator);
+read_modify_combine_gen!(sre_zero_page_x, CPU::get_zero_page_x, CPU::shift_right_value, CPU::exclusive_or_accumulator);
+read_modify_combine_gen!(sre_absolute, CPU::get_absolute, CPU::shift_right_value, CPU::exclusive_or_accumulator);
+read_modify_combine_gen!(sre_absolute_x, CPU::get_absolute_x, CPU::shift_right_value, CPU::exclusive_or_accumulator);
+read_modify_combine_gen!(sre_absolute_y, CPU::get_absolute_y, CPU::shift_right_value, CPU::exclusive_or_accumulator);
+read_modify_combine_gen!(sre_zero_page_x_indirect, CPU::get_zero_page_x_indirect, CPU::shift_right_value, CPU::exclusive_or_accumulator);
+read_modify_combine_gen!(sre_zero_page_y_indirect, CPU::get_zero_page_y_indirect, CPU::shift_right_value, CPU::exclusive_or_accumulator);
+
+// RRA: ROR then ADC, the carry from ROR is added
+read_modify_combine_gen!(rra_zero_page, CPU::get_zero_page, CPU::rotate_right_value, CPU::add_with_carry);
+read_modify_combine_gen!(rra_zero_page_x, CPU::get_zero_page_x, CPU::rotate_right_value, CPU::add_with_carry);
+read_modify_combine_gen!(rra_absolute, CPU::get_absolute, CPU::rotate_right_value, CPU::add_with_carry);
+read_modify_combine_gen!(rra_absolute_x, CPU::get_absolute_x, CPU::rotate_right_value, CPU::add_with_carry);
+read_modify_combine_gen!(rra_absolute_y, CPU::get_absolute_y, CPU::rotate_right_value, CPU::add_with_carry);
+read_modify_combine_gen!(rra_zero_page_x_indirect, CPU::get_zero_page_x_indirect, CPU::rotate_right_value, CPU::add_with_carry);
+read_modify_combine_gen!(rra_zero_page_y_indirect, CPU::get_zero_page_y_indirect, CPU::rotate_right_value, CPU::add_with_carry);
+
+// DCP: DEC then CMP
+read_modify_combine_gen!(dcp_zero_page, CPU::get_zero_page, CPU::decrement_value, CPU::compare_accumulator);
+read_modify_combine_gen!(dcp_zero_page_x, CPU::get_zero_page_x, CPU::decrement_value, CPU::compare_accumulator);
+read_modify_combine_gen!(dcp_absolute, CPU::get_absolute, CPU::decrement_value, CPU::compare_accumulator);
+read_modify_combine_gen!(dcp_absolute_x, CPU::get_absolute_x, CPU::decrement_value, CPU::compare_accumulator);
+read_modify_combine_gen!(dcp_absolute_y, CPU::get_absolute_y, CPU::decrement_value, CPU::compare_accumulator);
+read_modify_combine_gen!(dcp_zero_page_x_indirect, CPU::get_zero_page_x_indirect, CPU::decrement_value, CPU::compare_accumulator);
+read_modify_combine_gen!(dcp_zero_page_y_indirect, CPU::get_zero_page_y_indirect, CPU::decrement_value, CPU::compare_accumulator);
+
+// ISC: INC then SBC
+read_modify_combine_gen!(isc_zero_page, CPU::get_zero_page, CPU::increment_value, CPU::subtract_with_carry);
+read_modify_combine_gen!(isc_zero_page_x, CPU::get_zero_page_x, CPU::increment_value, CPU::subtract_with_carry);
+read_modify_combine_gen!(isc_absolute, CPU::get_absolute, CPU::increment_value, CPU::subtract_with_carry);
+read_modify_combine_gen!(isc_absolute_x, CPU::get_absolute_x, CPU::increment_value, CPU::subtract_with_carry);
+read_modify_combine_gen!(isc_absolute_y, CPU::get_absolute_y, CPU::increment_value, CPU::subtract_with_carry);
+read_modify_combine_gen!(isc_zero_page_x_indirect, CPU::get_zero_page_x_indirect, CPU::increment_value, CPU::subtract_with_carry);
+read_modify_combine_gen!(isc_zero_page_y_indirect, CPU::get_zero_page_y_indirect, CPU::increment_value, CPU::subtract_with_carry);
 
 
 
@@ -1049,9 +2025,9 @@ mod tests {
             instr.push(a[0]);
             instr.push(a[1]);
         }
-        let len = instr.len();
         let mut cpu = CPU::with_program(instr);
-        for _ in 0..len {
+        // 'or' followed by the stores
+        for _ in 0..1 + (1<<7) {
             cpu.advance();
         }
         for i in 0..1<<7 {
@@ -1110,8 +2086,21 @@ mod tests {
         cpu.execute(Some(3));
         assert!(cpu.memory.read(0x1100) == 0xaa);
 
-        //test absolute indirect
-
+        //test absolute indirect, the high byte is read from the start of the same page
+        /*
+        lda #$34
+        sta $02ff
+        lda #$12
+        sta $0200
+        lda #$56
+        sta $0300
+        jmp ($02ff)
+         */
+        let mut cpu = CPU::with_program(vec![
+            0xa9, 0x34, 0x8d, 0xff, 0x02, 0xa9, 0x12, 0x8d, 0x00, 0x02,
+            0xa9, 0x56, 0x8d, 0x00, 0x03, 0x6c, 0xff, 0x02]);
+        cpu.execute(Some(7));
+        assert!(cpu.program_counter == 0x1234);
 
         //test zero-page x indirect
         /*
@@ -1190,7 +2179,7 @@ mod tests {
     // and zero page x (Opcode: 0x35)
     test_and_or_instruction!(test_and_zero_page_x, 4, [0xa2, 0x50, 0x8d, 0x50, 0x00, 0xA9, 0b00001010, 0x35, 0x00], 0b10101010, 0b00001010); // Set accumulator to 0b10101010, LDX: 0x50, STA: 0x50, LDA: 0b00001010, AND 0x00 x
     // and abs (Opcode: 0x2D)
-    test_and_or_instruction!(test_and_absolute, 3, [0xa2, 0x50, 0x00, 0xA9, 0b00001010, 0x2D, 0x50, 0x00], 0b10101010, 0b00001010); // Set accumulator to 0b10101010, STA: 0x50, LDA: 0b00001010, AND 0x0050
+    test_and_or_instruction!(test_and_absolute, 3, [0x8d, 0x50, 0x00, 0xA9, 0b00001010, 0x2D, 0x50, 0x00], 0b10101010, 0b00001010); // Set accumulator to 0b10101010, STA: 0x50, LDA: 0b00001010, AND 0x0050
     // and abs X (Opcode: 0x3D)
     test_and_or_instruction!(test_and_absolute_x, 4, [0xa2, 0x50, 0x8d, 0x50, 0x00, 0xA9, 0b00001010, 0x3D, 0x00, 0x00], 0b10101010, 0b00001010); // Set accumulator to 0b10101010, LDX: 0x50, STA: 0x50, LDA: 0b00001010, AND 0x0000 x
     // and abs Y (Opcode: 0x39)
@@ -1225,7 +2214,7 @@ mod tests {
     // or zero page x (Opcode: 0x15)
     test_and_or_instruction!(test_or_zero_page_x, 4, [0xa2, 0x50, 0x8d, 0x50, 0x00, 0xA9, 0b00001010, 0x15, 0x00], 0b10101010, 0b10101010); // Set accumulator to 0b10101010, LDX: 0x50, STA: 0x50, LDA: 0b00001010, OR 0x00 x
     // and abs (Opcode: 0x0D)
-    test_and_or_instruction!(test_or_absolute, 3, [0xa2, 0x50, 0x00, 0xA9, 0b00001010, 0x0D, 0x50, 0x00], 0b10101010, 0b00001010); // Set accumulator to 0b10101010, STA: 0x50, LDA: 0b00001010, OR 0x0050
+    test_and_or_instruction!(test_or_absolute, 3, [0x8d, 0x50, 0x00, 0xA9, 0b00001010, 0x0D, 0x50, 0x00], 0b10101010, 0b10101010); // Set accumulator to 0b10101010, STA: 0x50, LDA: 0b00001010, OR 0x0050
     // Or abs X (Opcode: 0x1D)
     test_and_or_instruction!(test_or_absolute_x, 4, [0xa2, 0x50, 0x8d, 0x50, 0x00, 0xA9, 0b00001010, 0x1D, 0x00, 0x00], 0b10101010, 0b10101010); // Set accumulator to 0b10101010, LDX: 0x50, STA: 0x50, LDA: 0b00001010, OR 0x0000 x
     // Or abs y (Opcode: 0x19)
@@ -1362,9 +2351,9 @@ mod tests {
 
         let mut cpu = CPU::with_program(vec![0x08, 0xf8, 0x38, 0x78, 0x08, 0x28, 0x28 ]);
         cpu.execute(Some(6));
-        assert_eq!(cpu.processor_status, ProcessorStatusFlags::CARRY | ProcessorStatusFlags::INTERRUPT | ProcessorStatusFlags::DECIMAL);
+        assert_eq!(cpu.processor_status, ProcessorStatusFlags::CARRY | ProcessorStatusFlags::INTERRUPT | ProcessorStatusFlags::DECIMAL | ProcessorStatusFlags::UNUSED);
         cpu.advance();
-        assert_eq!(cpu.processor_status.bits(), 0x00);
+        assert_eq!(cpu.processor_status, ProcessorStatusFlags::UNUSED);
     }
 
     #[test]
@@ -1585,4 +2574,936 @@ mod tests {
             assert_eq!(cpu.processor_status.contains(ProcessorStatusFlags::CARRY), true);
 
         }
+
+    #[test]
+    fn test_cycles() {
+        let mut cpu = CPU::with_program(vec![
+            0xa2, 0x01,         // ldx #$01
+            0xbd, 0xff, 0x80,   // lda $80ff,X (page cross)
+            0xbd, 0x00, 0x80,   // lda $8000,X
+            0xa9, 0x00,         // lda #$00
+            0xf0, 0x00,         // beq (taken)
+            0xd0, 0x00,         // bne (not taken)
+            ]);
+        let cycles: Vec<u32> = (0..6).map(|_| cpu.advance()).collect();
+        assert_eq!(cycles, vec![2, 5, 4, 2, 3, 2]);
+
+        let mut cpu = CPU::with_program(vec![0xa2, 0x01, 0xbd, 0xff, 0x80]);
+        let start = cpu.cycle_count;
+        assert_eq!(cpu.execute(Some(2)), 7);
+        assert_eq!(cpu.cycle_count - start, 7);
+    }
+
+    #[test]
+    fn test_nmi() {
+        let mut program = vec![
+            0xa9, 0x01, // lda #$01
+            0xea,       // nop
+        ];
+        program.resize(0x4000, 0);
+        // handler at $8010
+        program[0x10] = 0xe8; // inx
+        program[0x11] = 0x40; // rti
+        program[0x3ffa] = 0x10;
+        program[0x3ffb] = 0x80;
+        let mut cpu = CPU::with_program(program);
+        cpu.processor_status = ProcessorStatusFlags::CARRY;
+        cpu.advance();
+        cpu.trigger_nmi();
+        assert_eq!(cpu.advance(), 7);
+        assert_eq!(cpu.program_counter, 0x8010);
+        assert_eq!(cpu.stack_pointer, STACK_RESET.wrapping_sub(3));
+        assert!(cpu.processor_status.contains(ProcessorStatusFlags::INTERRUPT));
+        // pushed status has the break flag clear
+        assert_eq!(cpu.memory.read(STACK_OFFSET + STACK_RESET as u16 - 2), (ProcessorStatusFlags::CARRY | ProcessorStatusFlags::UNUSED).bits());
+        cpu.execute(Some(2));
+        assert_eq!(cpu.idx_register_x, 1);
+        assert_eq!(cpu.program_counter, 0x8002);
+        assert_eq!(cpu.processor_status, ProcessorStatusFlags::CARRY | ProcessorStatusFlags::UNUSED);
+    }
+
+    #[test]
+    fn test_irq() {
+        let mut program = vec![
+            0x78,       // sei
+            0xea,       // nop
+            0x58,       // cli
+            0xea,       // nop
+        ];
+        program.resize(0x4000, 0);
+        // handler at $8010
+        program[0x10] = 0xe8; // inx
+        program[0x11] = 0x40; // rti
+        program[0x3ffe] = 0x10;
+        program[0x3fff] = 0x80;
+        let mut cpu = CPU::with_program(program);
+        cpu.assert_irq(IrqSource::MAPPER);
+        // taken immediately since interrupts start enabled
+        assert_eq!(cpu.advance(), 7);
+        assert_eq!(cpu.program_counter, 0x8010);
+        cpu.acknowledge_irq(IrqSource::MAPPER);
+        cpu.execute(Some(2));
+        assert_eq!(cpu.program_counter, 0x8000);
+
+        // masked while the interrupt flag is set, SEI's own effect is delayed by an instruction
+        cpu.execute(Some(2));
+        cpu.assert_irq(IrqSource::APU_FRAME | IrqSource::DMC);
+        cpu.execute(Some(1));
+        assert_eq!(cpu.program_counter, 0x8003);
+        // CLI's effect is delayed, the following NOP still runs
+        assert_eq!(cpu.advance(), 2);
+        assert_eq!(cpu.advance(), 7);
+        assert_eq!(cpu.program_counter, 0x8010);
+        assert_eq!(cpu.idx_register_x, 1);
+
+        cpu.acknowledge_irq(IrqSource::APU_FRAME);
+        assert!(cpu.irq_asserted());
+        cpu.acknowledge_irq(IrqSource::DMC);
+        assert!(!cpu.irq_asserted());
+    }
+
+    #[test]
+    fn test_lax_sax() {
+        let mut cpu = CPU::with_program(vec![
+            0xa9, 0x8f,         // lda #$8f
+            0x85, 0x10,         // sta $10
+            0xa9, 0x00,         // lda #$00
+            0xa7, 0x10,         // lax $10
+            0xa9, 0xf1,         // lda #$f1
+            0x87, 0x11,         // sax $11
+            0xa0, 0x01,         // ldy #$01
+            0xb7, 0x10,         // lax $10,Y
+            ]);
+        cpu.execute(Some(4));
+        assert_eq!(cpu.accumulator, 0x8f);
+        assert_eq!(cpu.idx_register_x, 0x8f);
+        assert!(cpu.processor_status.contains(ProcessorStatusFlags::NEGATIVE));
+        cpu.execute(Some(2));
+        assert_eq!(cpu.memory.read(0x11), 0x81);
+        // sax leaves the flags from lda
+        assert!(cpu.processor_status.contains(ProcessorStatusFlags::NEGATIVE));
+        cpu.execute(Some(2));
+        assert_eq!(cpu.accumulator, 0x81);
+        assert_eq!(cpu.idx_register_x, 0x81);
+    }
+
+    #[test]
+    fn test_read_modify_combine() {
+        let mut cpu = CPU::with_program(vec![
+            0xa9, 0x81,         // lda #$81
+            0x85, 0x10,         // sta $10
+            0xa9, 0x01,         // lda #$01
+            0x07, 0x10,         // slo $10
+            0x67, 0x10,         // rra $10
+            0xc7, 0x10,         // dcp $10
+            0xe7, 0x10,         // isc $10
+            ]);
+        cpu.execute(Some(4));
+        // $81 << 1 = $02 with carry, A = $01 | $02
+        assert_eq!(cpu.memory.read(0x10), 0x02);
+        assert_eq!(cpu.accumulator, 0x03);
+        assert!(cpu.processor_status.contains(ProcessorStatusFlags::CARRY));
+        // $02 ror with carry = $81, A = $03 + $81 + 0
+        assert_eq!(cpu.execute(Some(1)), 5);
+        assert_eq!(cpu.memory.read(0x10), 0x81);
+        assert_eq!(cpu.accumulator, 0x84);
+        // $81 - 1 = $80, compared with $84
+        cpu.execute(Some(1));
+        assert_eq!(cpu.memory.read(0x10), 0x80);
+        assert!(cpu.processor_status.contains(ProcessorStatusFlags::CARRY));
+        assert!(!cpu.processor_status.contains(ProcessorStatusFlags::ZERO));
+        // $80 + 1 = $81, A = $84 - $81
+        cpu.execute(Some(1));
+        assert_eq!(cpu.memory.read(0x10), 0x81);
+        assert_eq!(cpu.accumulator, 0x03);
+        assert!(cpu.processor_status.contains(ProcessorStatusFlags::CARRY));
+    }
+
+    #[test]
+    fn test_nop_operands() {
+        let mut cpu = CPU::with_program(vec![
+            0x1a,               // nop
+            0x80, 0xff,         // nop #$ff
+            0x44, 0xff,         // nop $ff
+            0xf4, 0xff,         // nop $ff,X
+            0x0c, 0xff, 0xff,   // nop $ffff
+            0xfc, 0xff, 0x00,   // nop $00ff,X
+            ]);
+        cpu.idx_register_x = 1;
+        let cycles: Vec<u32> = (0..6).map(|_| cpu.advance()).collect();
+        assert_eq!(cycles, vec![2, 2, 3, 4, 4, 5]);
+        assert_eq!(cpu.program_counter, 0x800d);
+        assert_eq!(cpu.processor_status, ProcessorStatusFlags::empty());
+    }
+
+    #[test]
+    fn test_kill() {
+        let mut cpu = CPU::with_program(vec![
+            0xe8,   // inx
+            0x02,   // kil
+            0xe8,   // inx
+            ]);
+        cpu.execute(None);
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.program_counter, 0x8001);
+        assert_eq!(cpu.execute(Some(10)), 0);
+        assert_eq!(cpu.idx_register_x, 1);
+    }
+
+    #[test]
+    fn test_zero_page_pointer_wrap() {
+        // pointers at $ff take their high byte from $00
+        /*
+        lda #$34
+        sta $ff
+        lda #$02
+        sta $00
+        ldx #$01
+        lda #$aa
+        sta ($fe, x)
+        ldy #$01
+        lda #$bb
+        sta ($ff), y
+         */
+        let mut cpu = CPU::with_program(vec![
+            0xa9, 0x34, 0x85, 0xff, 0xa9, 0x02, 0x85, 0x00, 0xa2, 0x01,
+            0xa9, 0xaa, 0x81, 0xfe, 0xa0, 0x01, 0xa9, 0xbb, 0x91, 0xff]);
+        cpu.execute(Some(7));
+        assert_eq!(cpu.memory.read(0x0234), 0xaa);
+        cpu.execute(Some(3));
+        assert_eq!(cpu.memory.read(0x0235), 0xbb);
+
+        // the pointer address itself wraps, ($ff, x) with x = 1 reads $00 and $01
+        /*
+        lda #$00
+        sta $00
+        lda #$03
+        sta $01
+        ldx #$01
+        lda ($ff, x)
+        ldx #$02
+        lda $fe, x
+         */
+        let mut cpu = CPU::with_program(vec![
+            0xa9, 0x00, 0x85, 0x00, 0xa9, 0x03, 0x85, 0x01, 0xa2, 0x01,
+            0xa1, 0xff, 0xa2, 0x02, 0xb5, 0xfe]);
+        cpu.memory.write(0x0300, 0x5a);
+        cpu.memory.write(0x0100, 0x77);
+        cpu.execute(Some(6));
+        assert_eq!(cpu.accumulator, 0x5a);
+        cpu.execute(Some(2));
+        assert_eq!(cpu.accumulator, 0x00);
+    }
+
+    #[test]
+    fn test_reset() {
+        let mut program = vec![
+            0xa9, 0x55, // lda #$55
+            0x85, 0x10, // sta $10
+            0x02,       // kil
+        ];
+        program.resize(0x4000, 0);
+        program[0x3ffc] = 0x02;
+        program[0x3ffd] = 0x80;
+        let mut cpu = CPU::with_program(program);
+        cpu.execute(None);
+        assert!(cpu.is_halted());
+
+        cpu.reset();
+        assert!(!cpu.is_halted());
+        assert_eq!(cpu.program_counter, 0x8002);
+        assert_eq!(cpu.stack_pointer, STACK_RESET - 3);
+        assert!(cpu.processor_status.contains(ProcessorStatusFlags::INTERRUPT));
+        assert_eq!(cpu.accumulator, 0x55);
+        assert_eq!(cpu.memory.read(0x10), 0x55);
+    }
+
+    #[test]
+    fn test_break() {
+        let mut program = vec![
+            0x00, 0xff, // brk with padding byte
+            0xe8,       // inx
+        ];
+        program.resize(0x4000, 0);
+        // handler at $8010
+        program[0x10] = 0xc8; // iny
+        program[0x11] = 0x40; // rti
+        program[0x3ffe] = 0x10;
+        program[0x3fff] = 0x80;
+        let mut cpu = CPU::with_program(program);
+        // BRK isn't masked by the interrupt flag
+        cpu.processor_status = ProcessorStatusFlags::INTERRUPT;
+        assert_eq!(cpu.advance(), 7);
+        assert_eq!(cpu.program_counter, 0x8010);
+        let pushed_status = cpu.memory.read(STACK_OFFSET + STACK_RESET as u16 - 2);
+        assert_eq!(pushed_status, (ProcessorStatusFlags::INTERRUPT | ProcessorStatusFlags::BREAK | ProcessorStatusFlags::UNUSED).bits());
+        cpu.execute(Some(3));
+        assert_eq!(cpu.program_counter, 0x8003);
+        assert_eq!((cpu.idx_register_x, cpu.idx_register_y), (1, 1));
+    }
+
+    #[test]
+    fn test_push_pull_status() {
+        let mut cpu = CPU::with_program(vec![
+            0x08,       // php
+            0xa9, 0xff, // lda #$ff
+            0x48,       // pha
+            0x28,       // plp
+            0x28,       // plp
+            ]);
+        cpu.processor_status = ProcessorStatusFlags::CARRY;
+        cpu.advance();
+        // pushed with break and unused set
+        assert_eq!(cpu.memory.read(STACK_OFFSET + STACK_RESET as u16), 0x31);
+        cpu.execute(Some(3));
+        // break is dropped and unused forced on
+        assert_eq!(cpu.processor_status.bits(), 0xef);
+        cpu.advance();
+        assert_eq!(cpu.processor_status, ProcessorStatusFlags::CARRY | ProcessorStatusFlags::UNUSED);
+    }
+
+    #[test]
+    fn test_nmi_hijack() {
+        let mut program = vec![
+            0x00, 0x00, // brk
+        ];
+        program.resize(0x4000, 0);
+        program[0x3ffa] = 0x10;
+        program[0x3ffb] = 0x80;
+        program[0x3ffe] = 0x20;
+        program[0x3fff] = 0x80;
+        let mut frame = vec![0u8; 256 * 240 * 3];
+
+        // vblank starts 3 cycles into the BRK, the NMI vector is used and the NMI isn't taken again
+        let mut cpu = CPU::with_program(program.clone());
+        cpu.memory.ppu.set_ppu_control_1(0x80);
+        cpu.memory.ppu.advance(242 * 341 - 7, &mut frame[..]);
+        assert_eq!(cpu.advance(), 7);
+        assert_eq!(cpu.program_counter, 0x8010);
+        cpu.memory.ppu.advance(7 * 3, &mut frame[..]);
+        assert!(!cpu.memory.ppu.take_nmi());
+
+        // too late to hijack, BRK runs normally and the NMI follows
+        let mut cpu = CPU::with_program(program);
+        cpu.memory.ppu.set_ppu_control_1(0x80);
+        cpu.memory.ppu.advance(242 * 341 - 13, &mut frame[..]);
+        cpu.advance();
+        assert_eq!(cpu.program_counter, 0x8020);
+        cpu.memory.ppu.advance(7 * 3, &mut frame[..]);
+        cpu.advance();
+        assert_eq!(cpu.program_counter, 0x8010);
+    }
+
+    #[test]
+    fn test_dummy_accesses() {
+        use crate::audio::{apu_queue, ApuEvent};
+        use crate::controller::Buttons;
+
+        // a page crossing read first reads $4016, shifting out the A button
+        let mut cpu = CPU::with_program(vec![
+            0xa9, 0x01,         // lda #$01
+            0x8d, 0x16, 0x40,   // sta $4016
+            0xa9, 0x00,         // lda #$00
+            0x8d, 0x16, 0x40,   // sta $4016
+            0xa2, 0x17,         // ldx #$17
+            0xbd, 0xff, 0x40,   // lda $40ff,X
+            0xad, 0x16, 0x40,   // lda $4016
+            ]);
+        cpu.memory.controllers[0].set_buttons(Buttons::B);
+        cpu.execute(Some(7));
+        assert_eq!(cpu.accumulator & 1, 1);
+
+        // read-modify-write instructions write the original value before the result,
+        // $4000 is write only so the read returns the open bus value $40
+        let mut cpu = CPU::with_program(vec![
+            0xee, 0x00, 0x40,   // inc $4000
+            ]);
+        let (producer, mut consumer) = apu_queue(8);
+        cpu.memory.attach_apu_queue(producer);
+        cpu.execute(Some(1));
+        let writes: Vec<u8> = std::iter::from_fn(|| consumer.pop())
+            .map(|event| match event {
+                ApuEvent::Write{data, ..} => data,
+                event => panic!("unexpected event {:?}", event),
+            })
+            .collect();
+        assert_eq!(writes, vec![0x40, 0x41]);
+    }
+
+    #[test]
+    fn test_open_bus() {
+        // unmapped reads return the last value on the bus, the high byte of the operand
+        let mut cpu = CPU::with_program(vec![
+            0xad, 0x00, 0x50,   // lda $5000
+            0xad, 0x00, 0x60,   // lda $6000 (no battery ram)
+            0xad, 0x16, 0x40,   // lda $4016
+            ]);
+        cpu.execute(Some(1));
+        assert_eq!(cpu.accumulator, 0x50);
+        cpu.execute(Some(1));
+        assert_eq!(cpu.accumulator, 0x60);
+        // controller reads only drive the low bits
+        cpu.execute(Some(1));
+        assert_eq!(cpu.accumulator, 0x40);
+    }
+
+    #[test]
+    fn test_step_info() {
+        /*
+            ldx #$02
+            lda $10,x
+            sta ($20,x)
+            jmp ($0030)
+         */
+        let mut program = vec![0xa2, 0x02, 0xb5, 0x10, 0x81, 0x20, 0x6c, 0x30, 0x00];
+        program.resize(0x4000, 0);
+        let mut cpu = CPU::with_program(program);
+        cpu.memory.write(0x12, 0x55);
+        cpu.memory.write(0x22, 0x00);
+        cpu.memory.write(0x23, 0x03);
+        cpu.memory.write(0x30, 0x00);
+        cpu.memory.write(0x31, 0x80);
+
+        let info = cpu.step();
+        assert_eq!((info.address, info.opcode, info.mnemonic), (0x8000, 0xa2, "LDX"));
+        assert_eq!(info.mode, AddressingMode::Immediate);
+        assert_eq!(info.operands(), &[0x02]);
+        assert_eq!(info.effective_address, None);
+        assert_eq!(info.cycles, 2);
+        assert_eq!(info.registers.idx_register_x, 2);
+
+        let info = cpu.step();
+        assert_eq!(info.mode, AddressingMode::ZeroPageX);
+        assert_eq!(info.effective_address, Some(0x12));
+        assert_eq!(info.cycles, 4);
+        assert_eq!(info.registers.accumulator, 0x55);
+
+        let info = cpu.step();
+        assert_eq!(info.mnemonic, "STA");
+        assert_eq!(info.effective_address, Some(0x0300));
+        assert_eq!(info.cycles, 6);
+        assert_eq!(cpu.memory.read(0x0300), 0x55);
+
+        let info = cpu.step();
+        assert_eq!(info.mode, AddressingMode::Indirect);
+        assert_eq!(info.operands(), &[0x30, 0x00]);
+        assert_eq!(info.effective_address, Some(0x8000));
+        assert_eq!(info.registers.program_counter, 0x8000);
+        assert_eq!(info.interrupt, None);
+
+        cpu.trigger_nmi();
+        let info = cpu.step();
+        assert_eq!(info.interrupt, Some(Interrupt::Nmi));
+        assert_eq!(info.cycles, 7);
+
+        assert_eq!(OPCODE_INFO[0x20].mode, AddressingMode::Absolute);
+        assert_eq!(OPCODE_INFO[0x0a].mode, AddressingMode::Accumulator);
+        assert_eq!(OPCODE_INFO[0xb6].mode, AddressingMode::ZeroPageY);
+        assert_eq!(OPCODE_INFO[0xbf].mode, AddressingMode::AbsoluteY);
+        assert_eq!(OPCODE_INFO[0xf0].mode, AddressingMode::Relative);
+        assert_eq!(OPCODE_INFO[0xea].mode, AddressingMode::Implied);
+    }
+
+    #[test]
+    fn test_run_for_cycles() {
+        // nop loop: nop (2), jmp $8000 (3)
+        let mut cpu = CPU::with_program(vec![0xea, 0x4c, 0x00, 0x80]);
+        let start = cpu.cycle_count;
+        assert_eq!(cpu.run_for_cycles(6), 1);
+        assert_eq!(cpu.cycle_count - start, 7);
+        // compensating keeps the total within one instruction of the budget
+        assert_eq!(cpu.run_for_cycles(10 - 1), 1);
+        assert_eq!(cpu.cycle_count - start, 17);
+        assert_eq!(cpu.run_for_cycles(5 - 1), 1);
+        assert_eq!(cpu.cycle_count - start, 22);
+        assert_eq!(cpu.run_for_cycles(0), 0);
+        assert_eq!(cpu.cycle_count - start, 22);
+
+        // a halted CPU stops short of the budget
+        let mut cpu = CPU::with_program(vec![0xea, 0x02]);
+        assert_eq!(cpu.run_for_cycles(100), 0);
+        assert!(cpu.is_halted());
+    }
+
+    #[test]
+    fn test_run_until() {
+        /*
+            inx
+            cpx #$05
+            bne $8000
+            ldy #$01
+            kil
+         */
+        let mut cpu = CPU::with_program(vec![0xe8, 0xe0, 0x05, 0xd0, 0xfb, 0xa0, 0x01, 0x02]);
+        cpu.run_until(|cpu| cpu.idx_register_x == 3);
+        assert_eq!((cpu.idx_register_x, cpu.program_counter), (3, 0x8001));
+        // already satisfied, nothing runs
+        assert_eq!(cpu.run_until(|cpu| cpu.idx_register_x == 3), 0);
+
+        assert_eq!(cpu.run_to_address(0x8005), (2 + 3 + 2) * 2 + 2 + 2);
+        assert_eq!(cpu.idx_register_x, 5);
+        assert_eq!(cpu.idx_register_y, 0);
+
+        // never reached, stops when the CPU halts
+        cpu.run_to_address(0x9000);
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.idx_register_y, 1);
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        /*
+            inx
+            iny
+            jmp $8000
+         */
+        let mut cpu = CPU::with_program(vec![0xe8, 0xc8, 0x4c, 0x00, 0x80]);
+        cpu.add_breakpoint(0x8001);
+        cpu.add_breakpoint(0x8002);
+        assert_eq!(cpu.execute(None), 2);
+        assert_eq!(cpu.breakpoint_hit(), Some(0x8001));
+        // resuming runs the instruction under the breakpoint
+        cpu.execute(None);
+        assert_eq!(cpu.breakpoint_hit(), Some(0x8002));
+        assert_eq!((cpu.idx_register_x, cpu.idx_register_y), (1, 1));
+
+        assert!(cpu.remove_breakpoint(0x8002));
+        assert!(!cpu.remove_breakpoint(0x8002));
+        assert_eq!(cpu.breakpoints().collect::<Vec<_>>(), vec![0x8001]);
+        cpu.execute(None);
+        assert_eq!(cpu.breakpoint_hit(), Some(0x8001));
+        assert_eq!((cpu.idx_register_x, cpu.idx_register_y), (2, 1));
+
+        cpu.clear_breakpoints();
+        cpu.execute(Some(3));
+        assert_eq!(cpu.breakpoint_hit(), None);
+        assert_eq!(cpu.program_counter, 0x8001);
+    }
+
+    #[test]
+    fn test_watchpoints() {
+        use crate::memory::WatchAccess;
+
+        /*
+            lda $10
+            sta $0300
+            inc $11
+            jmp $8000
+         */
+        let mut cpu = CPU::with_program(vec![0xa5, 0x10, 0x8d, 0x00, 0x03, 0xe6, 0x11, 0x4c, 0x00, 0x80]);
+        cpu.memory.write(0x10, 0x33);
+        cpu.memory.add_watchpoint(0x0300..=0x03ff, WatchAccess::WRITE);
+        cpu.memory.add_watchpoint(0x0011..=0x0011, WatchAccess::READ);
+        cpu.execute(None);
+        let hit = WatchpointHit { address: 0x0300, data: 0x33, access: WatchAccess::WRITE };
+        assert_eq!(cpu.watchpoint_hit(), Some(hit));
+        assert_eq!(cpu.program_counter, 0x8005);
+
+        cpu.execute(None);
+        let hit = WatchpointHit { address: 0x0011, data: 0x00, access: WatchAccess::READ };
+        assert_eq!(cpu.watchpoint_hit(), Some(hit));
+        assert_eq!(cpu.program_counter, 0x8007);
+        assert_eq!(cpu.memory.peek(0x11), 1);
+
+        // peeking never triggers
+        cpu.memory.peek(0x0011);
+        assert_eq!(cpu.memory.take_watchpoint_hit(), None);
+        cpu.memory.clear_watchpoints();
+        cpu.execute(Some(8));
+        assert_eq!(cpu.watchpoint_hit(), None);
+    }
+
+    #[test]
+    fn test_trace_hook() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+
+        // ldx #$05, stx $0200, kil
+        let mut cpu = CPU::with_program(vec![0xa2, 0x05, 0x8e, 0x00, 0x02, 0x02]);
+        let trace = Rc::new(RefCell::new(Vec::new()));
+        let log = trace.clone();
+        cpu.set_trace_hook(move |entry| log.borrow_mut().push(*entry));
+        cpu.execute(Some(2));
+
+        let trace = trace.borrow();
+        assert_eq!(trace.len(), 2);
+        assert_eq!(trace[0].registers.program_counter, 0x8000);
+        assert_eq!(trace[0].operands(), &[0x05]);
+        assert_eq!(trace[1].opcode, 0x8e);
+        assert_eq!(trace[1].operands(), &[0x00, 0x02]);
+        assert_eq!(trace[1].instruction().to_string(), "STX $0200");
+        assert_eq!(trace[1].registers.idx_register_x, 5);
+        assert_eq!(trace[1].cycle_count, trace[0].cycle_count + 2);
+        assert_eq!(
+            trace[1].to_string(),
+            format!("8002 OP:(8E){:30} A:00 X:05 Y:00 P:00 SP:FF CYC:9", "store_x_absolute")
+        );
+
+        cpu.clear_trace_hook();
+        cpu.execute(None);
+        assert_eq!(trace.len(), 2);
+    }
+
+    #[test]
+    fn test_save_state() {
+        // sei, ldx #$01, inx, inx, kil
+        let mut cpu = CPU::with_program(vec![0x78, 0xa2, 0x01, 0xe8, 0xe8, 0x02]);
+        cpu.execute(Some(3));
+        cpu.assert_irq(IrqSource::MAPPER);
+        let state = cpu.save_state();
+
+        cpu.execute(None);
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.idx_register_x, 3);
+
+        cpu.load_state(&state);
+        assert_eq!(cpu.save_state(), state);
+        assert!(!cpu.is_halted() && cpu.irq_asserted());
+        assert_eq!((cpu.program_counter, cpu.idx_register_x), (0x8004, 2));
+    }
+
+    #[test]
+    fn test_opcode_info_cycles() {
+        // every emulated opcode takes its base cycles when nothing crosses a page
+        for opcode in 0..=255u8 {
+            let info = OPCODE_INFO[opcode as usize];
+            if !info.is_implemented() || info.mode == AddressingMode::Relative || info.mnemonic == "KIL" {
+                continue;
+            }
+            let mut program = vec![opcode, 0x00, 0x02];
+            program.resize(0x4000, 0);
+            let mut cpu = CPU::with_program(program);
+            assert_eq!(cpu.execute(Some(1)), info.cycles as u32, "opcode {:02X} {}", opcode, info.name);
+        }
+
+        // a page cross adds at most one cycle, stores and read-modify-writes include it in the table
+        for opcode in 0..=255u8 {
+            let info = OPCODE_INFO[opcode as usize];
+            if !info.is_implemented() || info.mnemonic == "KIL"
+                || !matches!(info.mode, AddressingMode::AbsoluteX | AddressingMode::AbsoluteY | AddressingMode::ZeroPageYIndirect) {
+                continue;
+            }
+            // $02F0 indexed by $10, the pointer for (zp),Y is at $00
+            let mut program = vec![opcode, 0xf0, 0x02];
+            program.resize(0x4000, 0);
+            let mut cpu = CPU::with_program(program);
+            cpu.memory.write(0x00, 0xf0);
+            cpu.memory.write(0x01, 0x02);
+            cpu.idx_register_x = 0x10;
+            cpu.idx_register_y = 0x10;
+            let cycles = cpu.execute(Some(1));
+            assert!(cycles == info.cycles as u32 || cycles == info.cycles as u32 + 1, "opcode {:02X} {} took {}", opcode, info.name, cycles);
+        }
+
+        // branches take the table's cycles when not taken and one more when taken without a page cross
+        for opcode in (0..=255u8).filter(|&opcode| OPCODE_INFO[opcode as usize].mode == AddressingMode::Relative) {
+            let info = OPCODE_INFO[opcode as usize];
+            let mut cycles = [ProcessorStatusFlags::empty(), ProcessorStatusFlags::all()].map(|status| {
+                let mut program = vec![opcode, 0x02];
+                program.resize(0x4000, 0);
+                let mut cpu = CPU::with_program(program);
+                cpu.processor_status = status;
+                cpu.execute(Some(1))
+            });
+            cycles.sort();
+            assert_eq!(cycles, [info.cycles as u32, info.cycles as u32 + 1], "opcode {:02X} {}", opcode, info.name);
+        }
+        assert_eq!(OPCODE_INFO.iter().filter(|info| info.official).count(), 151);
+        assert_eq!(OPCODE_INFO[0x6c].length, 3);
+        assert!(!OPCODE_INFO[0xeb].official);
+    }
+
+    #[test]
+    fn test_stack_diagnostics() {
+        /*
+            ldx #$01
+            txs
+            pha
+            pha
+            pha
+            pla
+            pla
+            pla
+            rts
+         */
+        let mut cpu = CPU::with_program(vec![0xa2, 0x01, 0x9a, 0x48, 0x48, 0x48, 0x68, 0x68, 0x68, 0x60]);
+        cpu.set_stack_diagnostics(true);
+        cpu.execute(Some(5));
+        let overflow = StackEvent { kind: StackEventKind::Overflow, address: 0x8004, stack_pointer: 0x00 };
+        assert_eq!(cpu.stack_events(), &[overflow]);
+        cpu.execute(Some(3));
+        assert_eq!(cpu.take_stack_events(), vec![overflow]);
+        // RTS without a matching JSR pulls more than was pushed after TXS
+        cpu.execute(Some(1));
+        let underflow = |stack_pointer| StackEvent { kind: StackEventKind::Underflow, address: 0x8009, stack_pointer };
+        assert_eq!(cpu.take_stack_events(), vec![underflow(0x01), underflow(0x02)]);
+    }
+
+    #[test]
+    fn test_decimal_mode() {
+        /*
+            sed
+            clc
+            lda #$19
+            adc #$28
+            sta $00
+            lda #$99
+            adc #$01
+            php
+            sta $01
+            sec
+            lda #$42
+            sbc #$13
+            sta $02
+            lda #$00
+            sbc #$01
+            sta $03
+            kil
+         */
+        let program = vec![
+            0xf8, 0x18, 0xa9, 0x19, 0x69, 0x28, 0x85, 0x00, 0xa9, 0x99, 0x69, 0x01, 0x08, 0x85, 0x01,
+            0x38, 0xa9, 0x42, 0xe9, 0x13, 0x85, 0x02, 0xa9, 0x00, 0xe9, 0x01, 0x85, 0x03, 0x02,
+        ];
+        let mut cpu = CPU::with_program(program.clone());
+        assert_eq!(cpu.decimal_mode(), DecimalMode::Ignored);
+        cpu.set_decimal_mode(DecimalMode::Enabled);
+        cpu.execute(None);
+        assert_eq!(cpu.memory.read(0x00), 0x47);
+        assert_eq!(cpu.memory.read(0x01), 0x00);
+        // 99 + 1 carries out, Z reflects the binary result $9A
+        let flags = ProcessorStatusFlags::from_bits_truncate(cpu.memory.read(0x0100 + cpu.stack_pointer as u16 + 1));
+        assert!(flags.contains(ProcessorStatusFlags::CARRY));
+        assert!(!flags.contains(ProcessorStatusFlags::ZERO));
+        assert_eq!(cpu.memory.read(0x02), 0x29);
+        assert_eq!(cpu.memory.read(0x03), 0x99);
+        assert!(!cpu.processor_status.contains(ProcessorStatusFlags::CARRY));
+
+        // NES behavior, D is ignored
+        let mut cpu = CPU::with_program(program);
+        cpu.execute(None);
+        assert_eq!(cpu.memory.read(0x00), 0x41);
+        assert_eq!(cpu.memory.read(0x01), 0x9a);
+        assert_eq!(cpu.memory.read(0x02), 0x2f);
+        assert_eq!(cpu.memory.read(0x03), 0xff);
+    }
+
+    #[test]
+    fn test_flat_memory_machine() {
+        /*
+            $0400:
+            sed
+            lda #$09
+            clc
+            adc #$01
+            sta $0200
+            cmp #$10
+            bne $0410
+            jmp $040d
+            jmp $0410
+         */
+        let program = [
+            0xf8, 0xa9, 0x09, 0x18, 0x69, 0x01, 0x8d, 0x00, 0x02, 0xc9, 0x10, 0xd0, 0x03, 0x4c, 0x0d, 0x04,
+            0x4c, 0x10, 0x04,
+        ];
+        let mut cpu = CPU::with_flat_memory(&program, 0x0400, 0x0400);
+        assert_eq!(cpu.decimal_mode(), DecimalMode::Enabled);
+        let mut last = None;
+        // stop once the program traps in a jump to itself
+        cpu.run_until(|cpu| {
+            let trapped = last == Some(cpu.program_counter);
+            last = Some(cpu.program_counter);
+            trapped
+        });
+        assert_eq!(cpu.program_counter, 0x040d);
+        assert_eq!(cpu.memory.peek(0x0200), 0x10);
+    }
+
+    #[test]
+    fn test_unimplemented_opcode() {
+        /*
+            lda #$01
+            xaa #$ff
+         */
+        let mut cpu = CPU::with_program(vec![0xa9, 0x01, 0x8b, 0xff]);
+        match cpu.try_execute(None) {
+            Err(NesError::UnimplementedOpcode { pc, opcode }) => assert_eq!((pc, opcode), (0x8002, 0x8b)),
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.program_counter, 0x8002);
+        assert_eq!(cpu.unimplemented_opcode().unwrap().to_string(), "unimplemented opcode $8B (XAA) at $8002");
+
+        // the halt and its cause are restored together
+        let state = cpu.save_state();
+        cpu.reset();
+        assert!(cpu.unimplemented_opcode().is_none());
+        cpu.load_state(&state);
+        assert!(cpu.is_halted());
+        assert_eq!(cpu.unimplemented_opcode().unwrap().opcode, 0x8b);
+    }
+
+    #[test]
+    fn test_delayed_interrupt_disable() {
+        let mut program = vec![
+            0x58,       // cli
+            0x78,       // sei
+            0xea,       // nop
+        ];
+        program.resize(0x4000, 0);
+        // handler at $8010
+        program[0x10] = 0x40; // rti
+        program[0x3ffe] = 0x10;
+        program[0x3fff] = 0x80;
+        let mut cpu = CPU::with_program(program);
+        cpu.processor_status.insert(ProcessorStatusFlags::INTERRUPT);
+        cpu.assert_irq(IrqSource::MAPPER);
+        cpu.advance();
+        // polled before CLI cleared I, SEI sets it again but the IRQ sneaks in after it
+        assert_eq!(cpu.advance(), 2);
+        assert_eq!(cpu.program_counter, 0x8002);
+        assert_eq!(cpu.advance(), 7);
+        assert_eq!(cpu.program_counter, 0x8010);
+        // pushed with I set by SEI
+        let pushed = cpu.memory.read(STACK_OFFSET + cpu.stack_pointer.wrapping_add(1) as u16);
+        assert!(ProcessorStatusFlags::from_bits_truncate(pushed).contains(ProcessorStatusFlags::INTERRUPT));
+        // RTI restores I immediately, the IRQ stays masked
+        cpu.advance();
+        assert_eq!(cpu.advance(), 2);
+        assert_eq!(cpu.program_counter, 0x8003);
+    }
+
+    #[test]
+    fn test_oam_dma() {
+        /*
+            ldx #$00
+            txa
+            sta $0200,x
+            inx
+            bne $8001
+            lda #$10
+            sta $2003
+            lda #$02
+            sta $4014
+            kil
+         */
+        let mut cpu = CPU::with_program(vec![
+            0xa2, 0x00, 0x8a, 0x9d, 0x00, 0x02, 0xe8, 0xd0, 0xf9, 0xa9, 0x10, 0x8d, 0x03, 0x20,
+            0xa9, 0x02, 0x8d, 0x14, 0x40, 0x02,
+        ]);
+        cpu.execute(Some(256 * 4 + 4));
+        let before = cpu.cycle_count;
+        assert_eq!(cpu.advance(), 4 + 513 + ((before + 4) & 1));
+        // copied starting at OAMADDR, which wraps back to its start value
+        cpu.memory.write(0x2003, 0x10);
+        assert_eq!(cpu.memory.read(0x2004), 0x00);
+        cpu.memory.write(0x2003, 0x0f);
+        assert_eq!(cpu.memory.read(0x2004), 0xff);
+        cpu.memory.write(0x2003, 0x20);
+        assert_eq!(cpu.memory.read(0x2004), 0x10);
+    }
+
+    #[test]
+    fn test_dmc_stall() {
+        /*
+            lda #$0f
+            sta $4010
+            lda #$10
+            sta $4015
+            nop
+            nop
+         */
+        let mut cpu = CPU::with_program(vec![0xa9, 0x0f, 0x8d, 0x10, 0x40, 0xa9, 0x10, 0x8d, 0x15, 0x40, 0xea, 0xea]);
+        cpu.execute(Some(4));
+        // the first sample byte is fetched right away
+        assert_eq!(cpu.advance(), 2 + 4);
+        assert_eq!(cpu.advance(), 2);
+    }
+
+    #[test]
+    fn test_stop_on_spin() {
+        /*
+            ldx #$03
+            dex
+            bne $8002
+            jmp $8005
+         */
+        let mut cpu = CPU::with_program(vec![0xa2, 0x03, 0xca, 0xd0, 0xfd, 0x4c, 0x05, 0x80]);
+        cpu.set_stop_on_spin(true);
+        cpu.execute(None);
+        assert_eq!(cpu.spin_hit(), Some(0x8005));
+        assert_eq!(cpu.program_counter, 0x8005);
+        assert!(!cpu.is_halted());
+
+        // a pending interrupt can still leave the loop
+        cpu.trigger_nmi();
+        cpu.execute(Some(1));
+        assert_eq!(cpu.spin_hit(), None);
+    }
+
+    #[test]
+    fn test_total_cycles() {
+        /*
+            lda #$02
+            sta $4014
+            nop
+         */
+        let mut cpu = CPU::with_program(vec![0xa9, 0x02, 0x8d, 0x14, 0x40, 0xea]);
+        cpu.execute(Some(3));
+        // the DMA starts on an odd cycle
+        assert_eq!(cpu.total_cycles(), 7 + 2 + 4 + 514 + 2);
+        assert_eq!(cpu.total_cycles(), cpu.cycle_count as u64);
+        assert_eq!(cpu.trace_entry().cycle_count, cpu.total_cycles());
+
+        // a long session wraps the 32 bit counter, elapsed cycles are still reported correctly
+        cpu.cycle_count = u32::MAX - 1;
+        cpu.program_counter = 0x8005;
+        assert_eq!(cpu.execute(Some(1)), 2);
+        assert_eq!(cpu.cycle_count, 0);
+        assert_eq!(cpu.total_cycles(), 7 + 2 + 4 + 514 + 2 + 2);
+    }
+
+    #[test]
+    fn test_shift_rotate_operands() {
+        // (opcode for A, opcode for $10, input, carry in) -> both forms give the same result and flags
+        let cases = [
+            (0x0a, 0x06, 0x81, false),
+            (0x2a, 0x26, 0x40, true),
+            (0x2a, 0x26, 0x80, false),
+            (0x4a, 0x46, 0x01, true),
+            (0x6a, 0x66, 0x01, true),
+            (0x6a, 0x66, 0x02, false),
+        ];
+        for (accumulator_op, memory_op, input, carry) in cases {
+            let mut accumulator = CPU::with_program(vec![0xa9, input, accumulator_op]);
+            let mut memory = CPU::with_program(vec![0xa9, input, 0x85, 0x10, memory_op, 0x10]);
+            accumulator.processor_status.set(ProcessorStatusFlags::CARRY, carry);
+            memory.processor_status.set(ProcessorStatusFlags::CARRY, carry);
+            accumulator.execute(Some(2));
+            memory.execute(Some(3));
+            assert_eq!(accumulator.accumulator, memory.memory.read(0x10), "opcode {:02X}", accumulator_op);
+            assert_eq!(accumulator.processor_status, memory.processor_status, "opcode {:02X}", accumulator_op);
+        }
+    }
+
+    #[test]
+    fn test_display() {
+        /*
+            lda #$12
+            pha
+            lda #$34
+            pha
+            jmp $1234
+         */
+        let mut cpu = CPU::with_program(vec![0xa9, 0x12, 0x48, 0xa9, 0x34, 0x48, 0x4c, 0x34, 0x12]);
+        cpu.execute(Some(4));
+        let text = cpu.to_string();
+        let lines: Vec<&str> = text.lines().collect();
+        assert_eq!(lines[0], "PC:8006 A:34 X:00 Y:00 SP:FD P:00 (N:0 V:0 -:0 B:0 D:0 I:0 Z:0 C:0) CYC:17");
+        assert_eq!(lines[1], "next: 8006  4C 34 12  JMP $1234");
+        assert_eq!(lines[2], "stack: 01FE: 34 12");
+
+        let debug = format!("{:?}", cpu);
+        assert!(debug.starts_with("CPU { program_counter: 8006, accumulator: 34,"));
+        assert!(debug.contains("next_instruction: JMP $1234, stack: [34 12]"));
+    }
 }
@@ -0,0 +1,626 @@
+use std::fmt;
+
+use crate::cpu::{ProcessorStatusFlags, UnimplementedOpcode, CPU};
+
+/*
+    Watch expressions
+    A tiny expression language evaluated against the emulator state, e.g.
+        [0x00fe] + X > 0x40
+        PC == $C123 && C
+    Operands:
+        - numbers: decimal, hex ('0x' or '$' prefix) or binary ('%' prefix)
+        - registers: A, X, Y, SP, PC, P (status byte) and CYC (cycle count)
+        - flags: N, V, B, D, I, Z, C evaluate to 1 when set and 0 otherwise
+        - memory: [expr] reads the byte at address 'expr'
+    Operators (loosest binding first):
+        ||  &&  == != < <= > >=  |  ^  &  << >>  + -  * / %  unary - ! ~
+    Comparisons and logical operators evaluate to 1 or 0.
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Register {
+    A,
+    X,
+    Y,
+    SP,
+    PC,
+    P,
+    Cycles,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UnaryOp {
+    Negate,
+    Not,
+    Complement,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BinaryOp {
+    Or,
+    And,
+    Equal,
+    NotEqual,
+    Less,
+    LessEqual,
+    Greater,
+    GreaterEqual,
+    BitOr,
+    BitXor,
+    BitAnd,
+    ShiftLeft,
+    ShiftRight,
+    Add,
+    Subtract,
+    Multiply,
+    Divide,
+    Remainder,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Expr {
+    Literal(i32),
+    Register(Register),
+    Flag(ProcessorStatusFlags),
+    Memory(Box<Expr>),
+    Unary(UnaryOp, Box<Expr>),
+    Binary(BinaryOp, Box<Expr>, Box<Expr>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExprError {
+    // byte offset into the source where the error was detected
+    pub offset: usize,
+    pub reason: &'static str,
+}
+
+impl fmt::Display for ExprError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at offset {}", self.reason, self.offset)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Token {
+    Number(i32),
+    Ident(usize, usize),
+    Op(&'static str),
+    LeftParen,
+    RightParen,
+    LeftBracket,
+    RightBracket,
+}
+
+// longer operators must come first so that e.g. '<=' is not lexed as '<' '='
+const OPERATORS: [&str; 20] = [
+    "||", "&&", "==", "!=", "<=", ">=", "<<", ">>",
+    "<", ">", "|", "^", "&", "+", "-", "*", "/", "%", "!", "~",
+];
+
+fn follows_value(tokens: &[(usize, Token)]) -> bool {
+    matches!(tokens.last(), Some((_, Token::Number(_) | Token::Ident(..) | Token::RightParen | Token::RightBracket)))
+}
+
+fn tokenize(source: &str) -> Result<Vec<(usize, Token)>, ExprError> {
+    let bytes = source.as_bytes();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let c = bytes[i];
+        if c.is_ascii_whitespace() {
+            i += 1;
+            continue;
+        }
+        let start = i;
+        let token = match c {
+            b'(' => {i += 1; Token::LeftParen},
+            b')' => {i += 1; Token::RightParen},
+            b'[' => {i += 1; Token::LeftBracket},
+            b']' => {i += 1; Token::RightBracket},
+            // '%' directly following a value is the remainder operator, otherwise a binary prefix
+            b'0'..=b'9' | b'$' | b'%' if c != b'%' || !follows_value(&tokens) => {
+                let (radix, digits_start) = match c {
+                    b'$' => (16, i + 1),
+                    b'%' => (2, i + 1),
+                    b'0' if matches!(bytes.get(i + 1), Some(b'x') | Some(b'X')) => (16, i + 2),
+                    _ => (10, i),
+                };
+                let mut end = digits_start;
+                while end < bytes.len() && (bytes[end] as char).is_digit(radix) {end += 1}
+                if end == digits_start {
+                    return Err(ExprError{offset: start, reason: "expected digits"});
+                }
+                i = end;
+                let value = i32::from_str_radix(&source[digits_start..end], radix)
+                    .map_err(|_| ExprError{offset: start, reason: "number too large"})?;
+                Token::Number(value)
+            },
+            b'a'..=b'z' | b'A'..=b'Z' | b'_' => {
+                while i < bytes.len() && (bytes[i].is_ascii_alphanumeric() || bytes[i] == b'_') {i += 1}
+                Token::Ident(start, i)
+            },
+            _ => {
+                let op = OPERATORS.iter()
+                    .find(|op| source[i..].starts_with(**op))
+                    .ok_or(ExprError{offset: start, reason: "unexpected character"})?;
+                i += op.len();
+                Token::Op(op)
+            }
+        };
+        tokens.push((start, token));
+    }
+    Ok(tokens)
+}
+
+// binding power of each binary operator, higher binds tighter
+fn binary_op(op: &str) -> Option<(BinaryOp, u8)> {
+    Some(match op {
+        "||" => (BinaryOp::Or, 1),
+        "&&" => (BinaryOp::And, 2),
+        "==" => (BinaryOp::Equal, 3),
+        "!=" => (BinaryOp::NotEqual, 3),
+        "<" => (BinaryOp::Less, 3),
+        "<=" => (BinaryOp::LessEqual, 3),
+        ">" => (BinaryOp::Greater, 3),
+        ">=" => (BinaryOp::GreaterEqual, 3),
+        "|" => (BinaryOp::BitOr, 4),
+        "^" => (BinaryOp::BitXor, 5),
+        "&" => (BinaryOp::BitAnd, 6),
+        "<<" => (BinaryOp::ShiftLeft, 7),
+        ">>" => (BinaryOp::ShiftRight, 7),
+        "+" => (BinaryOp::Add, 8),
+        "-" => (BinaryOp::Subtract, 8),
+        "*" => (BinaryOp::Multiply, 9),
+        "/" => (BinaryOp::Divide, 9),
+        "%" => (BinaryOp::Remainder, 9),
+        _ => return None,
+    })
+}
+
+fn identifier(name: &str) -> Option<Expr> {
+    Some(match name.to_ascii_uppercase().as_str() {
+        "A" => Expr::Register(Register::A),
+        "X" => Expr::Register(Register::X),
+        "Y" => Expr::Register(Register::Y),
+        "SP" => Expr::Register(Register::SP),
+        "PC" => Expr::Register(Register::PC),
+        "P" => Expr::Register(Register::P),
+        "CYC" => Expr::Register(Register::Cycles),
+        "N" => Expr::Flag(ProcessorStatusFlags::NEGATIVE),
+        "V" => Expr::Flag(ProcessorStatusFlags::OVERFLOW),
+        "B" => Expr::Flag(ProcessorStatusFlags::BREAK),
+        "D" => Expr::Flag(ProcessorStatusFlags::DECIMAL),
+        "I" => Expr::Flag(ProcessorStatusFlags::INTERRUPT),
+        "Z" => Expr::Flag(ProcessorStatusFlags::ZERO),
+        "C" => Expr::Flag(ProcessorStatusFlags::CARRY),
+        _ => return None,
+    })
+}
+
+struct Parser<'a> {
+    source: &'a str,
+    tokens: Vec<(usize, Token)>,
+    position: usize,
+}
+
+impl Parser<'_> {
+    fn peek(&self) -> Option<Token> {
+        self.tokens.get(self.position).map(|(_, token)| *token)
+    }
+
+    fn offset(&self) -> usize {
+        self.tokens.get(self.position).map_or(self.source.len(), |(offset, _)| *offset)
+    }
+
+    fn error(&self, reason: &'static str) -> ExprError {
+        ExprError{offset: self.offset(), reason}
+    }
+
+    fn expect(&mut self, token: Token, reason: &'static str) -> Result<(), ExprError> {
+        if self.peek() == Some(token) {
+            self.position += 1;
+            Ok(())
+        } else {
+            Err(self.error(reason))
+        }
+    }
+
+    // precedence climbing, only operators binding tighter than 'min_power' are consumed
+    fn expression(&mut self, min_power: u8) -> Result<Expr, ExprError> {
+        let mut lhs = self.unary()?;
+        while let Some(Token::Op(op)) = self.peek() {
+            let Some((op, power)) = binary_op(op) else {break};
+            if power <= min_power {break}
+            self.position += 1;
+            let rhs = self.expression(power)?;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(rhs));
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, ExprError> {
+        let op = match self.peek() {
+            Some(Token::Op("-")) => UnaryOp::Negate,
+            Some(Token::Op("!")) => UnaryOp::Not,
+            Some(Token::Op("~")) => UnaryOp::Complement,
+            _ => return self.primary(),
+        };
+        self.position += 1;
+        Ok(Expr::Unary(op, Box::new(self.unary()?)))
+    }
+
+    fn primary(&mut self) -> Result<Expr, ExprError> {
+        let token = self.peek().ok_or(self.error("unexpected end of expression"))?;
+        let offset = self.offset();
+        self.position += 1;
+        match token {
+            Token::Number(value) => Ok(Expr::Literal(value)),
+            Token::Ident(start, end) => identifier(&self.source[start..end])
+                .ok_or(ExprError{offset, reason: "unknown register or flag"}),
+            Token::LeftParen => {
+                let expr = self.expression(0)?;
+                self.expect(Token::RightParen, "expected ')'")?;
+                Ok(expr)
+            },
+            Token::LeftBracket => {
+                let expr = self.expression(0)?;
+                self.expect(Token::RightBracket, "expected ']'")?;
+                Ok(Expr::Memory(Box::new(expr)))
+            },
+            _ => Err(ExprError{offset, reason: "expected a value"}),
+        }
+    }
+}
+
+impl Expr {
+    pub fn parse(source: &str) -> Result<Self, ExprError> {
+        let mut parser = Parser{source, tokens: tokenize(source)?, position: 0};
+        let expr = parser.expression(0)?;
+        if parser.peek().is_some() {
+            return Err(parser.error("unexpected token"));
+        }
+        Ok(expr)
+    }
+
+    // memory is read with 'Memory::peek' so evaluating never disturbs emulation
+    pub fn eval(&self, cpu: &CPU) -> i32 {
+        match self {
+            Expr::Literal(value) => *value,
+            Expr::Register(register) => match register {
+                Register::A => cpu.accumulator as i32,
+                Register::X => cpu.idx_register_x as i32,
+                Register::Y => cpu.idx_register_y as i32,
+                Register::SP => cpu.stack_pointer as i32,
+                Register::PC => cpu.program_counter as i32,
+                Register::P => cpu.processor_status.bits() as i32,
+                Register::Cycles => cpu.cycle_count as i32,
+            },
+            Expr::Flag(flag) => cpu.processor_status.contains(*flag) as i32,
+            Expr::Memory(address) => cpu.memory.peek(address.eval(cpu) as u16) as i32,
+            Expr::Unary(op, operand) => {
+                let value = operand.eval(cpu);
+                match op {
+                    UnaryOp::Negate => value.wrapping_neg(),
+                    UnaryOp::Not => (value == 0) as i32,
+                    UnaryOp::Complement => !value,
+                }
+            },
+            Expr::Binary(op, lhs, rhs) => {
+                let lhs = lhs.eval(cpu);
+                // short circuit so memory derefs on the right aren't evaluated needlessly
+                match op {
+                    BinaryOp::Or if lhs != 0 => return 1,
+                    BinaryOp::And if lhs == 0 => return 0,
+                    _ => (),
+                }
+                let rhs = rhs.eval(cpu);
+                match op {
+                    BinaryOp::Or | BinaryOp::And => (rhs != 0) as i32,
+                    BinaryOp::Equal => (lhs == rhs) as i32,
+                    BinaryOp::NotEqual => (lhs != rhs) as i32,
+                    BinaryOp::Less => (lhs < rhs) as i32,
+                    BinaryOp::LessEqual => (lhs <= rhs) as i32,
+                    BinaryOp::Greater => (lhs > rhs) as i32,
+                    BinaryOp::GreaterEqual => (lhs >= rhs) as i32,
+                    BinaryOp::BitOr => lhs | rhs,
+                    BinaryOp::BitXor => lhs ^ rhs,
+                    BinaryOp::BitAnd => lhs & rhs,
+                    BinaryOp::ShiftLeft => lhs.wrapping_shl(rhs as u32),
+                    BinaryOp::ShiftRight => lhs.wrapping_shr(rhs as u32),
+                    BinaryOp::Add => lhs.wrapping_add(rhs),
+                    BinaryOp::Subtract => lhs.wrapping_sub(rhs),
+                    BinaryOp::Multiply => lhs.wrapping_mul(rhs),
+                    // division by zero evaluates to 0 rather than stopping the emulator
+                    BinaryOp::Divide => lhs.checked_div(rhs).unwrap_or(0),
+                    BinaryOp::Remainder => lhs.checked_rem(rhs).unwrap_or(0),
+                }
+            },
+        }
+    }
+}
+
+// an expression along with the text it was parsed from, for display in watch panels
+#[derive(Debug, Clone)]
+pub struct WatchExpr {
+    source: String,
+    expr: Expr,
+}
+
+impl WatchExpr {
+    pub fn new(source: &str) -> Result<Self, ExprError> {
+        Ok(WatchExpr{source: source.to_string(), expr: Expr::parse(source)?})
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn eval(&self, cpu: &CPU) -> i32 {
+        self.expr.eval(cpu)
+    }
+
+    // a condition is met when the expression evaluates to a non-zero value
+    pub fn is_true(&self, cpu: &CPU) -> bool {
+        self.eval(cpu) != 0
+    }
+}
+
+const OP_JSR: u8 = 0x20;
+const OP_RTS: u8 = 0x60;
+
+// a subroutine call observed while stepping
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct CallFrame {
+    // address of the JSR instruction
+    pub call_site: u16,
+    // address of the subroutine
+    pub target: u16,
+    // stack pointer after the return address was pushed
+    pub stack_pointer: u8,
+}
+
+impl CallFrame {
+    pub fn return_address(&self) -> u16 {
+        self.call_site.wrapping_add(3)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    // the requested step, step over or step out finished
+    Completed,
+    // the break condition with the given id evaluated as true
+    BreakCondition(usize),
+    // the step limit was reached first
+    StepLimit,
+    // the CPU executed a KIL opcode and stopped
+    Halted,
+    // the CPU stopped on an opcode it doesn't emulate
+    UnimplementedOpcode(UnimplementedOpcode),
+}
+
+#[derive(Debug, Default)]
+pub struct Debugger {
+    watches: Vec<WatchExpr>,
+    break_conditions: Vec<WatchExpr>,
+    call_stack: Vec<CallFrame>,
+}
+
+impl Debugger {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // returns the id of the new watch
+    pub fn add_watch(&mut self, source: &str) -> Result<usize, ExprError> {
+        self.watches.push(WatchExpr::new(source)?);
+        Ok(self.watches.len() - 1)
+    }
+
+    pub fn remove_watch(&mut self, id: usize) -> Option<WatchExpr> {
+        (id < self.watches.len()).then(|| self.watches.remove(id))
+    }
+
+    pub fn watches(&self) -> &[WatchExpr] {
+        &self.watches
+    }
+
+    // current value of every watch, in the order they were added
+    pub fn watch_values<'a>(&'a self, cpu: &'a CPU) -> impl Iterator<Item = (&'a str, i32)> + 'a {
+        self.watches.iter().map(move |watch| (watch.source(), watch.eval(cpu)))
+    }
+
+    // execution stops once any break condition evaluates as true, returns the id of the condition
+    pub fn add_break_condition(&mut self, source: &str) -> Result<usize, ExprError> {
+        self.break_conditions.push(WatchExpr::new(source)?);
+        Ok(self.break_conditions.len() - 1)
+    }
+
+    pub fn remove_break_condition(&mut self, id: usize) -> Option<WatchExpr> {
+        (id < self.break_conditions.len()).then(|| self.break_conditions.remove(id))
+    }
+
+    pub fn break_conditions(&self) -> &[WatchExpr] {
+        &self.break_conditions
+    }
+
+    // id of the first break condition which currently holds
+    pub fn check_break_conditions(&self, cpu: &CPU) -> Option<usize> {
+        self.break_conditions.iter().position(|condition| condition.is_true(cpu))
+    }
+
+    // subroutine calls made while stepping through the debugger, innermost call last.
+    // Calls made before the debugger started stepping are unknown.
+    pub fn call_stack(&self) -> &[CallFrame] {
+        &self.call_stack
+    }
+
+    // execute a single instruction, keeping track of JSR/RTS pairs
+    pub fn step(&mut self, cpu: &mut CPU) {
+        let pc = cpu.program_counter;
+        let opcode = cpu.memory.peek(pc);
+        cpu.execute(Some(1));
+        match opcode {
+            OP_JSR => self.call_stack.push(CallFrame{
+                call_site: pc,
+                target: cpu.program_counter,
+                stack_pointer: cpu.stack_pointer,
+            }),
+            // drop every frame the stack has unwound past, this also recovers from
+            // subroutines which manipulate the stack instead of returning normally
+            OP_RTS => while self.call_stack.last().is_some_and(|frame| frame.stack_pointer < cpu.stack_pointer) {
+                self.call_stack.pop();
+            },
+            _ => (),
+        }
+    }
+
+    // step until 'done' holds or a break condition is met
+    fn step_until<F>(&mut self, cpu: &mut CPU, steps: Option<usize>, mut done: F) -> StopReason
+    where F: FnMut(&CPU, u8) -> bool {
+        let mut remaining = steps;
+        while remaining != Some(0) {
+            let opcode = cpu.memory.peek(cpu.program_counter);
+            self.step(cpu);
+            if done(cpu, opcode) {
+                return StopReason::Completed;
+            }
+            if let Some(id) = self.check_break_conditions(cpu) {
+                return StopReason::BreakCondition(id);
+            }
+            if let Some(unimplemented) = cpu.unimplemented_opcode() {
+                return StopReason::UnimplementedOpcode(unimplemented);
+            }
+            if cpu.is_halted() {
+                return StopReason::Halted;
+            }
+            remaining = remaining.map(|n| n - 1);
+        }
+        StopReason::StepLimit
+    }
+
+    // execute up to 'steps' instructions (forever if None), stopping early when a break condition holds
+    pub fn run(&mut self, cpu: &mut CPU, steps: Option<usize>) -> StopReason {
+        self.step_until(cpu, steps, |_, _| false)
+    }
+
+    // execute the next instruction, if it is a JSR run until the subroutine returns to the following instruction
+    pub fn step_over(&mut self, cpu: &mut CPU, steps: Option<usize>) -> StopReason {
+        if cpu.memory.peek(cpu.program_counter) != OP_JSR {
+            self.step(cpu);
+            return StopReason::Completed;
+        }
+        let return_address = cpu.program_counter.wrapping_add(3);
+        let stack_pointer = cpu.stack_pointer;
+        // comparing the stack pointer keeps recursive calls from stopping early
+        self.step_until(cpu, steps, |cpu, _| {
+            cpu.program_counter == return_address && cpu.stack_pointer == stack_pointer
+        })
+    }
+
+    // run until the current subroutine returns to its caller
+    pub fn step_out(&mut self, cpu: &mut CPU, steps: Option<usize>) -> StopReason {
+        // the return address of the current subroutine sits just above the stack pointer,
+        // so its RTS is the first one which leaves the stack pointer above the current value
+        let stack_pointer = cpu.stack_pointer;
+        self.step_until(cpu, steps, |cpu, opcode| {
+            opcode == OP_RTS && cpu.stack_pointer > stack_pointer
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn eval(source: &str, cpu: &CPU) -> i32 {
+        Expr::parse(source).expect("failed to parse").eval(cpu)
+    }
+
+    #[test]
+    fn test_literals_and_precedence() {
+        let cpu = CPU::with_program(vec![]);
+        assert_eq!(eval("1 + 2 * 3", &cpu), 7);
+        assert_eq!(eval("(1 + 2) * 3", &cpu), 9);
+        assert_eq!(eval("0x10 + $10 + %11 + 10", &cpu), 0x10 + 0x10 + 3 + 10);
+        assert_eq!(eval("1 << 4 | 1", &cpu), 0x11);
+        assert_eq!(eval("-1 + !0 + ~0", &cpu), -1);
+        assert_eq!(eval("7 % 4 == 3 && 2 > 1", &cpu), 1);
+        assert_eq!(eval("%101%%11", &cpu), 2);
+        assert_eq!(eval("1 / 0", &cpu), 0);
+    }
+
+    #[test]
+    fn test_registers_flags_memory() {
+        // lda #$80, ldx #$05, sta $fe
+        let mut cpu = CPU::with_program(vec![0xa9, 0x80, 0xa2, 0x05, 0x85, 0xfe]);
+        cpu.execute(Some(3));
+        assert_eq!(eval("A", &cpu), 0x80);
+        assert_eq!(eval("x", &cpu), 5);
+        assert_eq!(eval("PC", &cpu), 0x8006);
+        assert_eq!(eval("N + Z", &cpu), 0);
+        assert_eq!(eval("[0x00fe] + X > 0x40", &cpu), 1);
+        assert_eq!(eval("[$f9 + X] == A", &cpu), 1);
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert_eq!(Expr::parse("1 +").unwrap_err().offset, 3);
+        assert_eq!(Expr::parse("[1").unwrap_err().reason, "expected ']'");
+        assert_eq!(Expr::parse("Q == 1").unwrap_err().reason, "unknown register or flag");
+        assert_eq!(Expr::parse("1 # 2").unwrap_err().offset, 2);
+        assert!(Expr::parse("1 2").is_err());
+    }
+
+    #[test]
+    fn test_debugger_break_condition() {
+        // inx, jmp $8000
+        let mut cpu = CPU::with_program(vec![0xe8, 0x4c, 0x00, 0x80]);
+        let mut debugger = Debugger::new();
+        debugger.add_watch("X * 2").unwrap();
+        let id = debugger.add_break_condition("X == 3 && PC == $8001").unwrap();
+        assert_eq!(debugger.run(&mut cpu, Some(100)), StopReason::BreakCondition(id));
+        assert_eq!(cpu.idx_register_x, 3);
+        assert_eq!(debugger.watch_values(&cpu).collect::<Vec<_>>(), vec![("X * 2", 6)]);
+        assert_eq!(debugger.run(&mut cpu, Some(1)), StopReason::StepLimit);
+    }
+
+    #[test]
+    fn test_step_over_and_out() {
+        /*
+            $8000 jsr $8007
+            $8003 inx
+            $8004 jmp $8004
+            $8007 jsr $800b
+            $800a rts
+            $800b iny
+            $800c rts
+         */
+        let program = vec![
+            0x20, 0x07, 0x80, 0xe8, 0x4c, 0x04, 0x80,
+            0x20, 0x0b, 0x80, 0x60, 0xc8, 0x60,
+        ];
+        let mut cpu = CPU::with_program(program.clone());
+        let mut debugger = Debugger::new();
+        assert_eq!(debugger.step_over(&mut cpu, Some(100)), StopReason::Completed);
+        assert_eq!(cpu.program_counter, 0x8003);
+        assert_eq!(cpu.idx_register_y, 1);
+        assert!(debugger.call_stack().is_empty());
+        assert_eq!(debugger.step_over(&mut cpu, Some(100)), StopReason::Completed);
+        assert_eq!(cpu.idx_register_x, 1);
+
+        let mut cpu = CPU::with_program(program);
+        let mut debugger = Debugger::new();
+        debugger.step(&mut cpu);
+        debugger.step(&mut cpu);
+        assert_eq!(cpu.program_counter, 0x800b);
+        assert_eq!(debugger.call_stack().len(), 2);
+        assert_eq!(debugger.call_stack()[0].return_address(), 0x8003);
+        assert_eq!(debugger.step_out(&mut cpu, Some(100)), StopReason::Completed);
+        assert_eq!(cpu.program_counter, 0x800a);
+        assert_eq!(debugger.call_stack().len(), 1);
+        assert_eq!(debugger.step_out(&mut cpu, Some(100)), StopReason::Completed);
+        assert_eq!(cpu.program_counter, 0x8003);
+        assert!(debugger.call_stack().is_empty());
+    }
+}
@@ -0,0 +1,152 @@
+use std::fmt;
+
+use crate::opmap::{AddressingMode, OpInfo, OPCODE_INFO};
+
+/*
+    Disassembler
+    Decodes machine code into instructions printed in the usual assembler syntax,
+    e.g. 'LDA ($40,X)', 'ASL A' or 'BNE $C123' with branch targets resolved.
+    An instruction cut short by the end of the input is printed as '.byte' data.
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Instruction {
+    pub address: u16,
+    pub info: OpInfo,
+    bytes: [u8; 3],
+    // number of valid bytes, less than 'info.length' when truncated
+    len: usize,
+}
+
+impl Instruction {
+    // decode the instruction at the start of 'bytes', which is located at 'address', None if 'bytes' is empty
+    pub fn decode(bytes: &[u8], address: u16) -> Option<Self> {
+        let info = OPCODE_INFO[*bytes.first()? as usize];
+        let len = bytes.len().min(info.length as usize);
+        let mut raw = [0u8; 3];
+        raw[..len].copy_from_slice(&bytes[..len]);
+        Some(Instruction { address, info, bytes: raw, len })
+    }
+
+    pub fn opcode(&self) -> u8 {
+        self.bytes[0]
+    }
+
+    // opcode and operand bytes
+    pub fn bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    pub fn is_truncated(&self) -> bool {
+        self.len < self.info.length as usize
+    }
+
+    // 16 bit operand for absolute modes, the 8 bit operand otherwise
+    pub fn operand(&self) -> u16 {
+        u16::from_le_bytes([self.bytes[1], self.bytes[2]])
+    }
+
+    // destination of a branch, None for other instructions
+    pub fn branch_target(&self) -> Option<u16> {
+        if self.info.mode != AddressingMode::Relative || self.is_truncated() {
+            return None;
+        }
+        Some(self.address.wrapping_add(2).wrapping_add(self.bytes[1] as i8 as u16))
+    }
+
+    // address of the next instruction
+    pub fn next_address(&self) -> u16 {
+        self.address.wrapping_add(self.len as u16)
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_truncated() {
+            write!(f, ".byte")?;
+            for (i, byte) in self.bytes().iter().enumerate() {
+                write!(f, "{}${:02X}", if i == 0 {" "} else {", "}, byte)?;
+            }
+            return Ok(());
+        }
+        let mnemonic = self.info.mnemonic;
+        let zero_page = self.bytes[1];
+        let absolute = self.operand();
+        match self.info.mode {
+            AddressingMode::Implied => write!(f, "{}", mnemonic),
+            AddressingMode::Accumulator => write!(f, "{} A", mnemonic),
+            AddressingMode::Immediate => write!(f, "{} #${:02X}", mnemonic, zero_page),
+            AddressingMode::ZeroPage => write!(f, "{} ${:02X}", mnemonic, zero_page),
+            AddressingMode::ZeroPageX => write!(f, "{} ${:02X},X", mnemonic, zero_page),
+            AddressingMode::ZeroPageY => write!(f, "{} ${:02X},Y", mnemonic, zero_page),
+            AddressingMode::Absolute => write!(f, "{} ${:04X}", mnemonic, absolute),
+            AddressingMode::AbsoluteX => write!(f, "{} ${:04X},X", mnemonic, absolute),
+            AddressingMode::AbsoluteY => write!(f, "{} ${:04X},Y", mnemonic, absolute),
+            AddressingMode::Indirect => write!(f, "{} (${:04X})", mnemonic, absolute),
+            AddressingMode::ZeroPageXIndirect => write!(f, "{} (${:02X},X)", mnemonic, zero_page),
+            AddressingMode::ZeroPageYIndirect => write!(f, "{} (${:02X}),Y", mnemonic, zero_page),
+            AddressingMode::Relative => write!(f, "{} ${:04X}", mnemonic, self.branch_target().unwrap_or(0)),
+        }
+    }
+}
+
+// iterator over the instructions in a byte slice
+pub struct Disassembler<'a> {
+    bytes: &'a [u8],
+    offset: usize,
+    origin: u16,
+}
+
+impl<'a> Iterator for Disassembler<'a> {
+    type Item = Instruction;
+
+    fn next(&mut self) -> Option<Instruction> {
+        let instruction = Instruction::decode(self.bytes.get(self.offset..)?, self.origin.wrapping_add(self.offset as u16))?;
+        self.offset += instruction.bytes().len();
+        Some(instruction)
+    }
+}
+
+// disassemble 'bytes' which are located at 'origin' in the CPU address space
+pub fn disassemble(bytes: &[u8], origin: u16) -> Disassembler<'_> {
+    Disassembler { bytes, offset: 0, origin }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lines(bytes: &[u8], origin: u16) -> Vec<String> {
+        disassemble(bytes, origin).map(|instruction| instruction.to_string()).collect()
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let program = [
+            0xa1, 0x40, 0xd0, 0xfc, 0x0a, 0xa9, 0x10, 0xbe, 0x34, 0x12,
+            0x6c, 0xfc, 0xff, 0xb1, 0x02, 0x96, 0x80, 0x18, 0x20, 0x00,
+        ];
+        assert_eq!(lines(&program, 0xc121), vec![
+            "LDA ($40,X)",
+            "BNE $C121",
+            "ASL A",
+            "LDA #$10",
+            "LDX $1234,Y",
+            "JMP ($FFFC)",
+            "LDA ($02),Y",
+            "STX $80,Y",
+            "CLC",
+            ".byte $20, $00",
+        ]);
+    }
+
+    #[test]
+    fn test_decode() {
+        assert_eq!(Instruction::decode(&[], 0x8000), None);
+        let instruction = Instruction::decode(&[0x10, 0x05, 0xff], 0x80fe).unwrap();
+        assert_eq!(instruction.bytes(), &[0x10, 0x05]);
+        assert_eq!(instruction.branch_target(), Some(0x8105));
+        assert_eq!(instruction.next_address(), 0x8100);
+        assert_eq!(instruction.to_string(), "BPL $8105");
+    }
+}
@@ -0,0 +1,154 @@
+/*
+    DMC sample reader
+    The part of the APU's delta modulation channel which fetches sample bytes from CPU memory.
+    It runs on the CPU side, since every fetch steals CPU cycles and reads the CPU bus, the
+    fetched bytes are forwarded to the synthesizer with the other APU events.
+    Each fetch stalls the CPU for 4 cycles, fewer when it lands on a write cycle or during
+    OAM DMA on hardware, which isn't modelled. The DMC IRQ isn't raised yet.
+*/
+
+// CPU cycles per output bit for each rate index, NTSC
+const RATES: [u16; 16] = [428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54];
+pub const DMC_STALL_CYCLES: u32 = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DmcReader {
+    loop_flag: bool,
+    rate: u16,
+    // start and length of the sample set by $4012/$4013
+    sample_address: u16,
+    sample_length: u16,
+    current_address: u16,
+    bytes_remaining: u16,
+    // a fetched byte waits in the buffer until the output unit finishes its current byte
+    buffer_full: bool,
+    bits_remaining: u8,
+    // cycles until the next output bit
+    timer: u16,
+    // CPU cycle the reader has been advanced to
+    cycle: u64,
+}
+
+impl DmcReader {
+    pub fn new() -> Self {
+        DmcReader {
+            loop_flag: false,
+            rate: RATES[0],
+            sample_address: 0xc000,
+            sample_length: 1,
+            current_address: 0xc000,
+            bytes_remaining: 0,
+            buffer_full: false,
+            bits_remaining: 8,
+            timer: RATES[0],
+            cycle: 0,
+        }
+    }
+
+    // $4010, $4012 and $4013, other addresses are ignored
+    pub fn write(&mut self, address: u16, data: u8) {
+        match address {
+            0x4010 => {
+                self.loop_flag = data & 0x40 != 0;
+                self.rate = RATES[(data & 0x0f) as usize];
+            }
+            0x4012 => self.sample_address = 0xc000 | ((data as u16) << 6),
+            0x4013 => self.sample_length = ((data as u16) << 4) + 1,
+            _ => (),
+        }
+    }
+
+    // DMC enable bit of $4015, restarts the sample if it had finished
+    pub fn set_enabled(&mut self, enabled: bool) {
+        if !enabled {
+            self.bytes_remaining = 0;
+        } else if self.bytes_remaining == 0 {
+            self.restart();
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.bytes_remaining > 0
+    }
+
+    fn restart(&mut self) {
+        self.current_address = self.sample_address;
+        self.bytes_remaining = self.sample_length;
+    }
+
+    // run up to CPU cycle 'cycle', stops early with the address to fetch when the buffer needs a byte
+    pub fn advance(&mut self, cycle: u64) -> Option<u16> {
+        loop {
+            if !self.buffer_full && self.bytes_remaining > 0 {
+                return Some(self.current_address);
+            }
+            // the cycle counter can go backwards when a state is loaded
+            let elapsed = cycle.saturating_sub(self.cycle);
+            if elapsed < self.timer as u64 {
+                self.timer -= elapsed as u16;
+                self.cycle = self.cycle.max(cycle);
+                return None;
+            }
+            self.cycle += self.timer as u64;
+            self.timer = self.rate;
+            self.bits_remaining -= 1;
+            if self.bits_remaining == 0 {
+                // the output unit starts on the buffered byte
+                self.bits_remaining = 8;
+                self.buffer_full = false;
+            }
+        }
+    }
+
+    // the byte at the address returned by 'advance' was read into the buffer
+    pub fn fetched(&mut self) {
+        self.buffer_full = true;
+        // the address wraps to $8000
+        self.current_address = self.current_address.checked_add(1).unwrap_or(0x8000);
+        self.bytes_remaining -= 1;
+        if self.bytes_remaining == 0 && self.loop_flag {
+            self.restart();
+        }
+    }
+}
+
+impl Default for DmcReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dmc_reader() {
+        let mut dmc = DmcReader::new();
+        assert_eq!(dmc.advance(100), None);
+        // fastest rate, 17 byte sample at $C040
+        dmc.write(0x4010, 0x0f);
+        dmc.write(0x4012, 0x01);
+        dmc.write(0x4013, 0x01);
+        dmc.set_enabled(true);
+        assert_eq!(dmc.advance(100), Some(0xc040));
+        dmc.fetched();
+        // the current output byte ends on the old rate's bit at 428, followed by 7 bits at the new rate
+        assert_eq!(dmc.advance(428 + 54 * 7 - 1), None);
+        assert_eq!(dmc.advance(428 + 54 * 7), Some(0xc041));
+        dmc.fetched();
+        dmc.set_enabled(false);
+        assert!(!dmc.is_active());
+        assert_eq!(dmc.advance(10_000), None);
+
+        // a looping 1 byte sample is fetched again every 8 bits
+        dmc.write(0x4010, 0x4f);
+        dmc.write(0x4013, 0x00);
+        assert_eq!(dmc.advance(20_000), None);
+        dmc.set_enabled(true);
+        assert_eq!(dmc.advance(20_000), Some(0xc040));
+        dmc.fetched();
+        assert!(dmc.is_active());
+        assert_eq!(dmc.advance(20_000 + 54 * 8), Some(0xc040));
+    }
+}
@@ -0,0 +1,601 @@
+use std::sync::{Arc, Mutex};
+
+// Output frame dimensions in pixels
+pub const FRAME_WIDTH: usize = 256;
+pub const FRAME_HEIGHT: usize = 240;
+pub const FRAME_PIXELS: usize = FRAME_WIDTH * FRAME_HEIGHT;
+
+// The frame is split into 8x8 pixel tiles, matching the background tile grid
+pub const TILE_SIZE: usize = 8;
+pub const TILE_COLUMNS: usize = FRAME_WIDTH / TILE_SIZE;
+pub const TILE_ROWS: usize = FRAME_HEIGHT / TILE_SIZE;
+pub const TILE_COUNT: usize = TILE_COLUMNS * TILE_ROWS;
+
+// Pixel layout of the PPU output
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameFormat {
+    // RGB triples
+    Rgb888,
+    // 16 bit 5-6-5 RGB stored big endian, the byte order SPI LCD controllers expect
+    Rgb565,
+    // one NES color index (0-63) per pixel, expanded to RGB with 'expand_indexed'
+    Indexed8,
+}
+
+impl FrameFormat {
+    pub fn bytes_per_pixel(self) -> usize {
+        match self {
+            FrameFormat::Rgb888 => 3,
+            FrameFormat::Rgb565 => 2,
+            FrameFormat::Indexed8 => 1,
+        }
+    }
+}
+
+// RGB888 to the big endian bytes of an RGB565 pixel
+pub fn rgb565(rgb: [u8; 3]) -> [u8; 2] {
+    let [r, g, b] = rgb.map(u16::from);
+    (((r >> 3) << 11) | ((g >> 2) << 5) | (b >> 3)).to_be_bytes()
+}
+
+/*
+    Convert an Indexed8 frame or line to RGB triples using 'palette', e.g. 'PPU::output_palette'.
+    Indexed frames are a third of the size, so on the ESP32 they can be kept around and expanded
+    line by line while pushing them to the display.
+*/
+pub fn expand_indexed(indexed: &[u8], palette: &[[u8; 3]; 64], rgb: &mut [u8]) {
+    for (index, pixel) in indexed.iter().zip(rgb.chunks_exact_mut(3)) {
+        pixel.copy_from_slice(&palette[(index & 0x3f) as usize]);
+    }
+}
+
+/*
+    Destination the PPU renders into, one line of FRAME_WIDTH pixels at a time.
+    Lines are always written in order from top to bottom.
+*/
+pub trait FrameBuffer {
+    // storage for output line 'y'
+    fn line_mut(&mut self, y: usize) -> &mut [u8];
+
+    // called once line 'y' has been fully rendered
+    fn line_complete(&mut self, _y: usize) {}
+
+    // called after the last line of a frame has been rendered
+    fn frame_complete(&mut self) {}
+}
+
+// a single contiguous frame, rows stored one after another
+impl FrameBuffer for [u8] {
+    fn line_mut(&mut self, y: usize) -> &mut [u8] {
+        let line_bytes = self.len() / FRAME_HEIGHT;
+        &mut self[y * line_bytes..(y + 1) * line_bytes]
+    }
+}
+
+// A complete frame, FRAME_WIDTH * FRAME_HEIGHT pixels row by row
+pub struct Frame {
+    format: FrameFormat,
+    pixels: Box<[u8]>,
+}
+
+impl Default for Frame {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Frame {
+    pub fn new() -> Self {
+        Self::with_format(FrameFormat::Rgb888)
+    }
+
+    // the PPU has to be set to the same format
+    pub fn with_format(format: FrameFormat) -> Self {
+        Frame{format, pixels: vec![0u8; FRAME_PIXELS * format.bytes_per_pixel()].into_boxed_slice()}
+    }
+
+    pub fn format(&self) -> FrameFormat {
+        self.format
+    }
+
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+
+    // bytes of the pixel at 'x', 'y'
+    pub fn pixel(&self, x: usize, y: usize) -> &[u8] {
+        let bytes = self.format.bytes_per_pixel();
+        let start = (y * FRAME_WIDTH + x) * bytes;
+        &self.pixels[start..start + bytes]
+    }
+
+    // see 'frame_hash'
+    pub fn hash(&self) -> u32 {
+        frame_hash(&self.pixels)
+    }
+}
+
+impl FrameBuffer for Frame {
+    fn line_mut(&mut self, y: usize) -> &mut [u8] {
+        self.pixels.line_mut(y)
+    }
+}
+
+// Receives every frame the PPU completes, e.g. a frontend window, a PNG writer or a display driver
+pub trait FrameSink {
+    fn frame_ready(&mut self, frame: &Frame);
+}
+
+impl FrameSink for () {
+    fn frame_ready(&mut self, _frame: &Frame) {}
+}
+
+impl<F: FnMut(&Frame)> FrameSink for F {
+    fn frame_ready(&mut self, frame: &Frame) {
+        self(frame)
+    }
+}
+
+/*
+    Renders into a Frame and hands it to 'sink' once the last line is done, pass it to
+    'PPU::advance' instead of a raw buffer. The sink sees the frame before rendering of the
+    next one starts, so it has to copy or push out the pixels before returning.
+*/
+pub struct FrameOutput<S: FrameSink> {
+    frame: Frame,
+    sink: S,
+}
+
+impl<S: FrameSink> FrameOutput<S> {
+    pub fn new(sink: S) -> Self {
+        Self::with_format(FrameFormat::Rgb888, sink)
+    }
+
+    pub fn with_format(format: FrameFormat, sink: S) -> Self {
+        FrameOutput{frame: Frame::with_format(format), sink}
+    }
+
+    // the frame being rendered, or the last completed one during vblank
+    pub fn frame(&self) -> &Frame {
+        &self.frame
+    }
+
+    pub fn sink(&mut self) -> &mut S {
+        &mut self.sink
+    }
+}
+
+impl<S: FrameSink> FrameBuffer for FrameOutput<S> {
+    fn line_mut(&mut self, y: usize) -> &mut [u8] {
+        self.frame.line_mut(y)
+    }
+
+    fn frame_complete(&mut self) {
+        self.sink.frame_ready(&self.frame);
+    }
+}
+
+// frames handed between the renderer and the display side of a DoubleBuffer
+struct FrameSlots {
+    completed: Option<Frame>,
+    // frames given back by the display, reused as render targets
+    spare: Vec<Frame>,
+}
+
+/*
+    Renders into a back frame and swaps it out whole when it's complete, so the display never
+    sees a partially rendered frame. 'take_completed_frame' returns the latest complete frame,
+    from this thread or from a display thread through 'CompletedFrames'. Frames returned with
+    'recycle' are reused, so at most three frames are allocated: the one being rendered, the
+    latest completed one and the one being displayed. A completed frame which isn't taken before
+    the next one completes is dropped.
+*/
+pub struct DoubleBuffer {
+    back: Frame,
+    slots: Arc<Mutex<FrameSlots>>,
+}
+
+// the display side of a DoubleBuffer, can be moved to another thread
+#[derive(Clone)]
+pub struct CompletedFrames {
+    slots: Arc<Mutex<FrameSlots>>,
+}
+
+impl DoubleBuffer {
+    pub fn new(format: FrameFormat) -> Self {
+        DoubleBuffer{
+            back: Frame::with_format(format),
+            slots: Arc::new(Mutex::new(FrameSlots{completed: None, spare: Vec::new()})),
+        }
+    }
+
+    pub fn completed_frames(&self) -> CompletedFrames {
+        CompletedFrames{slots: self.slots.clone()}
+    }
+
+    pub fn take_completed_frame(&self) -> Option<Frame> {
+        self.slots.lock().unwrap().completed.take()
+    }
+
+    pub fn recycle(&self, frame: Frame) {
+        self.slots.lock().unwrap().spare.push(frame);
+    }
+}
+
+impl CompletedFrames {
+    // the latest completed frame, None if there is no new frame since the last call
+    pub fn take_completed_frame(&self) -> Option<Frame> {
+        self.slots.lock().unwrap().completed.take()
+    }
+
+    // give a displayed frame back for rendering
+    pub fn recycle(&self, frame: Frame) {
+        self.slots.lock().unwrap().spare.push(frame);
+    }
+}
+
+impl FrameBuffer for DoubleBuffer {
+    fn line_mut(&mut self, y: usize) -> &mut [u8] {
+        self.back.line_mut(y)
+    }
+
+    fn frame_complete(&mut self) {
+        let mut slots = self.slots.lock().unwrap();
+        // render the next frame into a recycled frame, or the completed frame nobody took
+        let next = match slots.spare.pop().or_else(|| slots.completed.take()) {
+            Some(frame) => frame,
+            None => Frame::with_format(self.back.format),
+        };
+        slots.completed = Some(std::mem::replace(&mut self.back, next));
+    }
+}
+
+type StripCallback = Box<dyn FnMut(usize, &[u8])>;
+
+/*
+    A frame split into horizontal strips held in separate buffers. Each strip is reported as
+    soon as its last line is rendered, so a display driver can start a DMA transfer of that
+    strip while the following strips are still being rendered.
+    On targets where DMA requires specially allocated memory, allocate the strips with the
+    platform allocator and pass them to 'from_strips'.
+*/
+pub struct StripedFrame {
+    format: FrameFormat,
+    strips: Vec<Box<[u8]>>,
+    lines_per_strip: usize,
+    line_bytes: usize,
+    on_strip_complete: Option<StripCallback>,
+}
+
+impl StripedFrame {
+    // 'strip_count' strips, the last strip is shorter if the frame height doesn't divide evenly
+    // the PPU has to be set to the same format
+    pub fn new(strip_count: usize, format: FrameFormat) -> Self {
+        let lines_per_strip = FRAME_HEIGHT.div_ceil(strip_count.clamp(1, FRAME_HEIGHT));
+        let line_bytes = FRAME_WIDTH * format.bytes_per_pixel();
+        let strips = (0..FRAME_HEIGHT.div_ceil(lines_per_strip))
+            .map(|strip| {
+                let lines = lines_per_strip.min(FRAME_HEIGHT - strip * lines_per_strip);
+                vec![0u8; lines * line_bytes].into_boxed_slice()
+            })
+            .collect();
+        StripedFrame{format, strips, lines_per_strip, line_bytes, on_strip_complete: None}
+    }
+
+    // use the largest strips which fit within a single transfer of 'max_transfer_bytes'
+    pub fn with_max_transfer(max_transfer_bytes: usize, format: FrameFormat) -> Self {
+        let lines_per_strip = (max_transfer_bytes / (FRAME_WIDTH * format.bytes_per_pixel())).max(1);
+        Self::new(FRAME_HEIGHT.div_ceil(lines_per_strip), format)
+    }
+
+    /*
+        Build a frame from caller allocated strips. Every strip must hold 'lines_per_strip' lines
+        except the last, which holds the remaining lines.
+     */
+    pub fn from_strips(strips: Vec<Box<[u8]>>, lines_per_strip: usize, format: FrameFormat) -> Self {
+        let line_bytes = FRAME_WIDTH * format.bytes_per_pixel();
+        assert!(lines_per_strip > 0 && strips.len() == FRAME_HEIGHT.div_ceil(lines_per_strip), "incorrect strip count");
+        for (idx, strip) in strips.iter().enumerate() {
+            let lines = lines_per_strip.min(FRAME_HEIGHT - idx * lines_per_strip);
+            assert_eq!(strip.len(), lines * line_bytes, "incorrectly sized strip");
+        }
+        StripedFrame{format, strips, lines_per_strip, line_bytes, on_strip_complete: None}
+    }
+
+    // 'callback' receives the index and contents of each strip once it is completely rendered
+    pub fn on_strip_complete<F: FnMut(usize, &[u8]) + 'static>(&mut self, callback: F) {
+        self.on_strip_complete = Some(Box::new(callback));
+    }
+
+    pub fn format(&self) -> FrameFormat {
+        self.format
+    }
+
+    pub fn strips(&self) -> &[Box<[u8]>] {
+        &self.strips
+    }
+
+    pub fn lines_per_strip(&self) -> usize {
+        self.lines_per_strip
+    }
+
+    // strip index and first line of the strip containing line 'y'
+    fn locate(&self, y: usize) -> (usize, usize) {
+        let strip = y / self.lines_per_strip;
+        (strip, strip * self.lines_per_strip)
+    }
+}
+
+impl FrameBuffer for StripedFrame {
+    fn line_mut(&mut self, y: usize) -> &mut [u8] {
+        let (strip, first_line) = self.locate(y);
+        let start = (y - first_line) * self.line_bytes;
+        &mut self.strips[strip][start..start + self.line_bytes]
+    }
+
+    fn line_complete(&mut self, y: usize) {
+        let (strip, first_line) = self.locate(y);
+        let last_line = (first_line + self.lines_per_strip).min(FRAME_HEIGHT) - 1;
+        if y == last_line {
+            if let Some(callback) = self.on_strip_complete.as_mut() {
+                callback(strip, &self.strips[strip]);
+            }
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Tile {
+    pub column: u8,
+    pub row: u8,
+}
+
+impl Tile {
+    // pixel coordinates of the upper left corner
+    pub fn position(&self) -> (usize, usize) {
+        (self.column as usize * TILE_SIZE, self.row as usize * TILE_SIZE)
+    }
+}
+
+// A rectangle of pixels, used to describe runs of dirty tiles
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rect {
+    pub x: usize,
+    pub y: usize,
+    pub width: usize,
+    pub height: usize,
+}
+
+/*
+    Tracks which tiles of the output changed since the last presented frame, so a display
+    driver (e.g. an SPI LCD) only needs to push changed regions.
+    Only a 32-bit hash is kept per tile instead of a copy of the previous frame to save memory
+    on embedded targets. A changed tile with a colliding hash would be missed, which is
+    unlikely enough to not be noticeable and corrected as soon as the tile changes again.
+*/
+pub struct DirtyTiles {
+    hashes: Box<[u32; TILE_COUNT]>,
+    dirty: Vec<Tile>,
+    // until a frame has been presented every tile is considered dirty
+    valid: bool,
+}
+
+impl Default for DirtyTiles {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DirtyTiles {
+    pub fn new() -> Self {
+        DirtyTiles {
+            hashes: Box::new([0; TILE_COUNT]),
+            dirty: Vec::with_capacity(TILE_COUNT),
+            valid: false,
+        }
+    }
+
+    // mark every tile dirty on the next update, e.g. after the display was cleared
+    pub fn invalidate(&mut self) {
+        self.valid = false;
+    }
+
+    /*
+        Compare 'frame' against the previously presented frame and record the tiles which changed.
+        'frame' holds FRAME_WIDTH * FRAME_HEIGHT pixels of any fixed size (e.g. 3 bytes for RGB)
+        stored row by row. Returns the dirty tiles in row-major order.
+     */
+    pub fn update(&mut self, frame: &[u8]) -> &[Tile] {
+        assert!(frame.len().is_multiple_of(FRAME_PIXELS) && !frame.is_empty(), "frame size doesn't match the output dimensions");
+        let bytes_per_pixel = frame.len() / FRAME_PIXELS;
+        let tile_row_bytes = TILE_SIZE * bytes_per_pixel;
+        let line_bytes = FRAME_WIDTH * bytes_per_pixel;
+
+        self.dirty.clear();
+        for row in 0..TILE_ROWS {
+            for column in 0..TILE_COLUMNS {
+                let mut hash = FNV_OFFSET;
+                for line in 0..TILE_SIZE {
+                    let start = (row * TILE_SIZE + line) * line_bytes + column * tile_row_bytes;
+                    hash = fnv1a(hash, &frame[start..start + tile_row_bytes]);
+                }
+                let index = row * TILE_COLUMNS + column;
+                if !self.valid || self.hashes[index] != hash {
+                    self.hashes[index] = hash;
+                    self.dirty.push(Tile{column: column as u8, row: row as u8});
+                }
+            }
+        }
+        self.valid = true;
+        &self.dirty
+    }
+
+    // tiles which changed in the last update
+    pub fn dirty(&self) -> &[Tile] {
+        &self.dirty
+    }
+
+    // dirty tiles with horizontally adjacent tiles merged, which reduces the number of
+    // display window commands needed to push an update
+    pub fn dirty_rects(&self) -> Vec<Rect> {
+        let mut rects: Vec<Rect> = Vec::new();
+        for tile in self.dirty.iter() {
+            let (x, y) = tile.position();
+            match rects.last_mut() {
+                Some(rect) if rect.y == y && rect.x + rect.width == x => rect.width += TILE_SIZE,
+                _ => rects.push(Rect{x, y, width: TILE_SIZE, height: TILE_SIZE}),
+            }
+        }
+        rects
+    }
+}
+
+/*
+    32-bit FNV-1a hash of a frame's pixel bytes. It only depends on the pixels, so tests can
+    compare a frame against a known hash instead of a stored golden image, hashes of the same
+    picture differ between output formats.
+*/
+pub fn frame_hash(pixels: &[u8]) -> u32 {
+    fnv1a(FNV_OFFSET, pixels)
+}
+
+const FNV_OFFSET: u32 = 0x811c9dc5;
+const FNV_PRIME: u32 = 0x01000193;
+
+fn fnv1a(mut hash: u32, data: &[u8]) -> u32 {
+    for byte in data {
+        hash ^= *byte as u32;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_dirty_tiles() {
+        let mut frame = vec![0u8; FRAME_PIXELS * 3];
+        let mut tracker = DirtyTiles::new();
+        assert_eq!(tracker.update(&frame).len(), TILE_COUNT);
+        assert!(tracker.update(&frame).is_empty());
+
+        // change a pixel in tile (1, 2) and in its right neighbour
+        frame[((2 * TILE_SIZE + 3) * FRAME_WIDTH + TILE_SIZE + 7) * 3] = 0xff;
+        frame[((2 * TILE_SIZE + 7) * FRAME_WIDTH + 2 * TILE_SIZE) * 3 + 2] = 0xff;
+        assert_eq!(tracker.update(&frame), &[Tile{column: 1, row: 2}, Tile{column: 2, row: 2}]);
+        assert_eq!(tracker.dirty_rects(), vec![Rect{x: 8, y: 16, width: 16, height: 8}]);
+        assert!(tracker.update(&frame).is_empty());
+
+        tracker.invalidate();
+        assert_eq!(tracker.update(&frame).len(), TILE_COUNT);
+        assert_eq!(tracker.dirty_rects().len(), TILE_ROWS);
+    }
+
+    #[test]
+    fn test_striped_frame() {
+        use std::{cell::RefCell, rc::Rc};
+        use crate::ppu::PPU;
+
+        let frame = StripedFrame::with_max_transfer(4092, FrameFormat::Rgb565);
+        assert_eq!(frame.lines_per_strip(), 7);
+        assert_eq!(frame.strips().len(), 35);
+        assert_eq!(frame.strips().last().unwrap().len(), 2 * FRAME_WIDTH * 2);
+
+        let mut frame = StripedFrame::new(4, FrameFormat::Rgb888);
+        let completed = Rc::new(RefCell::new(Vec::new()));
+        let log = completed.clone();
+        frame.on_strip_complete(move |strip, data| log.borrow_mut().push((strip, data.len())));
+        frame.line_mut(61)[0] = 0xaa;
+        assert_eq!(frame.strips()[1][FRAME_WIDTH * 3], 0xaa);
+
+        // render a full frame, each strip should be reported in order
+        let mut ppu = PPU::new(vec![]);
+        ppu.advance(341 * 262, &mut frame);
+        assert_eq!(*completed.borrow(), (0..4).map(|strip| (strip, 60 * FRAME_WIDTH * 3)).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_frame_sink() {
+        use crate::ppu::{NTSC_PALETTE, PPU};
+
+        struct Counter(usize, [u8; 3]);
+
+        impl FrameSink for Counter {
+            fn frame_ready(&mut self, frame: &Frame) {
+                self.0 += 1;
+                self.1 = frame.pixel(FRAME_WIDTH - 1, FRAME_HEIGHT - 1).try_into().unwrap();
+            }
+        }
+
+        let mut output = FrameOutput::new(Counter(0, [0; 3]));
+        let mut ppu = PPU::new(vec![]);
+        // the sink is called once the last line is rendered, before vblank
+        ppu.advance(341 * 240 + 200, &mut output);
+        assert_eq!(output.sink().0, 0);
+        ppu.advance(100, &mut output);
+        assert_eq!((output.sink().0, output.sink().1), (1, NTSC_PALETTE[0]));
+        ppu.advance(341 * 262, &mut output);
+        assert_eq!(output.sink().0, 2);
+
+        // closures are sinks too
+        let mut frames = 0;
+        let mut output = FrameOutput::new(|_: &Frame| frames += 1);
+        ppu.advance(341 * 262, &mut output);
+        drop(output);
+        assert_eq!(frames, 1);
+    }
+
+    #[test]
+    fn test_double_buffer() {
+        use crate::ppu::PPU;
+
+        let mut buffer = DoubleBuffer::new(FrameFormat::Rgb888);
+        let display = buffer.completed_frames();
+        let mut ppu = PPU::new(vec![]);
+        assert!(display.take_completed_frame().is_none());
+        ppu.advance(341 * 262, &mut buffer);
+        let frame = display.take_completed_frame().expect("no completed frame");
+        assert!(display.take_completed_frame().is_none());
+
+        // the next frame is rendered into a new buffer while 'frame' is displayed
+        buffer.line_mut(0)[0] = 0xaa;
+        assert_ne!(frame.pixels()[0], 0xaa);
+        let frame_ptr = frame.pixels().as_ptr();
+        display.recycle(frame);
+        ppu.advance(341 * 262, &mut buffer);
+        // the recycled frame is the render target again
+        assert_eq!(buffer.line_mut(0).as_ptr(), frame_ptr);
+        assert_ne!(buffer.take_completed_frame().unwrap().pixels().as_ptr(), frame_ptr);
+
+        // frames completed while the display holds one are handed over from another thread
+        let handle = std::thread::spawn(move || {
+            let mut frames = 0;
+            while frames < 3 {
+                if let Some(frame) = display.take_completed_frame() {
+                    frames += 1;
+                    display.recycle(frame);
+                }
+                std::thread::yield_now();
+            }
+        });
+        while !handle.is_finished() {
+            ppu.advance(341 * 262, &mut buffer);
+        }
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn test_frame_hash() {
+        let mut frame = Frame::new();
+        // FNV-1a of FRAME_PIXELS * 3 zero bytes
+        assert_eq!(frame.hash(), frame_hash(&vec![0u8; FRAME_PIXELS * 3]));
+        assert_eq!(frame_hash(b""), FNV_OFFSET);
+        assert_eq!(frame_hash(b"a"), 0xe40c292c);
+        let blank = frame.hash();
+        frame.line_mut(239)[767] = 1;
+        assert_ne!(frame.hash(), blank);
+        assert_ne!(Frame::with_format(FrameFormat::Indexed8).hash(), blank);
+    }
+}
@@ -0,0 +1,438 @@
+use std::io::{self, Read, Write};
+use std::net::{TcpListener, TcpStream, ToSocketAddrs};
+
+use crate::cpu::{ProcessorStatusFlags, CPU};
+use crate::frame::FrameSink;
+use crate::memory::WatchAccess;
+use crate::nes::{AudioSink, InputSource, Nes};
+
+/*
+    GDB remote serial protocol stub (feature "gdb")
+    Serves a single debugger connection over TCP, e.g. 'target remote localhost:2345'.
+    Supported packets:
+        ?               stop reason
+        g / G           read / write all registers
+        p n / P n=v     read / write one register
+        m a,l / M a,l:d read / write memory
+        c / s           continue / single step
+        Z0-Z1 / z0-z1   add / remove breakpoints
+        Z2-Z4 / z2-z4   add / remove write, read and access watchpoints
+        D / k           detach / kill
+        qXfer:features:read:target.xml   target description
+    Registers are numbered A=0, X=1, Y=2, P=3, SP=4 and PC=5, PC is 16 bit little endian.
+    GDB has no 6502 architecture, the target description tells it the register layout.
+*/
+
+// instructions executed between checks for a Ctrl-C from the debugger
+const INTERRUPT_POLL_STEPS: u32 = 1024;
+const INTERRUPT: u8 = 0x03;
+const SIGILL: u8 = 4;
+const SIGTRAP: u8 = 5;
+
+const TARGET_XML: &str = r#"<?xml version="1.0"?>
+<!DOCTYPE target SYSTEM "gdb-target.dtd">
+<target version="1.0">
+  <feature name="org.rust_nes_esp.6502">
+    <reg name="a" bitsize="8" regnum="0" type="uint8"/>
+    <reg name="x" bitsize="8" type="uint8"/>
+    <reg name="y" bitsize="8" type="uint8"/>
+    <reg name="p" bitsize="8" type="uint8"/>
+    <reg name="sp" bitsize="8" type="uint8"/>
+    <reg name="pc" bitsize="16" type="code_ptr"/>
+  </feature>
+</target>
+"#;
+
+// something the stub can run, either a bare CPU or a whole NES so the PPU keeps running
+pub trait GdbTarget {
+    fn cpu(&mut self) -> &mut CPU;
+
+    // execute a single instruction
+    fn step(&mut self);
+}
+
+impl GdbTarget for CPU {
+    fn cpu(&mut self) -> &mut CPU {
+        self
+    }
+
+    fn step(&mut self) {
+        self.execute(Some(1));
+    }
+}
+
+impl<V: FrameSink, A: AudioSink, I: InputSource> GdbTarget for Nes<V, A, I> {
+    fn cpu(&mut self) -> &mut CPU {
+        &mut self.cpu
+    }
+
+    fn step(&mut self) {
+        Nes::step(self);
+    }
+}
+
+// what to do after a packet was handled
+#[derive(Debug, PartialEq, Eq)]
+enum Action {
+    Reply(String),
+    Continue,
+    Step,
+    Detach,
+}
+
+fn checksum(data: &str) -> u8 {
+    data.bytes().fold(0u8, |sum, byte| sum.wrapping_add(byte))
+}
+
+fn parse_hex(hex: &str) -> Option<u32> {
+    u32::from_str_radix(hex, 16).ok()
+}
+
+fn decode_hex_bytes(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    // pairs of bytes rather than string slices, a multibyte character must not split a slice
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).ok()?, 16).ok())
+        .collect()
+}
+
+fn encode_hex_bytes(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn read_registers(cpu: &CPU) -> Vec<u8> {
+    let pc = cpu.program_counter.to_le_bytes();
+    vec![
+        cpu.accumulator,
+        cpu.idx_register_x,
+        cpu.idx_register_y,
+        cpu.processor_status.bits(),
+        cpu.stack_pointer,
+        pc[0],
+        pc[1],
+    ]
+}
+
+fn write_register(cpu: &mut CPU, register: u32, bytes: &[u8]) -> bool {
+    match (register, bytes) {
+        (0, [value]) => cpu.accumulator = *value,
+        (1, [value]) => cpu.idx_register_x = *value,
+        (2, [value]) => cpu.idx_register_y = *value,
+        (3, [value]) => cpu.processor_status = ProcessorStatusFlags::from_bits_truncate(*value),
+        (4, [value]) => cpu.stack_pointer = *value,
+        (5, [low, high]) => cpu.program_counter = u16::from_le_bytes([*low, *high]),
+        _ => return false,
+    }
+    true
+}
+
+// byte offset of each register in the 'g' packet
+const REGISTER_OFFSETS: [(usize, usize); 6] = [(0, 1), (1, 1), (2, 1), (3, 1), (4, 1), (5, 2)];
+
+// "addr,length" as used by memory and breakpoint packets
+fn parse_range(args: &str) -> Option<(u16, u32)> {
+    let (address, length) = args.split_once(',')?;
+    Some((parse_hex(address)? as u16, parse_hex(length)?))
+}
+
+fn watch_access(kind: &str) -> Option<WatchAccess> {
+    match kind {
+        "2" => Some(WatchAccess::WRITE),
+        "3" => Some(WatchAccess::READ),
+        "4" => Some(WatchAccess::READ | WatchAccess::WRITE),
+        _ => None,
+    }
+}
+
+// add (Z) or remove (z) a breakpoint or watchpoint, "kind,addr,length"
+fn set_breakpoint(cpu: &mut CPU, args: &str, insert: bool) -> String {
+    let Some((kind, range)) = args.split_once(',') else {
+        return String::from("E01");
+    };
+    let Some((address, length)) = parse_range(range) else {
+        return String::from("E01");
+    };
+    match kind {
+        "0" | "1" => {
+            if insert {
+                cpu.add_breakpoint(address);
+            } else {
+                cpu.remove_breakpoint(address);
+            }
+        }
+        _ => match watch_access(kind) {
+            Some(access) => {
+                // the length is clamped before narrowing, lengths of $10000 and up cover the rest of memory
+                let end = address.saturating_add((length.max(1) - 1).min(0xffff) as u16);
+                if insert {
+                    cpu.memory.add_watchpoint(address..=end, access);
+                } else {
+                    cpu.memory.remove_watchpoint(address..=end, access);
+                }
+            }
+            None => return String::new(),
+        },
+    }
+    String::from("OK")
+}
+
+// "annex:offset,length" of a qXfer:features:read packet, 'm' while more data follows, 'l' for the last part
+fn read_features(args: &str) -> String {
+    let Some((annex, range)) = args.split_once(':') else {
+        return String::from("E01");
+    };
+    if annex != "target.xml" {
+        return String::from("E00");
+    }
+    let Some((offset, length)) = range.split_once(',').and_then(|(offset, length)| Some((parse_hex(offset)?, parse_hex(length)?))) else {
+        return String::from("E01");
+    };
+    let start = (offset as usize).min(TARGET_XML.len());
+    let end = start.saturating_add(length as usize).min(TARGET_XML.len());
+    let marker = if end < TARGET_XML.len() {'m'} else {'l'};
+    format!("{}{}", marker, &TARGET_XML[start..end])
+}
+
+fn stop_reply(cpu: &CPU) -> String {
+    if cpu.is_halted() {
+        return format!("S{:02x}", SIGILL);
+    }
+    match cpu.watchpoint_hit() {
+        Some(hit) => {
+            let kind = if hit.access == WatchAccess::WRITE {"watch"} else {"rwatch"};
+            format!("T{:02x}{}:{:04x};", SIGTRAP, kind, hit.address)
+        }
+        None => format!("S{:02x}", SIGTRAP),
+    }
+}
+
+fn handle_packet<T: GdbTarget>(target: &mut T, packet: &str) -> Action {
+    let cpu = target.cpu();
+    let (command, args) = packet.split_at(packet.chars().next().map_or(0, char::len_utf8));
+    let reply = match command {
+        "?" => stop_reply(cpu),
+        "g" => encode_hex_bytes(&read_registers(cpu)),
+        "G" => match decode_hex_bytes(args) {
+            Some(bytes) if bytes.len() == 7 => {
+                for (register, (offset, size)) in REGISTER_OFFSETS.iter().enumerate() {
+                    write_register(cpu, register as u32, &bytes[*offset..offset + size]);
+                }
+                String::from("OK")
+            }
+            _ => String::from("E01"),
+        },
+        "p" => match parse_hex(args).and_then(|register| REGISTER_OFFSETS.get(register as usize)) {
+            Some((offset, size)) => encode_hex_bytes(&read_registers(cpu)[*offset..offset + size]),
+            None => String::from("E01"),
+        },
+        "P" => {
+            let written = args.split_once('=').and_then(|(register, value)| {
+                Some(write_register(cpu, parse_hex(register)?, &decode_hex_bytes(value)?))
+            });
+            if written == Some(true) {String::from("OK")} else {String::from("E01")}
+        }
+        "m" => match parse_range(args) {
+            Some((address, length)) => {
+                // lengths of $10000 and up read all of memory once instead of wrapping
+                let bytes: Vec<u8> = (0..length.min(0x10000)).map(|i| cpu.memory.peek(address.wrapping_add(i as u16))).collect();
+                encode_hex_bytes(&bytes)
+            }
+            None => String::from("E01"),
+        },
+        "M" => {
+            let data = args.split_once(':').and_then(|(range, data)| Some((parse_range(range)?, decode_hex_bytes(data)?)));
+            match data {
+                Some(((address, length), bytes)) if bytes.len() == length as usize => {
+                    for (i, byte) in bytes.iter().enumerate() {
+                        cpu.memory.write(address.wrapping_add(i as u16), *byte);
+                    }
+                    String::from("OK")
+                }
+                _ => String::from("E01"),
+            }
+        }
+        "Z" => set_breakpoint(cpu, args, true),
+        "z" => set_breakpoint(cpu, args, false),
+        "c" => return Action::Continue,
+        "s" => return Action::Step,
+        "D" | "k" => return Action::Detach,
+        "q" if args.starts_with("Supported") => String::from("PacketSize=1000;qXfer:features:read+"),
+        "q" if args.starts_with("Xfer:features:read:") => read_features(&args["Xfer:features:read:".len()..]),
+        "q" if args == "Attached" => String::from("1"),
+        // unsupported packets get an empty reply
+        _ => String::new(),
+    };
+    Action::Reply(reply)
+}
+
+// run until a breakpoint, watchpoint, KIL or 'interrupted' returns true, returns the stop reply
+fn resume<T: GdbTarget, F: FnMut() -> bool>(target: &mut T, mut interrupted: F) -> String {
+    let mut executed = 0u32;
+    loop {
+        target.step();
+        let cpu = target.cpu();
+        if cpu.is_halted() || cpu.watchpoint_hit().is_some() || cpu.has_breakpoint(cpu.program_counter) {
+            break;
+        }
+        executed = executed.wrapping_add(1);
+        if executed.is_multiple_of(INTERRUPT_POLL_STEPS) && interrupted() {
+            break;
+        }
+    }
+    stop_reply(target.cpu())
+}
+
+struct Connection {
+    stream: TcpStream,
+    buffer: Vec<u8>,
+}
+
+impl Connection {
+    fn send(&mut self, data: &str) -> io::Result<()> {
+        let packet = format!("${}#{:02x}", data, checksum(data));
+        self.stream.write_all(packet.as_bytes())
+    }
+
+    // next packet from the debugger, None when the connection was closed
+    fn receive(&mut self) -> io::Result<Option<String>> {
+        loop {
+            if let Some(start) = self.buffer.iter().position(|&byte| byte == b'$') {
+                if let Some(end) = self.buffer[start..].iter().position(|&byte| byte == b'#') {
+                    let end = start + end;
+                    if self.buffer.len() >= end + 3 {
+                        let packet = String::from_utf8_lossy(&self.buffer[start + 1..end]).into_owned();
+                        let valid = std::str::from_utf8(&self.buffer[end + 1..end + 3]).ok()
+                            .and_then(|sum| u8::from_str_radix(sum, 16).ok()) == Some(checksum(&packet));
+                        self.buffer.drain(..end + 3);
+                        self.stream.write_all(if valid {b"+"} else {b"-"})?;
+                        if valid {
+                            return Ok(Some(packet));
+                        }
+                        continue;
+                    }
+                }
+            } else {
+                // acknowledgements and interrupts outside of 'resume' are ignored
+                self.buffer.clear();
+            }
+            let mut chunk = [0u8; 1024];
+            let count = self.stream.read(&mut chunk)?;
+            if count == 0 {
+                return Ok(None);
+            }
+            self.buffer.extend_from_slice(&chunk[..count]);
+        }
+    }
+
+    // check for a Ctrl-C without blocking
+    fn interrupted(&mut self) -> bool {
+        let mut byte = [0u8; 1];
+        if self.stream.set_nonblocking(true).is_err() {
+            return false;
+        }
+        let interrupted = matches!(self.stream.read(&mut byte), Ok(1) if byte[0] == INTERRUPT);
+        let _ = self.stream.set_nonblocking(false);
+        interrupted
+    }
+}
+
+// wait for a debugger on 'address' and serve it until it detaches or disconnects
+pub fn serve<T: GdbTarget, S: ToSocketAddrs>(target: &mut T, address: S) -> io::Result<()> {
+    let listener = TcpListener::bind(address)?;
+    let (stream, _) = listener.accept()?;
+    stream.set_nodelay(true)?;
+    let mut connection = Connection { stream, buffer: Vec::new() };
+    while let Some(packet) = connection.receive()? {
+        match handle_packet(target, &packet) {
+            Action::Reply(reply) => connection.send(&reply)?,
+            Action::Continue => {
+                let reply = resume(target, || connection.interrupted());
+                connection.send(&reply)?;
+            }
+            Action::Step => {
+                target.step();
+                connection.send(&stop_reply(target.cpu()))?;
+            }
+            Action::Detach => {
+                connection.send("OK")?;
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn reply(cpu: &mut CPU, packet: &str) -> String {
+        match handle_packet(cpu, packet) {
+            Action::Reply(reply) => reply,
+            action => panic!("unexpected {:?}", action),
+        }
+    }
+
+    #[test]
+    fn test_gdb_packets() {
+        /*
+            ldx #$02
+            inx
+            stx $10
+            jmp $8002
+         */
+        let mut cpu = CPU::with_program(vec![0xa2, 0x02, 0xe8, 0x86, 0x10, 0x4c, 0x02, 0x80]);
+        assert_eq!(checksum("OK"), 0x9a);
+        assert_eq!(reply(&mut cpu, "g"), "00000000ff0080");
+        assert_eq!(reply(&mut cpu, "P0=41"), "OK");
+        assert_eq!(reply(&mut cpu, "p0"), "41");
+        assert_eq!(reply(&mut cpu, "m8000,3"), "a202e8");
+        assert_eq!(reply(&mut cpu, "M0020,2:beef"), "OK");
+        assert_eq!(reply(&mut cpu, "m20,2"), "beef");
+        assert_eq!(reply(&mut cpu, "vMustReplyEmpty"), "");
+
+        assert_eq!(handle_packet(&mut cpu, "s"), Action::Step);
+        cpu.step();
+        assert_eq!(reply(&mut cpu, "p5"), "0280");
+
+        assert_eq!(reply(&mut cpu, "Z0,8005,1"), "OK");
+        assert_eq!(resume(&mut cpu, || false), "S05");
+        assert_eq!(cpu.program_counter, 0x8005);
+        assert_eq!(reply(&mut cpu, "z0,8005,1"), "OK");
+
+        assert_eq!(reply(&mut cpu, "Z2,10,1"), "OK");
+        assert_eq!(resume(&mut cpu, || false), "T05watch:0010;");
+        assert_eq!(reply(&mut cpu, "z2,10,1"), "OK");
+        assert_eq!(reply(&mut cpu, "p1"), "04");
+
+        // oversized ranges are clamped, the end of memory is covered
+        assert_eq!(reply(&mut cpu, "Z2,10,10000"), "OK");
+        assert_eq!(reply(&mut cpu, "z2,10,10000"), "OK");
+        assert_eq!(reply(&mut cpu, "Z2,10,ffffffff"), "OK");
+        assert_eq!(reply(&mut cpu, "z2,10,ffffffff"), "OK");
+        assert_eq!(reply(&mut cpu, "m0,10000").len(), 0x20000);
+        assert_eq!(reply(&mut cpu, "m0,ffffffff").len(), 0x20000);
+        // non-ASCII hex digits and commands
+        assert_eq!(reply(&mut cpu, "P0=aéb"), "E01");
+        assert_eq!(reply(&mut cpu, "M0020,2:aéb"), "E01");
+        assert_eq!(reply(&mut cpu, "é"), "");
+
+        assert!(reply(&mut cpu, "qSupported:multiprocess+").contains("qXfer:features:read+"));
+        let mut xml = String::new();
+        loop {
+            let part = reply(&mut cpu, &format!("qXfer:features:read:target.xml:{:x},40", xml.len()));
+            xml.push_str(&part[1..]);
+            if part.starts_with('l') {break}
+            assert!(part.starts_with('m'));
+        }
+        assert_eq!(xml, TARGET_XML);
+        assert_eq!(reply(&mut cpu, "qXfer:features:read:other.xml:0,40"), "E00");
+
+        // nothing stops the loop except the debugger
+        assert_eq!(resume(&mut cpu, || true), "S05");
+        assert_eq!(handle_packet(&mut cpu, "D"), Action::Detach);
+    }
+}
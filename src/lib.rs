@@ -1,5 +1,22 @@
 #![recursion_limit = "500"]
+pub mod bus;
 pub mod cpu;
+pub mod disasm;
+pub mod asm;
+pub mod profiler;
+pub mod dmc;
+pub mod state;
 pub mod memory;
 pub mod ppu;
+pub mod frame;
+pub mod scale;
+pub mod audio;
+pub mod controller;
 pub mod opmap;
+pub mod debugger;
+pub mod symbols;
+pub mod nes;
+#[cfg(feature = "ntsc")]
+pub mod ntsc;
+#[cfg(feature = "gdb")]
+pub mod gdb;
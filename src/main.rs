@@ -1,13 +1,24 @@
 #![allow(unused_variables)]
+pub mod bus;
 pub mod cpu;
+pub mod disasm;
+pub mod asm;
+pub mod profiler;
+pub mod dmc;
+pub mod state;
 pub mod opmap;
 pub mod ppu;
+pub mod frame;
 pub mod memory;
+pub mod symbols;
+pub mod audio;
+pub mod controller;
 use crate::opmap::OP_MAP;
 use crate::cpu::CPU;
+use crate::memory::Memory;
 
 fn count_valid_ops() -> usize {
-    OP_MAP.iter().filter(|&&op| op as usize != CPU::noop as usize).count()
+    OP_MAP.iter().filter(|&&op| op as usize != CPU::<Memory>::unimplemented as usize).count()
 }
 
 fn main() {
@@ -1,11 +1,21 @@
 use std::{io::{self, Read}, cell::RefCell, marker::PhantomPinned, ops::{Index, IndexMut, Range}, ptr::NonNull, u16};
 use std::result::Result;
-use crate::ppu::PPU;
+use std::ops::RangeInclusive;
+use bitflags::bitflags;
+use crate::bus::Bus;
+use crate::ppu::{Mirroring, PPU, PpuState, Region};
+use crate::audio::{ApuEvent, ApuQueueProducer};
+use crate::controller::Controller;
+use crate::dmc::{DmcReader, DMC_STALL_CYCLES};
+use crate::state::Packed;
 
 // Memory Map constants
 // constants specify the start of named section
 pub const BUILTIN_RAM: u16 = 0;
 pub const MMIO: u16 = 0x2000;
+pub const APU_IO: u16 = 0x4000;
+// bits of the controller ports which aren't driven and read back as open bus
+const OPEN_BUS_CONTROLLER: u8 = 0xe0;
 pub const EXPANSION_ROM: u16 = 0x4020;
 pub const SRAM: u16 = 0x6000;
 pub const PROGRAM_ROM: u16 = 0x8000;
@@ -115,7 +125,11 @@ impl IndexMut<Range<u16> > for RAM {
 pub enum NesError {
     IO(io::Error),
     FileFormat(&'static str),
-    Emulator(&'static str)
+    Emulator(&'static str),
+    // the CPU fetched an opcode it doesn't emulate
+    UnimplementedOpcode { pc: u16, opcode: u8 },
+    // 'asm::assemble' rejected a source line, lines count from 1
+    Assembly { line: usize, reason: &'static str },
 }
 
 impl From<io::Error> for NesError {
@@ -124,6 +138,49 @@ impl From<io::Error> for NesError {
     }
 }
 
+bitflags! {
+    // kind of bus access a watchpoint triggers on
+    #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+    pub struct WatchAccess: u8 {
+        const READ  = 1 << 0;
+        const WRITE = 1 << 1;
+    }
+}
+
+// first access to a watched address during an instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchpointHit {
+    pub address: u16,
+    pub data: u8,
+    pub access: WatchAccess,
+}
+
+// RAM, PPU and controller state, program ROM and CHR ROM are not included
+#[derive(Debug, Clone)]
+pub struct MemoryState {
+    ram: Packed,
+    battery_ram: Option<Packed>,
+    controllers: [Controller; 2],
+    open_bus: u8,
+    dmc: DmcReader,
+    ppu: PpuState,
+    cycle: u64,
+    oam_dma: bool,
+    dmc_stall: u32,
+    // deferred PPU writes which haven't been applied yet
+    ppu_writes: Vec<(u16, u8)>,
+    ppu_write_cycle: u64,
+}
+
+impl MemoryState {
+    // size of the run length encoded buffers in bytes
+    pub fn packed_len(&self) -> usize {
+        self.ram.packed_len()
+            + self.battery_ram.as_ref().map_or(0, Packed::packed_len)
+            + self.ppu.packed_len()
+    }
+}
+
 pub struct Memory {
     program_rom: Vec<RAM>,
     /* Memory must uphold the following:
@@ -139,31 +196,85 @@ pub struct Memory {
     ram: [u8; (MMIO - BUILTIN_RAM) as usize],
     battery_ram: Option<RAM>,
     pub ppu: PPU,
+    pub controllers: [Controller; 2],
     mapper: u8, //TODO should be enum probably
+    // APU register writes are forwarded here when audio runs on its own thread
+    apu_queue: Option<ApuQueueProducer>,
+    // CPU cycle at the start of the current instruction, used to timestamp APU writes
+    pub(crate) cycle: u64,
+    // last value on the data bus, returned by reads of addresses nothing responds to
+    open_bus: u8,
+    // CPU address ranges which stop execution when accessed, mirrors are not matched
+    watchpoints: Vec<(RangeInclusive<u16>, WatchAccess)>,
+    watchpoint_hit: Option<WatchpointHit>,
+    // set by a write to $4014 until the CPU accounts for the stall
+    oam_dma: bool,
+    dmc: DmcReader,
+    // cycles stolen by DMC fetches which the CPU hasn't accounted for yet
+    dmc_stall: u32,
+    // PPU register writes of the current instruction when they are deferred, see 'defer_ppu_writes'
+    ppu_writes: Option<Vec<(u16, u8)>>,
+    // CPU cycle the deferred writes were made on
+    ppu_write_cycle: u64,
 }
 
 impl Memory {
     pub fn read(&mut self, address: u16) -> u8 {
-        match address {
+        let data = match address {
             BUILTIN_RAM..MMIO => self.ram[(address % 0x0800) as usize], // Mirror every 2 KB
-            MMIO..EXPANSION_ROM => self.ppu.read(address), // Mirrors every 8 bytes
-            EXPANSION_ROM..SRAM => 0u8, //EXPANSION_ROM
+            MMIO..APU_IO => self.ppu.read(address), // Mirrors every 8 bytes
+            APU_IO..EXPANSION_ROM => self.read_io(address),
+            EXPANSION_ROM..SRAM => self.open_bus, //EXPANSION_ROM
             SRAM..PROGRAM_ROM => if let Some(ref ram) = self.battery_ram {
                 ram[address - BATTERY_RAM]
             } else {
-                // ! What should these reads return
-                0u8
+                self.open_bus
             },
             // this is safe because active program roms are always selected
             PROGRAM_ROM..PROGRAM_ROM_2 => unsafe{self.active_program_1.as_ref()[address - PROGRAM_ROM]},
             PROGRAM_ROM_2..=u16::MAX => unsafe{self.active_program_2.as_ref()[address - PROGRAM_ROM_2]},
+        };
+        self.open_bus = data;
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(address, data, WatchAccess::READ);
+        }
+        data
+    }
+
+    /// Read 'address' without triggering side effects of memory mapped registers.
+    /// Intended for debuggers and other tooling which must not disturb emulation.
+    pub fn peek(&self, address: u16) -> u8 {
+        match address {
+            BUILTIN_RAM..MMIO => self.ram[(address % 0x0800) as usize],
+            MMIO..APU_IO => self.ppu.peek(address),
+            APU_IO..EXPANSION_ROM => match address {
+                0x4016 => (self.open_bus & OPEN_BUS_CONTROLLER) | self.controllers[0].peek() | self.controllers[1].microphone_bit(),
+                0x4017 => (self.open_bus & OPEN_BUS_CONTROLLER) | self.controllers[1].peek(),
+                _ => self.open_bus,
+            },
+            EXPANSION_ROM..SRAM => self.open_bus,
+            SRAM..PROGRAM_ROM => if let Some(ref ram) = self.battery_ram {
+                ram[address - BATTERY_RAM]
+            } else {
+                self.open_bus
+            },
+            PROGRAM_ROM..PROGRAM_ROM_2 => unsafe{self.active_program_1.as_ref()[address - PROGRAM_ROM]},
+            PROGRAM_ROM_2..=u16::MAX => unsafe{self.active_program_2.as_ref()[address - PROGRAM_ROM_2]},
         }
     }
 
     pub fn write(&mut self, address: u16, data: u8) {
+        self.open_bus = data;
+        if !self.watchpoints.is_empty() {
+            self.check_watchpoints(address, data, WatchAccess::WRITE);
+        }
         match address {
             BUILTIN_RAM..MMIO => self.ram[(address % 0x0800) as usize] = data, // Mirror every 2 KB
-            MMIO..EXPANSION_ROM => MMIO_WRITE_MAP[address_mmio_map(address)](&mut self.ppu, data),
+            MMIO..APU_IO => match self.ppu_writes {
+                Some(ref mut writes) => writes.push((address, data)),
+                None => self.write_ppu(address, data),
+            },
+            APU_IO..EXPANSION_ROM => self.write_io(address, data),
             EXPANSION_ROM..SRAM => (), //EXPANSION_ROM
             SRAM..PROGRAM_ROM => if let Some(ref mut ram) = self.battery_ram {
                 ram[address - BATTERY_RAM] = data;
@@ -174,19 +285,226 @@ impl Memory {
         }
     }
 
+    fn write_ppu(&mut self, address: u16, data: u8) {
+        self.ppu.drive_bus(data);
+        // PPUCTRL, PPUMASK, PPUSCROLL and PPUADDR aren't ready right after power-on and reset
+        if self.ppu.warming_up() && matches!(address & 0x07, 0 | 1 | 5 | 6) {
+            return;
+        }
+        MMIO_WRITE_MAP[address_mmio_map(address)](&mut self.ppu, data)
+    }
+
+    /*
+        The PPU is clocked after each instruction, so a register write would take effect as of the
+        start of the instruction. Deferred writes are held until 'apply_ppu_writes', which lets 'Nes'
+        run the PPU up to the cycle of the write first. Raster effects like status bar splits depend
+        on this, a write moved by a few dots shifts the split.
+     */
+    pub fn defer_ppu_writes(&mut self, enabled: bool) {
+        if !enabled {self.apply_ppu_writes()}
+        self.ppu_writes = enabled.then(Vec::new);
+    }
+
+    // CPU cycle of the deferred PPU writes, None if there are none
+    pub fn ppu_write_cycle(&self) -> Option<u64> {
+        self.ppu_writes.as_ref().filter(|writes| !writes.is_empty()).map(|_| self.ppu_write_cycle)
+    }
+
+    pub fn apply_ppu_writes(&mut self) {
+        if let Some(mut writes) = self.ppu_writes.take() {
+            for &(address, data) in &writes {
+                self.write_ppu(address, data);
+            }
+            writes.clear();
+            self.ppu_writes = Some(writes);
+        }
+    }
+
+    fn read_io(&mut self, address: u16) -> u8 {
+        match address {
+            0x4016 => (self.open_bus & OPEN_BUS_CONTROLLER) | self.controllers[0].read() | self.controllers[1].microphone_bit(),
+            0x4017 => (self.open_bus & OPEN_BUS_CONTROLLER) | self.controllers[1].read(),
+            // write only registers aren't driven
+            _ => self.open_bus,
+        }
+    }
+
+    /*
+        OAM DMA: copy CPU page $XX00-$XXFF to sprite RAM through $2004, starting at OAMADDR.
+        The copy happens at once, the CPU is stalled for the 513/514 cycles it takes afterwards.
+     */
+    fn oam_dma(&mut self, page: u8) {
+        let start = (page as u16) << 8;
+        for offset in 0..=0xff {
+            let data = self.read(start | offset);
+            self.ppu.drive_bus(data);
+            self.ppu.write_spram(data);
+        }
+        self.oam_dma = true;
+    }
+
+    /*
+        OAM DMA takes 513 cycles, plus one to align to a read cycle if it starts on an odd cycle.
+        DMC fetches since the last call are added on top.
+     */
+    pub fn take_stall_cycles(&mut self, cycle: u64) -> u32 {
+        // stores write on the last cycle of the instruction
+        self.ppu_write_cycle = cycle.saturating_sub(1);
+        let dmc = std::mem::take(&mut self.dmc_stall);
+        if !std::mem::take(&mut self.oam_dma) {
+            return dmc;
+        }
+        513 + (cycle & 1) as u32 + dmc
+    }
+
+    // fetch the DMC sample bytes due up to the current cycle
+    fn clock_dmc(&mut self) {
+        while let Some(address) = self.dmc.advance(self.cycle) {
+            let data = self.peek(address);
+            self.open_bus = data;
+            self.dmc.fetched();
+            self.dmc_stall += DMC_STALL_CYCLES;
+            if let Some(ref mut queue) = self.apu_queue {
+                queue.push(ApuEvent::DmcSample{cycle: self.cycle, data});
+            }
+        }
+    }
+
+    fn write_io(&mut self, address: u16, data: u8) {
+        match address {
+            0x4014 => self.oam_dma(data),
+            // strobe is shared by both controller ports
+            0x4016 => self.controllers.iter_mut().for_each(|controller| controller.write(data)),
+            // APU channel, status and frame counter registers
+            0x4000..=0x4013 | 0x4015 | 0x4017 => {
+                match address {
+                    0x4010..=0x4013 => self.dmc.write(address, data),
+                    0x4015 => self.dmc.set_enabled(data & 0x10 != 0),
+                    _ => (),
+                }
+                if let Some(ref mut queue) = self.apu_queue {
+                    queue.push(ApuEvent::Write{cycle: self.cycle, address, data});
+                }
+            },
+            _ => (),
+        }
+    }
+
+    // stop execution when an address in 'range' is accessed as described by 'access'
+    pub fn add_watchpoint(&mut self, range: RangeInclusive<u16>, access: WatchAccess) {
+        self.watchpoints.push((range, access));
+    }
+
+    // remove a watchpoint added with the same range and access, returns false if there was none
+    pub fn remove_watchpoint(&mut self, range: RangeInclusive<u16>, access: WatchAccess) -> bool {
+        let count = self.watchpoints.len();
+        self.watchpoints.retain(|watchpoint| *watchpoint != (range.clone(), access));
+        self.watchpoints.len() != count
+    }
+
+    pub fn clear_watchpoints(&mut self) {
+        self.watchpoints.clear();
+    }
+
+    // the pending watchpoint hit, cleared by reading it
+    pub fn take_watchpoint_hit(&mut self) -> Option<WatchpointHit> {
+        self.watchpoint_hit.take()
+    }
+
+    pub fn save_state(&self) -> MemoryState {
+        MemoryState {
+            ram: Packed::pack(&self.ram),
+            battery_ram: self.battery_ram.as_ref().map(|ram| Packed::pack(ram.as_slice())),
+            controllers: self.controllers,
+            open_bus: self.open_bus,
+            dmc: self.dmc,
+            ppu: self.ppu.save_state(),
+            cycle: self.cycle,
+            oam_dma: self.oam_dma,
+            dmc_stall: self.dmc_stall,
+            ppu_writes: self.ppu_writes.clone().unwrap_or_default(),
+            ppu_write_cycle: self.ppu_write_cycle,
+        }
+    }
+
+    // the state must come from a machine running the same cartridge
+    pub fn load_state(&mut self, state: &MemoryState) {
+        state.ram.unpack_into(&mut self.ram);
+        if let (Some(ram), Some(saved)) = (self.battery_ram.as_mut(), state.battery_ram.as_ref()) {
+            saved.unpack_into(ram.as_slice_mut());
+        }
+        self.controllers = state.controllers;
+        self.open_bus = state.open_bus;
+        self.dmc = state.dmc;
+        self.ppu.load_state(&state.ppu);
+        self.cycle = state.cycle;
+        self.oam_dma = state.oam_dma;
+        self.dmc_stall = state.dmc_stall;
+        // whether writes are deferred is a setting of this machine, only the pending writes are restored
+        if let Some(writes) = self.ppu_writes.as_mut() {
+            writes.clone_from(&state.ppu_writes);
+        }
+        self.ppu_write_cycle = state.ppu_write_cycle;
+    }
+
+    fn check_watchpoints(&mut self, address: u16, data: u8, access: WatchAccess) {
+        if self.watchpoint_hit.is_some() {
+            return;
+        }
+        let watched = self.watchpoints.iter().any(|(range, watch)| range.contains(&address) && watch.contains(access));
+        if watched {
+            self.watchpoint_hit = Some(WatchpointHit { address, data, access });
+        }
+    }
+
+    // reset line of the devices on the bus, RAM is left untouched
+    pub fn reset(&mut self) {
+        self.ppu.reset();
+        // silence all APU channels
+        self.write_io(0x4015, 0);
+    }
+
+    // send APU register writes to an audio thread, see 'audio::AudioThread'
+    pub fn attach_apu_queue(&mut self, queue: ApuQueueProducer) {
+        self.apu_queue = Some(queue);
+    }
+
+    pub fn detach_apu_queue(&mut self) -> Option<ApuQueueProducer> {
+        self.apu_queue.take()
+    }
+
+    // let the audio thread know emulation has reached 'cycle'
+    pub fn sync_apu(&mut self, cycle: u64) {
+        if let Some(ref mut queue) = self.apu_queue {
+            queue.push(ApuEvent::Sync{cycle});
+        }
+    }
+
     pub fn from_program(mut program: Vec<u8>) -> Self {
         program.resize(0x10000 - PROGRAM_ROM as usize, 0);
-        let mut program = RAM{file: program.into_boxed_slice()};
-        let ap1 = NonNull::new(&mut program).unwrap();
-        let ap2 = NonNull::new(&mut program).unwrap();
+        // the active program pointers must point into the vector, not at the local value that is moved
+        let mut program = vec![RAM{file: program.into_boxed_slice()}];
+        let ap1 = NonNull::new(&mut program[0]).unwrap();
+        let ap2 = NonNull::new(&mut program[0]).unwrap();
         Memory {
-            program_rom: vec![program],
+            program_rom: program,
             active_program_1: ap1,
             active_program_2: ap2,
             ram: [0u8; (MMIO - BUILTIN_RAM) as usize],
             battery_ram: None,
             mapper: 0,
             ppu: PPU::new(vec![]),
+            controllers: [Controller::new(); 2],
+            apu_queue: None,
+            cycle: 0,
+            open_bus: 0,
+            watchpoints: Vec::new(),
+            watchpoint_hit: None,
+            oam_dma: false,
+            dmc: DmcReader::new(),
+            dmc_stall: 0,
+            ppu_writes: None,
+            ppu_write_cycle: 0,
             _phantom_pin: PhantomPinned
         }
     }
@@ -199,7 +517,8 @@ impl Memory {
             return Err(NesError::FileFormat("incorrect identifying bytes, not a .nes file?"))
         };
 
-        if (header[7] & 0x0c) == 0x08 {eprintln!("Warning: NES2.0 file format unsupported")}
+        let nes2 = (header[7] & 0x0c) == 0x08;
+        if nes2 {eprintln!("Warning: NES2.0 file format unsupported")}
 
         let prg_rom_count = header[4];
         let vrom_count = header[5];
@@ -207,7 +526,17 @@ impl Memory {
         let ram_bank_count = header[8];
 
         let mapper_number = (rom_control[1] & 0xf0) | (rom_control[0] >> 4);
-        let mirroring_type = (rom_control[0] & 1) != 0;
+        let mirroring = match rom_control[0] & 0x09 {
+            0x00 => Mirroring::Horizontal,
+            0x01 => Mirroring::Vertical,
+            _ => Mirroring::FourScreen,
+        };
+        // NES 2.0 has the timing in byte 12, iNES only a rarely set PAL bit in byte 9
+        let region = match (nes2, header[12] & 0x03, header[9] & 1) {
+            (true, 1, _) | (false, _, 1) => Region::Pal,
+            (true, 3, _) => Region::Dendy,
+            _ => Region::Ntsc,
+        };
         let battery_ram = (rom_control[0] & 2) != 0;
         let trainer = (rom_control[0] & 4) != 0;
         if !battery_ram && trainer {panic!("idx what happens in this case");}
@@ -233,7 +562,8 @@ impl Memory {
             program.push(RAM{file: prg_rom_buf})
         }
 
-        for _ in 0..vrom_count {
+        // the header counts CHR ROM in 8KB units, two pattern tables each
+        for _ in 0..vrom_count as usize * 2 {
             let mut vrom_buf = Box::new([0u8; VROM_SIZE as usize]);
             file.read_exact(vrom_buf.as_mut_slice())?;
             vrom.push(RAM{file: vrom_buf})
@@ -247,6 +577,10 @@ impl Memory {
             NonNull::new(&mut program[1]).unwrap()
         };
 
+        let mut ppu = PPU::new(vrom);
+        ppu.set_mirroring(mirroring);
+        ppu.set_region(region);
+
         Ok(Memory{
             program_rom: program,
             active_program_1,
@@ -254,7 +588,18 @@ impl Memory {
             ram: [0u8; (MMIO - BUILTIN_RAM) as usize],
             battery_ram: battery_ram,
             mapper: mapper_number,
-            ppu: PPU::new(vrom),
+            ppu,
+            controllers: [Controller::new(); 2],
+            apu_queue: None,
+            cycle: 0,
+            open_bus: 0,
+            watchpoints: Vec::new(),
+            watchpoint_hit: None,
+            oam_dma: false,
+            dmc: DmcReader::new(),
+            dmc_stall: 0,
+            ppu_writes: None,
+            ppu_write_cycle: 0,
             _phantom_pin: PhantomPinned
         })
 
@@ -264,6 +609,10 @@ impl Memory {
         return &self.program_rom[idx]
     }
 
+    pub fn program_rom_count(&self) -> usize {
+        self.program_rom.len()
+    }
+
 
     fn mmio(&self, address: u16) -> &u8 {
         //TODO
@@ -272,3 +621,54 @@ impl Memory {
     }
 }
 
+impl Bus for Memory {
+    fn read(&mut self, address: u16) -> u8 {
+        Memory::read(self, address)
+    }
+
+    fn write(&mut self, address: u16, data: u8) {
+        Memory::write(self, address, data)
+    }
+
+    fn peek(&self, address: u16) -> u8 {
+        Memory::peek(self, address)
+    }
+
+    fn sync(&mut self, cycle: u64) {
+        self.cycle = cycle;
+        self.clock_dmc();
+    }
+
+    fn take_nmi(&mut self) -> bool {
+        self.ppu.take_nmi()
+    }
+
+    fn take_stall_cycles(&mut self, cycle: u64) -> u32 {
+        Memory::take_stall_cycles(self, cycle)
+    }
+
+    fn dots_until_nmi(&self) -> Option<usize> {
+        self.ppu.dots_until_nmi()
+    }
+
+    fn acknowledge_upcoming_nmi(&mut self) {
+        self.ppu.acknowledge_upcoming_nmi()
+    }
+
+    fn dots_per_cpu_cycle(&self) -> (usize, usize) {
+        self.ppu.region().dots_per_cpu_cycle()
+    }
+
+    fn reset(&mut self) {
+        Memory::reset(self)
+    }
+
+    fn ppu_position(&self) -> Option<(isize, usize)> {
+        Some(self.ppu.position())
+    }
+
+    fn take_watchpoint_hit(&mut self) -> Option<WatchpointHit> {
+        Memory::take_watchpoint_hit(self)
+    }
+}
+
@@ -0,0 +1,513 @@
+use crate::audio::{apu_queue, replay, ApuQueueConsumer, ApuSynth};
+use crate::controller::Buttons;
+use crate::cpu::CPU;
+use crate::frame::{Frame, FrameFormat, FrameSink};
+use crate::memory::NesError;
+use crate::ppu::PpuEvents;
+use crate::state::{RewindBuffer, Snapshot};
+
+/*
+    Frontend interface
+    Every frontend (SDL, pixels, WASM, libretro, the ESP32 display driver, ...) implements these
+    three traits and hands them to 'Nes', which owns the emulation loop. Video goes through the
+    'FrameSink' trait the PPU frame outputs use as well.
+    '()' implements each trait as a no-op for frontends that don't need one of them.
+*/
+
+// Receives audio samples produced during a frame
+pub trait AudioSink {
+    fn queue_samples(&mut self, samples: &[f32]);
+}
+
+// Provides controller state, polled once per frame for each port (0 or 1)
+pub trait InputSource {
+    fn poll(&mut self, port: usize) -> Buttons;
+
+    // Famicom microphone on controller 2, polled once per frame
+    fn microphone(&mut self) -> bool {
+        false
+    }
+}
+
+impl AudioSink for () {
+    fn queue_samples(&mut self, _samples: &[f32]) {}
+}
+
+impl InputSource for () {
+    fn poll(&mut self, _port: usize) -> Buttons {
+        Buttons::empty()
+    }
+}
+
+// APU events between two drains of the queue, a single instruction writes at most a few registers
+const APU_QUEUE_CAPACITY: usize = 64;
+
+// APU events replayed into a synthesizer on the emulation thread, see 'set_synth'
+struct InlineSynth {
+    queue: ApuQueueConsumer,
+    synth: Box<dyn ApuSynth>,
+}
+
+pub struct Nes<V: FrameSink, A: AudioSink, I: InputSource> {
+    pub cpu: CPU,
+    frame: Frame,
+    // samples produced by the APU during the current frame
+    samples: Vec<f32>,
+    synth: Option<InlineSynth>,
+    video: V,
+    audio: A,
+    input: I,
+    // snapshots taken at the start of every N-th frame, None while rewind is disabled
+    rewind: Option<RewindBuffer>,
+    // fraction of a PPU dot carried over to the next instruction, PAL runs 3.2 dots per CPU cycle
+    dot_remainder: usize,
+}
+
+impl<V: FrameSink, A: AudioSink, I: InputSource> Nes<V, A, I> {
+    pub fn new(mut cpu: CPU, video: V, audio: A, input: I) -> Self {
+        cpu.memory.defer_ppu_writes(true);
+        let frame = Frame::with_format(cpu.memory.ppu.frame_format());
+        Nes {
+            cpu,
+            frame,
+            samples: Vec::new(),
+            synth: None,
+            video,
+            audio,
+            input,
+            rewind: None,
+            dot_remainder: 0,
+        }
+    }
+
+    pub fn from_file(path: String, video: V, audio: A, input: I) -> Result<Self, NesError> {
+        Ok(Self::new(CPU::from_file(path)?, video, audio, input))
+    }
+
+    // execute a single instruction and clock the PPU for the elapsed cycles, returns what the PPU did meanwhile
+    pub fn step(&mut self) -> PpuEvents {
+        let start = self.cpu.total_cycles();
+        let cycles = self.cpu.execute(Some(1)) as usize;
+        let mut events = PpuEvents::default();
+        let mut clocked = 0;
+        // PPU register writes take effect once the PPU has caught up to the cycle they were made on
+        if let Some(cycle) = self.cpu.memory.ppu_write_cycle() {
+            clocked = (cycle - start) as usize;
+            events = self.clock_ppu(clocked);
+            self.cpu.memory.apply_ppu_writes();
+        }
+        events = events | self.clock_ppu(cycles - clocked);
+        self.drain_apu();
+        events
+    }
+
+    /*
+        Synthesize audio on the emulation thread, the samples of each frame are handed to the
+        AudioSink at its end. Frontends with an 'AudioThread' attach its queue to the memory instead.
+     */
+    pub fn set_synth<S: ApuSynth + 'static>(&mut self, synth: S) {
+        let (producer, queue) = apu_queue(APU_QUEUE_CAPACITY);
+        self.cpu.memory.attach_apu_queue(producer);
+        self.synth = Some(InlineSynth{queue, synth: Box::new(synth)});
+    }
+
+    fn drain_apu(&mut self) {
+        if let Some(InlineSynth{queue, synth}) = self.synth.as_mut() {
+            while let Some(event) = queue.pop() {
+                replay(synth.as_mut(), event, &mut self.samples);
+            }
+        }
+    }
+
+    fn clock_ppu(&mut self, cycles: usize) -> PpuEvents {
+        let (numerator, denominator) = self.cpu.memory.ppu.region().dots_per_cpu_cycle();
+        let dots = cycles * numerator + self.dot_remainder;
+        self.dot_remainder = dots % denominator;
+        self.cpu.memory.ppu.advance(dots / denominator, &mut self.frame)
+    }
+
+    // poll input, emulate until the PPU completes a frame, then hand the frame and audio to the frontend
+    pub fn run_frame(&mut self) {
+        for port in 0..2 {
+            let buttons = self.input.poll(port);
+            self.cpu.memory.controllers[port].set_buttons(buttons);
+        }
+        let microphone = self.input.microphone();
+        self.cpu.memory.controllers[1].set_microphone(microphone);
+
+        let frame = self.cpu.memory.ppu.frame_count();
+        if self.rewind.as_ref().is_some_and(|rewind| rewind.is_due(frame)) {
+            let snapshot = self.save_state();
+            if let Some(ref mut rewind) = self.rewind {
+                rewind.push(snapshot);
+            }
+        }
+        // a halted CPU would never finish the frame, present what has been rendered instead
+        while self.cpu.memory.ppu.frame_count() == frame && !self.cpu.is_halted() {
+            self.step();
+        }
+        self.cpu.memory.sync_apu(self.cpu.total_cycles());
+        self.drain_apu();
+
+        self.video.frame_ready(&self.frame);
+        if !self.samples.is_empty() {
+            self.audio.queue_samples(&self.samples);
+            self.samples.clear();
+        }
+    }
+
+    pub fn save_state(&self) -> Snapshot {
+        Snapshot {
+            cpu: self.cpu.save_state(),
+            memory: self.cpu.memory.save_state(),
+            dot_remainder: self.dot_remainder,
+        }
+    }
+
+    pub fn load_state(&mut self, snapshot: &Snapshot) {
+        self.cpu.load_state(&snapshot.cpu);
+        self.cpu.memory.load_state(&snapshot.memory);
+        self.dot_remainder = snapshot.dot_remainder;
+    }
+
+    // keep up to 'capacity' snapshots, one every 'interval' frames
+    pub fn enable_rewind(&mut self, interval: u64, capacity: usize) {
+        self.rewind = Some(RewindBuffer::new(interval, capacity));
+    }
+
+    pub fn disable_rewind(&mut self) {
+        self.rewind = None;
+    }
+
+    pub fn rewind_buffer(&self) -> Option<&RewindBuffer> {
+        self.rewind.as_ref()
+    }
+
+    // restore the most recent snapshot, returns false if there is none left
+    // calling it repeatedly steps further back, 'interval' frames at a time
+    pub fn rewind(&mut self) -> bool {
+        match self.rewind.as_mut().and_then(RewindBuffer::pop) {
+            Some(snapshot) => {
+                self.load_state(&snapshot);
+                true
+            }
+            None => false,
+        }
+    }
+
+    // reset button
+    pub fn reset(&mut self) {
+        self.cpu.reset();
+    }
+
+    // pixel layout of the frames handed to the FrameSink, sets the PPU output as well
+    pub fn set_frame_format(&mut self, format: FrameFormat) {
+        self.cpu.memory.ppu.set_frame_format(format);
+        self.frame = Frame::with_format(format);
+    }
+
+    // the most recently completed frame
+    pub fn frame(&self) -> &Frame {
+        &self.frame
+    }
+
+    // hash of the most recently completed frame, see 'frame_hash'
+    pub fn frame_hash(&self) -> u32 {
+        self.frame.hash()
+    }
+
+    pub fn video(&mut self) -> &mut V {
+        &mut self.video
+    }
+
+    pub fn audio(&mut self) -> &mut A {
+        &mut self.audio
+    }
+
+    pub fn input(&mut self) -> &mut I {
+        &mut self.input
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::frame::{FRAME_PIXELS, FRAME_WIDTH};
+    use crate::ppu::{Region, NTSC_PALETTE};
+
+    struct FrameCounter(usize);
+
+    impl FrameSink for FrameCounter {
+        fn frame_ready(&mut self, frame: &Frame) {
+            assert_eq!(frame.pixels().len(), FRAME_PIXELS * frame.format().bytes_per_pixel());
+            self.0 += 1;
+        }
+    }
+
+    struct HoldA;
+
+    impl InputSource for HoldA {
+        fn poll(&mut self, port: usize) -> Buttons {
+            if port == 0 {Buttons::A} else {Buttons::empty()}
+        }
+    }
+
+    #[test]
+    fn test_run_frame() {
+        /*
+            lda #$01
+            sta $4016
+            lda #$00
+            sta $4016
+            lda $4016
+            sta $00
+            lda $4017
+            sta $01
+            jmp $8014
+         */
+        let program = vec![
+            0xa9, 0x01, 0x8d, 0x16, 0x40, 0xa9, 0x00, 0x8d, 0x16, 0x40,
+            0xad, 0x16, 0x40, 0x85, 0x00, 0xad, 0x17, 0x40, 0x85, 0x01,
+            0x4c, 0x14, 0x80,
+        ];
+        let mut nes = Nes::new(CPU::with_program(program), FrameCounter(0), (), HoldA);
+        nes.run_frame();
+        nes.run_frame();
+        assert_eq!(nes.video().0, 2);
+        // the upper bits are open bus
+        assert_eq!(nes.cpu.memory.read(0x00), 0x41);
+        assert_eq!(nes.cpu.memory.read(0x01), 0x40);
+
+        nes.set_frame_format(FrameFormat::Rgb565);
+        nes.run_frame();
+        assert_eq!(nes.video().0, 3);
+        assert_eq!(nes.frame().format(), FrameFormat::Rgb565);
+    }
+
+    #[test]
+    fn test_rewind() {
+        /*
+            inc $00
+            jmp $8000
+         */
+        let program = vec![0xe6, 0x00, 0x4c, 0x00, 0x80];
+        let mut nes = Nes::new(CPU::with_program(program), (), (), ());
+        nes.enable_rewind(2, 3);
+        let mut counters = Vec::new();
+        for _ in 0..8 {
+            counters.push(nes.cpu.memory.peek(0x00));
+            nes.run_frame();
+        }
+        // snapshots of frames 2, 4 and 6, frame 0 was dropped
+        assert_eq!(nes.rewind_buffer().unwrap().len(), 3);
+
+        assert!(nes.rewind());
+        assert_eq!(nes.cpu.memory.ppu.frame_count(), 6);
+        assert_eq!(nes.cpu.memory.peek(0x00), counters[6]);
+        let cycles = nes.cpu.cycle_count;
+        assert!(nes.rewind());
+        assert!(nes.rewind());
+        assert_eq!(nes.cpu.memory.peek(0x00), counters[2]);
+        assert!(!nes.rewind());
+
+        // replaying from a snapshot is deterministic
+        nes.run_frame();
+        nes.run_frame();
+        nes.run_frame();
+        nes.run_frame();
+        assert_eq!(nes.cpu.memory.ppu.frame_count(), 6);
+        assert_eq!(nes.cpu.memory.peek(0x00), counters[6]);
+        assert_eq!(nes.cpu.cycle_count, cycles);
+    }
+
+    #[test]
+    fn test_pal_snapshot() {
+        // jmp $8000
+        let mut nes = Nes::new(CPU::with_program(vec![0x4c, 0x00, 0x80]), (), (), ());
+        nes.cpu.memory.ppu.set_region(Region::Pal);
+        nes.step();
+        let snapshot = nes.save_state();
+        // part of a dot is carried to the next instruction
+        assert_ne!(snapshot.dot_remainder, 0);
+        nes.run_frame();
+        let (hash, remainder) = (nes.frame_hash(), nes.dot_remainder);
+        nes.step();
+        nes.load_state(&snapshot);
+        assert_eq!(nes.dot_remainder, snapshot.dot_remainder);
+        nes.run_frame();
+        assert_eq!(nes.frame_hash(), hash);
+        assert_eq!(nes.dot_remainder, remainder);
+    }
+
+    #[test]
+    fn test_split_scroll() {
+        // a status bar split as in SMB: the scroll changes once sprite 0 hits on line 100
+        let source = "
+            wait1:  bit $2002
+                    bpl wait1
+            wait2:  bit $2002
+                    bpl wait2
+                    ; tile 1 is solid color 1
+                    lda #$00
+                    sta $2006
+                    lda #$10
+                    sta $2006
+                    lda #$ff
+                    ldx #8
+            pattern: sta $2007
+                    dex
+                    bne pattern
+                    ; tile 1 in the even columns of the nametable
+                    lda #$20
+                    sta $2006
+                    lda #$00
+                    sta $2006
+                    ldy #30
+            rows:   ldx #16
+            pairs:  lda #$01
+                    sta $2007
+                    lda #$00
+                    sta $2007
+                    dex
+                    bne pairs
+                    dey
+                    bne rows
+                    lda #$3f
+                    sta $2006
+                    lda #$00
+                    sta $2006
+                    lda #$0f
+                    sta $2007
+                    lda #$30
+                    sta $2007
+                    ; sprite 0 on lines 100-107 at x 100, over a column of tile 1
+                    lda #$00
+                    sta $2003
+                    lda #99
+                    sta $2004
+                    lda #$01
+                    sta $2004
+                    lda #$00
+                    sta $2004
+                    lda #100
+                    sta $2004
+                    lda #$1e
+                    sta $2001
+            frame:  bit $2002
+                    bpl frame
+                    lda #$00
+                    sta $2000
+                    sta $2005
+                    sta $2005
+            clear:  bit $2002
+                    bvs clear
+            hit:    bit $2002
+                    bvc hit
+                    lda #12
+                    sta $2005
+                    lda #$00
+                    sta $2005
+                    jmp frame
+        ";
+        let program = crate::asm::assemble(source, 0x8000).unwrap();
+        let mut nes = Nes::new(CPU::with_program(program), (), (), ());
+        for _ in 0..4 {
+            nes.run_frame();
+        }
+        let color = |x: usize, y: usize| nes.frame().pixel(x, y);
+        let column = |x: usize, shift: usize| if ((x + shift) / 8).is_multiple_of(2) {NTSC_PALETTE[0x30]} else {NTSC_PALETTE[0x0f]};
+        for x in 0..FRAME_WIDTH {
+            assert_eq!(color(x, 50), column(x, 0), "line 50 x {}", x);
+            assert_eq!(color(x, 150), column(x, 12), "line 150 x {}", x);
+        }
+        // fine x takes effect mid line from the dot of the write, which lands on the last cycle of
+        // the STA, and coarse x from the next line
+        let split = (108..FRAME_WIDTH).find(|&x| color(x, 100) != column(x, 0)).unwrap();
+        assert_eq!(split, 149);
+        for x in 0..100 {
+            assert_eq!(color(x, 100), column(x, 0), "line 100 x {}", x);
+        }
+        for x in split..FRAME_WIDTH {
+            assert_eq!(color(x, 100), column(x, 4), "line 100 x {}", x);
+        }
+    }
+
+    #[test]
+    fn test_ppu_warm_up() {
+        /*
+            lda #$21
+            sta $2006
+            sta $2006
+            jmp $8008
+         */
+        let program = vec![0xa9, 0x21, 0x8d, 0x06, 0x20, 0x8d, 0x06, 0x20, 0x4c, 0x08, 0x80];
+        let mut nes = Nes::new(CPU::with_program(program.clone()), (), (), ());
+        for _ in 0..3 {
+            nes.step();
+        }
+        assert_eq!(nes.cpu.memory.ppu.vram_address(), 0);
+        while nes.cpu.memory.ppu.warming_up() {
+            nes.step();
+        }
+        // the end of the first vblank, a frame after power-on
+        assert!((29_780..29_800).contains(&nes.cpu.total_cycles()));
+        nes.cpu.memory.write(0x2006, 0x21);
+        nes.cpu.memory.write(0x2006, 0x08);
+        nes.cpu.memory.apply_ppu_writes();
+        assert_eq!(nes.cpu.memory.ppu.vram_address(), 0x2108);
+
+        let mut nes = Nes::new(CPU::with_program(program), (), (), ());
+        nes.cpu.memory.ppu.set_warm_up(false);
+        for _ in 0..3 {
+            nes.step();
+        }
+        assert_eq!(nes.cpu.memory.ppu.vram_address(), 0x2121);
+    }
+
+    #[test]
+    fn test_inline_synth() {
+        use crate::audio::ApuSynth;
+
+        // one sample per CPU cycle holding the last value written to $4000
+        struct Latch {cycle: u64, value: f32}
+        impl ApuSynth for Latch {
+            fn write_register(&mut self, _address: u16, data: u8) {
+                self.value = data as f32;
+            }
+            fn run_until(&mut self, cycle: u64, output: &mut Vec<f32>) {
+                while self.cycle < cycle {
+                    output.push(self.value);
+                    self.cycle += 1;
+                }
+            }
+        }
+
+        struct Collect(Vec<f32>, usize);
+        impl AudioSink for Collect {
+            fn queue_samples(&mut self, samples: &[f32]) {
+                self.0.extend_from_slice(samples);
+                self.1 += 1;
+            }
+        }
+
+        /*
+            inc $00
+            lda $00
+            sta $4000
+            jmp $8000
+         */
+        let program = vec![0xe6, 0x00, 0xa5, 0x00, 0x8d, 0x00, 0x40, 0x4c, 0x00, 0x80];
+        let mut nes = Nes::new(CPU::with_program(program), (), Collect(Vec::new(), 0), ());
+        nes.set_synth(Latch{cycle: 0, value: 0.0});
+        nes.run_frame();
+        nes.run_frame();
+        let total = nes.cpu.total_cycles() as usize;
+        let audio = nes.audio();
+        // every cycle up to the end of the second frame, handed over once per frame
+        assert_eq!(audio.1, 2);
+        assert_eq!(audio.0.len(), total);
+        // the first write is timestamped with the start of the sta, cycle 7 + 5 + 3
+        assert_eq!(audio.0[..15], [0.0; 15]);
+        assert_eq!(audio.0[15], 1.0);
+    }
+}
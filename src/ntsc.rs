@@ -0,0 +1,202 @@
+/*
+    NTSC composite video filter
+    Turns an Indexed8 frame into RGB the way a TV decodes the NES composite signal, with the
+    color fringing, dot crawl and blurred edges of the real thing. Each pixel is expanded into
+    the 8 samples of square wave the PPU outputs for it, and the signal is decoded again as YIQ,
+    following the signal description on the nesdev wiki (blargg's nes_ntsc works the same way).
+    Much slower than a palette lookup, it is meant for desktop frontends.
+*/
+
+use crate::frame::{FRAME_HEIGHT, FRAME_PIXELS, FRAME_WIDTH};
+
+// the PPU outputs 8 samples per pixel, the color subcarrier takes 12 samples per cycle
+const SAMPLES_PER_PIXEL: usize = 8;
+const PHASES: usize = 12;
+const LINE_SAMPLES: usize = FRAME_WIDTH * SAMPLES_PER_PIXEL;
+// every line starts 341 * 8 samples after the previous one, 4 phases further
+const LINE_PHASE_STEP: usize = 341 * SAMPLES_PER_PIXEL % PHASES;
+
+// output width with about the 8:7 pixel aspect ratio when lines are doubled, the same as nes_ntsc
+pub const NTSC_FRAME_WIDTH: usize = 602;
+
+// signal voltages relative to sync, the low and high level of the square wave for each luma level
+const LEVELS_LOW: [f32; 4] = [0.350, 0.518, 0.962, 1.550];
+const LEVELS_HIGH: [f32; 4] = [1.094, 1.506, 1.962, 1.962];
+const BLACK: f32 = 0.518;
+const WHITE: f32 = 1.962;
+const EMPHASIS_ATTENUATION: f32 = 0.746;
+// phase offset of the decoder in twelfths of a subcarrier cycle, lines the hues up with the standard palettes
+const HUE_OFFSET: f32 = 3.9;
+const GAMMA: f32 = 2.0;
+
+// normalized signal of color 'color' (0-63) with emphasis bits 'emphasis' at subcarrier phase 'phase'
+fn signal(color: usize, emphasis: u8, phase: usize) -> f32 {
+    let hue = color & 0x0f;
+    // hues $E and $F are output at level 1 regardless of the luma bits
+    let level = if hue > 13 {1} else {color >> 4};
+    let (mut low, mut high) = (LEVELS_LOW[level], LEVELS_HIGH[level]);
+    if hue == 0 {
+        low = high;
+    }
+    if hue > 12 {
+        high = low;
+    }
+    let in_phase = |hue: usize| (hue + phase) % PHASES < PHASES / 2;
+    let mut signal = if in_phase(hue) {high} else {low};
+    // the emphasis bits attenuate the part of the wave of the red, green and blue phases
+    if (emphasis & 1 != 0 && in_phase(0)) || (emphasis & 2 != 0 && in_phase(4)) || (emphasis & 4 != 0 && in_phase(8)) {
+        signal *= EMPHASIS_ATTENUATION;
+    }
+    (signal - BLACK) / (WHITE - BLACK)
+}
+
+fn gamma(value: f32) -> u8 {
+    if value <= 0.0 {
+        return 0;
+    }
+    (255.95 * value.powf(2.2 / GAMMA)).min(255.0) as u8
+}
+
+pub struct NtscFilter {
+    // signal level of every color and emphasis combination at each phase
+    levels: Box<[[f32; PHASES]; 64 * 8]>,
+    // decoder carrier for each phase
+    cos: [f32; PHASES],
+    sin: [f32; PHASES],
+    saturation: f32,
+    // one line of signal
+    samples: Box<[f32; LINE_SAMPLES]>,
+}
+
+impl Default for NtscFilter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl NtscFilter {
+    pub fn new() -> Self {
+        let mut levels = Box::new([[0.0; PHASES]; 64 * 8]);
+        for (index, phases) in levels.iter_mut().enumerate() {
+            for (phase, level) in phases.iter_mut().enumerate() {
+                *level = signal(index & 0x3f, (index >> 6) as u8, phase);
+            }
+        }
+        let mut filter = NtscFilter {
+            levels,
+            cos: [0.0; PHASES],
+            sin: [0.0; PHASES],
+            saturation: 1.0,
+            samples: Box::new([0.0; LINE_SAMPLES]),
+        };
+        filter.set_hue(0.0);
+        filter
+    }
+
+    // rotate all hues by 'degrees', like the tint knob of a TV
+    pub fn set_hue(&mut self, degrees: f32) {
+        let offset = HUE_OFFSET + degrees / 360.0 * PHASES as f32;
+        for phase in 0..PHASES {
+            let angle = std::f32::consts::TAU * (phase as f32 + offset) / PHASES as f32;
+            self.cos[phase] = angle.cos();
+            self.sin[phase] = angle.sin();
+        }
+    }
+
+    // 0 for grayscale, 1 for the normal color intensity
+    pub fn set_saturation(&mut self, saturation: f32) {
+        self.saturation = saturation;
+    }
+
+    /*
+        Filter an Indexed8 frame into 'rgb', NTSC_FRAME_WIDTH * FRAME_HEIGHT RGB triples.
+        'emphasis' holds the PPUMASK emphasis bits ('PPU::emphasis'), which Indexed8 frames don't store.
+        The subcarrier phase alternates between frames, pass 'PPU::frame_count' to get the dot crawl
+        of the real thing or a constant for a still picture.
+     */
+    pub fn filter(&mut self, indexed: &[u8], emphasis: u8, frame: u64, rgb: &mut [u8]) {
+        assert_eq!(indexed.len(), FRAME_PIXELS, "not an Indexed8 frame");
+        assert_eq!(rgb.len(), NTSC_FRAME_WIDTH * FRAME_HEIGHT * 3, "incorrectly sized output");
+        // a frame is 4 phases longer than a multiple of the subcarrier, odd frames are a dot shorter
+        let frame_phase = (frame as usize & 1) * LINE_PHASE_STEP;
+        let emphasis = ((emphasis & 0x07) as usize) << 6;
+        for (y, (line, output)) in indexed.chunks_exact(FRAME_WIDTH).zip(rgb.chunks_exact_mut(NTSC_FRAME_WIDTH * 3)).enumerate() {
+            let phase = (frame_phase + y * LINE_PHASE_STEP) % PHASES;
+            for (x, color) in line.iter().enumerate() {
+                let levels = &self.levels[emphasis | (*color & 0x3f) as usize];
+                let start = x * SAMPLES_PER_PIXEL;
+                for (sample, signal) in self.samples[start..start + SAMPLES_PER_PIXEL].iter_mut().enumerate() {
+                    *signal = levels[(phase + start + sample) % PHASES];
+                }
+            }
+            self.decode_line(phase, output);
+        }
+    }
+
+    // average a subcarrier cycle of signal around each output pixel into YIQ and convert to RGB
+    fn decode_line(&self, phase: usize, output: &mut [u8]) {
+        for (x, pixel) in output.chunks_exact_mut(3).enumerate() {
+            // a whole cycle, moved inward at the ends of the line
+            let center = x * LINE_SAMPLES / NTSC_FRAME_WIDTH;
+            let begin = center.saturating_sub(PHASES / 2).min(LINE_SAMPLES - PHASES);
+            let (mut y, mut i, mut q) = (0.0, 0.0, 0.0);
+            for (sample, level) in self.samples[begin..begin + PHASES].iter().enumerate() {
+                let phase = (phase + begin + sample) % PHASES;
+                y += level;
+                i += level * self.cos[phase];
+                q += level * self.sin[phase];
+            }
+            y /= PHASES as f32;
+            i *= self.saturation / PHASES as f32;
+            q *= self.saturation / PHASES as f32;
+            pixel[0] = gamma(y + 0.946882 * i + 0.623557 * q);
+            pixel[1] = gamma(y - 0.274788 * i - 0.635691 * q);
+            pixel[2] = gamma(y - 1.108545 * i + 1.709007 * q);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn filter_color(filter: &mut NtscFilter, color: u8, emphasis: u8) -> Vec<u8> {
+        let indexed = vec![color; FRAME_PIXELS];
+        let mut rgb = vec![0u8; NTSC_FRAME_WIDTH * FRAME_HEIGHT * 3];
+        filter.filter(&indexed, emphasis, 0, &mut rgb);
+        rgb
+    }
+
+    // pixel away from the edges of the line
+    fn center(rgb: &[u8]) -> [u8; 3] {
+        let start = (120 * NTSC_FRAME_WIDTH + NTSC_FRAME_WIDTH / 2) * 3;
+        rgb[start..start + 3].try_into().unwrap()
+    }
+
+    #[test]
+    fn test_ntsc_filter() {
+        let mut filter = NtscFilter::new();
+        assert_eq!(center(&filter_color(&mut filter, 0x0f, 0)), [0, 0, 0]);
+        assert_eq!(center(&filter_color(&mut filter, 0x30, 0)), [255, 255, 255]);
+        // colors without chroma are an even gray
+        let gray = filter_color(&mut filter, 0x00, 0);
+        assert!(gray.chunks(3).all(|pixel| pixel == center(&gray)));
+        let [r, g, b] = center(&gray);
+        assert!(r == g && g == b && r > 0x40 && r < 0xa0);
+
+        let [r, g, b] = center(&filter_color(&mut filter, 0x16, 0));
+        assert!(r > g + 0x40 && r > b + 0x40, "red {:?}", [r, g, b]);
+        let [r, g, b] = center(&filter_color(&mut filter, 0x1a, 0));
+        assert!(g > r + 0x20 && g > b + 0x20, "green {:?}", [r, g, b]);
+        let [r, g, b] = center(&filter_color(&mut filter, 0x12, 0));
+        assert!(b > r + 0x40 && b > g + 0x20, "blue {:?}", [r, g, b]);
+
+        // red emphasis darkens white
+        let [r, g, b] = center(&filter_color(&mut filter, 0x30, 0x01));
+        assert!(r > g && r > b && b < 0xf0, "emphasis {:?}", [r, g, b]);
+
+        filter.set_saturation(0.0);
+        let [r, g, b] = center(&filter_color(&mut filter, 0x16, 0));
+        assert!(r == g && g == b);
+    }
+}
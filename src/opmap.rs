@@ -1,7 +1,11 @@
+use crate::bus::Bus;
 use crate::cpu::CPU;
 
-pub const OP_MAP: [fn(&mut CPU) -> (); 256] = {
-    let mut map = [CPU::noop as fn(&mut CPU); 256];
+pub const OP_MAP: [fn(&mut CPU) -> (); 256] = op_map();
+
+// opcode handlers for a CPU on bus 'B'
+pub const fn op_map<B: Bus>() -> [fn(&mut CPU<B>) -> (); 256] {
+    let mut map = [CPU::unimplemented as fn(&mut CPU<B>); 256];
 
     //'or' instructions
     map[0x09] = CPU::or_immediate;
@@ -204,11 +208,126 @@ pub const OP_MAP: [fn(&mut CPU) -> (); 256] = {
     // 'No Operation' instruction
     map[0xEA] = CPU::noop;
 
+    // unofficial 'LAX' instructions
+    map[0xaf] = CPU::lax_absolute;
+    map[0xbf] = CPU::lax_absolute_y;
+    map[0xa7] = CPU::lax_zero_page;
+    map[0xb7] = CPU::lax_zero_page_y;
+    map[0xa3] = CPU::lax_zero_page_x_indirect;
+    map[0xb3] = CPU::lax_zero_page_y_indirect;
+
+    // unofficial 'SAX' instructions
+    map[0x8f] = CPU::sax_absolute;
+    map[0x87] = CPU::sax_zero_page;
+    map[0x97] = CPU::sax_zero_page_y;
+    map[0x83] = CPU::sax_zero_page_x_indirect;
+
+    // unofficial 'SLO' instructions
+    map[0x07] = CPU::slo_zero_page;
+    map[0x17] = CPU::slo_zero_page_x;
+    map[0x0f] = CPU::slo_absolute;
+    map[0x1f] = CPU::slo_absolute_x;
+    map[0x1b] = CPU::slo_absolute_y;
+    map[0x03] = CPU::slo_zero_page_x_indirect;
+    map[0x13] = CPU::slo_zero_page_y_indirect;
+
+    // unofficial 'RLA' instructions
+    map[0x27] = CPU::rla_zero_page;
+    map[0x37] = CPU::rla_zero_page_x;
+    map[0x2f] = CPU::rla_absolute;
+    map[0x3f] = CPU::rla_absolute_x;
+    map[0x3b] = CPU::rla_absolute_y;
+    map[0x23] = CPU::rla_zero_page_x_indirect;
+    map[0x33] = CPU::rla_zero_page_y_indirect;
+
+    // unofficial 'SRE' instructions
+    map[0x47] = CPU::sre_zero_page;
+    map[0x57] = CPU::sre_zero_page_x;
+    map[0x4f] = CPU::sre_absolute;
+    map[0x5f] = CPU::sre_absolute_x;
+    map[0x5b] = CPU::sre_absolute_y;
+    map[0x43] = CPU::sre_zero_page_x_indirect;
+    map[0x53] = CPU::sre_zero_page_y_indirect;
+
+    // unofficial 'RRA' instructions
+    map[0x67] = CPU::rra_zero_page;
+    map[0x77] = CPU::rra_zero_page_x;
+    map[0x6f] = CPU::rra_absolute;
+    map[0x7f] = CPU::rra_absolute_x;
+    map[0x7b] = CPU::rra_absolute_y;
+    map[0x63] = CPU::rra_zero_page_x_indirect;
+    map[0x73] = CPU::rra_zero_page_y_indirect;
+
+    // unofficial 'DCP' instructions
+    map[0xc7] = CPU::dcp_zero_page;
+    map[0xd7] = CPU::dcp_zero_page_x;
+    map[0xcf] = CPU::dcp_absolute;
+    map[0xdf] = CPU::dcp_absolute_x;
+    map[0xdb] = CPU::dcp_absolute_y;
+    map[0xc3] = CPU::dcp_zero_page_x_indirect;
+    map[0xd3] = CPU::dcp_zero_page_y_indirect;
+
+    // unofficial 'ISC' instructions
+    map[0xe7] = CPU::isc_zero_page;
+    map[0xf7] = CPU::isc_zero_page_x;
+    map[0xef] = CPU::isc_absolute;
+    map[0xff] = CPU::isc_absolute_x;
+    map[0xfb] = CPU::isc_absolute_y;
+    map[0xe3] = CPU::isc_zero_page_x_indirect;
+    map[0xf3] = CPU::isc_zero_page_y_indirect;
+
+    // unofficial 'SBC' duplicate
+    map[0xeb] = CPU::sbc_immediate;
+
+    // unofficial 'No Operation' instructions
+    map[0x1a] = CPU::noop;
+    map[0x3a] = CPU::noop;
+    map[0x5a] = CPU::noop;
+    map[0x7a] = CPU::noop;
+    map[0xda] = CPU::noop;
+    map[0xfa] = CPU::noop;
+    map[0x80] = CPU::nop_immediate;
+    map[0x82] = CPU::nop_immediate;
+    map[0x89] = CPU::nop_immediate;
+    map[0xc2] = CPU::nop_immediate;
+    map[0xe2] = CPU::nop_immediate;
+    map[0x04] = CPU::nop_zero_page;
+    map[0x44] = CPU::nop_zero_page;
+    map[0x64] = CPU::nop_zero_page;
+    map[0x14] = CPU::nop_zero_page_x;
+    map[0x34] = CPU::nop_zero_page_x;
+    map[0x54] = CPU::nop_zero_page_x;
+    map[0x74] = CPU::nop_zero_page_x;
+    map[0xd4] = CPU::nop_zero_page_x;
+    map[0xf4] = CPU::nop_zero_page_x;
+    map[0x0c] = CPU::nop_absolute;
+    map[0x1c] = CPU::nop_absolute_x;
+    map[0x3c] = CPU::nop_absolute_x;
+    map[0x5c] = CPU::nop_absolute_x;
+    map[0x7c] = CPU::nop_absolute_x;
+    map[0xdc] = CPU::nop_absolute_x;
+    map[0xfc] = CPU::nop_absolute_x;
+
+    // unofficial 'KIL' instructions, these halt the CPU
+    map[0x02] = CPU::kill;
+    map[0x12] = CPU::kill;
+    map[0x22] = CPU::kill;
+    map[0x32] = CPU::kill;
+    map[0x42] = CPU::kill;
+    map[0x52] = CPU::kill;
+    map[0x62] = CPU::kill;
+    map[0x72] = CPU::kill;
+    map[0x92] = CPU::kill;
+    map[0xb2] = CPU::kill;
+    map[0xd2] = CPU::kill;
+    map[0xf2] = CPU::kill;
+
     map
-};
+}
 
 
-pub const OP_NAME_MAP: [&'static str; 256] = {
+// handler names, as printed in the nestest logs
+const OP_NAME_MAP: [&str; 256] = {
     let mut map = ["! INVALID !"; 256];
     macro_rules! gen_op_names {
         // this block matches twice as fast, which is useful for staying below recursion limit
@@ -240,7 +359,7 @@ pub const OP_NAME_MAP: [&'static str; 256] = {
     map[0x49] = CPU::exclusive_or_immediate;
     map[0x4D] = CPU::exclusive_or_absolute;
     map[0x5D] = CPU::exclusive_or_absolute_x;
-    map[0x6D] = CPU::exclusive_or_absolute_y;
+    map[0x59] = CPU::exclusive_or_absolute_y;
     map[0x45] = CPU::exclusive_or_zero_page;
     map[0x55] = CPU::exclusive_or_zero_page_x;
     map[0x41] = CPU::exclusive_or_zero_page_x_indirect;
@@ -426,7 +545,305 @@ pub const OP_NAME_MAP: [&'static str; 256] = {
 
     // 'No Operation' instruction
     map[0xEA] = CPU::noop;
+
+    // unofficial 'LAX' instructions
+    map[0xaf] = CPU::lax_absolute;
+    map[0xbf] = CPU::lax_absolute_y;
+    map[0xa7] = CPU::lax_zero_page;
+    map[0xb7] = CPU::lax_zero_page_y;
+    map[0xa3] = CPU::lax_zero_page_x_indirect;
+    map[0xb3] = CPU::lax_zero_page_y_indirect;
+
+    // unofficial 'SAX' instructions
+    map[0x8f] = CPU::sax_absolute;
+    map[0x87] = CPU::sax_zero_page;
+    map[0x97] = CPU::sax_zero_page_y;
+    map[0x83] = CPU::sax_zero_page_x_indirect;
+
+    // unofficial 'SLO' instructions
+    map[0x07] = CPU::slo_zero_page;
+    map[0x17] = CPU::slo_zero_page_x;
+    map[0x0f] = CPU::slo_absolute;
+    map[0x1f] = CPU::slo_absolute_x;
+    map[0x1b] = CPU::slo_absolute_y;
+    map[0x03] = CPU::slo_zero_page_x_indirect;
+    map[0x13] = CPU::slo_zero_page_y_indirect;
+
+    // unofficial 'RLA' instructions
+    map[0x27] = CPU::rla_zero_page;
+    map[0x37] = CPU::rla_zero_page_x;
+    map[0x2f] = CPU::rla_absolute;
+    map[0x3f] = CPU::rla_absolute_x;
+    map[0x3b] = CPU::rla_absolute_y;
+    map[0x23] = CPU::rla_zero_page_x_indirect;
+    map[0x33] = CPU::rla_zero_page_y_indirect;
+
+    // unofficial 'SRE' instructions
+    map[0x47] = CPU::sre_zero_page;
+    map[0x57] = CPU::sre_zero_page_x;
+    map[0x4f] = CPU::sre_absolute;
+    map[0x5f] = CPU::sre_absolute_x;
+    map[0x5b] = CPU::sre_absolute_y;
+    map[0x43] = CPU::sre_zero_page_x_indirect;
+    map[0x53] = CPU::sre_zero_page_y_indirect;
+
+    // unofficial 'RRA' instructions
+    map[0x67] = CPU::rra_zero_page;
+    map[0x77] = CPU::rra_zero_page_x;
+    map[0x6f] = CPU::rra_absolute;
+    map[0x7f] = CPU::rra_absolute_x;
+    map[0x7b] = CPU::rra_absolute_y;
+    map[0x63] = CPU::rra_zero_page_x_indirect;
+    map[0x73] = CPU::rra_zero_page_y_indirect;
+
+    // unofficial 'DCP' instructions
+    map[0xc7] = CPU::dcp_zero_page;
+    map[0xd7] = CPU::dcp_zero_page_x;
+    map[0xcf] = CPU::dcp_absolute;
+    map[0xdf] = CPU::dcp_absolute_x;
+    map[0xdb] = CPU::dcp_absolute_y;
+    map[0xc3] = CPU::dcp_zero_page_x_indirect;
+    map[0xd3] = CPU::dcp_zero_page_y_indirect;
+
+    // unofficial 'ISC' instructions
+    map[0xe7] = CPU::isc_zero_page;
+    map[0xf7] = CPU::isc_zero_page_x;
+    map[0xef] = CPU::isc_absolute;
+    map[0xff] = CPU::isc_absolute_x;
+    map[0xfb] = CPU::isc_absolute_y;
+    map[0xe3] = CPU::isc_zero_page_x_indirect;
+    map[0xf3] = CPU::isc_zero_page_y_indirect;
+
+    // unofficial 'SBC' duplicate
+    map[0xeb] = CPU::sbc_immediate;
+
+    // unofficial 'No Operation' instructions
+    map[0x1a] = CPU::noop;
+    map[0x3a] = CPU::noop;
+    map[0x5a] = CPU::noop;
+    map[0x7a] = CPU::noop;
+    map[0xda] = CPU::noop;
+    map[0xfa] = CPU::noop;
+    map[0x80] = CPU::nop_immediate;
+    map[0x82] = CPU::nop_immediate;
+    map[0x89] = CPU::nop_immediate;
+    map[0xc2] = CPU::nop_immediate;
+    map[0xe2] = CPU::nop_immediate;
+    map[0x04] = CPU::nop_zero_page;
+    map[0x44] = CPU::nop_zero_page;
+    map[0x64] = CPU::nop_zero_page;
+    map[0x14] = CPU::nop_zero_page_x;
+    map[0x34] = CPU::nop_zero_page_x;
+    map[0x54] = CPU::nop_zero_page_x;
+    map[0x74] = CPU::nop_zero_page_x;
+    map[0xd4] = CPU::nop_zero_page_x;
+    map[0xf4] = CPU::nop_zero_page_x;
+    map[0x0c] = CPU::nop_absolute;
+    map[0x1c] = CPU::nop_absolute_x;
+    map[0x3c] = CPU::nop_absolute_x;
+    map[0x5c] = CPU::nop_absolute_x;
+    map[0x7c] = CPU::nop_absolute_x;
+    map[0xdc] = CPU::nop_absolute_x;
+    map[0xfc] = CPU::nop_absolute_x;
+
+    // unofficial 'KIL' instructions, these halt the CPU
+    map[0x02] = CPU::kill;
+    map[0x12] = CPU::kill;
+    map[0x22] = CPU::kill;
+    map[0x32] = CPU::kill;
+    map[0x42] = CPU::kill;
+    map[0x52] = CPU::kill;
+    map[0x62] = CPU::kill;
+    map[0x72] = CPU::kill;
+    map[0x92] = CPU::kill;
+    map[0xb2] = CPU::kill;
+    map[0xd2] = CPU::kill;
+    map[0xf2] = CPU::kill;
     );
 
     map
-};
\ No newline at end of file
+};
+// operand addressing of an opcode, used to decode instructions without executing them
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AddressingMode {
+    Implied,
+    Accumulator,
+    Immediate,
+    ZeroPage,
+    ZeroPageX,
+    ZeroPageY,
+    Absolute,
+    AbsoluteX,
+    AbsoluteY,
+    Indirect,
+    ZeroPageXIndirect,
+    ZeroPageYIndirect,
+    Relative,
+}
+
+impl AddressingMode {
+    // number of operand bytes following the opcode
+    pub const fn operand_count(self) -> usize {
+        match self {
+            AddressingMode::Implied | AddressingMode::Accumulator => 0,
+            AddressingMode::Absolute | AddressingMode::AbsoluteX | AddressingMode::AbsoluteY
+                | AddressingMode::Indirect => 2,
+            _ => 1,
+        }
+    }
+}
+
+/*
+    Opcodes are laid out as aaabbbcc, 'bbb' selects the addressing mode within each 'cc' group.
+    The exceptions are the control instructions in the cc=00 group and the X register
+    instructions (STX, LDX, SAX, LAX, ...) which index with Y instead of X.
+*/
+const OP_MODE_MAP: [AddressingMode; 256] = {
+    let mut map = [AddressingMode::Implied; 256];
+    let mut opcode = 0;
+    while opcode < 256 {
+        let a = opcode >> 5;
+        let b = (opcode >> 2) & 0b111;
+        let c = opcode & 0b11;
+        // STX/LDX/SAX/LAX/SHX/AHX index with Y
+        let index_y = c >= 2 && (a == 4 || a == 5);
+        map[opcode] = match (c, b) {
+            (_, 1) => AddressingMode::ZeroPage,
+            (0, 0) if opcode == 0x20 => AddressingMode::Absolute,
+            (0, 0) | (2, 0) if a < 4 => AddressingMode::Implied,
+            (0, 0) | (2, 0) => AddressingMode::Immediate,
+            (0, 2) | (0, 6) | (2, 4) | (2, 6) => AddressingMode::Implied,
+            (2, 2) if a < 4 => AddressingMode::Accumulator,
+            (2, 2) => AddressingMode::Implied,
+            (0, 3) if opcode == 0x6c => AddressingMode::Indirect,
+            (_, 3) => AddressingMode::Absolute,
+            (0, 4) => AddressingMode::Relative,
+            (_, 5) if index_y => AddressingMode::ZeroPageY,
+            (_, 5) => AddressingMode::ZeroPageX,
+            (_, 7) if index_y => AddressingMode::AbsoluteY,
+            (_, 7) => AddressingMode::AbsoluteX,
+            (_, 0) => AddressingMode::ZeroPageXIndirect,
+            (_, 2) => AddressingMode::Immediate,
+            (_, 4) => AddressingMode::ZeroPageYIndirect,
+            _ => AddressingMode::AbsoluteY,
+        };
+        opcode += 1;
+    }
+    map
+};
+
+// assembler mnemonic of each opcode, unofficial opcodes use their common names
+const OP_MNEMONIC_MAP: [&str; 256] = [
+    "BRK", "ORA", "KIL", "SLO", "NOP", "ORA", "ASL", "SLO", "PHP", "ORA", "ASL", "ANC", "NOP", "ORA", "ASL", "SLO",
+    "BPL", "ORA", "KIL", "SLO", "NOP", "ORA", "ASL", "SLO", "CLC", "ORA", "NOP", "SLO", "NOP", "ORA", "ASL", "SLO",
+    "JSR", "AND", "KIL", "RLA", "BIT", "AND", "ROL", "RLA", "PLP", "AND", "ROL", "ANC", "BIT", "AND", "ROL", "RLA",
+    "BMI", "AND", "KIL", "RLA", "NOP", "AND", "ROL", "RLA", "SEC", "AND", "NOP", "RLA", "NOP", "AND", "ROL", "RLA",
+    "RTI", "EOR", "KIL", "SRE", "NOP", "EOR", "LSR", "SRE", "PHA", "EOR", "LSR", "ALR", "JMP", "EOR", "LSR", "SRE",
+    "BVC", "EOR", "KIL", "SRE", "NOP", "EOR", "LSR", "SRE", "CLI", "EOR", "NOP", "SRE", "NOP", "EOR", "LSR", "SRE",
+    "RTS", "ADC", "KIL", "RRA", "NOP", "ADC", "ROR", "RRA", "PLA", "ADC", "ROR", "ARR", "JMP", "ADC", "ROR", "RRA",
+    "BVS", "ADC", "KIL", "RRA", "NOP", "ADC", "ROR", "RRA", "SEI", "ADC", "NOP", "RRA", "NOP", "ADC", "ROR", "RRA",
+    "NOP", "STA", "NOP", "SAX", "STY", "STA", "STX", "SAX", "DEY", "NOP", "TXA", "XAA", "STY", "STA", "STX", "SAX",
+    "BCC", "STA", "KIL", "AHX", "STY", "STA", "STX", "SAX", "TYA", "STA", "TXS", "TAS", "SHY", "STA", "SHX", "AHX",
+    "LDY", "LDA", "LDX", "LAX", "LDY", "LDA", "LDX", "LAX", "TAY", "LDA", "TAX", "LAX", "LDY", "LDA", "LDX", "LAX",
+    "BCS", "LDA", "KIL", "LAX", "LDY", "LDA", "LDX", "LAX", "CLV", "LDA", "TSX", "LAS", "LDY", "LDA", "LDX", "LAX",
+    "CPY", "CMP", "NOP", "DCP", "CPY", "CMP", "DEC", "DCP", "INY", "CMP", "DEX", "AXS", "CPY", "CMP", "DEC", "DCP",
+    "BNE", "CMP", "KIL", "DCP", "NOP", "CMP", "DEC", "DCP", "CLD", "CMP", "NOP", "DCP", "NOP", "CMP", "DEC", "DCP",
+    "CPX", "SBC", "NOP", "ISC", "CPX", "SBC", "INC", "ISC", "INX", "SBC", "NOP", "SBC", "CPX", "SBC", "INC", "ISC",
+    "BEQ", "SBC", "KIL", "ISC", "NOP", "SBC", "INC", "ISC", "SED", "SBC", "NOP", "ISC", "NOP", "SBC", "INC", "ISC",
+];
+
+// cycles taken without page cross or branch penalties, KIL never completes
+const OP_CYCLE_MAP: [u8; 256] = [
+    7, 6, 0, 8, 3, 3, 5, 5, 3, 2, 2, 2, 4, 4, 6, 6,
+    2, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+    6, 6, 0, 8, 3, 3, 5, 5, 4, 2, 2, 2, 4, 4, 6, 6,
+    2, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+    6, 6, 0, 8, 3, 3, 5, 5, 3, 2, 2, 2, 3, 4, 6, 6,
+    2, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+    6, 6, 0, 8, 3, 3, 5, 5, 4, 2, 2, 2, 5, 4, 6, 6,
+    2, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+    2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4,
+    2, 6, 0, 6, 4, 4, 4, 4, 2, 5, 2, 5, 5, 5, 5, 5,
+    2, 6, 2, 6, 3, 3, 3, 3, 2, 2, 2, 2, 4, 4, 4, 4,
+    2, 5, 0, 5, 4, 4, 4, 4, 2, 4, 2, 4, 4, 4, 4, 4,
+    2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6,
+    2, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+    2, 6, 2, 8, 3, 3, 5, 5, 2, 2, 2, 2, 4, 4, 6, 6,
+    2, 5, 0, 8, 4, 4, 6, 6, 2, 4, 2, 7, 4, 4, 7, 7,
+];
+
+const UNOFFICIAL_MNEMONICS: [&str; 19] = [
+    "KIL", "SLO", "RLA", "SRE", "RRA", "SAX", "LAX", "DCP", "ISC", "ANC",
+    "ALR", "ARR", "XAA", "AXS", "AHX", "SHY", "SHX", "TAS", "LAS",
+];
+
+const fn str_eq(a: &str, b: &str) -> bool {
+    let (a, b) = (a.as_bytes(), b.as_bytes());
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut i = 0;
+    while i < a.len() {
+        if a[i] != b[i] {
+            return false;
+        }
+        i += 1;
+    }
+    true
+}
+
+// the 151 documented opcodes, EA is the only official NOP and E9 the only official SBC
+const fn is_official(opcode: usize) -> bool {
+    let mnemonic = OP_MNEMONIC_MAP[opcode];
+    let mut i = 0;
+    while i < UNOFFICIAL_MNEMONICS.len() {
+        if str_eq(mnemonic, UNOFFICIAL_MNEMONICS[i]) {
+            return false;
+        }
+        i += 1;
+    }
+    !(str_eq(mnemonic, "NOP") && opcode != 0xea) && opcode != 0xeb
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpInfo {
+    pub mnemonic: &'static str,
+    pub mode: AddressingMode,
+    // opcode and operand bytes
+    pub length: u8,
+    pub cycles: u8,
+    pub official: bool,
+    // name of the handler in OP_MAP, "! INVALID !" if the opcode isn't emulated
+    pub name: &'static str,
+}
+
+impl OpInfo {
+    pub const fn is_implemented(&self) -> bool {
+        !str_eq(self.name, "! INVALID !")
+    }
+}
+
+// everything known about each opcode, the single source for decoding, disassembly and tracing
+pub const OPCODE_INFO: [OpInfo; 256] = {
+    let mut map = [OpInfo {
+        mnemonic: "",
+        mode: AddressingMode::Implied,
+        length: 1,
+        cycles: 0,
+        official: false,
+        name: "",
+    }; 256];
+    let mut opcode = 0;
+    while opcode < 256 {
+        map[opcode] = OpInfo {
+            mnemonic: OP_MNEMONIC_MAP[opcode],
+            mode: OP_MODE_MAP[opcode],
+            length: 1 + OP_MODE_MAP[opcode].operand_count() as u8,
+            cycles: OP_CYCLE_MAP[opcode],
+            official: is_official(opcode),
+            name: OP_NAME_MAP[opcode],
+        };
+        opcode += 1;
+    }
+    map
+};
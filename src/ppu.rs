@@ -1,17 +1,84 @@
 
 use crate::memory::{NesError, MMIO, RAM};
+use crate::frame::{rgb565, FrameBuffer, FrameFormat};
+use crate::state::Packed;
 use bitflags::{bitflags, Flags};
+use std::path::Path;
 use std::u8;
 #[cfg(feature = "image")]
-use image::{GrayImage, RgbImage};
+use image::RgbImage;
 
 const VRAM_SIZE: u16 = 16 * (1 << 10);
 const SPRAM_SIZE: u16 = 1 << 8;
 const PATTERN_TABLE_SIZE: usize = 1 << 12;
-const NAME_TABLE_SIZE: usize = 8 * 8 + 64;
-const PALETTE: [[u8; 3]; 64] = [[0; 3]; 64];
-const FRAME_WIDTH: usize = 256;
-const FRAME_HEIGHT: usize = 240;
+const PALETTE_RAM_SIZE: usize = 32;
+const SPRITE_COUNT: usize = 64;
+const SPRITES_PER_LINE: usize = 8;
+const LINE_WIDTH: usize = 256;
+// sprite line buffer entries hold the palette index (0x10-0x1f, 0 if transparent) and these flags
+const SPRITE_BEHIND_BACKGROUND: u8 = 0x20;
+const SPRITE_ZERO: u8 = 0x40;
+
+// RGB output of the 64 colors of the NTSC 2C02 PPU, 'PPU::load_palette' replaces it
+pub const NTSC_PALETTE: [[u8; 3]; 64] = [
+    [0x54, 0x54, 0x54], [0x00, 0x1e, 0x74], [0x08, 0x10, 0x90], [0x30, 0x00, 0x88],
+    [0x44, 0x00, 0x64], [0x5c, 0x00, 0x30], [0x54, 0x04, 0x00], [0x3c, 0x18, 0x00],
+    [0x20, 0x2a, 0x00], [0x08, 0x3a, 0x00], [0x00, 0x40, 0x00], [0x00, 0x3c, 0x00],
+    [0x00, 0x32, 0x3c], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00],
+    [0x98, 0x96, 0x98], [0x08, 0x4c, 0xc4], [0x30, 0x32, 0xec], [0x5c, 0x1e, 0xe4],
+    [0x88, 0x14, 0xb0], [0xa0, 0x14, 0x64], [0x98, 0x22, 0x20], [0x78, 0x3c, 0x00],
+    [0x54, 0x5a, 0x00], [0x28, 0x72, 0x00], [0x08, 0x7c, 0x00], [0x00, 0x76, 0x28],
+    [0x00, 0x66, 0x78], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00],
+    [0xec, 0xee, 0xec], [0x4c, 0x9a, 0xec], [0x78, 0x7c, 0xec], [0xb0, 0x62, 0xec],
+    [0xe4, 0x54, 0xec], [0xec, 0x58, 0xb4], [0xec, 0x6a, 0x64], [0xd4, 0x88, 0x20],
+    [0xa0, 0xaa, 0x00], [0x74, 0xc4, 0x00], [0x4c, 0xd0, 0x20], [0x38, 0xcc, 0x6c],
+    [0x38, 0xb4, 0xcc], [0x3c, 0x3c, 0x3c], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00],
+    [0xec, 0xee, 0xec], [0xa8, 0xcc, 0xec], [0xbc, 0xbc, 0xec], [0xd4, 0xb2, 0xec],
+    [0xec, 0xae, 0xec], [0xec, 0xae, 0xd4], [0xec, 0xb4, 0xb0], [0xe4, 0xc4, 0x90],
+    [0xcc, 0xd2, 0x78], [0xb4, 0xde, 0x78], [0xa8, 0xe2, 0x90], [0x98, 0xe2, 0xb4],
+    [0xa0, 0xd6, 0xe4], [0xa0, 0xa2, 0xa0], [0x00, 0x00, 0x00], [0x00, 0x00, 0x00],
+];
+// size of the 'render_nametables' image, the four nametables in a 2x2 grid
+pub const NAMETABLE_VIEW_WIDTH: usize = 2 * 256;
+pub const NAMETABLE_VIEW_HEIGHT: usize = 2 * 240;
+// size of the 'render_pattern_table' image, 16x16 tiles
+pub const PATTERN_VIEW_SIZE: usize = 16 * 8;
+// size of the 'render_palette' image, a row of 16 swatches for the background and one for sprites
+pub const PALETTE_SWATCH_SIZE: usize = 8;
+pub const PALETTE_VIEW_WIDTH: usize = 16 * PALETTE_SWATCH_SIZE;
+pub const PALETTE_VIEW_HEIGHT: usize = 2 * PALETTE_SWATCH_SIZE;
+// size of the 'render_oam' image, 8 rows of 8 sprites with room for 8x16 sprites
+pub const OAM_VIEW_WIDTH: usize = 8 * 8;
+pub const OAM_VIEW_HEIGHT: usize = 8 * 16;
+const SCROLL_OVERLAY_COLOR: [u8; 3] = [0xff, 0x00, 0xff];
+// frames until undriven open bus bits read as 0, about 600ms
+const OPEN_BUS_DECAY_FRAMES: u64 = 36;
+// size of a .pal file, 3 bytes for each color
+pub const PALETTE_FILE_SIZE: usize = 64 * 3;
+// each PPUMASK emphasis bit darkens the other two color channels to about 82%
+const EMPHASIS_ATTENUATION: f32 = 0.816328;
+
+// the palette for each combination of the emphasis bits, bit 0 is red, bit 1 green and bit 2 blue
+// as returned by 'PPU::emphasis', which undoes the PAL order of the PPUMASK bits
+fn emphasis_palettes(palette: &[[u8; 3]; 64]) -> [[[u8; 3]; 64]; 8] {
+    let mut palettes = [*palette; 8];
+    for (emphasis, palette) in palettes.iter_mut().enumerate().skip(1) {
+        for color in palette.iter_mut() {
+            for (channel, value) in color.iter_mut().enumerate() {
+                let attenuated = (0..3).filter(|&bit| bit != channel && emphasis & (1 << bit) != 0).count();
+                *value = (*value as f32 * EMPHASIS_ATTENUATION.powi(attenuated as i32)).round() as u8;
+            }
+        }
+    }
+    palettes
+}
+
+// $3F00-$3FFF mirror the 32 palette entries, and $3F10/$3F14/$3F18/$3F1C mirror $3F00/$3F04/$3F08/$3F0C
+#[inline]
+fn palette_index(address: u16) -> usize {
+    let index = address as usize & 0x1f;
+    if index & 0x13 == 0x10 {index & 0x0f} else {index}
+}
 
 struct PatternTable<'a> {
     data: &'a [u8; 16],
@@ -27,40 +94,28 @@ impl PatternTable<'_> {
         let high_bit = (self.data[i + 8] >> (7 - j)) & 1;
         low_bit | ( high_bit <<  1 )
     }
+}
 
-    // writes pixels where pixels[0][0] is the upper left and pixels[15][15] is bottom right
-    // *NOTE: scales pixel value for a greyscale image
-    fn write_greyscale_pixels(&self, pixels: &mut[[u8; 8]]) {
-        for i in 0..8 {
-            for j in 0..8 {
-                pixels[i][j] = self.get_pixel((i,j)) << 7;
-            }
-        }
+#[cfg(feature = "image")]
+impl PPU {
+    pub fn nametable_image(&self, scroll_overlay: bool) -> RgbImage {
+        RgbImage::from_vec(NAMETABLE_VIEW_WIDTH as u32, NAMETABLE_VIEW_HEIGHT as u32, self.render_nametables(scroll_overlay)).unwrap()
     }
 
-    fn write_rgb_row(&self, pixels: &mut[u8], row: usize, upper_bits: u8) {
-        let mut pixels_view = pixels.chunks_mut(3);
-        for j in 0..8 {
-            pixels_view.next().unwrap().copy_from_slice(&PALETTE[(upper_bits | self.get_pixel((row,j))) as usize]);
-        }
+    pub fn pattern_table_image(&self, table: usize, palette: u8) -> RgbImage {
+        RgbImage::from_vec(PATTERN_VIEW_SIZE as u32, PATTERN_VIEW_SIZE as u32, self.render_pattern_table(table, palette)).unwrap()
     }
 
-}
+    pub fn pattern_table_palettes_image(&self, table: usize) -> RgbImage {
+        RgbImage::from_vec(8 * PATTERN_VIEW_SIZE as u32, PATTERN_VIEW_SIZE as u32, self.render_pattern_table_palettes(table)).unwrap()
+    }
 
-#[cfg(feature = "image")]
-impl PatternTable<'_> {
-    fn generate_pattern_table_image(pattern_tables: &[u8; PATTERN_TABLE_SIZE as usize]) -> GrayImage {
-        let mut image = Vec::new();
-        image.resize(1 << 14, 0u8);
-        let mut image_view: Vec<&mut [u8]> = image.chunks_mut(8).collect();
-        let mut pixel_tmp = [[0u8; 8]; 8];
-        for (id, pattern_table) in pattern_tables.chunks(16).map(|s| PatternTable{data: s.try_into().expect("")}).enumerate(){
-            pattern_table.write_greyscale_pixels(&mut pixel_tmp);
-            // 16 tiles per row
-            // 8 rows per tile layer
-            for row in 0..8 {image_view[(id/16)*128 + id%16 + row*16].copy_from_slice(&pixel_tmp[row])}
-        }
-        GrayImage::from_vec(1 << 7, 1 << 7, image).unwrap()
+    pub fn palette_image(&self) -> RgbImage {
+        RgbImage::from_vec(PALETTE_VIEW_WIDTH as u32, PALETTE_VIEW_HEIGHT as u32, self.render_palette()).unwrap()
+    }
+
+    pub fn oam_image(&self) -> RgbImage {
+        RgbImage::from_vec(OAM_VIEW_WIDTH as u32, OAM_VIEW_HEIGHT as u32, self.render_oam()).unwrap()
     }
 }
 
@@ -70,67 +125,37 @@ impl<'a> From<&'a [u8]> for PatternTable<'a> {
     }
 }
 
-struct NameTable<'a> {
-    table_ids: &'a [u8],
-    attribute: &'a [u8],
+// A sprite as stored in OAM, for debuggers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OamEntry {
+    // y is one less than the first line the sprite is drawn on
+    pub y: u8,
+    pub tile: u8,
+    pub attributes: u8,
+    pub x: u8,
 }
 
-struct Attribute(u8);
-
-impl<'a> From<&'a [u8]> for NameTable<'a> {
-    fn from(value: &'a[u8]) -> Self {
-        let (table_ids, attribute) = value.split_at(961);
-        NameTable { table_ids, attribute}
+impl OamEntry {
+    // sprite palette 0-3, palette RAM $3F10 + 4 * palette
+    pub fn palette(&self) -> u8 {
+        self.attributes & 0x03
     }
-}
 
-impl NameTable<'_> {
-
-    // buf should be (32 * 8) * (30 * 8) * 3 = 184320 = 45*2^12 bytes
-    // this is equivalent to a 256*240 RgbImage
-    fn get_frame(&self, tables: &[PatternTable], buf: &mut[u8]) {
-
-
-        //each chunk is one row of pixels in a pattern
-        let mut table_row_pixels = buf.chunks_mut(8*3);
-        for row in self.table_ids.chunks(32) {
-            for row_pixels in 0..8 {
-                for address in row.iter() {
-                    let attribute_byte = self.attribute[((*address % 32) / 4 + (*address / 128) * 8) as usize];
-                    let shift_amnt = ((*address % 4) / 2) | (((*address / 32) % 2) << 1);
-                    tables[*address as usize].write_rgb_row(
-                        table_row_pixels.next().unwrap(),
-                        row_pixels,
-                        (attribute_byte >> (3 - shift_amnt) & 0x3) << 2
-                        );
-                }
-            }
-        }
+    pub fn behind_background(&self) -> bool {
+        self.attributes & 0x20 != 0
     }
 
-    // write 8 pixels to the image buffer,
-    // the pixels correspond to 'row' in the pattern at the 'address' in the given pattern table
-    fn write_tile_row(&self, pattern_id: u8, row: usize, pattern: &PatternTable, buf: &mut[u8]) {
-        // each attribute is split into 4 2-bit sections. Each section specifies the high color bits
-        // of a 2x2 pattern grid
-        let attribute_byte = self.attribute[((pattern_id % 32) / 4 + (pattern_id / 128) * 8) as usize];
-        let shift_amnt = ((pattern_id % 4) / 2) | (((pattern_id / 32) % 2) << 1);
-
+    pub fn flip_horizontal(&self) -> bool {
+        self.attributes & 0x40 != 0
     }
 
-    #[inline]
-    fn map_pattern_to_attribute(&self, pattern: u8) -> u8 {
-        // each attribute is split into 4 sections of 2-bits. Each section specifies the high color bits
-        // of a 2x2 pattern grid.
-        // every 4 columns of patterns is another attribute byte
-        // every 4 rows of 32 patterns each is another row of attribute bytes
-        let attribute_byte = self.attribute[((pattern % 32) / 4 + (pattern / 128) * 8) as usize];
-        let shift_amnt = ((pattern % 4) / 2) | (((pattern / 32) % 2) << 1);
-        (attribute_byte >> (3 - shift_amnt)) & 0x3
+    pub fn flip_vertical(&self) -> bool {
+        self.attributes & 0x80 != 0
     }
 }
 
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 enum PPUState {
     PreRender(usize),
     VisibleLines(usize, PPUScanLineState),
@@ -138,7 +163,18 @@ enum PPUState {
     Vblank(usize),
 }
 
+// bytes of the background tile being fetched, loaded into the shift registers once complete
+#[derive(Debug, Clone, Copy, Default)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+struct TileFetch {
+    name: u8,
+    palette: u8,
+    low: u8,
+    high: u8,
+}
+
 #[derive(Debug, Clone, Copy)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 enum PPUScanLineState {
     Idle(usize),
     Render(usize),
@@ -157,6 +193,112 @@ enum PPUScanLineState {
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 struct PPUStatus(u8);
 
+// how the 4 logical nametables map onto the 2KB of nametable RAM, set by the cartridge and changed by some mappers
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub enum Mirroring {
+    // $2000 = $2400 and $2800 = $2C00, for vertical scrolling
+    Horizontal,
+    // $2000 = $2800 and $2400 = $2C00, for horizontal scrolling
+    Vertical,
+    // all four show the same 1KB page (0 or 1), AxROM and MMC1 switch between the pages
+    SingleScreen(u8),
+    // 2KB of extra RAM on the cartridge gives every nametable its own memory
+    FourScreen,
+}
+
+// TV system, decides the frame timing
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Region {
+    // 262 lines, 3 dots per CPU cycle, 60 frames per second
+    Ntsc,
+    // 312 lines with a 70 line vblank, 3.2 dots per CPU cycle, 50 frames per second
+    Pal,
+    // Famiclones, 312 lines with the NTSC vblank length moved 50 lines down, 3 dots per CPU cycle
+    Dendy,
+}
+
+impl Region {
+    // lines between the visible lines and vblank, the vblank flag is set at the start of the line after them
+    fn post_render_scanlines(self) -> usize {
+        match self {
+            Region::Dendy => 51,
+            _ => 1,
+        }
+    }
+
+    fn vblank_scanlines(self) -> usize {
+        match self {
+            Region::Pal => 70,
+            _ => 20,
+        }
+    }
+
+    pub fn scanlines(self) -> usize {
+        1 + 240 + self.post_render_scanlines() + self.vblank_scanlines()
+    }
+
+    // PPU dots per CPU cycle as a fraction, (16, 5) is 3.2
+    pub fn dots_per_cpu_cycle(self) -> (usize, usize) {
+        match self {
+            Region::Pal => (16, 5),
+            _ => (3, 1),
+        }
+    }
+
+    // only the NTSC PPU shortens odd frames
+    fn skips_odd_dot(self) -> bool {
+        self == Region::Ntsc
+    }
+}
+
+// how lines are drawn
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Renderer {
+    // one pixel per dot through the fetch pipeline, register writes in the middle of a line take effect as on hardware
+    Dot,
+    // each line in one pass when it ends, using the scroll position latched at its start, much faster on the ESP32
+    Scanline,
+}
+
+// coarse x of 'v' plus one, wrapping into the horizontally adjacent nametable
+fn next_column(v: u16) -> u16 {
+    if v & 0x001f == 31 {
+        (v & !0x001f) ^ 0x0400
+    } else {
+        v + 1
+    }
+}
+
+// What happened during a call to 'PPU::advance', for the machine to react to
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PpuEvents {
+    // the vblank NMI was raised, it's also taken by the CPU through 'take_nmi'
+    pub nmi: bool,
+    // the last visible line was rendered
+    pub frame_complete: bool,
+    // the sprite 0 hit flag was set
+    pub sprite_zero_hit: bool,
+    // a filtered rise of PPU address line A12, which clocks the MMC3 scanline counter, see 'set_a12_hook'
+    pub scanline_irq_a12: bool,
+}
+
+// called with the (scanline, dot) of each filtered A12 rise
+pub type A12Hook = Box<dyn FnMut(isize, usize)>;
+
+impl std::ops::BitOr for PpuEvents {
+    type Output = Self;
+
+    fn bitor(self, other: Self) -> Self {
+        PpuEvents {
+            nmi: self.nmi || other.nmi,
+            frame_complete: self.frame_complete || other.frame_complete,
+            sprite_zero_hit: self.sprite_zero_hit || other.sprite_zero_hit,
+            scanline_irq_a12: self.scanline_irq_a12 || other.scanline_irq_a12,
+        }
+    }
+}
+
 bitflags! {
     #[derive(Debug, Clone, Copy, PartialEq, Eq)]
     pub struct PPUControl1: u8 {
@@ -190,16 +332,115 @@ bitflags! {
 pub struct PPU {
     state: PPUState,
     vrom: Vec<RAM>,
+    // carts without CHR ROM have 8KB of RAM for the pattern tables, otherwise pattern table writes are ignored
+    chr_ram: bool,
     vram: RAM,
     sprite_ram: RAM,
+    // background palettes followed by sprite palettes, entry 0 is the backdrop color
+    palette_ram: [u8; PALETTE_RAM_SIZE],
+    // RGB of each color index, for each combination of the emphasis bits
+    palette: [[[u8; 3]; 64]; 8],
+    // the same colors converted for the Rgb565 output
+    palette_565: [[[u8; 2]; 64]; 8],
+    mirroring: Mirroring,
     ppu_control_1: PPUControl1,
     ppu_control_2: PPUControl2,
     ppu_status: PPUStatus,
     spr_ram_address: u8,
+    /*
+        Scroll registers as named on the nesdev wiki, 'v' and 't' are laid out as 0yyy NNYY YYYX XXXX
+        (fine y, nametable, coarse y, coarse x). $2000/$2005/$2006 writes go to 't', which is copied
+        to 'v' by the second $2006 write and at fixed dots while rendering.
+     */
+    // v
+    vram_address: u16,
+    // t
+    temp_address: u16,
+    fine_x: u8,
+    // w, shared by $2005 and $2006, set after the first write and cleared by $2002 reads
+    write_latch: bool,
+    // $2007 reads below the palette return this buffer, which is then refilled from VRAM
+    read_buffer: u8,
+    // open bus, see 'io_latch'
+    io_latch: u8,
+    // frame each bit of 'io_latch' was last driven
+    io_latch_refreshed: [u64; 8],
+    tile_fetch: TileFetch,
+    // pattern planes of two tiles, the current tile in the high byte, shifted left every dot
+    pattern_shift: [u16; 2],
+    // the 2 palette bits of the same pixels, expanded to one bit per pixel
+    attribute_shift: [u16; 2],
+    renderer: Renderer,
+    frame_format: FrameFormat,
+    // 'v' and fine x at the start of the line, used by the scanline renderer
+    line_scroll: (u16, u8),
+    // number of completed frames
+    frame_count: u64,
+    region: Region,
+    // overclocking: additional post-render scanlines per frame during which only the CPU runs
+    extra_scanlines: usize,
+    // when false every sprite on a line is drawn instead of only the first 8
+    sprite_limit: bool,
+    // setting, see 'set_warm_up'
+    warm_up: bool,
+    // PPUCTRL, PPUMASK, PPUSCROLL and PPUADDR ignore writes until the end of the first vblank after power-on or reset
+    warming_up: bool,
+    // sprite indices selected for the current line
+    line_sprites: [u8; SPRITE_COUNT],
+    // sprite pixels of the current line, fetched during the sprite fetch period of the previous line
+    sprite_line: [u8; LINE_WIDTH],
+    // NMI raised at the start of vblank, taken by the CPU
+    nmi: bool,
+    // the next vblank NMI was already taken by a hijacked BRK/IRQ
+    nmi_hijacked: bool,
+    // $2002 was read the dot before vblank starts, the flag and NMI are skipped for this frame
+    vblank_suppressed: bool,
+    // events of the running 'advance' call
+    events: PpuEvents,
+    // A12 of the last pattern fetch
+    a12: bool,
+    a12_hook: Option<A12Hook>,
+}
+
+// registers, VRAM and OAM, CHR ROM and settings like the sprite limit are not included
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct PpuState {
+    state: PPUState,
+    vram: Packed,
+    sprite_ram: Packed,
+    palette_ram: [u8; PALETTE_RAM_SIZE],
+    mirroring: Mirroring,
+    ppu_control_1: u8,
+    ppu_control_2: u8,
+    ppu_status: u8,
+    spr_ram_address: u8,
     vram_address: u16,
-    byte_shift: u8,
-    x_scroll: u8,
-    y_scroll: u8
+    temp_address: u16,
+    fine_x: u8,
+    write_latch: bool,
+    read_buffer: u8,
+    io_latch: u8,
+    io_latch_refreshed: [u64; 8],
+    a12: bool,
+    tile_fetch: TileFetch,
+    pattern_shift: [u16; 2],
+    attribute_shift: [u16; 2],
+    line_scroll: (u16, u8),
+    frame_count: u64,
+    nmi: bool,
+    nmi_hijacked: bool,
+    vblank_suppressed: bool,
+    warming_up: bool,
+    // sprite pixels fetched for the current line
+    sprite_line: Packed,
+}
+
+impl PpuState {
+    // size of the run length encoded buffers in bytes
+    pub fn packed_len(&self) -> usize {
+        self.vram.packed_len() + self.sprite_ram.packed_len() + self.sprite_line.packed_len()
+    }
 }
 
 // TODO many state variables aren't properly updated
@@ -208,18 +449,48 @@ impl PPU {
         let mut ppu = PPU{
             state: PPUState::PreRender(0),
             vram: RAM::new::<{VRAM_SIZE as usize}>(),
+            chr_ram: vrom.is_empty(),
             vrom,
             sprite_ram: RAM::new::<{SPRAM_SIZE as usize}>(),
+            palette_ram: [0; PALETTE_RAM_SIZE],
+            palette: emphasis_palettes(&NTSC_PALETTE),
+            palette_565: [[[0; 2]; 64]; 8],
+            mirroring: Mirroring::Horizontal,
             ppu_control_1: PPUControl1::from_bits_truncate(0),
             ppu_control_2: PPUControl2::from_bits_truncate(0),
             ppu_status: PPUStatus::from_bits_truncate(0),
             spr_ram_address: 0,
             vram_address: 0,
-            byte_shift: 8,
-            x_scroll: 0,
-            y_scroll: 0,
+            temp_address: 0,
+            fine_x: 0,
+            write_latch: false,
+            read_buffer: 0,
+            io_latch: 0,
+            io_latch_refreshed: [0; 8],
+            tile_fetch: TileFetch::default(),
+            pattern_shift: [0; 2],
+            attribute_shift: [0; 2],
+            renderer: Renderer::Dot,
+            frame_format: FrameFormat::Rgb888,
+            line_scroll: (0, 0),
+            frame_count: 0,
+            region: Region::Ntsc,
+            extra_scanlines: 0,
+            sprite_limit: true,
+            line_sprites: [0; SPRITE_COUNT],
+            sprite_line: [0; LINE_WIDTH],
+            nmi: false,
+            nmi_hijacked: false,
+            vblank_suppressed: false,
+            warm_up: true,
+            warming_up: true,
+            events: PpuEvents::default(),
+            a12: false,
+            a12_hook: None,
         };
 
+        ppu.update_palette_565();
+
         if ppu.vrom.len() > 0 {
             // by default load first two vroms into program tables
             // if only a single vrom is present, duplicate this vrom
@@ -230,6 +501,81 @@ impl PPU {
         ppu
     }
 
+    // registers cleared by the reset line, VRAM and OAM keep their contents
+    pub fn reset(&mut self) {
+        self.ppu_control_1 = PPUControl1::from_bits_truncate(0);
+        self.ppu_control_2 = PPUControl2::from_bits_truncate(0);
+        self.temp_address = 0;
+        self.fine_x = 0;
+        self.write_latch = false;
+        self.nmi = false;
+        self.nmi_hijacked = false;
+        self.vblank_suppressed = false;
+        self.warming_up = self.warm_up;
+    }
+
+    pub fn save_state(&self) -> PpuState {
+        PpuState {
+            state: self.state,
+            vram: Packed::pack(self.vram.as_slice()),
+            sprite_ram: Packed::pack(self.sprite_ram.as_slice()),
+            palette_ram: self.palette_ram,
+            mirroring: self.mirroring,
+            ppu_control_1: self.ppu_control_1.bits(),
+            ppu_control_2: self.ppu_control_2.bits(),
+            ppu_status: self.ppu_status.0,
+            spr_ram_address: self.spr_ram_address,
+            vram_address: self.vram_address,
+            temp_address: self.temp_address,
+            fine_x: self.fine_x,
+            write_latch: self.write_latch,
+            read_buffer: self.read_buffer,
+            io_latch: self.io_latch,
+            io_latch_refreshed: self.io_latch_refreshed,
+            a12: self.a12,
+            tile_fetch: self.tile_fetch,
+            pattern_shift: self.pattern_shift,
+            attribute_shift: self.attribute_shift,
+            line_scroll: self.line_scroll,
+            frame_count: self.frame_count,
+            nmi: self.nmi,
+            nmi_hijacked: self.nmi_hijacked,
+            vblank_suppressed: self.vblank_suppressed,
+            warming_up: self.warming_up,
+            sprite_line: Packed::pack(&self.sprite_line),
+        }
+    }
+
+    pub fn load_state(&mut self, state: &PpuState) {
+        self.state = state.state;
+        state.vram.unpack_into(self.vram.as_slice_mut());
+        state.sprite_ram.unpack_into(self.sprite_ram.as_slice_mut());
+        self.palette_ram = state.palette_ram;
+        self.mirroring = state.mirroring;
+        self.ppu_control_1 = PPUControl1::from_bits_retain(state.ppu_control_1);
+        self.ppu_control_2 = PPUControl2::from_bits_retain(state.ppu_control_2);
+        self.ppu_status = PPUStatus(state.ppu_status);
+        self.spr_ram_address = state.spr_ram_address;
+        self.vram_address = state.vram_address;
+        self.temp_address = state.temp_address;
+        self.fine_x = state.fine_x;
+        self.write_latch = state.write_latch;
+        self.read_buffer = state.read_buffer;
+        self.io_latch = state.io_latch;
+        self.io_latch_refreshed = state.io_latch_refreshed;
+        self.a12 = state.a12;
+        self.tile_fetch = state.tile_fetch;
+        self.pattern_shift = state.pattern_shift;
+        self.attribute_shift = state.attribute_shift;
+        self.line_scroll = state.line_scroll;
+        self.frame_count = state.frame_count;
+        self.nmi = state.nmi;
+        self.nmi_hijacked = state.nmi_hijacked;
+        self.vblank_suppressed = state.vblank_suppressed;
+        self.warming_up = state.warming_up;
+        state.sprite_line.unpack_into(&mut self.sprite_line);
+    }
+
     /*
         dst: 1 or 0, target pattern table
         src: vrom to load
@@ -238,25 +584,157 @@ impl PPU {
         self.vram.as_slice_mut()[dst*PATTERN_TABLE_SIZE..(dst+1)*PATTERN_TABLE_SIZE].copy_from_slice(self.vrom[src].as_slice());
     }
 
+    pub fn set_mirroring(&mut self, mirroring: Mirroring) {
+        self.mirroring = mirroring;
+    }
+
+    pub fn mirroring(&self) -> Mirroring {
+        self.mirroring
+    }
+
+    // false for CHR ROM, which ignores writes
+    pub fn has_chr_ram(&self) -> bool {
+        self.chr_ram
+    }
+
+    // index into 'vram' of a PPU address, applies the nametable mirroring
+    // $3000-$3EFF mirror the nametables at $2000-$2EFF
+    #[inline]
+    fn vram_index(&self, address: u16) -> u16 {
+        let address = address % VRAM_SIZE;
+        if !(0x2000..0x3f00).contains(&address) {
+            return address;
+        }
+        0x2000 | match self.mirroring {
+            Mirroring::Horizontal => ((address >> 1) & 0x0400) | (address & 0x03ff),
+            Mirroring::Vertical => address & 0x07ff,
+            Mirroring::SingleScreen(page) => ((page as u16 & 1) << 10) | (address & 0x03ff),
+            Mirroring::FourScreen => address & 0x0fff,
+        }
+    }
+
+    // registers are mirrored every 8 bytes up to $3FFF
     pub fn read(&mut self, address: u16) -> u8 {
-        match address {
+        let latch = self.io_latch();
+        let data = match 0x2000 | (address & 0x07) {
             0x2002 => {
-                self.byte_shift = 8;
-                self.ppu_status.0
+                match self.state {
+                    // a read just before the flag is set returns it clear and suppresses the flag and NMI
+                    PPUState::Vblank(1) => self.vblank_suppressed = true,
+                    // a read on the dot the flag is set or the one after returns it set but cancels the NMI
+                    PPUState::Vblank(2 | 3) => self.nmi = false,
+                    _ => (),
+                }
+                self.write_latch = false;
+                let status = self.ppu_status.0;
+                self.ppu_status.remove(PPUStatus::VBlankIndicator);
+                // only the 3 flag bits are driven, the rest is stale bus content
+                let data = (status & 0xe0) | (latch & 0x1f);
+                self.drive_io_latch(data, 0xe0);
+                return data;
             }
             0x2004 => self.sprite_ram[self.spr_ram_address as u16],
             0x2007 => {
-                let vaddress = self.vram_address;
-                let tmp = self.vram[vaddress];
-                self.vram_address = vaddress.wrapping_add(if self.ppu_control_1.contains(PPUControl1::AddressIncrement) {1} else {32});
-                tmp
+                let address = self.vram_address % VRAM_SIZE;
+                let data = if address >= 0x3f00 {
+                    // palette reads are immediate, the buffer gets the nametable byte underneath
+                    self.read_buffer = self.vram[self.vram_index(address - 0x1000)];
+                    // palette entries are 6 bits, the top 2 bits come from the bus
+                    (latch & 0xc0) | self.palette_entry(palette_index(address))
+                } else {
+                    let data = self.vram[self.vram_index(address)];
+                    std::mem::replace(&mut self.read_buffer, data)
+                };
+                self.increment_vram_address();
+                data
             },
-            _ => 0,
+            // write only registers return what was last on the bus
+            _ => return latch,
+        };
+        self.drive_io_latch(data, 0xff);
+        data
+    }
+
+    /*
+        The data bus between the CPU and the PPU registers holds the last value written or read,
+        reads of write only registers and unused bits return it. Bits which aren't driven for
+        about 600ms decay to 0.
+     */
+    fn io_latch(&self) -> u8 {
+        (0..8)
+            .filter(|&bit| self.frame_count.saturating_sub(self.io_latch_refreshed[bit]) < OPEN_BUS_DECAY_FRAMES)
+            .fold(0, |latch, bit| latch | (self.io_latch & (1 << bit)))
+    }
+
+    // put the bits of 'data' selected by 'mask' on the PPU data bus
+    fn drive_io_latch(&mut self, data: u8, mask: u8) {
+        self.io_latch = (self.io_latch & !mask) | (data & mask);
+        for (bit, refreshed) in self.io_latch_refreshed.iter_mut().enumerate() {
+            if mask & (1 << bit) != 0 {*refreshed = self.frame_count}
+        }
+    }
+
+    // every CPU write to a PPU register, including OAM DMA, goes over the bus
+    pub fn drive_bus(&mut self, data: u8) {
+        self.drive_io_latch(data, 0xff);
+    }
+
+    // same as 'read' but leaves the address latch and vram address untouched
+    pub fn peek(&self, address: u16) -> u8 {
+        let latch = self.io_latch();
+        match 0x2000 | (address & 0x07) {
+            0x2002 => (self.ppu_status.0 & 0xe0) | (latch & 0x1f),
+            0x2004 => self.sprite_ram[self.spr_ram_address as u16],
+            0x2007 => match self.vram_address % VRAM_SIZE {
+                address @ 0x3f00.. => (latch & 0xc0) | self.palette_entry(palette_index(address)),
+                _ => self.read_buffer,
+            },
+            _ => latch,
+        }
+    }
+
+    /*
+        Access to the PPU address space for debuggers and memory viewers, which leaves the address
+        latch, 'v' and the read buffer alone. Nametable addresses are mirrored like $2007 accesses,
+        palette entries are read without the grayscale mask. Pokes also change CHR ROM, until the
+        mapper switches the bank out.
+     */
+    pub fn peek_vram(&self, address: u16) -> u8 {
+        match address % VRAM_SIZE {
+            address @ 0x3f00.. => self.palette_ram[palette_index(address)],
+            address => self.vram[self.vram_index(address)],
+        }
+    }
+
+    pub fn poke_vram(&mut self, address: u16, data: u8) {
+        match address % VRAM_SIZE {
+            address @ 0x3f00.. => self.palette_ram[palette_index(address)] = data & 0x3f,
+            address => {
+                let index = self.vram_index(address);
+                self.vram[index] = data;
+            }
         }
     }
 
+    // OAM without going through OAMADDR
+    pub fn peek_oam(&self, address: u8) -> u8 {
+        self.sprite_ram[address as u16]
+    }
+
+    pub fn poke_oam(&mut self, address: u8, data: u8) {
+        self.sprite_ram[address as u16] = data;
+    }
+
     pub fn set_ppu_control_1(&mut self, data: u8) {
-        self.ppu_control_1 = PPUControl1::from_bits_retain(data);
+        let control = PPUControl1::from_bits_retain(data);
+        // the nametable select bits are the nametable bits of 't'
+        self.temp_address = (self.temp_address & !0x0c00) | ((data as u16 & 0x03) << 10);
+        // enabling NMI during vblank raises an NMI immediately
+        if control.contains(PPUControl1::IntteruptOnVBlank) && !self.ppu_control_1.contains(PPUControl1::IntteruptOnVBlank)
+            && self.ppu_status.contains(PPUStatus::VBlankIndicator) {
+            self.nmi = true;
+        }
+        self.ppu_control_1 = control;
     }
 
     pub fn set_ppu_control_2(&mut self, data: u8) {
@@ -267,21 +745,28 @@ impl PPU {
         self.spr_ram_address = data;
     }
 
+    // first write sets coarse and fine x, the second coarse and fine y
     pub fn set_scroll(&mut self, data: u8) {
-        if self.byte_shift != 0 {
-            self.x_scroll = data;
+        let data = data as u16;
+        if !self.write_latch {
+            self.temp_address = (self.temp_address & !0x001f) | (data >> 3);
+            self.fine_x = data as u8 & 0x07;
         } else {
-            self.y_scroll = data;
+            self.temp_address = (self.temp_address & !0x73e0) | ((data & 0x07) << 12) | ((data & 0xf8) << 2);
         }
-        if self.byte_shift == 0 {self.byte_shift = 8;} else {self.byte_shift = 0;}
+        self.write_latch = !self.write_latch;
     }
 
+    // high byte first, the upper two bits are dropped, 'v' is only updated by the second write
     pub fn set_vram_address(&mut self, data: u8) {
-        //clear bits to write
-        self.vram_address &= !(0xff << self.byte_shift);
-        //write address portion, ignore upper two bits
-        self.vram_address |= ((data as u16) << self.byte_shift) & 0xa0;
-        if self.byte_shift == 0 {self.byte_shift = 8;} else {self.byte_shift = 0;}
+        let data = data as u16;
+        if !self.write_latch {
+            self.temp_address = (self.temp_address & 0x00ff) | ((data & 0x3f) << 8);
+        } else {
+            self.temp_address = (self.temp_address & 0xff00) | data;
+            self.vram_address = self.temp_address;
+        }
+        self.write_latch = !self.write_latch;
     }
 
     pub fn write_spram(&mut self, data: u8) {
@@ -290,148 +775,1986 @@ impl PPU {
     }
 
     pub fn write_vram(&mut self, data: u8) {
-        self.vram[self.vram_address % VRAM_SIZE] = data;
-        self.vram_address = self.vram_address.wrapping_add(if self.ppu_control_1.contains(PPUControl1::AddressIncrement) {1} else {32});
+        match self.vram_address % VRAM_SIZE {
+            // palette entries are 6 bits wide
+            address @ 0x3f00.. => self.palette_ram[palette_index(address)] = data & 0x3f,
+            ..0x2000 if !self.chr_ram => (),
+            address => {
+                let index = self.vram_index(address);
+                self.vram[index] = data;
+            }
+        }
+        self.increment_vram_address();
+    }
+
+    // after every $2007 access, by 32 (one row of tiles) when PPUCTRL bit 2 is set
+    fn increment_vram_address(&mut self) {
+        // while rendering the access collides with the background fetches, coarse x and y are incremented instead
+        if self.rendering_enabled() && matches!(self.state, PPUState::PreRender(_) | PPUState::VisibleLines(..)) {
+            self.vram_address = next_column(self.vram_address);
+            self.increment_y();
+            return;
+        }
+        let increment = if self.ppu_control_1.contains(PPUControl1::AddressIncrement) {32} else {1};
+        self.vram_address = self.vram_address.wrapping_add(increment) & 0x7fff;
     }
 
     pub fn ignore(&mut self, _data: u8) {}
 
-    pub fn advance(&mut self, cycles: usize, buf: &mut [u8]) {
-        const CYCLES_SCANLINE: usize = 341;
-        const SCANLINES_VBLANK: usize = 20;
-        const SCANLINES_VISIBLE: usize = 240;
-        const SCANLINES_PRERENDER: usize = 1;
-        const SCANLINES_POSTRENDER: usize = 1;
-        const IDLE_CYCLES: usize = 1;
-        const RENDER_CYCLES: usize = 256;
-        const SPRITE_FETCH_CYCLES: usize = 64;
-        const PRE_FETCH_CYCLES: usize = 16;
-        const OTHER_FETCH_CYCLES: usize = 4;
+    // palette RAM entry, grayscale mode keeps only the brightness bits
+    #[inline]
+    fn palette_entry(&self, index: usize) -> u8 {
+        let mask = if self.ppu_control_2.contains(PPUControl2::ColorMode) {0x30} else {0x3f};
+        self.palette_ram[index] & mask
+    }
 
-        // ! TODO: even/odd frame cycle skip thing
-        // ! TODO: sprite rendering
-        // ! TODO: sprite hit detection
-        // ! TODO: VBLANK flag
-        // ! TODO: NMI
-        match self.state {
-            PPUState::PreRender(cycle) => {
-                if cycle + cycles > SCANLINES_PRERENDER * CYCLES_SCANLINE {
-                    self.state = PPUState::VisibleLines(
-                        0,
-                        PPUScanLineState::Idle(0));
-                    self.advance(cycle + cycles - SCANLINES_PRERENDER * CYCLES_SCANLINE, buf);
-                } else {
-                    self.state = PPUState::PreRender(cycle + cycles);
-                }
-            },
-            PPUState::VisibleLines(line, line_state) => {
-                macro_rules! next_state {
-                    ($current: expr, $threshhold: expr, $stay: path, $next: path) => {
-                        if $current > $threshhold {
-                            self.state = PPUState::VisibleLines(line, $next(0));
-                            self.advance($current - $threshhold, buf);
-                        } else {
-                            self.state = PPUState::VisibleLines(line, $stay($current));
-                        }
-                    };
-                }
+    // output color of a palette RAM index with the grayscale and emphasis bits of PPUMASK applied
+    #[inline]
+    fn color(&self, index: u8) -> &[u8; 3] {
+        &self.output_palette()[self.palette_entry(index as usize) as usize]
+    }
 
-                match line_state {
-                    PPUScanLineState::Idle(cycle) => {
-                        next_state!(cycle + cycles, IDLE_CYCLES, PPUScanLineState::Idle, PPUScanLineState::Render);
-                    }
-                    PPUScanLineState::Render(cycle) => {
-                        let mut next = cycle / 8 * 8;
-                        // rendering has granularity of 8 pixels, so every 8 ppu cycles
-                        // 8 pixels are rendered. This is an approximation of hardware.
-                        // this is to reduce memory accesses in software
-                        let dest = (cycles + cycle) / 8 * 8;
-                        while next < dest && next < RENDER_CYCLES {
-                            let name_table_address = (self.ppu_control_1 & PPUControl1::NameTableAddressMask).bits() as u16 * NAME_TABLE_SIZE as u16 + 0x2000;
+    // pixel 'px' of a line in the output format, 'index' is a palette RAM index
+    #[inline]
+    fn write_pixel(&self, pixels: &mut [u8], px: usize, index: u8) {
+        match self.frame_format {
+            FrameFormat::Rgb888 => pixels[px * 3..(px + 1) * 3].copy_from_slice(self.color(index)),
+            FrameFormat::Rgb565 => {
+                let color = &self.palette_565[self.emphasis() as usize][self.palette_entry(index as usize) as usize];
+                pixels[px * 2..(px + 1) * 2].copy_from_slice(color);
+            }
+            FrameFormat::Indexed8 => pixels[px] = self.palette_entry(index as usize),
+        }
+    }
 
-                            let pattern_idx = PPU::map_pixel_to_pattern(next);
+    // true once if an NMI was raised since the last call
+    pub fn take_nmi(&mut self) -> bool {
+        std::mem::take(&mut self.nmi)
+    }
 
-                            let name_table: NameTable = self.vram[name_table_address..name_table_address + NAME_TABLE_SIZE as u16].into();
+    /*
+        Dots until the vblank NMI is raised, None if NMI is disabled or vblank isn't close.
+        The CPU runs ahead of the PPU by a whole instruction, so this lets it find out
+        whether an NMI arrives part way through an interrupt sequence.
+     */
+    pub fn dots_until_nmi(&self) -> Option<usize> {
+        if !self.ppu_control_1.contains(PPUControl1::IntteruptOnVBlank) || self.nmi_hijacked || self.vblank_suppressed {
+            return None;
+        }
+        match self.state {
+            // the vblank flag is set 2 dots into the first vblank line
+            PPUState::PostRender(cycle) => Some((self.region.post_render_scanlines() + self.extra_scanlines) * 341 + 2 - cycle),
+            PPUState::Vblank(cycle) if cycle < 2 => Some(2 - cycle),
+            _ => None,
+        }
+    }
 
-                            let pattern_address =
-                                (((self.ppu_control_1 & PPUControl1::BackgroundTable).bits() as u16) << 8) &
-                                ((name_table.table_ids[pattern_idx as usize] as u16) << 4);
+    // the upcoming vblank NMI was already serviced, don't raise it again
+    pub fn acknowledge_upcoming_nmi(&mut self) {
+        self.nmi_hijacked = true;
+    }
 
-                            let pattern: PatternTable = self.vram[pattern_address..pattern_address + 16].into();
+    // current (scanline, dot), the pre-render line is -1 and vblank starts at line 241
+    pub fn position(&self) -> (isize, usize) {
+        const CYCLES_SCANLINE: usize = 341;
+        match self.state {
+            PPUState::PreRender(cycle) => (-1, cycle),
+            PPUState::VisibleLines(line, line_state) => (line as isize, match line_state {
+                PPUScanLineState::Idle(cycle) => cycle,
+                PPUScanLineState::Render(cycle) => 1 + cycle,
+                PPUScanLineState::SpriteFetch(cycle) => 257 + cycle,
+                PPUScanLineState::PreFetch(cycle) => 321 + cycle,
+                PPUScanLineState::OtherFetch(cycle) => 337 + cycle,
+            }),
+            // overclocking lines are numbered after the post-render line
+            PPUState::PostRender(cycle) => ((240 + cycle / CYCLES_SCANLINE) as isize, cycle % CYCLES_SCANLINE),
+            PPUState::Vblank(cycle) => ((240 + self.region.post_render_scanlines() + cycle / CYCLES_SCANLINE) as isize, cycle % CYCLES_SCANLINE),
+        }
+    }
 
-                            pattern.write_rgb_row(
-                                buf,
-                                (next / FRAME_WIDTH) % 8,
-                                name_table.map_pattern_to_attribute(pattern_idx) << 2 //TODO: high bits controlled by PPUControl2
-                                );
+    // replace the output colors, 'palette' is 64 RGB triples as stored in .pal files
+    // the emphasized colors are derived from it
+    pub fn load_palette(&mut self, palette: &[u8; PALETTE_FILE_SIZE]) {
+        let mut colors = [[0u8; 3]; 64];
+        for (color, rgb) in colors.iter_mut().zip(palette.chunks_exact(3)) {
+            color.copy_from_slice(rgb);
+        }
+        self.palette = emphasis_palettes(&colors);
+        self.update_palette_565();
+    }
 
-                            next += 8;
-                        }
-                        next_state!(cycle + cycles, RENDER_CYCLES, PPUScanLineState::Render, PPUScanLineState::SpriteFetch);
-                    }
-                    PPUScanLineState::SpriteFetch(cycle) => {
-                        next_state!(cycle + cycles, SPRITE_FETCH_CYCLES, PPUScanLineState::SpriteFetch, PPUScanLineState::OtherFetch);
-                    }
-                    PPUScanLineState::PreFetch(cycle) => {
-                        next_state!(cycle + cycles, PRE_FETCH_CYCLES, PPUScanLineState::PreFetch, PPUScanLineState::OtherFetch);
+    // load a .pal file, files with 8 * 64 colors provide their own colors for the emphasis combinations
+    pub fn load_palette_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), NesError> {
+        let data = std::fs::read(path)?;
+        match data.len() {
+            PALETTE_FILE_SIZE => self.load_palette(data[..].try_into().unwrap()),
+            size if size == 8 * PALETTE_FILE_SIZE => {
+                for (palette, data) in self.palette.iter_mut().zip(data.chunks_exact(PALETTE_FILE_SIZE)) {
+                    for (color, rgb) in palette.iter_mut().zip(data.chunks_exact(3)) {
+                        color.copy_from_slice(rgb);
                     }
-                    PPUScanLineState::OtherFetch(cycle) => {
-                        if cycle + cycles > OTHER_FETCH_CYCLES {
-                            if line + 1 >= SCANLINES_VISIBLE {
-                                self.state = PPUState::PostRender(0);
-                            } else {
-                                self.state = PPUState::VisibleLines(
-                                    line + 1,
-                                    PPUScanLineState::Idle(0));
-                            }
-                            self.advance(cycle + cycles - OTHER_FETCH_CYCLES, buf);
-                        } else {
-                            self.state = PPUState::VisibleLines(line, PPUScanLineState::OtherFetch(cycle + cycles));
-                        }
-                    }
-                }
-            },
-            PPUState::PostRender(cycle) => {
-                if cycle + cycles > SCANLINES_POSTRENDER * CYCLES_SCANLINE {
-                    self.state = PPUState::Vblank(0);
-                    self.advance(cycle + cycles - SCANLINES_POSTRENDER * CYCLES_SCANLINE, buf);
-                } else {
-                    self.state = PPUState::PostRender(cycle + cycles);
-                }
-            }
-            PPUState::Vblank(cycle) => {
-                let next = cycle + cycles;
-                if cycle < 2 && next >= 2 {self.ppu_status |= PPUStatus::VBlankIndicator}
-                if next > SCANLINES_VBLANK * CYCLES_SCANLINE {
-                    self.state = PPUState::PreRender(0);
-                    self.advance(next - SCANLINES_VBLANK * CYCLES_SCANLINE, buf);
-                } else {
-                    self.state = PPUState::Vblank(next);
                 }
+                self.update_palette_565();
             }
+            _ => return Err(NesError::FileFormat("palette files hold 64 or 512 RGB colors")),
         }
+        Ok(())
     }
 
-    #[inline]
-    const fn map_pixel_to_pattern(pixel: usize) -> u8 {
-        // on average one pixel is rendered each cycle,
-        // Each row of pattern tables corresponds to 8 rows of pixels in a frame, and each pattern table has 8 columns of pixels
-        ((pixel / (FRAME_WIDTH * 8))*8 + (pixel/FRAME_WIDTH) % 8) as u8
+    fn update_palette_565(&mut self) {
+        for (palette_565, palette) in self.palette_565.iter_mut().zip(self.palette.iter()) {
+            *palette_565 = palette.map(rgb565);
+        }
     }
-}
 
-mod tests {
-    use super::*;
-    use crate::cpu::CPU;
-    use crate::memory::Memory;
+    // colors without emphasis
+    pub fn palette(&self) -> &[[u8; 3]; 64] {
+        &self.palette[0]
+    }
 
-    #[cfg(feature = "image")]
-    #[test]
-    fn test_pattern_table_image() {
-        let mem = Memory::from_file(String::from("../galaga.nes")).expect("failed to load file");
-        for (i, table) in mem.ppu.vrom.iter().enumerate() {
-            let image= PatternTable::generate_pattern_table_image(table.as_slice().try_into().expect("incorrectly sized pattern table"));
-            image.save_with_format(format!("pattern_table_{i}.png"), image::ImageFormat::Png).expect("failed to save pattern table to png");
+    // colors with the current emphasis bits, to expand Indexed8 frames which don't store the emphasis
+    pub fn output_palette(&self) -> &[[u8; 3]; 64] {
+        &self.palette[self.emphasis() as usize]
+    }
+
+    // emphasis bits of PPUMASK, bit 0 is red, bit 1 green and bit 2 blue
+    // the PAL and Dendy PPUs swap the red and green bits, they are returned in the same order
+    pub fn emphasis(&self) -> u8 {
+        let bits = (self.ppu_control_2 & PPUControl2::BackgroundColorMask).bits() >> 5;
+        match self.region {
+            Region::Ntsc => bits,
+            _ => (bits & 0x04) | (bits & 0x01) << 1 | (bits & 0x02) >> 1,
+        }
+    }
+
+    // layout of the pixels written by 'advance', buffers have to be sized for it
+    pub fn set_frame_format(&mut self, format: FrameFormat) {
+        self.frame_format = format;
+    }
+
+    pub fn frame_format(&self) -> FrameFormat {
+        self.frame_format
+    }
+
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    pub fn scanline(&self) -> isize {
+        self.position().0
+    }
+
+    pub fn dot(&self) -> usize {
+        self.position().1
+    }
+
+    // register getters for debuggers, they have no side effects unlike reading the registers
+    // v
+    pub fn vram_address(&self) -> u16 {
+        self.vram_address
+    }
+
+    // t
+    pub fn temp_address(&self) -> u16 {
+        self.temp_address
+    }
+
+    pub fn fine_x(&self) -> u8 {
+        self.fine_x
+    }
+
+    // w
+    pub fn write_latch(&self) -> bool {
+        self.write_latch
+    }
+
+    // PPUSTATUS flags without the open bus bits
+    pub fn status(&self) -> u8 {
+        self.ppu_status.0
+    }
+
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    /*
+        Overclock by inserting 'scanlines' invisible lines after the post-render line. The CPU keeps
+        running during these lines while the PPU idles, so games which overrun their frame budget
+        slow down less, while the visible frame and the vblank timing seen by the game are unchanged.
+     */
+    pub fn set_extra_scanlines(&mut self, scanlines: usize) {
+        self.extra_scanlines = scanlines;
+    }
+
+    pub fn extra_scanlines(&self) -> usize {
+        self.extra_scanlines
+    }
+
+    /*
+        After power-on and reset the PPU ignores writes to $2000, $2001, $2005 and $2006 until the end
+        of the first vblank, about 29658 CPU cycles. Games wait for two vblanks before setting up the
+        PPU because of it, and test ROMs check it. Disabling it accepts the writes right away.
+     */
+    pub fn set_warm_up(&mut self, enabled: bool) {
+        self.warm_up = enabled;
+        self.warming_up &= enabled;
+    }
+
+    pub fn warm_up(&self) -> bool {
+        self.warm_up
+    }
+
+    // true while register writes are ignored, see 'set_warm_up'
+    pub fn warming_up(&self) -> bool {
+        self.warming_up
+    }
+
+    /*
+        With the limit disabled all sprites on a line are drawn, which removes the flicker games
+        use to work around the limit. The overflow flag is still set as it would be on hardware.
+     */
+    pub fn set_sprite_limit(&mut self, enabled: bool) {
+        self.sprite_limit = enabled;
+    }
+
+    pub fn sprite_limit(&self) -> bool {
+        self.sprite_limit
+    }
+
+    /*
+        The scanline renderer skips the fetch pipeline, it draws a line at once when it ends. Scroll
+        changes in the middle of a line only show up on the next one and sprite 0 hits are reported
+        at the end of the line, which most games tolerate.
+     */
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        self.renderer = renderer;
+    }
+
+    pub fn renderer(&self) -> Renderer {
+        self.renderer
+    }
+
+    /*
+        Debug view of pattern table 'table' (0 for $0000, 1 for $1000) as a PATTERN_VIEW_SIZE square
+        RGB image, colored with 'palette' 0-3 for the background or 4-7 for sprites. It shows the
+        current contents of the pattern tables, so CHR RAM and switched banks are shown as the PPU sees them.
+     */
+    pub fn render_pattern_table(&self, table: usize, palette: u8) -> Vec<u8> {
+        let mut pixels = vec![0u8; PATTERN_VIEW_SIZE * PATTERN_VIEW_SIZE * 3];
+        self.draw_pattern_table(table, palette, &mut pixels, PATTERN_VIEW_SIZE);
+        pixels
+    }
+
+    // pattern table 'table' in all 8 palettes side by side, 8 * PATTERN_VIEW_SIZE wide
+    pub fn render_pattern_table_palettes(&self, table: usize) -> Vec<u8> {
+        const WIDTH: usize = 8 * PATTERN_VIEW_SIZE;
+        let mut pixels = vec![0u8; WIDTH * PATTERN_VIEW_SIZE * 3];
+        for palette in 0..8 {
+            self.draw_pattern_table(table, palette as u8, &mut pixels[palette * PATTERN_VIEW_SIZE * 3..], WIDTH);
+        }
+        pixels
+    }
+
+    // draw a pattern table into 'pixels', an RGB image 'width' pixels wide
+    fn draw_pattern_table(&self, table: usize, palette: u8, pixels: &mut [u8], width: usize) {
+        let base = (table & 1) * PATTERN_TABLE_SIZE;
+        for tile in 0..256 {
+            let pattern: PatternTable = self.vram.as_slice()[base + tile * 16..base + (tile + 1) * 16].into();
+            for row in 0..8 {
+                let px = ((tile / 16 * 8 + row) * width + tile % 16 * 8) * 3;
+                for (column, rgb) in pixels[px..px + 8 * 3].chunks_exact_mut(3).enumerate() {
+                    let pixel = pattern.get_pixel((row, column));
+                    rgb.copy_from_slice(self.color(if pixel == 0 {0} else {((palette & 0x07) << 2) | pixel}));
+                }
+            }
+        }
+    }
+
+    // the 32 palette RAM entries as NES color indices, $3F10/$3F14/$3F18/$3F1C show the entries they mirror
+    pub fn palette_indices(&self) -> [u8; PALETTE_RAM_SIZE] {
+        std::array::from_fn(|index| self.palette_ram[palette_index(index as u16)])
+    }
+
+    // the 32 palette RAM entries as output colors, with the grayscale and emphasis bits of PPUMASK applied
+    pub fn palette_colors(&self) -> [[u8; 3]; PALETTE_RAM_SIZE] {
+        std::array::from_fn(|index| *self.color(palette_index(index as u16) as u8))
+    }
+
+    // debug view of 'palette_colors' as an RGB image of PALETTE_VIEW_WIDTH x PALETTE_VIEW_HEIGHT
+    pub fn render_palette(&self) -> Vec<u8> {
+        let colors = self.palette_colors();
+        let mut pixels = vec![0u8; PALETTE_VIEW_WIDTH * PALETTE_VIEW_HEIGHT * 3];
+        for (y, row) in pixels.chunks_exact_mut(PALETTE_VIEW_WIDTH * 3).enumerate() {
+            for (x, rgb) in row.chunks_exact_mut(3).enumerate() {
+                rgb.copy_from_slice(&colors[y / PALETTE_SWATCH_SIZE * 16 + x / PALETTE_SWATCH_SIZE]);
+            }
+        }
+        pixels
+    }
+
+    // the 64 sprites decoded from OAM, in OAM order
+    pub fn oam_entries(&self) -> [OamEntry; SPRITE_COUNT] {
+        std::array::from_fn(|sprite| {
+            let entry = &self.sprite_ram.as_slice()[sprite * 4..(sprite + 1) * 4];
+            OamEntry{y: entry[0], tile: entry[1], attributes: entry[2], x: entry[3]}
+        })
+    }
+
+    /*
+        Debug view of the 64 sprites in OAM order as an RGB image of OAM_VIEW_WIDTH x OAM_VIEW_HEIGHT,
+        8 sprites per row, each in an 8x16 cell. Sprites are drawn with their palette and flips,
+        using the current sprite size and pattern table, transparent pixels show the backdrop.
+     */
+    pub fn render_oam(&self) -> Vec<u8> {
+        let mut pixels = vec![0u8; OAM_VIEW_WIDTH * OAM_VIEW_HEIGHT * 3];
+        let height = self.sprite_height();
+        for (sprite, entry) in self.oam_entries().iter().enumerate() {
+            for row in 0..16 {
+                let y = sprite / 8 * 16 + row;
+                let px = (y * OAM_VIEW_WIDTH + sprite % 8 * 8) * 3;
+                if row >= height {
+                    for rgb in pixels[px..px + 8 * 3].chunks_exact_mut(3) {
+                        rgb.copy_from_slice(self.color(0));
+                    }
+                    continue;
+                }
+                let pattern_row = if entry.flip_vertical() {height - 1 - row} else {row};
+                let pattern_address = self.sprite_pattern_address(entry.tile, pattern_row);
+                let pattern: PatternTable = self.vram[pattern_address..pattern_address + 16].into();
+                for (column, rgb) in pixels[px..px + 8 * 3].chunks_exact_mut(3).enumerate() {
+                    let pixel = pattern.get_pixel((pattern_row % 8, if entry.flip_horizontal() {7 - column} else {column}));
+                    rgb.copy_from_slice(self.color(if pixel == 0 {0} else {0x10 | (entry.palette() << 2) | pixel}));
+                }
+            }
+        }
+        pixels
+    }
+
+    /*
+        Debug view of the nametables $2000, $2400, $2800 and $2C00 as an RGB image of
+        NAMETABLE_VIEW_WIDTH x NAMETABLE_VIEW_HEIGHT, with mirrored nametables showing the same tiles.
+        Tiles use the background pattern table selected by PPUCTRL. The overlay outlines the
+        screen at the scroll position in 't', which during vblank is the start of the next frame.
+     */
+    pub fn render_nametables(&self, scroll_overlay: bool) -> Vec<u8> {
+        let mut pixels = vec![0u8; NAMETABLE_VIEW_WIDTH * NAMETABLE_VIEW_HEIGHT * 3];
+        for (y, row) in pixels.chunks_exact_mut(NAMETABLE_VIEW_WIDTH * 3).enumerate() {
+            for (column, tile_pixels) in row.chunks_exact_mut(8 * 3).enumerate() {
+                // 'v' of the tile, the nametable bits select the quadrant
+                let v = (((y % 8) << 12) | ((y / 240) << 11) | ((column / 32) << 10) | ((y % 240 / 8) << 5) | (column % 32)) as u16;
+                let (low, high, palette) = self.background_tile(v);
+                for (x, rgb) in tile_pixels.chunks_exact_mut(3).enumerate() {
+                    let pixel = ((low >> (7 - x)) & 1) | (((high >> (7 - x)) & 1) << 1);
+                    rgb.copy_from_slice(self.color(if pixel == 0 {0} else {(palette << 2) | pixel}));
+                }
+            }
+        }
+        if scroll_overlay {
+            let t = self.temp_address as usize;
+            let left = ((t >> 10) & 1) * 256 + (t & 0x1f) * 8 + self.fine_x as usize;
+            let top = ((t >> 11) & 1) * 240 + ((t >> 5) & 0x1f) * 8 + ((t >> 12) & 0x07);
+            let mut mark = |x: usize, y: usize| {
+                let px = (y % NAMETABLE_VIEW_HEIGHT * NAMETABLE_VIEW_WIDTH + x % NAMETABLE_VIEW_WIDTH) * 3;
+                pixels[px..px + 3].copy_from_slice(&SCROLL_OVERLAY_COLOR);
+            };
+            // the screen wraps around the edges of the view like scrolling does
+            for x in left..left + 256 {
+                mark(x, top);
+                mark(x, top + 239);
+            }
+            for y in top..top + 240 {
+                mark(left, y);
+                mark(left + 255, y);
+            }
+        }
+        pixels
+    }
+
+    // with both layers hidden the PPU doesn't fetch, evaluate sprites or touch the scroll registers
+    fn rendering_enabled(&self) -> bool {
+        self.ppu_control_2.intersects(PPUControl2::DisplayBackground | PPUControl2::DisplaySprite)
+    }
+
+    // nametable byte of the tile 'v' points to
+    fn fetch_name(&self, v: u16) -> u8 {
+        self.vram[self.vram_index(0x2000 | (v & 0x0fff))]
+    }
+
+    // palette of the tile 'v' points to, each attribute byte covers 4x4 tiles, 2 bits for each 2x2 quadrant
+    fn fetch_palette(&self, v: u16) -> u8 {
+        let attribute = self.vram[self.vram_index(0x23c0 | (v & 0x0c00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07))];
+        (attribute >> (((v >> 4) & 0x04) | (v & 0x02))) & 0x03
+    }
+
+    // low (0) or high (1) pattern plane of tile 'name' in the row the fine y of 'v' selects
+    fn fetch_pattern(&self, v: u16, name: u8, plane: u16) -> u8 {
+        let pattern_address =
+            (if self.ppu_control_1.contains(PPUControl1::BackgroundTable) {PATTERN_TABLE_SIZE as u16} else {0}) +
+            ((name as u16) << 4) + plane * 8 + (v >> 12);
+        self.vram[pattern_address]
+    }
+
+    // pattern planes and palette of the tile 'v' points to, all fetched at once
+    fn background_tile(&self, v: u16) -> (u8, u8, u8) {
+        let name = self.fetch_name(v);
+        (self.fetch_pattern(v, name, 0), self.fetch_pattern(v, name, 1), self.fetch_palette(v))
+    }
+
+    /*
+        One dot of the background pipeline, dots 1-256 fetch the tiles of the current line and
+        dots 321-336 the first two tiles of the next line. A tile takes 8 dots: the nametable,
+        attribute and the two pattern bytes are read on every other dot, then coarse x is incremented
+        and the tile is loaded into the shift registers on the next dot, after they have shifted.
+     */
+    fn background_dot(&mut self, dot: usize) {
+        if dot != 1 && dot != 321 {
+            for register in self.pattern_shift.iter_mut().chain(self.attribute_shift.iter_mut()) {
+                *register <<= 1;
+            }
+            if dot % 8 == 1 {
+                let tile = self.tile_fetch;
+                self.pattern_shift[0] |= tile.low as u16;
+                self.pattern_shift[1] |= tile.high as u16;
+                self.attribute_shift[0] |= if tile.palette & 1 != 0 {0xff} else {0};
+                self.attribute_shift[1] |= if tile.palette & 2 != 0 {0xff} else {0};
+            }
+        }
+        match dot % 8 {
+            1 => self.tile_fetch.name = self.fetch_name(self.vram_address),
+            3 => self.tile_fetch.palette = self.fetch_palette(self.vram_address),
+            5 => self.tile_fetch.low = self.fetch_pattern(self.vram_address, self.tile_fetch.name, 0),
+            7 => self.tile_fetch.high = self.fetch_pattern(self.vram_address, self.tile_fetch.name, 1),
+            0 => self.increment_coarse_x(),
+            _ => (),
+        }
+    }
+
+    // the scanline renderer, draws the whole line from the scroll position latched at its start
+    fn render_line(&mut self, pixels: &mut [u8]) {
+        if !self.rendering_enabled() {
+            for px in 0..LINE_WIDTH {
+                self.write_pixel(pixels, px, self.forced_blank_pixel());
+            }
+            return;
+        }
+        let (mut v, fine_x) = self.line_scroll;
+        let mut tile = self.background_tile(v);
+        for px in 0..LINE_WIDTH {
+            let shift = 7 - (px + fine_x as usize) % 8;
+            // the next tile starts
+            if shift == 7 && px != 0 {
+                v = next_column(v);
+                tile = self.background_tile(v);
+            }
+            let (low, high, palette) = tile;
+            let pixel = ((low >> shift) & 1) | (((high >> shift) & 1) << 1);
+            self.draw_pixel(px, if pixel == 0 {0} else {(palette << 2) | pixel}, pixels);
+        }
+    }
+
+    // combine background color 'color' with the sprite at 'px' and write the result, sets the sprite 0 hit flag
+    fn draw_pixel(&mut self, px: usize, mut color: u8, pixels: &mut [u8]) {
+        // hidden and clipped background pixels can't cover sprites or cause a sprite 0 hit either
+        if !self.ppu_control_2.contains(PPUControl2::DisplayBackground)
+            || px < 8 && !self.ppu_control_2.contains(PPUControl2::BackgroundClip) {
+            color = 0;
+        }
+        // transparent pixels show the backdrop color
+        self.write_pixel(pixels, px, color);
+
+        let sprite = self.sprite_line[px];
+        if sprite == 0 {return}
+        let background_opaque = color != 0;
+        let sprite_clipped = px < 8 && !self.ppu_control_2.contains(PPUControl2::SpriteClip);
+        // sprite 0 hit needs both layers shown at the pixel and never happens at x=255
+        if sprite & SPRITE_ZERO != 0 && background_opaque && !sprite_clipped && px != 255
+            && self.ppu_control_2.contains(PPUControl2::DisplayBackground | PPUControl2::DisplaySprite) {
+            if !self.ppu_status.contains(PPUStatus::SpriteCollision) {self.events.sprite_zero_hit = true}
+            self.ppu_status |= PPUStatus::SpriteCollision;
+        }
+        if sprite_clipped || !self.ppu_control_2.contains(PPUControl2::DisplaySprite) {return}
+        // priority bit places the sprite behind opaque background pixels
+        if sprite & SPRITE_BEHIND_BACKGROUND != 0 && background_opaque {return}
+        self.write_pixel(pixels, px, sprite & 0x1f);
+    }
+
+    // palette index (0-15) of the background pixel at the front of the shift registers, 'fine_x' selects the bit
+    fn background_pixel(&self) -> u8 {
+        let bit = 15 - self.fine_x as u16;
+        let plane = |registers: &[u16; 2]| (((registers[0] >> bit) & 1) | (((registers[1] >> bit) & 1) << 1)) as u8;
+        let pixel = plane(&self.pattern_shift);
+        if pixel == 0 {0} else {(plane(&self.attribute_shift) << 2) | pixel}
+    }
+
+    /*
+        With rendering disabled nothing is fetched and the backdrop color is output, unless 'v'
+        points into the palette. Then the palette entry it points to is shown instead, which
+        games and demos use to draw colors during forced blank.
+     */
+    fn forced_blank_pixel(&self) -> u8 {
+        match self.vram_address & 0x3fff {
+            address @ 0x3f00.. => palette_index(address) as u8,
+            _ => 0,
+        }
+    }
+
+    fn increment_coarse_x(&mut self) {
+        self.vram_address = next_column(self.vram_address);
+    }
+
+    // fine y, then coarse y which wraps into the vertically adjacent nametable after row 29
+    fn increment_y(&mut self) {
+        if self.vram_address & 0x7000 != 0x7000 {
+            self.vram_address += 0x1000;
+            return;
+        }
+        self.vram_address &= !0x7000;
+        let coarse_y = match (self.vram_address >> 5) & 0x1f {
+            29 => {
+                self.vram_address ^= 0x0800;
+                0
+            }
+            // rows 30 and 31 hold attributes, they wrap without switching nametables
+            31 => 0,
+            coarse_y => coarse_y + 1,
+        };
+        self.vram_address = (self.vram_address & !0x03e0) | (coarse_y << 5);
+    }
+
+    fn copy_horizontal(&mut self) {
+        self.vram_address = (self.vram_address & !0x041f) | (self.temp_address & 0x041f);
+    }
+
+    fn copy_vertical(&mut self) {
+        self.vram_address = (self.vram_address & !0x7be0) | (self.temp_address & 0x7be0);
+    }
+
+    fn sprite_height(&self) -> usize {
+        if self.ppu_control_1.contains(PPUControl1::SpriteSize) {16} else {8}
+    }
+
+    // address of the 8x8 tile holding 'row' of a sprite, counted from the top of the unflipped sprite
+    fn sprite_pattern_address(&self, tile: u8, row: usize) -> u16 {
+        if self.sprite_height() == 16 {
+            // 8x16 sprites select the pattern table with bit 0 of the tile index
+            (tile as u16 & 1) * PATTERN_TABLE_SIZE as u16 + (((tile & 0xfe) as u16 + (row / 8) as u16) << 4)
+        } else {
+            (if self.ppu_control_1.contains(PPUControl1::SpritePatternTable) {PATTERN_TABLE_SIZE as u16} else {0}) +
+            ((tile as u16) << 4)
+        }
+    }
+
+    // select the sprites visible on 'line' in OAM order, returns the number of sprites selected
+    // *NOTE: the hardware overflow bug (false positives/negatives) isn't emulated
+    fn evaluate_sprites(&mut self, line: usize) -> usize {
+        let height = self.sprite_height();
+        let mut count = 0;
+        for sprite in 0..SPRITE_COUNT {
+            // sprites are delayed by a line, so y is one less than the first line of the sprite
+            let y = self.sprite_ram[(sprite * 4) as u16] as usize + 1;
+            if line < y || line >= y + height {continue}
+            if count == SPRITES_PER_LINE {
+                self.ppu_status |= PPUStatus::ScanlineSpriteCount;
+                if self.sprite_limit {break}
+            }
+            self.line_sprites[count] = sprite as u8;
+            count += 1;
+        }
+        count
+    }
+
+    // evaluate the sprites of 'line' and fill the sprite line buffer with their pixels, returns the number of sprites
+    fn fetch_sprites(&mut self, line: usize) -> usize {
+        let count = self.evaluate_sprites(line);
+        let height = self.sprite_height();
+        self.sprite_line = [0; LINE_WIDTH];
+        for &sprite in &self.line_sprites[..count] {
+            let base = sprite as u16 * 4;
+            let y = self.sprite_ram[base] as usize + 1;
+            let tile = self.sprite_ram[base + 1];
+            let attributes = self.sprite_ram[base + 2];
+            let x = self.sprite_ram[base + 3] as usize;
+
+            let mut row = line - y;
+            if attributes & 0x80 != 0 {row = height - 1 - row}
+            let pattern_address = self.sprite_pattern_address(tile, row);
+            let pattern: PatternTable = self.vram[pattern_address..pattern_address + 16].into();
+
+            let mut flags = attributes & SPRITE_BEHIND_BACKGROUND;
+            if sprite == 0 {flags |= SPRITE_ZERO}
+            for column in 0..8 {
+                let px = x + column;
+                // the first opaque sprite pixel in OAM order wins, even when it's behind the background
+                if px >= LINE_WIDTH || self.sprite_line[px] != 0 {continue}
+                let pixel = pattern.get_pixel((row % 8, if attributes & 0x40 != 0 {7 - column} else {column}));
+                if pixel == 0 {continue}
+                self.sprite_line[px] = 0x10 | ((attributes & 0x3) << 2) | pixel | flags;
+            }
+        }
+        count
+    }
+
+    /*
+        A12 is bit 12 of the PPU address, set by pattern fetches from $1000. A line is fetched in 8 dot
+        slots of a nametable, an attribute and two pattern fetches, so A12 drops for at least 4 dots
+        between pattern fetches. The MMC3 ignores rises after A12 was low for that short, it only counts
+        one when the previous slot fetched from $0000. Such a rise is reported once per line when the
+        background and the sprites use different pattern tables.
+     */
+    fn a12_fetch(&mut self, high: bool, line: isize, dot: usize) {
+        if high && !self.a12 {
+            self.events.scanline_irq_a12 = true;
+            if let Some(hook) = self.a12_hook.as_mut() {hook(line, dot)}
+        }
+        self.a12 = high;
+    }
+
+    // the background pattern fetches starting on 'dot' 1 or 321
+    fn background_fetch_a12(&mut self, line: isize, dot: usize) {
+        if !self.rendering_enabled() {
+            self.a12 = false;
+            return;
+        }
+        self.a12_fetch(self.ppu_control_1.contains(PPUControl1::BackgroundTable), line, dot + 4);
+    }
+
+    // the sprite pattern fetches of dots 257-320, 'count' sprites were selected for the next line
+    fn sprite_fetch_a12(&mut self, count: usize, line: isize) {
+        if !self.rendering_enabled() {
+            self.a12 = false;
+            return;
+        }
+        for slot in 0..SPRITES_PER_LINE {
+            // empty slots fetch tile $FF, from $1000 for 8x16 sprites
+            let tile = if slot < count {self.sprite_ram[self.line_sprites[slot] as u16 * 4 + 1]} else {0xff};
+            let high = self.sprite_pattern_address(tile, 0) & 0x1000 != 0;
+            self.a12_fetch(high, line, 257 + slot * 8 + 4);
+        }
+    }
+
+    // call 'hook' on every filtered A12 rise, for mappers like the MMC3 which count scanlines with it
+    pub fn set_a12_hook<F>(&mut self, hook: F)
+    where F: FnMut(isize, usize) + 'static {
+        self.a12_hook = Some(Box::new(hook));
+    }
+
+    pub fn clear_a12_hook(&mut self) {
+        self.a12_hook = None;
+    }
+
+    // run the PPU for 'cycles' dots, rendering visible lines into 'buf'
+    pub fn advance<B: FrameBuffer + ?Sized>(&mut self, cycles: usize, buf: &mut B) -> PpuEvents {
+        self.run(cycles, buf);
+        std::mem::take(&mut self.events)
+    }
+
+    fn run<B: FrameBuffer + ?Sized>(&mut self, cycles: usize, buf: &mut B) {
+        const CYCLES_SCANLINE: usize = 341;
+        const SCANLINES_VISIBLE: usize = 240;
+        const SCANLINES_PRERENDER: usize = 1;
+        const IDLE_CYCLES: usize = 1;
+        const RENDER_CYCLES: usize = 256;
+        const SPRITE_FETCH_CYCLES: usize = 64;
+        const PRE_FETCH_CYCLES: usize = 16;
+        const OTHER_FETCH_CYCLES: usize = 4;
+
+        match self.state {
+            PPUState::PreRender(cycle) => {
+                let next = cycle + cycles;
+                // y increment and horizontal copy as on visible lines, then dots 280-304 copy the vertical bits of 't'
+                if cycle <= 1 && next > 1 {self.background_fetch_a12(-1, 1)}
+                if cycle <= 257 && next > 257 {self.sprite_fetch_a12(0, -1)}
+                if cycle <= 321 && next > 321 {self.background_fetch_a12(-1, 321)}
+                if self.rendering_enabled() {
+                    if cycle <= 256 && next > 256 {self.increment_y()}
+                    if cycle <= 257 && next > 257 {self.copy_horizontal()}
+                    if cycle <= 304 && next >= 280 {self.copy_vertical()}
+                    // the first two tiles of line 0
+                    if self.renderer == Renderer::Dot {
+                        for dot in cycle.max(321)..next.min(338) {
+                            self.background_dot(dot);
+                        }
+                    }
+                }
+                // on odd frames with rendering enabled the last dot of the pre-render line is skipped
+                let skip = (self.region.skips_odd_dot() && self.frame_count % 2 == 1 && self.rendering_enabled()) as usize;
+                let pre_render_cycles = SCANLINES_PRERENDER * CYCLES_SCANLINE - skip;
+                if next > pre_render_cycles {
+                    self.state = PPUState::VisibleLines(
+                        0,
+                        PPUScanLineState::Idle(0));
+                    self.run(next - pre_render_cycles, buf);
+                } else {
+                    self.state = PPUState::PreRender(cycle + cycles);
+                }
+            },
+            PPUState::VisibleLines(line, line_state) => {
+                macro_rules! next_state {
+                    ($current: expr, $threshhold: expr, $stay: path, $next: path) => {
+                        if $current > $threshhold {
+                            self.state = PPUState::VisibleLines(line, $next(0));
+                            self.run($current - $threshhold, buf);
+                        } else {
+                            self.state = PPUState::VisibleLines(line, $stay($current));
+                        }
+                    };
+                }
+
+                match line_state {
+                    PPUScanLineState::Idle(cycle) => {
+                        next_state!(cycle + cycles, IDLE_CYCLES, PPUScanLineState::Idle, PPUScanLineState::Render);
+                    }
+                    PPUScanLineState::Render(cycle) => {
+                        if cycle == 0 && cycles > 0 {self.background_fetch_a12(line as isize, 1)}
+                        let pixels = buf.line_mut(line);
+                        let rendering = self.rendering_enabled();
+                        let end = (cycle + cycles).min(RENDER_CYCLES);
+                        match self.renderer {
+                            // one pixel per dot, dot 'px + 1' outputs pixel 'px'
+                            Renderer::Dot => for px in cycle..end {
+                                if !rendering {
+                                    self.write_pixel(pixels, px, self.forced_blank_pixel());
+                                    continue;
+                                }
+                                self.background_dot(px + 1);
+                                self.draw_pixel(px, self.background_pixel(), pixels);
+                            },
+                            Renderer::Scanline => {
+                                if cycle == 0 && end > 0 {self.line_scroll = (self.vram_address, self.fine_x)}
+                                if cycle < RENDER_CYCLES && end == RENDER_CYCLES {self.render_line(pixels)}
+                            }
+                        }
+                        if cycle + cycles > RENDER_CYCLES {
+                            buf.line_complete(line);
+                            if line + 1 == SCANLINES_VISIBLE {
+                                buf.frame_complete();
+                                self.events.frame_complete = true;
+                            }
+                            if rendering {self.increment_y()}
+                        }
+                        next_state!(cycle + cycles, RENDER_CYCLES, PPUScanLineState::Render, PPUScanLineState::SpriteFetch);
+                    }
+                    PPUScanLineState::SpriteFetch(cycle) => {
+                        // the sprites of the next line are evaluated and fetched at once
+                        if cycle == 0 && cycles > 0 {
+                            if !self.rendering_enabled() {
+                                self.sprite_line = [0; LINE_WIDTH];
+                                self.a12 = false;
+                            } else {
+                                self.copy_horizontal();
+                                let count = if line + 1 < SCANLINES_VISIBLE {self.fetch_sprites(line + 1)} else {0};
+                                self.sprite_fetch_a12(count, line as isize);
+                            }
+                        }
+                        next_state!(cycle + cycles, SPRITE_FETCH_CYCLES, PPUScanLineState::SpriteFetch, PPUScanLineState::PreFetch);
+                    }
+                    PPUScanLineState::PreFetch(cycle) => {
+                        if cycle == 0 && cycles > 0 {self.background_fetch_a12(line as isize, 321)}
+                        if self.rendering_enabled() && self.renderer == Renderer::Dot {
+                            for dot in 321 + cycle..321 + (cycle + cycles).min(PRE_FETCH_CYCLES) {
+                                self.background_dot(dot);
+                            }
+                        }
+                        next_state!(cycle + cycles, PRE_FETCH_CYCLES, PPUScanLineState::PreFetch, PPUScanLineState::OtherFetch);
+                    }
+                    PPUScanLineState::OtherFetch(cycle) => {
+                        // the second tile is loaded into the shift registers on dot 337
+                        if cycle == 0 && cycles > 0 && self.rendering_enabled() && self.renderer == Renderer::Dot {
+                            self.background_dot(337);
+                        }
+                        if cycle + cycles > OTHER_FETCH_CYCLES {
+                            if line + 1 >= SCANLINES_VISIBLE {
+                                self.state = PPUState::PostRender(0);
+                            } else {
+                                self.state = PPUState::VisibleLines(
+                                    line + 1,
+                                    PPUScanLineState::Idle(0));
+                            }
+                            self.run(cycle + cycles - OTHER_FETCH_CYCLES, buf);
+                        } else {
+                            self.state = PPUState::VisibleLines(line, PPUScanLineState::OtherFetch(cycle + cycles));
+                        }
+                    }
+                }
+            },
+            PPUState::PostRender(cycle) => {
+                let post_render_cycles = (self.region.post_render_scanlines() + self.extra_scanlines) * CYCLES_SCANLINE;
+                // no fetches until the pre-render line
+                self.a12 = false;
+                if cycle + cycles > post_render_cycles {
+                    self.frame_count += 1;
+                    self.state = PPUState::Vblank(0);
+                    self.run(cycle + cycles - post_render_cycles, buf);
+                } else {
+                    self.state = PPUState::PostRender(cycle + cycles);
+                }
+            }
+            PPUState::Vblank(cycle) => {
+                let next = cycle + cycles;
+                if cycle < 2 && next >= 2 {
+                    if !std::mem::take(&mut self.vblank_suppressed) {
+                        self.ppu_status |= PPUStatus::VBlankIndicator;
+                        if self.ppu_control_1.contains(PPUControl1::IntteruptOnVBlank) && !self.nmi_hijacked {
+                            self.nmi = true;
+                            self.events.nmi = true;
+                        }
+                    }
+                    self.nmi_hijacked = false;
+                }
+                let vblank_cycles = self.region.vblank_scanlines() * CYCLES_SCANLINE;
+                if next > vblank_cycles {
+                    // flags are cleared at dot 1 of the pre-render line, its dot 0 is still counted as vblank
+                    self.ppu_status.remove(PPUStatus::ScanlineSpriteCount | PPUStatus::SpriteCollision | PPUStatus::VBlankIndicator);
+                    // no sprites are evaluated for the first line
+                    self.sprite_line = [0; LINE_WIDTH];
+                    self.warming_up = false;
+                    self.state = PPUState::PreRender(0);
+                    self.run(next - vblank_cycles, buf);
+                } else {
+                    self.state = PPUState::Vblank(next);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPU;
+
+    // RGB of pixel 'x', 'y' of an RGB888 image 'width' pixels wide
+    fn image_pixel(image: &[u8], width: usize, x: usize, y: usize) -> [u8; 3] {
+        image[(y * width + x) * 3..][..3].try_into().unwrap()
+    }
+
+    // RGB of pixel 'x', 'y' of an RGB888 frame
+    fn pixel(frame: &[u8], x: usize, y: usize) -> [u8; 3] {
+        image_pixel(frame, 256, x, y)
+    }
+
+    // advance 'ppu' by the 262 lines of an NTSC frame, returns what was rendered meanwhile
+    fn run_frame(ppu: &mut PPU) -> Vec<u8> {
+        let mut frame = vec![0u8; 256 * 240 * 3];
+        ppu.advance(262 * 341, &mut frame[..]);
+        frame
+    }
+
+    #[test]
+    fn test_extra_scanlines() {
+        const CYCLES_SCANLINE: usize = 341;
+        let mut frame = vec![0u8; 256 * 240 * 3];
+        let mut ppu = PPU::new(vec![]);
+        ppu.set_extra_scanlines(10);
+        // pre-render, visible and post-render lines are followed by the extra lines before vblank
+        ppu.advance((1 + 240 + 1 + 10) * CYCLES_SCANLINE, &mut frame[..]);
+        assert_eq!(ppu.frame_count(), 0);
+        ppu.advance(1, &mut frame[..]);
+        assert_eq!(ppu.frame_count(), 1);
+        ppu.advance(2, &mut frame[..]);
+        assert!(ppu.ppu_status.contains(PPUStatus::VBlankIndicator));
+    }
+
+    #[test]
+    fn test_sprite_limit() {
+        let mut ppu = PPU::new(vec![]);
+        // 10 sprites starting on line 21, the rest off screen
+        for sprite in 0..SPRITE_COUNT as u16 {
+            ppu.sprite_ram[sprite * 4] = if sprite < 10 {20} else {0xf0};
+        }
+        assert_eq!(ppu.evaluate_sprites(20), 0);
+        assert_eq!(ppu.evaluate_sprites(21), SPRITES_PER_LINE);
+        assert!(ppu.ppu_status.contains(PPUStatus::ScanlineSpriteCount));
+
+        ppu.ppu_status = PPUStatus::from_bits_truncate(0);
+        ppu.set_sprite_limit(false);
+        assert_eq!(ppu.evaluate_sprites(28), 10);
+        assert_eq!(&ppu.line_sprites[..10], &[0, 1, 2, 3, 4, 5, 6, 7, 8, 9]);
+        assert!(ppu.ppu_status.contains(PPUStatus::ScanlineSpriteCount));
+        assert_eq!(ppu.evaluate_sprites(29), 0);
+    }
+
+    #[test]
+    fn test_vblank_nmi() {
+        const CYCLES_SCANLINE: usize = 341;
+        let mut frame = vec![0u8; 256 * 240 * 3];
+        let mut ppu = PPU::new(vec![]);
+        ppu.set_ppu_control_1(PPUControl1::IntteruptOnVBlank.bits());
+        ppu.advance(242 * CYCLES_SCANLINE + 2, &mut frame[..]);
+        assert!(ppu.take_nmi());
+        assert!(!ppu.take_nmi());
+        // reading the status clears the vblank flag
+        assert_eq!(ppu.read(0x2002) & 0x80, 0x80);
+        assert_eq!(ppu.read(0x2002) & 0x80, 0);
+
+        // enabling NMI while in vblank raises one immediately
+        run_frame(&mut ppu);
+        ppu.set_ppu_control_1(0);
+        assert!(ppu.take_nmi());
+        ppu.set_ppu_control_1(PPUControl1::IntteruptOnVBlank.bits());
+        assert!(ppu.take_nmi());
+
+        // reading $2002 on the dot the flag is set returns it but cancels the NMI
+        run_frame(&mut ppu);
+        assert_eq!(ppu.position(), (241, 2));
+        assert_eq!(ppu.read(0x2002) & 0x80, 0x80);
+        assert!(!ppu.take_nmi());
+
+        // reading it the dot before hides the flag and skips the NMI for the whole frame
+        ppu.advance(262 * CYCLES_SCANLINE - 1, &mut frame[..]);
+        assert_eq!(ppu.position(), (241, 1));
+        assert_eq!(ppu.dots_until_nmi(), Some(1));
+        assert_eq!(ppu.read(0x2002) & 0x80, 0);
+        assert_eq!(ppu.dots_until_nmi(), None);
+        ppu.advance(10, &mut frame[..]);
+        assert_eq!(ppu.read(0x2002) & 0x80, 0);
+        assert!(!ppu.take_nmi());
+        // the next frame is unaffected
+        run_frame(&mut ppu);
+        assert!(ppu.take_nmi());
+    }
+
+    #[test]
+    fn test_position() {
+        let mut frame = vec![0u8; 256 * 240 * 3];
+        let mut ppu = PPU::new(vec![]);
+        ppu.advance(10, &mut frame[..]);
+        assert_eq!(ppu.position(), (-1, 10));
+        ppu.advance(341, &mut frame[..]);
+        assert_eq!(ppu.position(), (0, 10));
+        ppu.advance(300, &mut frame[..]);
+        assert_eq!(ppu.position(), (0, 310));
+        ppu.advance(241 * 341, &mut frame[..]);
+        assert_eq!(ppu.position(), (241, 310));
+    }
+
+    #[test]
+    fn test_sprite_fetch() {
+        let mut frame = vec![0u8; 256 * 240 * 3];
+        let mut ppu = PPU::new(vec![]);
+        // tile 1 has its left column set in every row, tile 2 is opaque
+        for row in 0..8 {
+            ppu.vram[0x10 + row] = 0x80;
+            ppu.vram[0x20 + row] = 0xff;
+        }
+        // sprite 0 on lines 10-17 flipped horizontally with palette 1, sprite 1 behind the background
+        ppu.sprite_ram.as_slice_mut()[..8].copy_from_slice(&[9, 1, 0x41, 20, 9, 2, 0x22, 24]);
+        ppu.fetch_sprites(10);
+        assert_eq!(ppu.sprite_line[27], 0x15 | SPRITE_ZERO);
+        assert_eq!(ppu.sprite_line[20], 0);
+        // sprite 0 is transparent at 24-26 so sprite 1 shows through
+        assert_eq!(ppu.sprite_line[24..27], [0x19 | SPRITE_BEHIND_BACKGROUND; 3]);
+        ppu.fetch_sprites(18);
+        assert!(ppu.sprite_line.iter().all(|&pixel| pixel == 0));
+
+        // fetched during the sprite fetch period of the previous line
+        ppu.set_ppu_control_2(PPUControl2::DisplaySprite.bits());
+        ppu.advance(341 + 9 * 341 + 258, &mut frame[..]);
+        assert_eq!(ppu.position(), (9, 258));
+        assert_eq!(ppu.sprite_line[27], 0x15 | SPRITE_ZERO);
+    }
+
+    #[test]
+    fn test_sprite_zero_hit() {
+        let mut frame = vec![0u8; 256 * 240 * 3];
+        let mut ppu = PPU::new(vec![]);
+        // background tile 0 is opaque everywhere, sprite 0 uses tile 1 with only its left column set
+        for row in 0..8 {
+            ppu.vram[row] = 0xff;
+            ppu.vram[0x10 + row] = 0x80;
+        }
+        ppu.sprite_ram.as_slice_mut()[..4].copy_from_slice(&[9, 1, 0, 20]);
+        ppu.set_ppu_control_2((PPUControl2::DisplayBackground | PPUControl2::DisplaySprite
+            | PPUControl2::BackgroundClip | PPUControl2::SpriteClip).bits());
+
+        ppu.advance(341 + 10 * 341 + 16, &mut frame[..]);
+        assert!(!ppu.ppu_status.contains(PPUStatus::SpriteCollision));
+        ppu.advance(16, &mut frame[..]);
+        assert!(ppu.ppu_status.contains(PPUStatus::SpriteCollision));
+        // cleared at the start of the pre-render line
+        ppu.advance(251 * 341, &mut frame[..]);
+        assert_eq!(ppu.position(), (-1, 32));
+        assert!(!ppu.ppu_status.contains(PPUStatus::SpriteCollision));
+
+        // no hit with the background hidden, or in the clipped left column
+        ppu.set_ppu_control_2(PPUControl2::DisplaySprite.bits());
+        run_frame(&mut ppu);
+        assert!(!ppu.ppu_status.contains(PPUStatus::SpriteCollision));
+        ppu.sprite_ram[3] = 4;
+        ppu.set_ppu_control_2((PPUControl2::DisplayBackground | PPUControl2::DisplaySprite | PPUControl2::SpriteClip).bits());
+        run_frame(&mut ppu);
+        assert!(!ppu.ppu_status.contains(PPUStatus::SpriteCollision));
+    }
+
+    #[test]
+    fn test_sprite_limit_fetch() {
+        let mut ppu = PPU::new(vec![]);
+        for row in 0..8 {
+            ppu.vram[0x10 + row] = 0xff;
+        }
+        // 9 opaque sprites side by side on lines 21-28
+        for sprite in 0..SPRITE_COUNT as u16 {
+            let entry = if sprite < 9 {[20, 1, 0, sprite as u8 * 8]} else {[0xf0, 0, 0, 0]};
+            ppu.sprite_ram.as_slice_mut()[sprite as usize * 4..][..4].copy_from_slice(&entry);
+        }
+        ppu.fetch_sprites(21);
+        assert!(ppu.sprite_line[..64].iter().all(|&pixel| pixel != 0));
+        assert!(ppu.sprite_line[64..72].iter().all(|&pixel| pixel == 0));
+        assert!(ppu.ppu_status.contains(PPUStatus::ScanlineSpriteCount));
+
+        ppu.set_sprite_limit(false);
+        ppu.fetch_sprites(21);
+        assert!(ppu.sprite_line[..72].iter().all(|&pixel| pixel != 0));
+    }
+
+    #[test]
+    fn test_8x16_sprites() {
+        let mut ppu = PPU::new(vec![]);
+        // odd tile index 3 selects tiles 2 and 3 of the $1000 pattern table
+        for row in 0..8 {
+            ppu.vram[0x1020 + row] = 0x80;
+            ppu.vram[0x1030 + row] = 0x01;
+        }
+        ppu.set_ppu_control_1(PPUControl1::SpriteSize.bits());
+        ppu.sprite_ram.as_slice_mut()[..4].copy_from_slice(&[9, 3, 0, 100]);
+        let opaque = |ppu: &PPU| (ppu.sprite_line[100] != 0, ppu.sprite_line[107] != 0);
+
+        ppu.fetch_sprites(10);
+        assert_eq!(opaque(&ppu), (true, false));
+        ppu.fetch_sprites(25);
+        assert_eq!(opaque(&ppu), (false, true));
+        ppu.fetch_sprites(26);
+        assert_eq!(opaque(&ppu), (false, false));
+
+        // vertical flip swaps the two tiles as well as the rows within them
+        ppu.sprite_ram[2] = 0x80;
+        ppu.fetch_sprites(10);
+        assert_eq!(opaque(&ppu), (false, true));
+        ppu.fetch_sprites(18);
+        assert_eq!(opaque(&ppu), (true, false));
+    }
+
+    #[test]
+    fn test_scroll_registers() {
+        let mut frame = vec![0u8; 256 * 240 * 3];
+        let mut ppu = PPU::new(vec![]);
+        // the write sequence from the nesdev wiki's scrolling page
+        ppu.set_ppu_control_1(0x00);
+        ppu.read(0x2002);
+        ppu.set_scroll(0x7d);
+        assert_eq!((ppu.temp_address, ppu.fine_x, ppu.write_latch), (0x000f, 5, true));
+        ppu.set_scroll(0x5e);
+        assert_eq!((ppu.temp_address, ppu.write_latch), (0x616f, false));
+        ppu.set_vram_address(0x3d);
+        assert_eq!((ppu.temp_address, ppu.vram_address), (0x3d6f, 0));
+        ppu.set_vram_address(0xf0);
+        assert_eq!((ppu.temp_address, ppu.vram_address, ppu.write_latch), (0x3df0, 0x3df0, false));
+
+        // coarse x and y wrap into the neighbouring nametables
+        ppu.vram_address = 0x001f;
+        ppu.increment_coarse_x();
+        assert_eq!(ppu.vram_address, 0x0400);
+        ppu.vram_address = 0x73a0;
+        ppu.increment_y();
+        assert_eq!(ppu.vram_address, 0x0800);
+        ppu.vram_address = 0x73e0;
+        ppu.increment_y();
+        assert_eq!(ppu.vram_address, 0x0000);
+
+        // the pre-render line loads 't' and prefetches two tiles, each line then increments y and restores the horizontal bits
+        ppu.set_ppu_control_2(PPUControl2::DisplayBackground.bits());
+        ppu.set_ppu_control_1(0x01);
+        ppu.set_scroll(0x08);
+        ppu.set_scroll(0x13);
+        assert_eq!(ppu.temp_address, 0x3441);
+        ppu.advance(341, &mut frame[..]);
+        assert_eq!(ppu.vram_address, 0x3443);
+        // 32 coarse x increments wrap back to the same column of the other nametable
+        ppu.advance(257, &mut frame[..]);
+        assert_eq!(ppu.vram_address, 0x3043);
+        ppu.advance(1, &mut frame[..]);
+        assert_eq!(ppu.vram_address, 0x4441);
+
+        // forced blank leaves 'v' alone
+        ppu.set_ppu_control_2(0);
+        ppu.advance(2 * 341, &mut frame[..]);
+        assert_eq!(ppu.vram_address, 0x4441);
+    }
+
+    #[test]
+    fn test_shared_write_latch() {
+        let mut ppu = PPU::new(vec![]);
+        // a $2006 write following a single $2005 write is taken as the second write
+        ppu.set_scroll(0x7d);
+        ppu.set_vram_address(0x21);
+        assert_eq!((ppu.temp_address, ppu.vram_address, ppu.fine_x, ppu.write_latch), (0x0021, 0x0021, 5, false));
+
+        // reading $2002, or one of its mirrors, restarts the sequence
+        ppu.set_vram_address(0x3f);
+        ppu.read(0x3ffa);
+        ppu.set_vram_address(0x23);
+        ppu.set_vram_address(0xc0);
+        assert_eq!(ppu.vram_address, 0x23c0);
+
+        // the first $2006 write drops the top two bits, a $2005 write after it sets the y scroll
+        ppu.set_vram_address(0xff);
+        assert_eq!(ppu.temp_address, 0x3fc0);
+        ppu.set_scroll(0x10);
+        assert_eq!((ppu.temp_address, ppu.vram_address, ppu.fine_x, ppu.write_latch), (0x0c40, 0x23c0, 5, false));
+    }
+
+    #[test]
+    fn test_read_buffer() {
+        let mut ppu = PPU::new(vec![]);
+        ppu.set_mirroring(Mirroring::Vertical);
+        ppu.vram[0x2400] = 0x11;
+        ppu.vram[0x2420] = 0x22;
+        // $2F00
+        ppu.vram[0x2700] = 0x33;
+        ppu.palette_ram[0] = 0x0f;
+
+        // the first read after setting the address returns the stale buffer
+        ppu.set_vram_address(0x24);
+        ppu.set_vram_address(0x00);
+        ppu.read(0x2007);
+        assert_eq!(ppu.peek(0x2007), 0x11);
+        assert_eq!(ppu.read(0x2007), 0x11);
+        assert_eq!(ppu.vram_address, 0x2402);
+
+        // increment by 32 with PPUCTRL bit 2
+        ppu.set_ppu_control_1(PPUControl1::AddressIncrement.bits());
+        ppu.set_vram_address(0x24);
+        ppu.set_vram_address(0x00);
+        ppu.read(0x2007);
+        assert_eq!(ppu.vram_address, 0x2420);
+        assert_eq!(ppu.read(0x2007), 0x11);
+        assert_eq!(ppu.read(0x2007), 0x22);
+
+        // palette reads aren't delayed and fill the buffer from the nametable below
+        ppu.set_vram_address(0x3f);
+        ppu.set_vram_address(0x00);
+        assert_eq!(ppu.read(0x2007), 0x0f);
+        assert_eq!(ppu.read_buffer, 0x33);
+    }
+
+    #[test]
+    fn test_palette_ram() {
+        let mut ppu = PPU::new(vec![]);
+        let write = |ppu: &mut PPU, address: u16, data: u8| {
+            ppu.set_vram_address((address >> 8) as u8);
+            ppu.set_vram_address(address as u8);
+            ppu.write_vram(data);
+        };
+        write(&mut ppu, 0x3f10, 0x21);
+        write(&mut ppu, 0x3f15, 0xff);
+        write(&mut ppu, 0x3fe1, 0x16);
+        // the sprite backdrop entries are the background ones, other entries are separate
+        assert_eq!(ppu.palette_ram[0x00], 0x21);
+        assert_eq!(ppu.palette_ram[0x15], 0x3f);
+        assert_eq!(ppu.palette_ram[0x05], 0);
+        // and the 32 entries repeat up to $3FFF
+        assert_eq!(ppu.palette_ram[0x01], 0x16);
+        ppu.set_vram_address(0x3f);
+        ppu.set_vram_address(0x1c);
+        ppu.palette_ram[0x0c] = 0x2a;
+        assert_eq!(ppu.read(0x2007), 0x2a);
+        // nametable memory below the palette is untouched
+        assert_eq!(ppu.vram[0x3f10], 0);
+    }
+
+    #[test]
+    fn test_load_palette() {
+        let mut ppu = PPU::new(vec![]);
+        assert_eq!(ppu.palette()[0x30], [0xec, 0xee, 0xec]);
+        let mut palette = [0u8; PALETTE_FILE_SIZE];
+        palette[0x16 * 3..0x17 * 3].copy_from_slice(&[0xff, 0x00, 0x00]);
+        ppu.load_palette(&palette);
+        ppu.palette_ram[0] = 0x16;
+        assert_eq!(ppu.color(0), &[0xff, 0x00, 0x00]);
+
+        let path = std::env::temp_dir().join(format!("rust_nes_esp_{}.pal", std::process::id()));
+        let mut file = [0x10u8; 8 * PALETTE_FILE_SIZE];
+        file[PALETTE_FILE_SIZE..].fill(0x20);
+        std::fs::write(&path, file).unwrap();
+        ppu.load_palette_file(&path).unwrap();
+        assert_eq!(ppu.color(0), &[0x10; 3]);
+        ppu.set_ppu_control_2(0x20);
+        assert_eq!(ppu.color(0), &[0x20; 3]);
+        std::fs::write(&path, [0u8; 100]).unwrap();
+        assert!(matches!(ppu.load_palette_file(&path), Err(NesError::FileFormat(_))));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_color_emphasis() {
+        let mut ppu = PPU::new(vec![]);
+        ppu.palette_ram[0] = 0x30;
+        assert_eq!(ppu.color(0), &[0xec, 0xee, 0xec]);
+        // red emphasis darkens green and blue
+        ppu.set_ppu_control_2(0x20);
+        assert_eq!(ppu.color(0), &[0xec, 0xc2, 0xc1]);
+        // with all three set every channel is darkened twice
+        ppu.set_ppu_control_2(0xe0);
+        assert_eq!(ppu.color(0), &[0x9d, 0x9f, 0x9d]);
+        // the PAL PPU has the red and green bits swapped
+        ppu.set_region(Region::Pal);
+        ppu.set_ppu_control_2(0x40);
+        assert_eq!(ppu.emphasis(), 0x01);
+        assert_eq!(ppu.color(0), &[0xec, 0xc2, 0xc1]);
+        ppu.set_ppu_control_2(0x20);
+        assert_eq!(ppu.emphasis(), 0x02);
+        ppu.set_ppu_control_2(0x80);
+        assert_eq!(ppu.emphasis(), 0x04);
+    }
+
+    #[test]
+    fn test_grayscale() {
+        let mut ppu = PPU::new(vec![]);
+        ppu.palette_ram[1] = 0x27;
+        ppu.set_ppu_control_2(PPUControl2::ColorMode.bits());
+        assert_eq!(ppu.color(1), &NTSC_PALETTE[0x20]);
+        // palette reads are masked as well
+        ppu.set_vram_address(0x3f);
+        ppu.set_vram_address(0x01);
+        assert_eq!(ppu.read(0x2007), 0x20);
+        ppu.set_ppu_control_2(0);
+        assert_eq!(ppu.color(1), &NTSC_PALETTE[0x27]);
+    }
+
+    #[test]
+    fn test_left_clipping() {
+        let mut frame = vec![0u8; 256 * 240 * 3];
+        let mut ppu = PPU::new(vec![]);
+        // opaque background, a sprite on tile 1 at x=0 covering the first 8 pixels
+        for row in 0..8 {
+            ppu.vram[row] = 0xff;
+            ppu.vram[0x10 + row] = 0xff;
+        }
+        ppu.palette_ram[..2].copy_from_slice(&[0x0f, 0x16]);
+        ppu.palette_ram[0x11] = 0x2a;
+        ppu.sprite_ram.as_slice_mut()[..8].copy_from_slice(&[0, 1, 0, 0, 0, 1, 0, 8]);
+
+        // both clipped, the backdrop shows
+        ppu.set_ppu_control_2((PPUControl2::DisplayBackground | PPUControl2::DisplaySprite).bits());
+        ppu.advance(341 + 2 * 341, &mut frame[..]);
+        assert_eq!(pixel(&frame, 0, 1), NTSC_PALETTE[0x0f]);
+        assert_eq!(pixel(&frame, 8, 1), NTSC_PALETTE[0x2a]);
+        assert_eq!(pixel(&frame, 16, 1), NTSC_PALETTE[0x16]);
+        assert!(!ppu.ppu_status.contains(PPUStatus::SpriteCollision));
+
+        // only the background clipped
+        ppu.set_ppu_control_2((PPUControl2::DisplayBackground | PPUControl2::DisplaySprite | PPUControl2::SpriteClip).bits());
+        ppu.advance(341, &mut frame[..]);
+        assert_eq!(pixel(&frame, 0, 2), NTSC_PALETTE[0x2a]);
+        assert!(!ppu.ppu_status.contains(PPUStatus::SpriteCollision));
+
+        // only sprites clipped
+        ppu.set_ppu_control_2((PPUControl2::DisplayBackground | PPUControl2::DisplaySprite | PPUControl2::BackgroundClip).bits());
+        ppu.advance(341, &mut frame[..]);
+        assert_eq!(pixel(&frame, 0, 3), NTSC_PALETTE[0x16]);
+        assert!(!ppu.ppu_status.contains(PPUStatus::SpriteCollision));
+    }
+
+    #[test]
+    fn test_rendering_disabled() {
+        let mut frame = vec![0u8; 256 * 240 * 3];
+        let mut ppu = PPU::new(vec![]);
+        for row in 0..8 {
+            ppu.vram[row] = 0xff;
+        }
+        ppu.palette_ram[..2].copy_from_slice(&[0x0f, 0x16]);
+        ppu.sprite_ram.as_slice_mut()[..4].copy_from_slice(&[0, 0, 0, 0]);
+        ppu.set_vram_address(0x23);
+        ppu.set_vram_address(0x45);
+
+        // the backdrop is drawn and 'v' keeps the address set through $2006
+        ppu.advance(341 + 3 * 341, &mut frame[..]);
+        assert!(frame[..256 * 3 * 3].chunks(3).all(|rgb| rgb == NTSC_PALETTE[0x0f]));
+        assert_eq!(ppu.vram_address, 0x2345);
+        assert!(ppu.sprite_line.iter().all(|&pixel| pixel == 0));
+
+        // sprites alone still fetch the background but show the backdrop behind them
+        ppu.set_ppu_control_2((PPUControl2::DisplaySprite | PPUControl2::SpriteClip).bits());
+        ppu.advance(2 * 341, &mut frame[..]);
+        assert_ne!(ppu.vram_address, 0x2345);
+        let line = &frame[4 * 256 * 3..5 * 256 * 3];
+        assert!(line[8 * 3..].chunks(3).all(|rgb| rgb == NTSC_PALETTE[0x0f]));
+        assert!(!ppu.ppu_status.contains(PPUStatus::SpriteCollision));
+    }
+
+    #[test]
+    fn test_nametable_mirroring() {
+        let mut ppu = PPU::new(vec![]);
+        let write = |ppu: &mut PPU, address: u16, data: u8| {
+            ppu.set_vram_address((address >> 8) as u8);
+            ppu.set_vram_address(address as u8);
+            ppu.write_vram(data);
+        };
+        write(&mut ppu, 0x2405, 0x11);
+        write(&mut ppu, 0x2c06, 0x22);
+        assert_eq!((ppu.vram[0x2005], ppu.vram[0x2406]), (0x11, 0x22));
+
+        ppu.set_mirroring(Mirroring::Vertical);
+        write(&mut ppu, 0x2805, 0x33);
+        write(&mut ppu, 0x3c06, 0x44);
+        assert_eq!((ppu.vram[0x2005], ppu.vram[0x2406]), (0x33, 0x44));
+        // the background fetches go through the same mapping
+        ppu.vram[0x2400] = 0x01;
+        ppu.vram[0x1010] = 0x55;
+        ppu.set_ppu_control_1(PPUControl1::BackgroundTable.bits());
+        assert_eq!(ppu.background_tile(0x0c00), (0x55, 0, 0));
+
+        ppu.set_mirroring(Mirroring::SingleScreen(1));
+        write(&mut ppu, 0x2807, 0x55);
+        assert_eq!(ppu.vram[0x2407], 0x55);
+        ppu.set_mirroring(Mirroring::SingleScreen(0));
+        write(&mut ppu, 0x2c07, 0x66);
+        assert_eq!(ppu.vram[0x2007], 0x66);
+
+        ppu.set_mirroring(Mirroring::FourScreen);
+        write(&mut ppu, 0x2c08, 0x77);
+        write(&mut ppu, 0x3808, 0x88);
+        assert_eq!((ppu.vram[0x2c08], ppu.vram[0x2808]), (0x77, 0x88));
+    }
+
+    #[test]
+    fn test_chr_ram() {
+        // write 'data' to $1020 and read it back
+        let write_read = |ppu: &mut PPU, data: u8| {
+            ppu.set_vram_address(0x10);
+            ppu.set_vram_address(0x20);
+            ppu.write_vram(data);
+            ppu.set_vram_address(0x10);
+            ppu.set_vram_address(0x20);
+            ppu.read(0x2007);
+            ppu.read(0x2007)
+        };
+
+        let mut ppu = PPU::new(Vec::new());
+        assert!(ppu.has_chr_ram());
+        assert_eq!(write_read(&mut ppu, 0x5a), 0x5a);
+
+        // CHR ROM can't be written
+        let mut rom = RAM::new::<PATTERN_TABLE_SIZE>();
+        rom.as_slice_mut()[0x20] = 0x11;
+        let mut ppu = PPU::new(vec![rom]);
+        assert!(!ppu.has_chr_ram());
+        assert_eq!(write_read(&mut ppu, 0x5a), 0x11);
+    }
+
+    #[test]
+    fn test_pre_render_flags() {
+        const CYCLES_SCANLINE: usize = 341;
+        let mut frame = vec![0u8; 256 * 240 * 3];
+        let mut ppu = PPU::new(vec![]);
+        ppu.advance(242 * CYCLES_SCANLINE + 2, &mut frame[..]);
+        ppu.ppu_status |= PPUStatus::ScanlineSpriteCount | PPUStatus::SpriteCollision;
+        // dot 0 of the pre-render line is the last vblank dot
+        ppu.advance(20 * CYCLES_SCANLINE - 2, &mut frame[..]);
+        assert_eq!(ppu.peek(0x2002), 0xe0);
+        ppu.advance(1, &mut frame[..]);
+        assert_eq!(ppu.position(), (-1, 1));
+        assert_eq!(ppu.peek(0x2002), 0);
+
+        // the vertical scroll bits of 't' are copied to 'v' during dots 280-304
+        ppu.set_ppu_control_2(PPUControl2::DisplayBackground.bits());
+        ppu.set_scroll(0);
+        ppu.set_scroll(0xff);
+        ppu.advance(278, &mut frame[..]);
+        // only the y increment at dot 256 so far
+        assert_eq!(ppu.vram_address & 0x7be0, 0x1000);
+        ppu.advance(2, &mut frame[..]);
+        assert_eq!(ppu.vram_address & 0x7be0, 0x73e0);
+    }
+
+    #[test]
+    fn test_odd_frame_skip() {
+        const CYCLES_FRAME: usize = 341 * 262;
+        let mut frame = vec![0u8; 256 * 240 * 3];
+        let mut ppu = PPU::new(vec![]);
+        ppu.set_ppu_control_2(PPUControl2::DisplayBackground.bits());
+        // the first frame is even, (261, 0) is dot 0 of the pre-render line
+        run_frame(&mut ppu);
+        assert_eq!((ppu.frame_count, ppu.position()), (1, (261, 0)));
+        // the odd frame is one dot shorter
+        ppu.advance(CYCLES_FRAME - 1, &mut frame[..]);
+        assert_eq!((ppu.frame_count, ppu.position()), (2, (261, 0)));
+        // without rendering every frame has the same length
+        ppu.set_ppu_control_2(0);
+        run_frame(&mut ppu);
+        run_frame(&mut ppu);
+        assert_eq!((ppu.frame_count, ppu.position()), (4, (261, 0)));
+    }
+
+    #[test]
+    fn test_regions() {
+        const CYCLES_SCANLINE: usize = 341;
+        let mut frame = vec![0u8; 256 * 240 * 3];
+        for (region, vblank_line, scanlines) in [(Region::Ntsc, 241, 262), (Region::Pal, 241, 312), (Region::Dendy, 291, 312)] {
+            let mut ppu = PPU::new(vec![]);
+            ppu.set_region(region);
+            assert_eq!(region.scanlines(), scanlines);
+            ppu.set_ppu_control_1(PPUControl1::IntteruptOnVBlank.bits());
+            ppu.set_ppu_control_2(PPUControl2::DisplayBackground.bits());
+            ppu.advance((vblank_line + 1) * CYCLES_SCANLINE + 1, &mut frame[..]);
+            assert_eq!(ppu.position(), (vblank_line as isize, 1));
+            assert_eq!(ppu.dots_until_nmi(), Some(1));
+            ppu.advance(1, &mut frame[..]);
+            assert!(ppu.take_nmi());
+            // the second frame is odd, only NTSC skips a dot
+            ppu.advance((scanlines - vblank_line - 1) * CYCLES_SCANLINE - 2, &mut frame[..]);
+            ppu.advance(scanlines * CYCLES_SCANLINE - (region == Region::Ntsc) as usize, &mut frame[..]);
+            assert_eq!((ppu.frame_count(), ppu.position()), (2, (scanlines as isize - 1, 0)));
+        }
+    }
+
+    #[test]
+    fn test_background_pipeline() {
+        let mut frame = vec![0u8; 256 * 240 * 3];
+        let mut ppu = PPU::new(vec![]);
+        // tile 1 has its leftmost pixel set on the first two rows, and sits in column 20
+        ppu.vram[0x10] = 0x80;
+        ppu.vram[0x11] = 0x80;
+        ppu.vram[0x2000 + 20] = 1;
+        ppu.palette_ram[0] = 0x0f;
+        ppu.palette_ram[1] = 0x16;
+        ppu.set_ppu_control_2((PPUControl2::DisplayBackground | PPUControl2::BackgroundClip).bits());
+        ppu.set_scroll(0x03);
+        ppu.set_scroll(0x00);
+        ppu.advance(341 + 341, &mut frame[..]);
+        // fine x moves the line 3 pixels left
+        assert_eq!(pixel(&frame, 156, 0), NTSC_PALETTE[0x0f]);
+        assert_eq!(pixel(&frame, 157, 0), NTSC_PALETTE[0x16]);
+
+        // moving 'v' to column 10 mid line takes effect after the two tiles already in the pipeline
+        ppu.advance(1 + 100, &mut frame[..]);
+        ppu.set_vram_address(0x10);
+        ppu.set_vram_address(0x0a);
+        ppu.advance(341 - 101, &mut frame[..]);
+        assert_eq!(pixel(&frame, 157, 1), NTSC_PALETTE[0x0f]);
+        assert_eq!(pixel(&frame, 188, 1), NTSC_PALETTE[0x0f]);
+        assert_eq!(pixel(&frame, 189, 1), NTSC_PALETTE[0x16]);
+    }
+
+    #[test]
+    fn test_scanline_renderer() {
+        let mut frames = [vec![0u8; 256 * 240 * 3], vec![0u8; 256 * 240 * 3]];
+        for (renderer, frame) in [Renderer::Dot, Renderer::Scanline].into_iter().zip(frames.iter_mut()) {
+            let mut ppu = PPU::new(vec![]);
+            ppu.set_renderer(renderer);
+            // the setup of test_background_pipeline, plus sprite 0 over the background tile from line 4 on
+            for row in 0..8 {
+                ppu.vram[0x10 + row] = 0x80;
+            }
+            ppu.vram[0x2000 + 20] = 1;
+            ppu.sprite_ram[0u16] = 3;
+            ppu.sprite_ram[1u16] = 1;
+            ppu.sprite_ram[3u16] = 77;
+            ppu.palette_ram[0] = 0x0f;
+            ppu.palette_ram[1] = 0x16;
+            ppu.set_ppu_control_2((PPUControl2::DisplayBackground | PPUControl2::DisplaySprite
+                | PPUControl2::BackgroundClip | PPUControl2::SpriteClip).bits());
+            ppu.set_scroll(0x03);
+            ppu.set_scroll(0x00);
+            ppu.advance(341 + 341 + 1 + 100, &mut frame[..]);
+            ppu.set_vram_address(0x10);
+            ppu.set_vram_address(0x0a);
+            ppu.advance(341 - 101 + 3 * 341, &mut frame[..]);
+            assert!(ppu.ppu_status.contains(PPUStatus::SpriteCollision));
+        }
+        let [dot, scanline] = &frames;
+        assert_eq!(pixel(dot, 157, 0), NTSC_PALETTE[0x16]);
+        assert_eq!(pixel(scanline, 157, 0), NTSC_PALETTE[0x16]);
+        // the scanline renderer applies the mid line write from the next line on
+        assert_eq!(pixel(dot, 189, 1), NTSC_PALETTE[0x16]);
+        assert_eq!(pixel(scanline, 157, 1), NTSC_PALETTE[0x16]);
+        assert_eq!(pixel(scanline, 77, 2), NTSC_PALETTE[0x16]);
+        // lines without register writes come out the same
+        assert_eq!(dot[2 * 256 * 3..5 * 256 * 3], scanline[2 * 256 * 3..5 * 256 * 3]);
+    }
+
+    #[test]
+    fn test_output_formats() {
+        use crate::frame::expand_indexed;
+
+        let mut rgb = vec![0u8; 256 * 240 * 3];
+        let mut rgb_565 = vec![0u8; 256 * 240 * 2];
+        let mut indexed = vec![0u8; 256 * 240];
+        for (format, frame) in [(FrameFormat::Rgb888, &mut rgb), (FrameFormat::Rgb565, &mut rgb_565), (FrameFormat::Indexed8, &mut indexed)] {
+            let mut ppu = PPU::new(vec![]);
+            ppu.set_frame_format(format);
+            ppu.vram[0x10] = 0xf0;
+            ppu.vram[0x2000 + 3] = 1;
+            ppu.palette_ram[0] = 0x0f;
+            ppu.palette_ram[1] = 0x16;
+            // red emphasis
+            ppu.set_ppu_control_2(PPUControl2::DisplayBackground.bits() | 0x20);
+            ppu.advance(341 * 2, &mut frame[..]);
+        }
+        assert_eq!(indexed[20..36], [0x0f, 0x0f, 0x0f, 0x0f, 0x16, 0x16, 0x16, 0x16, 0x0f, 0x0f, 0x0f, 0x0f, 0x0f, 0x0f, 0x0f, 0x0f]);
+        // expanding with the emphasized palette gives the RGB output
+        let mut ppu = PPU::new(vec![]);
+        ppu.set_ppu_control_2(0x20);
+        let mut expanded = vec![0u8; 256 * 3];
+        expand_indexed(&indexed[..256], ppu.output_palette(), &mut expanded);
+        assert_eq!(expanded, rgb[..256 * 3]);
+        // and the RGB565 output is the converted RGB output
+        assert_eq!((rgb565([0xff, 0x00, 0x00]), rgb565([0x00, 0xff, 0x00]), rgb565([0x00, 0x00, 0xff])), ([0xf8, 0x00], [0x07, 0xe0], [0x00, 0x1f]));
+        let converted: Vec<u8> = rgb[..256 * 3].chunks_exact(3).flat_map(|pixel| rgb565(pixel.try_into().unwrap())).collect();
+        assert_eq!(converted, rgb_565[..256 * 2]);
+    }
+
+    #[test]
+    fn test_save_state() {
+        let mut ppu = PPU::new(vec![]);
+        ppu.vram[0x10] = 0xf0;
+        ppu.vram[0x2000 + 3] = 1;
+        ppu.palette_ram[1] = 0x16;
+        ppu.set_ppu_control_2(PPUControl2::DisplayBackground.bits());
+        ppu.set_scroll(0x05);
+        ppu.set_scroll(0x00);
+        // part way through a line on an odd frame, with the write latch set
+        let mut frame = run_frame(&mut ppu);
+        ppu.advance(341 + 100, &mut frame[..]);
+        ppu.set_scroll(0x01);
+        let state = ppu.save_state();
+
+        let mut restored = PPU::new(vec![]);
+        restored.load_state(&state);
+        let frames = [&mut ppu, &mut restored].map(|ppu| {
+            assert_eq!(ppu.position(), (0, 101));
+            ppu.set_scroll(0x00);
+            run_frame(ppu)
+        });
+        assert_eq!(frames[0], frames[1]);
+        assert_eq!((restored.vram_address, restored.temp_address, restored.fine_x), (ppu.vram_address, ppu.temp_address, ppu.fine_x));
+        assert_eq!(restored.frame_count(), 2);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_state_serde() {
+        let mut ppu = PPU::new(vec![]);
+        for row in 0..8 {
+            ppu.vram[row] = 0xf0;
+            ppu.vram[0x10 + row] = 0x3c;
+        }
+        ppu.vram[0x2000 + 40] = 1;
+        ppu.palette_ram[1] = 0x16;
+        ppu.palette_ram[0x11] = 0x2a;
+        // the sprite covers the line the PPU stops on, so its pixels are already fetched
+        ppu.sprite_ram.as_slice_mut()[..4].copy_from_slice(&[15, 1, 0, 150]);
+        ppu.set_ppu_control_2((PPUControl2::DisplayBackground | PPUControl2::DisplaySprite).bits());
+        ppu.set_scroll(3);
+        ppu.set_scroll(0);
+        // stop part way through the next frame
+        let mut frame = run_frame(&mut ppu);
+        ppu.advance(20 * 341 + 100, &mut frame[..]);
+
+        let json = serde_json::to_string(&ppu.save_state()).unwrap();
+        let mut restored = PPU::new(vec![]);
+        restored.load_state(&serde_json::from_str::<PpuState>(&json).unwrap());
+        assert_eq!(restored.position(), ppu.position());
+        assert_eq!((restored.ppu_control_1, restored.ppu_control_2, restored.ppu_status.0), (ppu.ppu_control_1, ppu.ppu_control_2, ppu.ppu_status.0));
+        assert_eq!((restored.vram_address, restored.temp_address, restored.fine_x), (ppu.vram_address, ppu.temp_address, ppu.fine_x));
+        assert_eq!(restored.vram.as_slice(), ppu.vram.as_slice());
+        assert_eq!(restored.sprite_ram.as_slice(), ppu.sprite_ram.as_slice());
+        assert_eq!(restored.palette_ram, ppu.palette_ram);
+        assert_eq!(serde_json::to_string(&restored.save_state()).unwrap(), json);
+
+        // both finish the frame and render the next one the same
+        assert_eq!(run_frame(&mut restored), run_frame(&mut ppu));
+        assert_eq!(restored.frame_count(), ppu.frame_count());
+    }
+
+    #[test]
+    fn test_open_bus() {
+        let mut ppu = PPU::new(vec![]);
+        // write only registers return the last value on the bus
+        ppu.drive_bus(0x5a);
+        ppu.set_ppu_control_1(0x00);
+        assert_eq!(ppu.read(0x2000), 0x5a);
+        assert_eq!(ppu.peek(0x2005), 0x5a);
+        // the low 5 bits of the status come from the bus
+        assert_eq!(ppu.read(0x2002), 0x1a);
+        // reads drive the bus as well
+        ppu.set_spr_ram_address(0x00);
+        ppu.write_spram(0xc3);
+        ppu.set_spr_ram_address(0x00);
+        assert_eq!(ppu.read(0x2004), 0xc3);
+        assert_eq!(ppu.read(0x2006), 0xc3);
+        // the top 2 bits of palette reads come from the bus
+        ppu.set_vram_address(0x3f);
+        ppu.set_vram_address(0x00);
+        assert_eq!(ppu.read(0x2007) & 0xc0, 0xc0);
+
+        // undriven bits decay after about 600ms
+        ppu.drive_bus(0xff);
+        for _ in 0..OPEN_BUS_DECAY_FRAMES - 1 {
+            run_frame(&mut ppu);
+        }
+        assert_eq!(ppu.read(0x2003), 0xff);
+        run_frame(&mut ppu);
+        assert_eq!(ppu.read(0x2003), 0x00);
+    }
+
+    #[test]
+    fn test_introspection() {
+        let mut frame = vec![0u8; 256 * 240 * 3];
+        let mut ppu = PPU::new(vec![]);
+        assert_eq!((ppu.scanline(), ppu.dot()), (-1, 0));
+        ppu.advance(242 * 341 + 12, &mut frame[..]);
+        assert_eq!((ppu.scanline(), ppu.dot(), ppu.frame_count()), (241, 12, 1));
+        assert_eq!(ppu.status(), 0x80);
+        // getters don't clear the vblank flag
+        assert_eq!(ppu.status(), 0x80);
+
+        ppu.set_scroll(0x7d);
+        assert!(ppu.write_latch());
+        ppu.set_scroll(0x5e);
+        assert!(!ppu.write_latch());
+        assert_eq!((ppu.temp_address(), ppu.fine_x()), (0x616f, 0x05));
+        ppu.set_vram_address(0x23);
+        ppu.set_vram_address(0x45);
+        assert_eq!((ppu.vram_address(), ppu.temp_address()), (0x2345, 0x2345));
+        ppu.read(0x2002);
+        assert_eq!(ppu.status(), 0x00);
+    }
+
+    #[test]
+    fn test_render_nametables() {
+        let mut ppu = PPU::new(vec![]);
+        ppu.set_mirroring(Mirroring::Vertical);
+        // tile 1 has its top left pixel set in both planes, it's in the top left of $2000 with palette 1
+        ppu.vram[0x10] = 0x80;
+        ppu.vram[0x18] = 0x80;
+        ppu.vram[0x2000] = 1;
+        ppu.vram[0x23c0] = 0x01;
+        ppu.palette_ram[0] = 0x0f;
+        ppu.palette_ram[7] = 0x30;
+        let image = ppu.render_nametables(false);
+        assert_eq!(image.len(), NAMETABLE_VIEW_WIDTH * NAMETABLE_VIEW_HEIGHT * 3);
+        assert_eq!(image_pixel(&image, NAMETABLE_VIEW_WIDTH, 0, 0), NTSC_PALETTE[0x30]);
+        assert_eq!(image_pixel(&image, NAMETABLE_VIEW_WIDTH, 1, 0), NTSC_PALETTE[0x0f]);
+        // $2800 mirrors $2000, $2400 doesn't
+        assert_eq!(image_pixel(&image, NAMETABLE_VIEW_WIDTH, 0, 240), NTSC_PALETTE[0x30]);
+        assert_eq!(image_pixel(&image, NAMETABLE_VIEW_WIDTH, 256, 0), NTSC_PALETTE[0x0f]);
+
+        // scrolled 16 pixels right and 8 down
+        ppu.set_scroll(0x10);
+        ppu.set_scroll(0x08);
+        let image = ppu.render_nametables(true);
+        assert_eq!(image_pixel(&image, NAMETABLE_VIEW_WIDTH, 0, 0), NTSC_PALETTE[0x30]);
+        assert_eq!(image_pixel(&image, NAMETABLE_VIEW_WIDTH, 16, 8), SCROLL_OVERLAY_COLOR);
+        assert_eq!(image_pixel(&image, NAMETABLE_VIEW_WIDTH, 16 + 255, 8 + 239), SCROLL_OVERLAY_COLOR);
+        assert_eq!(image_pixel(&image, NAMETABLE_VIEW_WIDTH, 17, 9), NTSC_PALETTE[0x0f]);
+        // starting in $2400 the outline wraps around to the left edge
+        ppu.set_ppu_control_1(0x01);
+        ppu.set_scroll(0xf8);
+        ppu.set_scroll(0x00);
+        let image = ppu.render_nametables(true);
+        assert_eq!(image_pixel(&image, NAMETABLE_VIEW_WIDTH, 504, 100), SCROLL_OVERLAY_COLOR);
+        assert_eq!(image_pixel(&image, NAMETABLE_VIEW_WIDTH, 504 + 255 - NAMETABLE_VIEW_WIDTH, 100), SCROLL_OVERLAY_COLOR);
+        assert_eq!(image_pixel(&image, NAMETABLE_VIEW_WIDTH, 504 + 254 - NAMETABLE_VIEW_WIDTH, 100), NTSC_PALETTE[0x0f]);
+    }
+
+    #[test]
+    fn test_render_oam() {
+        let mut ppu = PPU::new(vec![]);
+        // sprite 0 uses tile 1 with palette 2, flipped horizontally
+        ppu.sprite_ram.as_slice_mut()[..4].copy_from_slice(&[0x20, 1, 0x62, 0x30]);
+        ppu.vram[0x10] = 0x80;
+        ppu.palette_ram[0] = 0x0f;
+        ppu.palette_ram[0x19] = 0x16;
+        let entries = ppu.oam_entries();
+        assert_eq!(entries[0], OamEntry{y: 0x20, tile: 1, attributes: 0x62, x: 0x30});
+        assert_eq!((entries[0].palette(), entries[0].behind_background()), (2, true));
+        assert_eq!((entries[0].flip_horizontal(), entries[0].flip_vertical()), (true, false));
+        let image = ppu.render_oam();
+        assert_eq!(image.len(), OAM_VIEW_WIDTH * OAM_VIEW_HEIGHT * 3);
+        assert_eq!(image_pixel(&image, OAM_VIEW_WIDTH, 0, 0), NTSC_PALETTE[0x0f]);
+        assert_eq!(image_pixel(&image, OAM_VIEW_WIDTH, 7, 0), NTSC_PALETTE[0x16]);
+
+        // sprite 9 is in the second cell of the second row, as 8x16 sprite tile 3 is tiles 2 and 3
+        // of the right pattern table, flipped vertically its top row is at the bottom
+        ppu.set_ppu_control_1(PPUControl1::SpriteSize.bits());
+        ppu.sprite_ram.as_slice_mut()[9 * 4..10 * 4].copy_from_slice(&[0, 3, 0x82, 0]);
+        ppu.vram[0x1020] = 0x80;
+        let image = ppu.render_oam();
+        assert_eq!(image_pixel(&image, OAM_VIEW_WIDTH, 8, 16), NTSC_PALETTE[0x0f]);
+        assert_eq!(image_pixel(&image, OAM_VIEW_WIDTH, 8, 16 + 15), NTSC_PALETTE[0x16]);
+    }
+
+    #[test]
+    fn test_palette_view() {
+        let mut ppu = PPU::new(vec![]);
+        for (address, color) in [(0x3f00, 0x0f), (0x3f01, 0x16), (0x3f11, 0x2a), (0x3f14, 0x30)] {
+            ppu.set_vram_address((address >> 8) as u8);
+            ppu.set_vram_address(address as u8);
+            ppu.write_vram(color);
+        }
+        let indices = ppu.palette_indices();
+        assert_eq!(&indices[..2], &[0x0f, 0x16]);
+        assert_eq!(&indices[0x10..0x12], &[0x0f, 0x2a]);
+        // $3F14 mirrors $3F04
+        assert_eq!((indices[0x04], indices[0x14]), (0x30, 0x30));
+        assert_eq!(ppu.palette_colors()[0x11], NTSC_PALETTE[0x2a]);
+        // grayscale applies to the output colors only
+        ppu.set_ppu_control_2(PPUControl2::ColorMode.bits());
+        assert_eq!(ppu.palette_indices()[0x11], 0x2a);
+        assert_eq!(ppu.palette_colors()[0x11], NTSC_PALETTE[0x20]);
+        ppu.set_ppu_control_2(0);
+
+        let image = ppu.render_palette();
+        assert_eq!(image.len(), PALETTE_VIEW_WIDTH * PALETTE_VIEW_HEIGHT * 3);
+        assert_eq!(image_pixel(&image, PALETTE_VIEW_WIDTH, PALETTE_SWATCH_SIZE - 1, PALETTE_SWATCH_SIZE - 1), NTSC_PALETTE[0x0f]);
+        assert_eq!(image_pixel(&image, PALETTE_VIEW_WIDTH, PALETTE_SWATCH_SIZE, 0), NTSC_PALETTE[0x16]);
+        assert_eq!(image_pixel(&image, PALETTE_VIEW_WIDTH, PALETTE_SWATCH_SIZE, PALETTE_SWATCH_SIZE), NTSC_PALETTE[0x2a]);
+    }
+
+    #[test]
+    fn test_render_pattern_table() {
+        let mut ppu = PPU::new(vec![]);
+        // CHR RAM tile $21 of the right table, top left pixel 1 and the pixel right of it 2
+        ppu.set_vram_address(0x12);
+        ppu.set_vram_address(0x10);
+        ppu.write_vram(0x80);
+        ppu.set_vram_address(0x12);
+        ppu.set_vram_address(0x18);
+        ppu.write_vram(0x40);
+        ppu.palette_ram[0] = 0x0f;
+        ppu.palette_ram[0x05] = 0x16;
+        ppu.palette_ram[0x1a] = 0x2a;
+        let image = ppu.render_pattern_table(1, 1);
+        assert_eq!(image.len(), PATTERN_VIEW_SIZE * PATTERN_VIEW_SIZE * 3);
+        assert_eq!(image_pixel(&image, PATTERN_VIEW_SIZE, 8, 16), NTSC_PALETTE[0x16]);
+        assert_eq!(image_pixel(&image, PATTERN_VIEW_SIZE, 10, 16), NTSC_PALETTE[0x0f]);
+        assert_eq!(ppu.render_pattern_table(0, 1), vec![NTSC_PALETTE[0x0f]; PATTERN_VIEW_SIZE * PATTERN_VIEW_SIZE].concat());
+
+        // the sprite palette 6 is the seventh view
+        let width = 8 * PATTERN_VIEW_SIZE;
+        let image = ppu.render_pattern_table_palettes(1);
+        assert_eq!(image.len(), width * PATTERN_VIEW_SIZE * 3);
+        assert_eq!(image_pixel(&image, width, PATTERN_VIEW_SIZE + 8, 16), NTSC_PALETTE[0x16]);
+        assert_eq!(image_pixel(&image, width, 6 * PATTERN_VIEW_SIZE + 9, 16), NTSC_PALETTE[0x2a]);
+    }
+
+    #[test]
+    fn test_events() {
+        let mut frame = vec![0u8; 256 * 240 * 3];
+        let mut ppu = PPU::new(vec![]);
+        ppu.set_ppu_control_1(PPUControl1::IntteruptOnVBlank.bits());
+        assert_eq!(ppu.advance(240 * 341, &mut frame[..]), PpuEvents::default());
+        let events = ppu.advance(341, &mut frame[..]);
+        assert!(events.frame_complete && !events.nmi);
+        let events = ppu.advance(2 * 341 + 2, &mut frame[..]);
+        assert!(events.nmi && !events.frame_complete);
+        assert!(ppu.take_nmi());
+        ppu.advance(19 * 341 - 2, &mut frame[..]);
+        assert_eq!(ppu.position(), (261, 0));
+
+        // sprite 0 overlaps an opaque background row on line 8, sprites use the right pattern table
+        ppu.vram[0x10] = 0xff;
+        ppu.vram[0x1010] = 0xff;
+        ppu.vram[0x2022] = 1;
+        ppu.sprite_ram.as_slice_mut()[..4].copy_from_slice(&[7, 1, 0, 20]);
+        ppu.set_ppu_control_1(PPUControl1::SpritePatternTable.bits());
+        ppu.set_ppu_control_2((PPUControl2::DisplayBackground | PPUControl2::DisplaySprite).bits());
+        // the second frame is odd, its pre-render line is a dot short
+        let events = ppu.advance(340 + 8 * 341, &mut frame[..]);
+        assert!(events.scanline_irq_a12 && !events.sprite_zero_hit);
+        let events = ppu.advance(341, &mut frame[..]);
+        assert!(events.scanline_irq_a12 && events.sprite_zero_hit);
+        // the flag stays set, the event is only reported once
+        assert!(!ppu.advance(341, &mut frame[..]).sprite_zero_hit);
+        // no A12 rise with both layers on the same pattern table
+        ppu.set_ppu_control_1(0);
+        assert!(!ppu.advance(341, &mut frame[..]).scanline_irq_a12);
+    }
+
+    #[test]
+    fn test_sprite_priority() {
+        let mut frame = vec![0u8; 256 * 240 * 3];
+        let mut ppu = PPU::new(vec![]);
+        // solid background tile at x 16-23 of lines 8-15, solid sprite tile 2
+        ppu.vram.as_slice_mut()[0x10..0x18].fill(0xff);
+        ppu.vram.as_slice_mut()[0x20..0x28].fill(0xff);
+        ppu.vram[0x2022] = 1;
+        ppu.palette_ram[0] = 0x0f;
+        ppu.palette_ram[0x01] = 0x16;
+        ppu.palette_ram[0x11] = 0x2a;
+        ppu.palette_ram[0x15] = 0x30;
+        // sprite 0 behind the background at x 20-27, sprite 1 in front of it at x 20-27 and on its own at x 40-47
+        ppu.sprite_ram.as_slice_mut()[..12].copy_from_slice(&[9, 2, 0x20, 20, 9, 2, 0x01, 20, 9, 2, 0x01, 40]);
+        ppu.set_ppu_control_2(0x1e);
+        ppu.advance(341 + 11 * 341, &mut frame[..]);
+        // behind the opaque background the sprite is hidden, in front of the backdrop it shows
+        assert_eq!(pixel(&frame, 20, 10), NTSC_PALETTE[0x16]);
+        assert_eq!(pixel(&frame, 24, 10), NTSC_PALETTE[0x2a]);
+        // sprite 0 wins over sprite 1 even where the background covers it
+        assert_eq!(pixel(&frame, 22, 10), NTSC_PALETTE[0x16]);
+        assert_eq!(pixel(&frame, 40, 10), NTSC_PALETTE[0x30]);
+        assert_eq!(pixel(&frame, 30, 10), NTSC_PALETTE[0x0f]);
+        // a hidden sprite 0 still hits
+        assert!(ppu.ppu_status.contains(PPUStatus::SpriteCollision));
+    }
+
+    #[test]
+    fn test_a12_hook() {
+        use std::cell::RefCell;
+        use std::rc::Rc;
+        let mut ppu = PPU::new(vec![]);
+        let rises = Rc::new(RefCell::new(Vec::new()));
+        let log = rises.clone();
+        ppu.set_a12_hook(move |line, dot| log.borrow_mut().push((line, dot)));
+        ppu.set_ppu_control_2(PPUControl2::DisplayBackground.bits());
+
+        // background from $0000 and sprites from $1000, one rise at the first sprite fetch of each line
+        ppu.set_ppu_control_1(PPUControl1::SpritePatternTable.bits());
+        run_frame(&mut ppu);
+        assert_eq!(rises.borrow().len(), 241);
+        assert_eq!(rises.borrow()[..2], [(-1, 261), (0, 261)]);
+        assert_eq!(rises.borrow()[240], (239, 261));
+
+        // background from $1000, the rise moves to the prefetch, the first fetch after vblank rises too
+        rises.borrow_mut().clear();
+        ppu.set_ppu_control_1(PPUControl1::BackgroundTable.bits());
+        run_frame(&mut ppu);
+        assert_eq!(rises.borrow().len(), 242);
+        assert_eq!(rises.borrow()[..3], [(-1, 5), (-1, 325), (0, 325)]);
+
+        // 8x16 sprites pick the table per tile, empty slots fetch tile $FF from $1000
+        rises.borrow_mut().clear();
+        ppu.sprite_ram.as_slice_mut().fill(0xff);
+        ppu.sprite_ram.as_slice_mut()[..4].copy_from_slice(&[9, 2, 0, 0]);
+        ppu.set_ppu_control_1(PPUControl1::SpriteSize.bits());
+        run_frame(&mut ppu);
+        assert_eq!(rises.borrow()[9..12], [(8, 261), (9, 269), (10, 269)]);
+
+        // no rises when both use the same table or rendering is off
+        rises.borrow_mut().clear();
+        ppu.set_ppu_control_1(0);
+        run_frame(&mut ppu);
+        ppu.set_ppu_control_1(PPUControl1::SpritePatternTable.bits());
+        ppu.set_ppu_control_2(0);
+        run_frame(&mut ppu);
+        assert!(rises.borrow().is_empty());
+        ppu.clear_a12_hook();
+    }
+
+    #[test]
+    fn test_vram_access_increment() {
+        let mut frame = vec![0u8; 256 * 240 * 3];
+        let mut ppu = PPU::new(vec![]);
+        // writes increment like reads, $2006 writes don't
+        ppu.set_vram_address(0x21);
+        assert_eq!(ppu.vram_address, 0);
+        ppu.set_vram_address(0x00);
+        assert_eq!(ppu.vram_address, 0x2100);
+        ppu.write_vram(0x11);
+        ppu.set_ppu_control_1(PPUControl1::AddressIncrement.bits());
+        ppu.write_vram(0x22);
+        ppu.write_vram(0x33);
+        assert_eq!((ppu.vram[0x2100], ppu.vram[0x2101], ppu.vram[0x2121]), (0x11, 0x22, 0x33));
+        assert_eq!(ppu.vram_address, 0x2141);
+
+        // during rendering an access increments coarse x and y, coarse x 31 wraps into the next nametable
+        ppu.set_ppu_control_2(PPUControl2::DisplayBackground.bits());
+        ppu.advance(341 + 10 * 341 + 100, &mut frame[..]);
+        ppu.set_vram_address(0x33);
+        ppu.set_vram_address(0xbf);
+        ppu.read(0x2007);
+        assert_eq!(ppu.vram_address, 0x47a0);
+        ppu.write_vram(0x44);
+        assert_eq!(ppu.vram_address, 0x57a1);
+        assert_eq!(ppu.vram[0x07a0], 0x44);
+        // the normal increment applies again in vblank
+        ppu.advance(240 * 341, &mut frame[..]);
+        let v = ppu.vram_address;
+        ppu.write_vram(0x55);
+        assert_eq!(ppu.vram_address, v + 32);
+    }
+
+    #[test]
+    fn test_warm_up() {
+        let mut frame = vec![0u8; 256 * 240 * 3];
+        let mut ppu = PPU::new(vec![]);
+        assert!(ppu.warming_up());
+        // until the flags are cleared at dot 1 of the next pre-render line, the CPU bus drops the writes
+        for _ in 0..262 {
+            ppu.advance(341, &mut frame[..]);
+            assert!(ppu.warming_up());
+        }
+        assert_eq!(ppu.position(), (261, 0));
+        ppu.advance(1, &mut frame[..]);
+        assert!(!ppu.warming_up());
+
+        ppu.reset();
+        assert!(ppu.warming_up());
+        ppu.set_warm_up(false);
+        assert!(!ppu.warming_up());
+        ppu.reset();
+        assert!(!ppu.warming_up());
+    }
+
+    #[test]
+    fn test_peek_poke() {
+        let mut ppu = PPU::new(vec![]);
+        ppu.set_mirroring(Mirroring::Horizontal);
+        ppu.set_vram_address(0x23);
+        ppu.poke_vram(0x2405, 0x42);
+        assert_eq!(ppu.peek_vram(0x2005), 0x42);
+        assert_eq!(ppu.peek_vram(0x3005), 0x42);
+        ppu.poke_vram(0x0010, 0x99);
+        assert_eq!(ppu.peek_vram(0x4010), 0x99);
+        ppu.poke_vram(0x3f10, 0xff);
+        assert_eq!(ppu.peek_vram(0x3f00), 0x3f);
+        // grayscale only applies to rendering and $2007
+        ppu.set_ppu_control_2(0x01);
+        assert_eq!(ppu.peek_vram(0x3f00), 0x3f);
+        ppu.poke_oam(0xff, 0x17);
+        assert_eq!(ppu.peek_oam(0xff), 0x17);
+        assert_eq!(ppu.sprite_ram.as_slice()[0xff], 0x17);
+
+        // the second half of the address still completes the first write
+        assert_eq!(ppu.vram_address(), 0);
+        assert_eq!(ppu.spr_ram_address, 0);
+        ppu.set_vram_address(0x05);
+        assert_eq!(ppu.vram_address(), 0x2305);
+    }
+
+    #[test]
+    fn test_forced_blank() {
+        for renderer in [Renderer::Dot, Renderer::Scanline] {
+            let mut frame = vec![0u8; 256 * 240];
+            let mut ppu = PPU::new(vec![]);
+            ppu.set_renderer(renderer);
+            ppu.set_frame_format(FrameFormat::Indexed8);
+            ppu.palette_ram[0] = 0x0f;
+            ppu.palette_ram[0x15] = 0x16;
+            // opaque background and sprites which aren't drawn
+            ppu.vram.as_slice_mut()[..0x1000].fill(0xff);
+            ppu.set_vram_address(0x23);
+            ppu.set_vram_address(0x45);
+            let hits = std::rc::Rc::new(std::cell::Cell::new(0));
+            let counter = hits.clone();
+            ppu.set_a12_hook(move |_, _| counter.set(counter.get() + 1));
+            ppu.advance(341 * 100, &mut frame[..]);
+            assert!(frame[..256 * 99].iter().all(|&color| color == 0x0f));
+
+            // v pointing into the palette shows that entry, $3F35 mirrors $3F15
+            ppu.set_vram_address(0x3f);
+            ppu.set_vram_address(0x35);
+            ppu.advance(341 * 200, &mut frame[..]);
+            assert!(frame[256 * 101..].iter().all(|&color| color == 0x16));
+            // nothing was fetched and the scroll wasn't touched
+            assert_eq!(ppu.vram_address(), 0x3f35);
+            assert_eq!(hits.get(), 0);
+            assert_eq!(ppu.status() & 0x40, 0);
         }
     }
 }
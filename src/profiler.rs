@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::fmt;
+
+use crate::opmap::OPCODE_INFO;
+
+/*
+    Execution profiler
+    Counts how often each opcode (and optionally each instruction address) runs and the
+    cycles spent in it, to find the hot loops worth optimizing on the ESP32.
+    Enable it with 'CPU::enable_profiler', the CPU records every executed instruction.
+*/
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct ProfileEntry {
+    pub count: u64,
+    pub cycles: u64,
+}
+
+#[derive(Debug, Clone)]
+pub struct Profiler {
+    opcodes: [ProfileEntry; 256],
+    // per instruction address, only tracked when requested since it costs a hash lookup per instruction
+    addresses: Option<HashMap<u16, ProfileEntry>>,
+}
+
+impl Profiler {
+    pub fn new(per_address: bool) -> Self {
+        Profiler {
+            opcodes: [ProfileEntry::default(); 256],
+            addresses: if per_address {Some(HashMap::new())} else {None},
+        }
+    }
+
+    pub fn record(&mut self, address: u16, opcode: u8, cycles: u32) {
+        let entry = &mut self.opcodes[opcode as usize];
+        entry.count += 1;
+        entry.cycles += cycles as u64;
+        if let Some(ref mut addresses) = self.addresses {
+            let entry = addresses.entry(address).or_default();
+            entry.count += 1;
+            entry.cycles += cycles as u64;
+        }
+    }
+
+    pub fn clear(&mut self) {
+        self.opcodes = [ProfileEntry::default(); 256];
+        if let Some(ref mut addresses) = self.addresses {
+            addresses.clear();
+        }
+    }
+
+    pub fn opcode(&self, opcode: u8) -> ProfileEntry {
+        self.opcodes[opcode as usize]
+    }
+
+    // executed opcodes, most cycles first
+    pub fn opcode_report(&self) -> Vec<(u8, ProfileEntry)> {
+        let mut report: Vec<(u8, ProfileEntry)> = (0..=255u8)
+            .map(|opcode| (opcode, self.opcodes[opcode as usize]))
+            .filter(|(_, entry)| entry.count > 0)
+            .collect();
+        report.sort_by(|a, b| b.1.cycles.cmp(&a.1.cycles).then(a.0.cmp(&b.0)));
+        report
+    }
+
+    // executed instruction addresses, most cycles first, empty unless tracked
+    pub fn address_report(&self) -> Vec<(u16, ProfileEntry)> {
+        let mut report: Vec<(u16, ProfileEntry)> = self.addresses.iter()
+            .flat_map(|addresses| addresses.iter().map(|(&address, &entry)| (address, entry)))
+            .collect();
+        report.sort_by(|a, b| b.1.cycles.cmp(&a.1.cycles).then(a.0.cmp(&b.0)));
+        report
+    }
+}
+
+impl fmt::Display for Profiler {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let total: u64 = self.opcodes.iter().map(|entry| entry.cycles).sum();
+        writeln!(f, "opcode        count       cycles      %")?;
+        for (opcode, entry) in self.opcode_report() {
+            writeln!(
+                f,
+                "{:02X} {:<4} {:>12} {:>12} {:>6.2}",
+                opcode,
+                OPCODE_INFO[opcode as usize].mnemonic,
+                entry.count,
+                entry.cycles,
+                entry.cycles as f64 * 100.0 / total as f64
+            )?;
+        }
+        if self.addresses.is_some() {
+            writeln!(f, "address       count       cycles      %")?;
+            for (address, entry) in self.address_report() {
+                writeln!(
+                    f,
+                    "${:04X}  {:>12} {:>12} {:>6.2}",
+                    address,
+                    entry.count,
+                    entry.cycles,
+                    entry.cycles as f64 * 100.0 / total as f64
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cpu::CPU;
+
+    #[test]
+    fn test_profiler() {
+        /*
+            ldx #$03
+            dex
+            bne $8002
+            kil
+         */
+        let mut cpu = CPU::with_program(vec![0xa2, 0x03, 0xca, 0xd0, 0xfd, 0x02]);
+        cpu.enable_profiler(true);
+        cpu.execute(None);
+
+        let profiler = cpu.profiler().unwrap();
+        assert_eq!(profiler.opcode(0xca), ProfileEntry { count: 3, cycles: 6 });
+        // taken twice, falls through once
+        assert_eq!(profiler.opcode(0xd0), ProfileEntry { count: 3, cycles: 3 + 3 + 2 });
+        let report = profiler.opcode_report();
+        assert_eq!(report.iter().map(|(opcode, _)| *opcode).collect::<Vec<_>>(), vec![0xd0, 0xca, 0xa2, 0x02]);
+        assert_eq!(profiler.address_report()[0], (0x8003, ProfileEntry { count: 3, cycles: 8 }));
+        assert!(profiler.to_string().lines().nth(1).unwrap().starts_with("D0 BNE"));
+
+        cpu.disable_profiler();
+        assert!(cpu.profiler().is_none());
+    }
+}
@@ -0,0 +1,123 @@
+/*
+    Integer upscaling of PPU frames in any FrameFormat, pixels are copied as whole units of
+    'bytes_per_pixel' bytes so the format doesn't matter.
+    Nearest neighbor is the cheap path, a line can be scaled on its own with 'scale_line' as it
+    is pushed to a display. Scale2x (EPX) smooths diagonal edges without blurring, at the cost of
+    comparing each pixel with its four neighbours.
+*/
+
+use crate::frame::{FrameFormat, FRAME_HEIGHT, FRAME_PIXELS, FRAME_WIDTH};
+
+// size of a frame scaled by 'factor' in pixels
+pub fn scaled_size(factor: usize) -> (usize, usize) {
+    (FRAME_WIDTH * factor, FRAME_HEIGHT * factor)
+}
+
+// repeat every pixel of 'line' 'factor' times into 'output'
+pub fn scale_line(line: &[u8], bytes_per_pixel: usize, factor: usize, output: &mut [u8]) {
+    assert_eq!(output.len(), line.len() * factor, "incorrectly sized output");
+    for (pixel, scaled) in line.chunks_exact(bytes_per_pixel).zip(output.chunks_exact_mut(bytes_per_pixel * factor)) {
+        for copy in scaled.chunks_exact_mut(bytes_per_pixel) {
+            copy.copy_from_slice(pixel);
+        }
+    }
+}
+
+// nearest neighbor, every pixel becomes a 'factor' x 'factor' block
+pub fn scale_nearest(frame: &[u8], format: FrameFormat, factor: usize, output: &mut [u8]) {
+    let bytes = format.bytes_per_pixel();
+    assert!(factor > 0, "scale factor of 0");
+    assert_eq!(frame.len(), FRAME_PIXELS * bytes, "frame doesn't match the format");
+    assert_eq!(output.len(), frame.len() * factor * factor, "incorrectly sized output");
+    let line_bytes = FRAME_WIDTH * bytes * factor;
+    for (line, rows) in frame.chunks_exact(FRAME_WIDTH * bytes).zip(output.chunks_exact_mut(line_bytes * factor)) {
+        let (first, copies) = rows.split_at_mut(line_bytes);
+        scale_line(line, bytes, factor, first);
+        for copy in copies.chunks_exact_mut(line_bytes) {
+            copy.copy_from_slice(first);
+        }
+    }
+}
+
+/*
+    Scale2x, doubles the frame like nearest neighbor but fills each corner of a 2x2 block with
+    the neighbouring color where two adjacent neighbours agree, e.g. the top left corner takes
+    the color of the pixel above when it matches the pixel to the left.
+ */
+pub fn scale2x(frame: &[u8], format: FrameFormat, output: &mut [u8]) {
+    let bytes = format.bytes_per_pixel();
+    assert_eq!(frame.len(), FRAME_PIXELS * bytes, "frame doesn't match the format");
+    assert_eq!(output.len(), frame.len() * 4, "incorrectly sized output");
+    // pixels beyond the edges repeat the edge
+    let pixel = |x: usize, y: usize| {
+        let start = (y * FRAME_WIDTH + x) * bytes;
+        &frame[start..start + bytes]
+    };
+    let line_bytes = FRAME_WIDTH * 2 * bytes;
+    for y in 0..FRAME_HEIGHT {
+        let (top, bottom) = output[y * 2 * line_bytes..(y + 1) * 2 * line_bytes].split_at_mut(line_bytes);
+        for x in 0..FRAME_WIDTH {
+            let center = pixel(x, y);
+            let above = pixel(x, y.saturating_sub(1));
+            let below = pixel(x, (y + 1).min(FRAME_HEIGHT - 1));
+            let left = pixel(x.saturating_sub(1), y);
+            let right = pixel((x + 1).min(FRAME_WIDTH - 1), y);
+            let mut corners = [center; 4];
+            if above != below && left != right {
+                if left == above {corners[0] = left}
+                if above == right {corners[1] = right}
+                if left == below {corners[2] = left}
+                if below == right {corners[3] = right}
+            }
+            let start = x * 2 * bytes;
+            top[start..start + bytes].copy_from_slice(corners[0]);
+            top[start + bytes..start + 2 * bytes].copy_from_slice(corners[1]);
+            bottom[start..start + bytes].copy_from_slice(corners[2]);
+            bottom[start + bytes..start + 2 * bytes].copy_from_slice(corners[3]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_scale_nearest() {
+        let mut frame = vec![0u8; FRAME_PIXELS * 2];
+        frame[0..4].copy_from_slice(&[1, 2, 3, 4]);
+        let (width, height) = scaled_size(3);
+        let mut output = vec![0u8; width * height * 2];
+        scale_nearest(&frame, FrameFormat::Rgb565, 3, &mut output);
+        for row in 0..3 {
+            let line = &output[row * width * 2..];
+            assert_eq!(line[..14], [1, 2, 1, 2, 1, 2, 3, 4, 3, 4, 3, 4, 0, 0]);
+        }
+        assert!(output[3 * width * 2..].iter().all(|&byte| byte == 0));
+
+        let mut line = vec![0u8; FRAME_WIDTH * 4];
+        scale_line(&frame[..FRAME_WIDTH], 1, 4, &mut line);
+        assert_eq!(line[..12], [1, 1, 1, 1, 2, 2, 2, 2, 3, 3, 3, 3]);
+    }
+
+    #[test]
+    fn test_scale2x() {
+        // the steps of a diagonal line are filled in
+        let mut frame = vec![0u8; FRAME_PIXELS];
+        frame[2 * FRAME_WIDTH + 2] = 1;
+        frame[3 * FRAME_WIDTH + 3] = 1;
+        let mut output = vec![0u8; FRAME_PIXELS * 4];
+        scale2x(&frame, FrameFormat::Indexed8, &mut output);
+        let row = |y: usize| &output[y * FRAME_WIDTH * 2 + 4..y * FRAME_WIDTH * 2 + 8];
+        assert_eq!(row(4), [1, 1, 0, 0]);
+        assert_eq!(row(5), [1, 1, 1, 0]);
+        assert_eq!(row(6), [0, 1, 1, 1]);
+        assert_eq!(row(7), [0, 0, 1, 1]);
+
+        // without edges it matches nearest neighbor
+        let frame = vec![7u8; FRAME_PIXELS * 3];
+        let mut output = vec![0u8; FRAME_PIXELS * 12];
+        scale2x(&frame, FrameFormat::Rgb888, &mut output);
+        assert!(output.iter().all(|&byte| byte == 7));
+    }
+}
@@ -0,0 +1,163 @@
+use std::collections::VecDeque;
+
+use crate::cpu::CpuState;
+use crate::memory::MemoryState;
+
+/*
+    Machine snapshots and rewind
+    A snapshot holds the CPU, memory and PPU state. Large buffers (RAM, VRAM, OAM) are run length
+    encoded since they are mostly zeros or repeated tiles, which keeps a few seconds of rewind
+    history within the ESP32's PSRAM.
+*/
+
+// run length encoded bytes, stored as (run length, value) pairs
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct Packed {
+    runs: Vec<u8>,
+    len: usize,
+}
+
+impl Packed {
+    pub fn pack(data: &[u8]) -> Self {
+        let mut runs = Vec::new();
+        let mut i = 0;
+        while i < data.len() {
+            let value = data[i];
+            let mut run = 1;
+            while run < u8::MAX as usize && i + run < data.len() && data[i + run] == value {
+                run += 1;
+            }
+            runs.push(run as u8);
+            runs.push(value);
+            i += run;
+        }
+        Packed { runs, len: data.len() }
+    }
+
+    // length of the unpacked data
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    // size of the encoded data in bytes
+    pub fn packed_len(&self) -> usize {
+        self.runs.len()
+    }
+
+    // 'dst' must be exactly 'len()' bytes long
+    pub fn unpack_into(&self, dst: &mut [u8]) {
+        assert_eq!(dst.len(), self.len, "unpacking into a buffer of the wrong size");
+        let mut offset = 0;
+        for pair in self.runs.chunks_exact(2) {
+            let run = pair[0] as usize;
+            dst[offset..offset + run].fill(pair[1]);
+            offset += run;
+        }
+    }
+
+    pub fn unpack(&self) -> Vec<u8> {
+        let mut data = vec![0u8; self.len];
+        self.unpack_into(&mut data);
+        data
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    pub cpu: CpuState,
+    pub memory: MemoryState,
+    // fraction of a PPU dot carried between CPU cycles on PAL
+    pub dot_remainder: usize,
+}
+
+impl Snapshot {
+    // approximate heap and inline size of the snapshot
+    pub fn size(&self) -> usize {
+        std::mem::size_of::<Self>() + self.memory.packed_len()
+    }
+}
+
+// ring buffer of snapshots taken every 'interval' frames, the oldest is dropped when full
+pub struct RewindBuffer {
+    snapshots: VecDeque<Snapshot>,
+    capacity: usize,
+    interval: u64,
+}
+
+impl RewindBuffer {
+    pub fn new(interval: u64, capacity: usize) -> Self {
+        assert!(interval > 0, "rewind interval must be at least one frame");
+        RewindBuffer {
+            snapshots: VecDeque::with_capacity(capacity),
+            capacity,
+            interval,
+        }
+    }
+
+    // true if a snapshot should be taken at the start of 'frame'
+    pub fn is_due(&self, frame: u64) -> bool {
+        self.capacity > 0 && frame.is_multiple_of(self.interval)
+    }
+
+    pub fn push(&mut self, snapshot: Snapshot) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.snapshots.len() == self.capacity {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(snapshot);
+    }
+
+    // the most recent snapshot, removed from the buffer
+    pub fn pop(&mut self) -> Option<Snapshot> {
+        self.snapshots.pop_back()
+    }
+
+    pub fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snapshots.is_empty()
+    }
+
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+    }
+
+    pub fn interval(&self) -> u64 {
+        self.interval
+    }
+
+    // total size of the stored snapshots in bytes
+    pub fn size(&self) -> usize {
+        self.snapshots.iter().map(Snapshot::size).sum()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_packed() {
+        let mut data = vec![0u8; 2048];
+        data[100] = 7;
+        data[101] = 7;
+        data[1000..1300].fill(0xff);
+        let packed = Packed::pack(&data);
+        assert!(packed.packed_len() < 40);
+        assert_eq!(packed.len(), 2048);
+        assert_eq!(packed.unpack(), data);
+
+        let noise: Vec<u8> = (0..=255u8).collect();
+        assert_eq!(Packed::pack(&noise).unpack(), noise);
+        assert!(Packed::pack(&[]).is_empty());
+    }
+}
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::memory::NesError;
+
+/*
+    Symbol tables map CPU addresses to names so tooling can print 'reset:' or 'nmi_handler'
+    instead of raw addresses. Two formats are supported:
+        - FCEUX name lists (.nl), one symbol per line: "$C000#reset#optional comment"
+        - ca65/ld65 debug info (.dbg), from which the labels of 'sym' lines are read:
+          "sym	id=0,name="reset",addrsize=absolute,...,val=0xC000,seg=0,type=lab"
+          Constants (type=equ) and imports (type=imp) aren't addresses and are skipped.
+*/
+
+#[derive(Debug, Clone, Default)]
+pub struct SymbolTable {
+    labels: HashMap<u16, String>,
+}
+
+impl SymbolTable {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    // load a symbol file, the format is chosen by extension ('.nl' or '.dbg')
+    pub fn from_file<P: AsRef<Path>>(path: P) -> Result<Self, NesError> {
+        let mut table = SymbolTable::new();
+        table.load_file(path)?;
+        Ok(table)
+    }
+
+    // add the symbols of another file to this table, later definitions replace earlier ones
+    pub fn load_file<P: AsRef<Path>>(&mut self, path: P) -> Result<(), NesError> {
+        let path = path.as_ref();
+        let contents = std::fs::read_to_string(path)?;
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("nl") => self.load_fceux(&contents),
+            Some("dbg") => self.load_ca65(&contents),
+            _ => Err(NesError::FileFormat("unknown symbol file extension, expected .nl or .dbg")),
+        }
+    }
+
+    pub fn load_fceux(&mut self, contents: &str) -> Result<(), NesError> {
+        for line in contents.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            let mut fields = line.splitn(3, '#');
+            let address = fields.next().unwrap_or_default();
+            let name = fields.next().ok_or(NesError::FileFormat("name list entry is missing a name"))?;
+            // array entries are written as "$0200/10", only the base address is labeled
+            let address = address.split('/').next().unwrap_or_default();
+            let address = address.strip_prefix('$')
+                .and_then(|hex| u16::from_str_radix(hex, 16).ok())
+                .ok_or(NesError::FileFormat("invalid address in name list"))?;
+            if !name.is_empty() {
+                self.insert(address, name);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn load_ca65(&mut self, contents: &str) -> Result<(), NesError> {
+        for line in contents.lines() {
+            let Some(attributes) = line.strip_prefix("sym\t") else {continue};
+            let mut name = None;
+            let mut value = None;
+            let mut kind = None;
+            for attribute in attributes.split(',') {
+                match attribute.split_once('=') {
+                    Some(("name", quoted)) => name = Some(quoted.trim_matches('"')),
+                    Some(("val", number)) => value = Some(number),
+                    Some(("type", symbol_type)) => kind = Some(symbol_type),
+                    _ => (),
+                }
+            }
+            let Some(name) = name else {
+                return Err(NesError::FileFormat("debug info symbol is missing a name"));
+            };
+            // imports have no value, the module defining them has the label
+            let (Some("lab"), Some(value)) = (kind, value) else {continue};
+            let value = value.strip_prefix("0x")
+                .map_or_else(|| value.parse::<u32>(), |hex| u32::from_str_radix(hex, 16))
+                .map_err(|_| NesError::FileFormat("invalid symbol value in debug info"))?;
+            // far labels of other CPUs don't fit the 6502 address space
+            if let Ok(address) = u16::try_from(value) {
+                self.insert(address, name);
+            }
+        }
+        Ok(())
+    }
+
+    pub fn insert(&mut self, address: u16, name: &str) {
+        self.labels.insert(address, name.to_string());
+    }
+
+    pub fn label(&self, address: u16) -> Option<&str> {
+        self.labels.get(&address).map(String::as_str)
+    }
+
+    pub fn address(&self, name: &str) -> Option<u16> {
+        self.labels.iter().find(|(_, label)| *label == name).map(|(address, _)| *address)
+    }
+
+    // the label for 'address' if one exists, otherwise the address formatted as '$XXXX'
+    pub fn format_address(&self, address: u16) -> String {
+        self.label(address).map_or_else(|| format!("${:04X}", address), String::from)
+    }
+
+    pub fn len(&self) -> usize {
+        self.labels.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.labels.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_fceux_name_list() {
+        let mut table = SymbolTable::new();
+        table.load_fceux("$C000#reset#entry point\n$C123#nmi_handler#\n$0200/100#oam_buffer#\n").unwrap();
+        assert_eq!(table.label(0xc000), Some("reset"));
+        assert_eq!(table.label(0xc123), Some("nmi_handler"));
+        assert_eq!(table.label(0x0200), Some("oam_buffer"));
+        assert_eq!(table.address("nmi_handler"), Some(0xc123));
+        assert_eq!(table.format_address(0xc001), "$C001");
+        assert!(table.load_fceux("C000#reset#").is_err());
+    }
+
+    #[test]
+    fn test_ca65_debug_info() {
+        let mut table = SymbolTable::new();
+        table.load_ca65(concat!(
+            "version\tmajor=2,minor=0\n",
+            "sym\tid=0,name=\"reset\",addrsize=absolute,scope=0,def=1,ref=4,val=0xC000,seg=0,type=lab\n",
+            "sym\tid=1,name=\"frame_count\",addrsize=zeropage,scope=0,def=2,val=0x10,type=lab\n",
+            "sym\tid=2,name=\"BIG\",addrsize=absolute,scope=0,def=3,val=0x12345,type=equ\n",
+            "sym\tid=3,name=\"SPRITE_COUNT\",addrsize=zeropage,scope=0,def=5,val=0x8,type=equ\n",
+            "sym\tid=4,name=\"famitone_update\",addrsize=absolute,scope=0,ref=7,exp=12,type=imp\n",
+        )).unwrap();
+        assert_eq!(table.label(0xc000), Some("reset"));
+        assert_eq!(table.label(0x0010), Some("frame_count"));
+        assert_eq!(table.label(0x0008), None);
+        assert_eq!(table.address("famitone_update"), None);
+        assert_eq!(table.len(), 2);
+        assert!(table.load_ca65("sym\tid=5,val=0x8000,type=lab\n").is_err());
+    }
+}
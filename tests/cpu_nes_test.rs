@@ -9,8 +9,47 @@ fn test_nes_execution(){
             return;  // Exit the program
         }
     };
-    // Test all instructions, undocumented instructions start at ~5000 seemingly
+    // Test all instructions, undocumented instructions start at ~5000
     cpu.execute_with_logging(Some(8991), "test_data/nes_test_data/cpu_log.txt");
     println!("Test Result: 0x{:02X}: 0x{:02X}", cpu.memory.read(0x0002), cpu.memory.read(0x0003));
     assert!(cpu.memory.read(0x0002) == 0);
-}
\ No newline at end of file
+    // result of the undocumented instruction tests
+    assert!(cpu.memory.read(0x0003) == 0);
+
+    // the log is a plain diff against the reference log
+    let ours = std::fs::read_to_string("test_data/nes_test_data/cpu_log.txt").expect("missing cpu_log.txt");
+    let expected = std::fs::read_to_string("test_data/nes_test_data/nestest.log").expect("missing nestest.log");
+    for (line, (ours, theirs)) in ours.lines().zip(expected.lines()).enumerate() {
+        assert_eq!(ours, theirs, "line {}", line + 1);
+    }
+    assert_eq!(ours.lines().count(), expected.lines().count());
+}
+#[test]
+fn test_mesen_trace() {
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    let mut cpu = match CPU::from_file_nestest(String::from("test_data/nes_test_data/nestest.nes")) {
+        Ok(cpu) => cpu,
+        Err(e) => {
+            eprintln!("Failed to load NES file: {:?}", e);
+            return;
+        }
+    };
+    let trace = Rc::new(RefCell::new(Vec::new()));
+    let log = trace.clone();
+    cpu.set_trace_hook(move |entry| log.borrow_mut().push(entry.mesen().to_string()));
+    cpu.execute(Some(8991));
+
+    let expected = std::fs::read_to_string("test_data/nes_test_data/nestest.log").expect("missing nestest.log");
+    for (line, (ours, theirs)) in trace.borrow().iter().zip(expected.lines()).enumerate() {
+        // address and raw bytes, nestest.log marks unofficial opcodes with a '*' in the next column
+        assert_eq!(ours[..15], theirs[..15], "line {}", line + 1);
+        // registers, the PPU isn't clocked without a frontend so only the CPU cycle is compared
+        assert_eq!(ours[48..73], theirs[48..73], "line {}", line + 1);
+        assert_eq!(ours.rsplit(' ').next(), theirs.rsplit(' ').next(), "line {}", line + 1);
+    }
+    // official instructions disassemble the same way, nestest.log annotates memory operands with '= value'
+    let ours = &trace.borrow()[0];
+    assert_eq!(ours[..48], expected.lines().next().unwrap()[..48]);
+}
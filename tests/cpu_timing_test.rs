@@ -0,0 +1,126 @@
+use rust_nes_esp::bus::FlatMemory;
+use rust_nes_esp::cpu::{ProcessorStatusFlags, CPU};
+use rust_nes_esp::opmap::{AddressingMode, OPCODE_INFO};
+
+/*
+    Cycle timing of every official opcode against the published 6502 timing table.
+    The table is kept separate from the opcode map on purpose, so a wrong cycle count
+    in a handler or in the map doesn't go unnoticed. 0 marks unofficial opcodes.
+*/
+const CYCLES: [u8; 256] = [
+    7, 6, 0, 0, 0, 3, 5, 0, 3, 2, 2, 0, 0, 4, 6, 0,
+    2, 5, 0, 0, 0, 4, 6, 0, 2, 4, 0, 0, 0, 4, 7, 0,
+    6, 6, 0, 0, 3, 3, 5, 0, 4, 2, 2, 0, 4, 4, 6, 0,
+    2, 5, 0, 0, 0, 4, 6, 0, 2, 4, 0, 0, 0, 4, 7, 0,
+    6, 6, 0, 0, 0, 3, 5, 0, 3, 2, 2, 0, 3, 4, 6, 0,
+    2, 5, 0, 0, 0, 4, 6, 0, 2, 4, 0, 0, 0, 4, 7, 0,
+    6, 6, 0, 0, 0, 3, 5, 0, 4, 2, 2, 0, 5, 4, 6, 0,
+    2, 5, 0, 0, 0, 4, 6, 0, 2, 4, 0, 0, 0, 4, 7, 0,
+    0, 6, 0, 0, 3, 3, 3, 0, 2, 0, 2, 0, 4, 4, 4, 0,
+    2, 6, 0, 0, 4, 4, 4, 0, 2, 5, 2, 0, 0, 5, 0, 0,
+    2, 6, 2, 0, 3, 3, 3, 0, 2, 2, 2, 0, 4, 4, 4, 0,
+    2, 5, 0, 0, 4, 4, 4, 0, 2, 4, 2, 0, 4, 4, 4, 0,
+    2, 6, 0, 0, 3, 3, 5, 0, 2, 2, 2, 0, 4, 4, 6, 0,
+    2, 5, 0, 0, 0, 4, 6, 0, 2, 4, 0, 0, 0, 4, 7, 0,
+    2, 6, 0, 0, 3, 3, 5, 0, 2, 2, 2, 0, 4, 4, 6, 0,
+    2, 5, 0, 0, 0, 4, 6, 0, 2, 4, 0, 0, 0, 4, 7, 0,
+];
+
+// reads taking an extra cycle when indexing crosses a page, writes and read-modify-writes always take it
+const PAGE_CROSS_PENALTY: [u8; 23] = [
+    0x11, 0x19, 0x1d, 0x31, 0x39, 0x3d, 0x51, 0x59, 0x5d, 0x71, 0x79, 0x7d,
+    0xb1, 0xb9, 0xbc, 0xbd, 0xbe, 0xd1, 0xd9, 0xdd, 0xf1, 0xf9, 0xfd,
+];
+
+const PROGRAM: u16 = 0x0400;
+// indexed operands point to $12F0, an index of $0F stays in the page and $10 crosses it
+const BASE: u16 = 0x12f0;
+const POINTER: u8 = 0x10;
+
+// cycles of the instruction 'bytes' at 'address'
+fn run(bytes: &[u8], address: u16, index: u8, status: ProcessorStatusFlags) -> u32 {
+    let mut memory = FlatMemory::new();
+    memory.load(address, bytes);
+    memory.load(POINTER as u16, &BASE.to_le_bytes());
+    let mut cpu = CPU::new(memory);
+    cpu.program_counter = address;
+    cpu.idx_register_x = index;
+    cpu.idx_register_y = index;
+    cpu.processor_status = status;
+    cpu.execute(Some(1))
+}
+
+fn official_opcodes() -> impl Iterator<Item = u8> {
+    (0..=255u8).filter(|&opcode| OPCODE_INFO[opcode as usize].official)
+}
+
+#[test]
+fn test_reference_table() {
+    for opcode in 0..=255u8 {
+        assert_eq!(OPCODE_INFO[opcode as usize].official, CYCLES[opcode as usize] != 0, "opcode {:02X}", opcode);
+    }
+    assert_eq!(official_opcodes().count(), 151);
+}
+
+#[test]
+fn test_instruction_timing() {
+    let mut failures = Vec::new();
+    for opcode in official_opcodes() {
+        let info = OPCODE_INFO[opcode as usize];
+        if info.mode == AddressingMode::Relative {
+            continue;
+        }
+        let bytes = match info.mode.operand_count() {
+            0 => vec![opcode],
+            1 => vec![opcode, POINTER],
+            _ => vec![opcode, BASE as u8, (BASE >> 8) as u8],
+        };
+        let indexed = matches!(info.mode, AddressingMode::AbsoluteX | AddressingMode::AbsoluteY | AddressingMode::ZeroPageYIndirect);
+        for cross in [false, true] {
+            if cross && !indexed {
+                continue;
+            }
+            let penalty = cross && PAGE_CROSS_PENALTY.contains(&opcode);
+            let expected = CYCLES[opcode as usize] as u32 + penalty as u32;
+            let index = if cross {0x10} else {0x0f};
+            let cycles = run(&bytes, PROGRAM, index, ProcessorStatusFlags::empty());
+            if cycles != expected {
+                failures.push(format!("{:02X} {} {:?} page cross: {} took {} cycles, expected {}",
+                    opcode, info.mnemonic, info.mode, cross, cycles, expected));
+            }
+        }
+    }
+    assert!(failures.is_empty(), "timing mismatches:\n{}", failures.join("\n"));
+}
+
+#[test]
+fn test_branch_timing() {
+    let mut failures = Vec::new();
+    for opcode in official_opcodes().filter(|&opcode| OPCODE_INFO[opcode as usize].mode == AddressingMode::Relative) {
+        // bits 7-6 select the flag, bit 5 the value the branch is taken on
+        let flag = [
+            ProcessorStatusFlags::NEGATIVE,
+            ProcessorStatusFlags::OVERFLOW,
+            ProcessorStatusFlags::CARRY,
+            ProcessorStatusFlags::ZERO,
+        ][opcode as usize >> 6];
+        let (taken, not_taken) = match opcode & 0x20 != 0 {
+            true => (flag, ProcessorStatusFlags::empty()),
+            false => (ProcessorStatusFlags::empty(), flag),
+        };
+        let cases = [
+            ("not taken", PROGRAM, 0x04, not_taken, 2),
+            ("taken", PROGRAM, 0x04, taken, 3),
+            ("taken forward across a page", 0x04f0, 0x20, taken, 4),
+            ("taken backward across a page", 0x0500, 0xf0, taken, 4),
+        ];
+        for (case, address, offset, status, expected) in cases {
+            let cycles = run(&[opcode, offset], address, 0, status);
+            if cycles != expected {
+                failures.push(format!("{:02X} {} {} took {} cycles, expected {}",
+                    opcode, OPCODE_INFO[opcode as usize].mnemonic, case, cycles, expected));
+            }
+        }
+    }
+    assert!(failures.is_empty(), "timing mismatches:\n{}", failures.join("\n"));
+}
@@ -0,0 +1,21 @@
+use rust_nes_esp::nes::Nes;
+
+// golden hashes of whole frames, a change means the rendered picture changed
+#[test]
+fn test_nestest_frame_hash() {
+    let mut nes = match Nes::from_file(String::from("test_data/nes_test_data/nestest.nes"), (), (), ()) {
+        Ok(nes) => nes,
+        Err(e) => {
+            eprintln!("Failed to load NES file: {:?}", e);
+            return;
+        }
+    };
+    // the menu is up after a few frames and stays unchanged without input
+    for _ in 0..60 {
+        nes.run_frame();
+    }
+    let menu = nes.frame_hash();
+    nes.run_frame();
+    assert_eq!(nes.frame_hash(), menu);
+    assert_eq!(menu, 0xc43b825f);
+}